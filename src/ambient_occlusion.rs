@@ -0,0 +1,163 @@
+use crate::{
+    tasks::map_in_pool, DirtyChunks, EmptyChunks, ThreadLocalVoxelCache, Voxel, VoxelMap,
+    VoxelTaskPool,
+};
+
+use bevy::prelude::*;
+use building_blocks::prelude::*;
+use fnv::FnvHashMap;
+
+/// Manages the `ChunkAmbientOcclusion` resource by recomputing ambient occlusion for every dirty
+/// chunk. Depends on the `MapIoPlugin`.
+///
+/// Occlusion depends on the neighboring chunks, so edits should use
+/// `VoxelEditor::edit_extent_and_touch_neighbors` to keep the values on chunk boundaries up to date.
+pub struct AmbientOcclusionPlugin<V> {
+    marker: std::marker::PhantomData<V>,
+}
+
+impl<V> Default for AmbientOcclusionPlugin<V> {
+    fn default() -> Self {
+        Self {
+            marker: Default::default(),
+        }
+    }
+}
+
+impl<V> Plugin for AmbientOcclusionPlugin<V>
+where
+    V: Voxel,
+    for<'r> &'r V::TypeInfo: IsEmpty,
+{
+    fn build(&self, app: &mut AppBuilder) {
+        app.insert_resource(ChunkAmbientOcclusion::<V>::default())
+            // Compute occlusion before meshing systems run in UPDATE.
+            .add_system_to_stage(stage::PRE_UPDATE, ambient_occlusion_system::<V>.system())
+            .add_system_to_stage(
+                stage::POST_UPDATE,
+                ambient_occlusion_removal_system::<V>.system(),
+            );
+    }
+}
+
+/// Per-corner ambient occlusion for every chunk, for use by meshers.
+///
+/// Each chunk gets an array with one more point than the chunk along each axis. The value at `p`
+/// counts the occupied voxels among the 8 voxels that share the corner at the minimum of voxel `p`,
+/// i.e. the voxels at `p + (dx, dy, dz)` where each offset is -1 or 0.
+#[derive(Default)]
+pub struct ChunkAmbientOcclusion<V> {
+    chunks: FnvHashMap<Point3i, Array3<u8>>,
+    marker: std::marker::PhantomData<V>,
+}
+
+impl<V> ChunkAmbientOcclusion<V> {
+    pub fn get(&self, chunk_key: &Point3i) -> Option<&Array3<u8>> {
+        self.chunks.get(chunk_key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.chunks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chunks.is_empty()
+    }
+}
+
+const CORNER_VOXEL_OFFSETS: [Point3i; 8] = [
+    PointN([-1, -1, -1]),
+    PointN([0, -1, -1]),
+    PointN([-1, 0, -1]),
+    PointN([0, 0, -1]),
+    PointN([-1, -1, 0]),
+    PointN([0, -1, 0]),
+    PointN([-1, 0, 0]),
+    PointN([0, 0, 0]),
+];
+
+fn ambient_occlusion_system<V>(
+    pool: Res<VoxelTaskPool>,
+    voxel_map: Res<VoxelMap<V>>,
+    local_caches: Res<ThreadLocalVoxelCache<V>>,
+    dirty_chunks: Res<DirtyChunks<V>>,
+    mut occlusion: ResMut<ChunkAmbientOcclusion<V>>,
+) where
+    V: Voxel,
+    for<'r> &'r V::TypeInfo: IsEmpty,
+{
+    let map = &*voxel_map;
+    let local_caches = &*local_caches;
+    let new_chunk_occlusion = map_in_pool(
+        &*pool,
+        dirty_chunks.dirty_chunk_keys.iter().cloned(),
+        |chunk_key| {
+            (
+                chunk_key,
+                compute_chunk_occlusion(chunk_key, map, local_caches),
+            )
+        },
+    );
+
+    for (chunk_key, chunk_occlusion) in new_chunk_occlusion.into_iter() {
+        match chunk_occlusion {
+            Some(chunk_occlusion) => {
+                occlusion.chunks.insert(chunk_key, chunk_occlusion);
+            }
+            None => {
+                occlusion.chunks.remove(&chunk_key);
+            }
+        }
+    }
+}
+
+/// Returns `None` if the chunk doesn't exist.
+fn compute_chunk_occlusion<V>(
+    chunk_key: Point3i,
+    map: &VoxelMap<V>,
+    local_caches: &ThreadLocalVoxelCache<V>,
+) -> Option<Array3<u8>>
+where
+    V: Voxel,
+    for<'r> &'r V::TypeInfo: IsEmpty,
+{
+    let cache_tls = local_caches.get();
+    let reader = map.reader(&cache_tls);
+    reader.get_chunk(chunk_key)?;
+
+    let chunk_extent = reader.indexer.extent_for_chunk_at_key(chunk_key);
+    let padded_extent = Extent3i::from_min_and_max(
+        chunk_extent.minimum - PointN([1; 3]),
+        chunk_extent.max() + PointN([1; 3]),
+    );
+    let mut occupancy = Array3::fill(padded_extent, 0u8);
+    reader.for_each(&padded_extent, |p: Point3i, voxel: V| {
+        if !map.palette.get_voxel_type_info(voxel).is_empty() {
+            *occupancy.get_mut(&p) = 1;
+        }
+    });
+
+    let corner_extent =
+        Extent3i::from_min_and_shape(chunk_extent.minimum, chunk_extent.shape + PointN([1; 3]));
+    let mut chunk_occlusion = Array3::fill(corner_extent, 0u8);
+    chunk_occlusion.for_each_mut(&corner_extent, |corner: Point3i, value: &mut u8| {
+        *value = CORNER_VOXEL_OFFSETS
+            .iter()
+            .map(|offset| occupancy.get(&(corner + *offset)))
+            .sum();
+    });
+
+    Some(chunk_occlusion)
+}
+
+/// Drops the occlusion of chunks that are about to be removed.
+fn ambient_occlusion_removal_system<V>(
+    empty_chunks: Res<EmptyChunks<V>>,
+    mut occlusion: ResMut<ChunkAmbientOcclusion<V>>,
+) where
+    V: Voxel,
+{
+    for chunk_key in empty_chunks.chunk_keys() {
+        occlusion.chunks.remove(chunk_key);
+    }
+}
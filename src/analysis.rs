@@ -0,0 +1,386 @@
+use crate::{
+    ChunkCacheConfig, ChunkCacheStats, EvictionPolicy, MapIoFrameStats, Voxel, VoxelMap,
+    VoxelTaskPool,
+};
+
+use bevy::prelude::*;
+use building_blocks::prelude::*;
+use fnv::FnvHashMap;
+use std::fmt;
+
+/// Records the access and edit patterns of the `MapIoPlugin` during a play session, so the
+/// `MapIoAnalysis` resource can recommend a better configuration. Depends on the `MapIoPlugin`.
+///
+/// Recording is cheap, but it's only intended for tuning sessions.
+pub struct MapIoAnalysisPlugin<V> {
+    marker: std::marker::PhantomData<V>,
+}
+
+impl<V> Default for MapIoAnalysisPlugin<V> {
+    fn default() -> Self {
+        Self {
+            marker: Default::default(),
+        }
+    }
+}
+
+impl<V> Plugin for MapIoAnalysisPlugin<V>
+where
+    V: Voxel,
+{
+    fn build(&self, app: &mut AppBuilder) {
+        app.insert_resource(MapIoAnalysis::<V>::default())
+            // The frame stats are written in the LAST stage, so sample them at the start of the
+            // following frame.
+            .add_system_to_stage(stage::FIRST, map_io_analysis_system::<V>.system());
+    }
+}
+
+/// The most chunks whose misses are counted separately, so long sessions don't grow without bound.
+const MAX_TRACKED_MISSED_CHUNKS: usize = 100000;
+
+/// Aggregated `MapIoFrameStats` and `ChunkCacheStats` for the current session.
+///
+/// Only the lookups of the `VoxelReader` are recorded, like in the `ChunkCacheStats`.
+#[derive(Clone, Debug, Default)]
+pub struct MapIoAnalysis<V> {
+    /// Set this to stop recording, e.g. during loading screens.
+    pub paused: bool,
+    num_frames: usize,
+    num_frames_with_edits: usize,
+    num_frames_with_compression: usize,
+    num_frames_at_compression_limit: usize,
+    total_edited_voxels: u64,
+    total_edited_chunks: u64,
+    total_dirty_chunks: u64,
+    total_compressed_chunks: u64,
+    peak_cached_chunks: usize,
+    total_lookups: u64,
+    total_misses: u64,
+    // Chunks that missed more than once were evicted and read again.
+    total_repeat_misses: u64,
+    // Bounded by `MAX_TRACKED_MISSED_CHUNKS`.
+    chunk_misses: FnvHashMap<Point3i, u32>,
+    // The `ChunkCacheStats` counters as of the previous frame.
+    last_hits: u64,
+    last_misses: u64,
+    chunk_shape: Option<Point3i>,
+    cache_config: Option<ChunkCacheConfig<V>>,
+    marker: std::marker::PhantomData<V>,
+}
+
+impl<V> MapIoAnalysis<V>
+where
+    V: Voxel,
+{
+    pub fn num_frames(&self) -> usize {
+        self.num_frames
+    }
+
+    /// The fraction of recorded frames on which any chunks were edited.
+    pub fn edit_frame_ratio(&self) -> f32 {
+        self.num_frames_with_edits as f32 / self.num_frames.max(1) as f32
+    }
+
+    /// The average number of dirty chunks for each edited chunk. This grows with the number of
+    /// edits that touch neighboring chunks.
+    pub fn dirty_chunks_per_edited_chunk(&self) -> Option<f32> {
+        if self.total_edited_chunks == 0 {
+            return None;
+        }
+
+        Some(self.total_dirty_chunks as f32 / self.total_edited_chunks as f32)
+    }
+
+    /// The fraction of chunk lookups that found the chunk decompressed.
+    pub fn hit_ratio(&self) -> Option<f32> {
+        if self.total_lookups == 0 {
+            return None;
+        }
+
+        Some(1.0 - self.total_misses as f32 / self.total_lookups as f32)
+    }
+
+    /// The fraction of misses that were of chunks that had already missed before, i.e. chunks that
+    /// were evicted and then read again. High values mean the same areas are revisited.
+    pub fn repeat_miss_ratio(&self) -> Option<f32> {
+        if self.total_misses == 0 {
+            return None;
+        }
+
+        Some(self.total_repeat_misses as f32 / self.total_misses as f32)
+    }
+
+    /// The number of distinct chunks that lookups missed. To bound memory, chunks that only missed
+    /// once are forgotten after 100000 chunks have missed, so this is a lower bound.
+    pub fn num_missed_chunks(&self) -> usize {
+        self.chunk_misses.len()
+    }
+
+    /// Clears everything recorded so far.
+    pub fn reset(&mut self) {
+        *self = Self {
+            paused: self.paused,
+            last_hits: self.last_hits,
+            last_misses: self.last_misses,
+            ..Default::default()
+        };
+    }
+
+    fn record(
+        &mut self,
+        stats: &MapIoFrameStats<V>,
+        cache_stats: &ChunkCacheStats<V>,
+        chunk_shape: Point3i,
+        cache_config: ChunkCacheConfig<V>,
+        num_threads: usize,
+    ) {
+        self.record_lookups(cache_stats);
+        self.num_frames += 1;
+        self.chunk_shape = Some(chunk_shape);
+        self.cache_config = Some(cache_config);
+
+        if stats.edited_chunks > 0 {
+            self.num_frames_with_edits += 1;
+        }
+        if stats.compressed_chunks > 0 {
+            self.num_frames_with_compression += 1;
+        }
+        if stats.compressed_chunks
+            >= num_threads * cache_config.max_chunks_compressed_per_frame_per_thread
+        {
+            self.num_frames_at_compression_limit += 1;
+        }
+        self.total_edited_voxels += stats.edited_voxels as u64;
+        self.total_edited_chunks += stats.edited_chunks as u64;
+        self.total_dirty_chunks += stats.dirty_chunks as u64;
+        self.total_compressed_chunks += stats.compressed_chunks as u64;
+        self.peak_cached_chunks = self
+            .peak_cached_chunks
+            .max(stats.cached_chunks + stats.compressed_chunks);
+    }
+
+    fn record_lookups(&mut self, cache_stats: &ChunkCacheStats<V>) {
+        // The counters start over when the stats are reset.
+        let delta = |now: u64, last: u64| if now >= last { now - last } else { now };
+        let hits = delta(cache_stats.hits, self.last_hits);
+        let misses = delta(cache_stats.misses, self.last_misses);
+        self.last_hits = cache_stats.hits;
+        self.last_misses = cache_stats.misses;
+        self.total_lookups += hits + misses;
+        self.total_misses += misses;
+
+        if self.chunk_misses.len() >= MAX_TRACKED_MISSED_CHUNKS {
+            // Chunks that were read again are the ones that matter for repeat misses.
+            self.chunk_misses.retain(|_, count| *count > 1);
+            if self.chunk_misses.len() >= MAX_TRACKED_MISSED_CHUNKS / 2 {
+                self.chunk_misses.clear();
+            }
+        }
+        for chunk_key in cache_stats.last_frame_missed_chunk_keys() {
+            let count = self.chunk_misses.entry(*chunk_key).or_insert(0);
+            if *count > 0 {
+                self.total_repeat_misses += 1;
+            }
+            *count += 1;
+        }
+    }
+
+    /// The fraction of voxels in edited chunks that were actually covered by edits. Low values mean
+    /// that small edits are paying for merging (and post-processing) much larger chunks.
+    pub fn edit_efficiency(&self) -> Option<f32> {
+        let chunk_shape = self.chunk_shape?;
+        if self.total_edited_chunks == 0 {
+            return None;
+        }
+        let chunk_volume = (chunk_shape.x() * chunk_shape.y() * chunk_shape.z()) as f64;
+
+        Some(
+            (self.total_edited_voxels as f64 / (self.total_edited_chunks as f64 * chunk_volume))
+                .min(1.0) as f32,
+        )
+    }
+
+    /// Produces a recommended configuration from everything recorded so far. Returns `None` if
+    /// nothing has been recorded yet.
+    pub fn recommend(&self) -> Option<MapIoRecommendation<V>> {
+        let chunk_shape = self.chunk_shape?;
+        let cache_config = self.cache_config?;
+        if self.num_frames == 0 {
+            return None;
+        }
+
+        let mut notes = Vec::new();
+
+        // Chunk shape: small edits in big chunks waste merge and post-processing time, while big
+        // edits in small chunks waste per-chunk overhead.
+        let mut edge = chunk_shape.x();
+        if let Some(efficiency) = self.edit_efficiency() {
+            if efficiency < 0.05 && edge > 8 {
+                edge /= 2;
+                notes.push(format!(
+                    "Edits only covered {:.1}% of the chunks they touched; smaller chunks should \
+                    reduce merge and post-processing work.",
+                    100.0 * efficiency
+                ));
+            } else if efficiency > 0.5 && edge < 64 {
+                edge *= 2;
+                notes.push(format!(
+                    "Edits covered {:.1}% of the chunks they touched; larger chunks should reduce \
+                    per-chunk overhead.",
+                    100.0 * efficiency
+                ));
+            }
+        }
+
+        // Cache size: avoid thrashing the compressor, but don't reserve memory that's never used.
+        let compression_frame_ratio =
+            self.num_frames_with_compression as f32 / self.num_frames as f32;
+        let hit_ratio = self.hit_ratio().unwrap_or(1.0);
+        let repeat_miss_ratio = self.repeat_miss_ratio().unwrap_or(0.0);
+        let mut max_cached_chunks = cache_config.max_cached_chunks;
+        if hit_ratio < 0.9 && repeat_miss_ratio > 0.5 {
+            max_cached_chunks = self.peak_cached_chunks + self.peak_cached_chunks / 2;
+            notes.push(format!(
+                "Only {:.1}% of chunk lookups hit the cache, and {:.1}% of the misses were of \
+                chunks that had been read before; a larger cache should keep them decompressed.",
+                100.0 * hit_ratio,
+                100.0 * repeat_miss_ratio
+            ));
+        } else if compression_frame_ratio > 0.1 {
+            max_cached_chunks = self.peak_cached_chunks + self.peak_cached_chunks / 4;
+            notes.push(format!(
+                "Chunks were compressed on {:.1}% of frames; a larger cache should avoid \
+                repeatedly decompressing the same chunks.",
+                100.0 * compression_frame_ratio
+            ));
+        } else if self.num_frames_with_compression == 0
+            && self.total_misses == 0
+            && self.peak_cached_chunks < cache_config.max_cached_chunks / 2
+        {
+            max_cached_chunks = (2 * self.peak_cached_chunks).max(1);
+            notes.push(format!(
+                "At most {} chunks were ever cached; the cache limit can be lowered.",
+                self.peak_cached_chunks
+            ));
+        }
+
+        let mut max_chunks_compressed_per_frame_per_thread =
+            cache_config.max_chunks_compressed_per_frame_per_thread;
+        if self.num_frames_at_compression_limit * 4 > self.num_frames_with_compression.max(1) {
+            max_chunks_compressed_per_frame_per_thread *= 2;
+            notes.push(
+                "The compressor frequently hit its per-frame limit, so the cache kept growing."
+                    .to_string(),
+            );
+        }
+
+        // Eviction policy: frequency counts only pay off when the same chunks are read again.
+        let mut eviction_policy = cache_config.eviction_policy;
+        if self.total_misses > 0 {
+            if repeat_miss_ratio > 0.5 && eviction_policy == EvictionPolicy::Lru {
+                eviction_policy = EvictionPolicy::Lfu;
+                notes.push(format!(
+                    "{:.1}% of the misses were of chunks that had been read before; the Lfu \
+                    eviction policy should keep revisited chunks cached.",
+                    100.0 * repeat_miss_ratio
+                ));
+            } else if repeat_miss_ratio < 0.1 && eviction_policy == EvictionPolicy::Lfu {
+                eviction_policy = EvictionPolicy::Lru;
+                notes.push(format!(
+                    "Only {:.1}% of the misses were of chunks that had been read before; chunks \
+                    are rarely revisited, so the Lru eviction policy is enough.",
+                    100.0 * repeat_miss_ratio
+                ));
+            }
+        }
+
+        // Codec: trade compression ratio for speed when lots of chunks are compressed every frame.
+        let avg_compressed_per_frame = self.total_compressed_chunks as f32 / self.num_frames as f32;
+        let mut compression_level = cache_config.compression_level;
+        if avg_compressed_per_frame > 100.0 && compression_level > 1 {
+            compression_level = 1;
+            notes.push(format!(
+                "An average of {:.1} chunks were compressed per frame; a lower compression level \
+                should reduce latency.",
+                avg_compressed_per_frame
+            ));
+        }
+
+        Some(MapIoRecommendation {
+            chunk_shape: PointN([edge; 3]),
+            cache_config: ChunkCacheConfig {
+                max_cached_chunks,
+                max_chunks_compressed_per_frame_per_thread,
+                eviction_policy,
+                compression_level,
+                marker: Default::default(),
+            },
+            notes,
+        })
+    }
+}
+
+/// A suggested `MapIoPlugin` configuration. The `Display` impl produces a human-readable report.
+#[derive(Clone, Debug)]
+pub struct MapIoRecommendation<V> {
+    pub chunk_shape: Point3i,
+    pub cache_config: ChunkCacheConfig<V>,
+    /// The reasoning behind each change from the current configuration.
+    pub notes: Vec<String>,
+}
+
+impl<V> fmt::Display for MapIoRecommendation<V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Recommended MapIoPlugin configuration:")?;
+        writeln!(f, "  chunk shape: {:?}", self.chunk_shape.0)?;
+        writeln!(
+            f,
+            "  max cached chunks: {}",
+            self.cache_config.max_cached_chunks
+        )?;
+        writeln!(
+            f,
+            "  max chunks compressed per frame per thread: {}",
+            self.cache_config.max_chunks_compressed_per_frame_per_thread
+        )?;
+        writeln!(
+            f,
+            "  eviction policy: {:?}",
+            self.cache_config.eviction_policy
+        )?;
+        writeln!(
+            f,
+            "  compression level: {}",
+            self.cache_config.compression_level
+        )?;
+        for note in self.notes.iter() {
+            writeln!(f, "  - {}", note)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn map_io_analysis_system<V>(
+    frame_stats: Res<MapIoFrameStats<V>>,
+    cache_stats: Res<ChunkCacheStats<V>>,
+    cache_config: Res<ChunkCacheConfig<V>>,
+    pool: Res<VoxelTaskPool>,
+    voxel_map: Res<VoxelMap<V>>,
+    mut analysis: ResMut<MapIoAnalysis<V>>,
+) where
+    V: Voxel,
+{
+    if analysis.paused {
+        return;
+    }
+
+    analysis.record(
+        &*frame_stats,
+        &*cache_stats,
+        voxel_map.voxels.indexer.chunk_shape(),
+        *cache_config,
+        pool.thread_num(),
+    );
+}
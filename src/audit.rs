@@ -0,0 +1,250 @@
+use crate::{BrickAtlas, ChunkColumns, ChunkOctrees, SpilledChunks, Voxel, VoxelMap};
+
+use bevy::prelude::*;
+use building_blocks::prelude::*;
+use fnv::FnvHashSet;
+use std::fmt;
+
+/// Cross-checks the chunk keys tracked by other subsystems against the chunks in the `VoxelMap`.
+/// Depends on the `MapIoPlugin`.
+///
+/// Lifecycle bugs, like forgetting to clean up after a removed chunk, usually show up as slow leaks
+/// or ghost colliders. An audit reports them directly as orphaned or missing chunk keys.
+///
+/// Audits only run when requested with `ChunkAudit::request`, because they visit every chunk key.
+/// Each voxel type has its own `ChunkAudit<V>`.
+pub struct ChunkAuditPlugin<V> {
+    marker: std::marker::PhantomData<V>,
+}
+
+impl<V> Default for ChunkAuditPlugin<V> {
+    fn default() -> Self {
+        Self {
+            marker: Default::default(),
+        }
+    }
+}
+
+impl<V> Plugin for ChunkAuditPlugin<V>
+where
+    V: Voxel,
+{
+    fn build(&self, app: &mut AppBuilder) {
+        app.insert_resource(ChunkAudit::<V>::default())
+            // Companion data is updated in UPDATE and POST_UPDATE, and chunks are removed in LAST,
+            // so everything should agree by the start of the following frame.
+            .add_system_to_stage(stage::FIRST, chunk_audit_system::<V>.system());
+    }
+}
+
+/// Requests audits and holds the most recent report.
+///
+/// The `ChunkOctrees`, `ChunkColumns`, and `BrickAtlas` resources are checked automatically when
+/// they exist. Any other subsystem that tracks chunks, like chunk entities or a persistence index,
+/// can take part by submitting its keys with `submit_source` before the audit runs.
+///
+/// The `ChunkOctrees` and `ChunkColumns` only know about chunks that were edited since their
+/// plugins were added, so they're only checked for orphaned keys.
+pub struct ChunkAudit<V> {
+    requested: bool,
+    sources: Vec<ChunkKeySource>,
+    last_report: Option<ChunkAuditReport>,
+    marker: std::marker::PhantomData<V>,
+}
+
+impl<V> Default for ChunkAudit<V> {
+    fn default() -> Self {
+        Self {
+            requested: false,
+            sources: Vec::new(),
+            last_report: None,
+            marker: Default::default(),
+        }
+    }
+}
+
+impl<V> ChunkAudit<V> {
+    /// Runs an audit at the start of the next frame.
+    pub fn request(&mut self) {
+        self.requested = true;
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.requested
+    }
+
+    /// Includes `chunk_keys` in the next audit under `name`. If `covers_map` is `true`, every chunk
+    /// in the `VoxelMap` is expected to be in `chunk_keys`, so missing keys are reported as well.
+    pub fn submit_source(
+        &mut self,
+        name: impl Into<String>,
+        chunk_keys: impl IntoIterator<Item = Point3i>,
+        covers_map: bool,
+    ) {
+        self.sources.push(ChunkKeySource {
+            name: name.into(),
+            chunk_keys: chunk_keys.into_iter().collect(),
+            covers_map,
+        });
+    }
+
+    pub fn last_report(&self) -> Option<&ChunkAuditReport> {
+        self.last_report.as_ref()
+    }
+
+    pub fn take_report(&mut self) -> Option<ChunkAuditReport> {
+        self.last_report.take()
+    }
+}
+
+/// The chunk keys tracked by a single subsystem.
+#[derive(Clone, Debug)]
+pub struct ChunkKeySource {
+    pub name: String,
+    pub chunk_keys: FnvHashSet<Point3i>,
+    /// Whether every chunk in the `VoxelMap` is expected to be tracked by this source.
+    pub covers_map: bool,
+}
+
+/// The result of comparing each `ChunkKeySource` with the chunks in the `VoxelMap`.
+#[derive(Clone, Debug, Default)]
+pub struct ChunkAuditReport {
+    pub num_map_chunks: usize,
+    /// One entry for every source that disagrees with the map.
+    pub mismatches: Vec<ChunkKeyMismatch>,
+}
+
+#[derive(Clone, Debug)]
+pub struct ChunkKeyMismatch {
+    pub source: String,
+    /// Keys tracked by the source for chunks that don't exist in the map.
+    pub orphaned: Vec<Point3i>,
+    /// Keys of chunks in the map that the source should track, but doesn't.
+    pub missing: Vec<Point3i>,
+}
+
+impl ChunkAuditReport {
+    pub fn new(map_chunk_keys: &FnvHashSet<Point3i>, sources: &[ChunkKeySource]) -> Self {
+        let mismatches = sources
+            .iter()
+            .filter_map(|source| {
+                let orphaned: Vec<Point3i> = source
+                    .chunk_keys
+                    .difference(map_chunk_keys)
+                    .cloned()
+                    .collect();
+                let missing: Vec<Point3i> = if source.covers_map {
+                    map_chunk_keys
+                        .difference(&source.chunk_keys)
+                        .cloned()
+                        .collect()
+                } else {
+                    Vec::new()
+                };
+                if orphaned.is_empty() && missing.is_empty() {
+                    return None;
+                }
+
+                Some(ChunkKeyMismatch {
+                    source: source.name.clone(),
+                    orphaned,
+                    missing,
+                })
+            })
+            .collect();
+
+        Self {
+            num_map_chunks: map_chunk_keys.len(),
+            mismatches,
+        }
+    }
+
+    /// Returns `true` if every source agreed with the map.
+    pub fn is_clean(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+}
+
+impl fmt::Display for ChunkAuditReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Chunk audit of {} map chunks:", self.num_map_chunks)?;
+        if self.is_clean() {
+            writeln!(f, "  no mismatches")?;
+        }
+        for mismatch in self.mismatches.iter() {
+            writeln!(
+                f,
+                "  {}: {} orphaned, {} missing",
+                mismatch.source,
+                mismatch.orphaned.len(),
+                mismatch.missing.len()
+            )?;
+            for key in mismatch.orphaned.iter() {
+                writeln!(f, "    orphaned {:?}", key.0)?;
+            }
+            for key in mismatch.missing.iter() {
+                writeln!(f, "    missing {:?}", key.0)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn chunk_audit_system<V>(
+    voxel_map: Res<VoxelMap<V>>,
+    spilled_chunks: Option<Res<SpilledChunks<V>>>,
+    chunk_octrees: Option<Res<ChunkOctrees<V>>>,
+    chunk_columns: Option<Res<ChunkColumns<V>>>,
+    brick_atlas: Option<Res<BrickAtlas<V>>>,
+    mut audit: ResMut<ChunkAudit<V>>,
+) where
+    V: Voxel,
+{
+    if !audit.requested {
+        return;
+    }
+    audit.requested = false;
+
+    // Collapsed and spilled chunks are still part of the map.
+    let mut map_chunk_keys: FnvHashSet<Point3i> =
+        voxel_map.voxels.storage().chunk_keys().cloned().collect();
+    map_chunk_keys.extend(
+        voxel_map
+            .uniform_chunks
+            .iter()
+            .map(|(chunk_key, _)| *chunk_key),
+    );
+    if let Some(spilled_chunks) = spilled_chunks {
+        map_chunk_keys.extend(spilled_chunks.chunk_keys().cloned());
+    }
+
+    let mut sources = std::mem::replace(&mut audit.sources, Vec::new());
+    if let Some(chunk_octrees) = chunk_octrees {
+        sources.push(ChunkKeySource {
+            name: "ChunkOctrees".to_string(),
+            chunk_keys: chunk_octrees.chunk_keys().cloned().collect(),
+            covers_map: false,
+        });
+    }
+    if let Some(chunk_columns) = chunk_columns {
+        sources.push(ChunkKeySource {
+            name: "ChunkColumns".to_string(),
+            chunk_keys: chunk_columns
+                .iter()
+                .flat_map(|(_, column)| column.chunk_keys().cloned())
+                .collect(),
+            covers_map: false,
+        });
+    }
+    if let Some(brick_atlas) = brick_atlas {
+        // The atlas only holds chunks near the camera.
+        sources.push(ChunkKeySource {
+            name: "BrickAtlas".to_string(),
+            chunk_keys: brick_atlas.chunk_keys().cloned().collect(),
+            covers_map: false,
+        });
+    }
+
+    audit.last_report = Some(ChunkAuditReport::new(&map_chunk_keys, &sources));
+}
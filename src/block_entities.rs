@@ -0,0 +1,204 @@
+use crate::{DirtyChunks, EmptyChunks, Voxel, VoxelReader};
+
+use bevy::prelude::*;
+use building_blocks::prelude::*;
+use fnv::{FnvHashMap, FnvHashSet};
+
+/// Manages the `BlockEntities` resource, which binds entities like chests, machines, or spawners to
+/// the voxels they live in. Depends on the `MapIoPlugin`, configured with
+/// `with_type_change_tracking`.
+///
+/// Every binding remembers the voxel type it was made for. When the voxel at a bound point is
+/// edited to a different type, or its chunk is replaced or removed, the binding is dropped, a
+/// `BlockEntityUnbound` event is sent, and the entity is despawned, unless the plugin was built
+/// with `without_despawn`. Bindings are checked against the previous frame's `DirtyChunks` in the
+/// `PRE_UPDATE` stage.
+///
+/// Since the type is checked against the map after the edit is merged, an entity can be bound on the
+/// same frame that its voxel is placed.
+pub struct BlockEntitiesPlugin<V> {
+    despawn_unbound: bool,
+    marker: std::marker::PhantomData<V>,
+}
+
+impl<V> Default for BlockEntitiesPlugin<V> {
+    fn default() -> Self {
+        Self {
+            despawn_unbound: true,
+            marker: Default::default(),
+        }
+    }
+}
+
+impl<V> BlockEntitiesPlugin<V> {
+    /// Keeps entities alive after they're unbound, e.g. to drop the contents of a chest. Handle the
+    /// `BlockEntityUnbound` events to despawn them.
+    pub fn without_despawn(mut self) -> Self {
+        self.despawn_unbound = false;
+
+        self
+    }
+}
+
+impl<V> Plugin for BlockEntitiesPlugin<V>
+where
+    V: Voxel,
+{
+    fn build(&self, app: &mut AppBuilder) {
+        app.insert_resource(BlockEntities::<V>::default())
+            .insert_resource(BlockEntitiesConfig::<V> {
+                despawn_unbound: self.despawn_unbound,
+                marker: Default::default(),
+            })
+            .add_event::<BlockEntityUnbound<V>>()
+            .add_system_to_stage(stage::PRE_UPDATE, block_entities_system::<V>.system());
+    }
+}
+
+/// Sent when the voxel under a bound entity changes type, or its chunk is replaced or removed.
+pub struct BlockEntityUnbound<V> {
+    pub point: Point3i,
+    pub entity: Entity,
+    marker: std::marker::PhantomData<V>,
+}
+
+/// A bidirectional index between voxel points and the entities bound to them. Each point has at
+/// most one entity, and each entity at most one point.
+pub struct BlockEntities<V> {
+    by_point: FnvHashMap<Point3i, (Entity, usize)>,
+    by_entity: FnvHashMap<Entity, Point3i>,
+    marker: std::marker::PhantomData<V>,
+}
+
+impl<V> Default for BlockEntities<V> {
+    fn default() -> Self {
+        Self {
+            by_point: Default::default(),
+            by_entity: Default::default(),
+            marker: Default::default(),
+        }
+    }
+}
+
+impl<V> BlockEntities<V> {
+    /// Binds `entity` to the voxel at `point`, which is expected to have the type `type_index`.
+    /// Any previous bindings of the point or the entity are replaced, and the entity previously
+    /// bound to `point` is returned.
+    pub fn bind(&mut self, point: Point3i, entity: Entity, type_index: usize) -> Option<Entity> {
+        if let Some(old_point) = self.by_entity.remove(&entity) {
+            self.by_point.remove(&old_point);
+        }
+        let old_entity = self.unbind_point(&point);
+        self.by_point.insert(point, (entity, type_index));
+        self.by_entity.insert(entity, point);
+
+        old_entity
+    }
+
+    pub fn unbind_point(&mut self, point: &Point3i) -> Option<Entity> {
+        let (entity, _) = self.by_point.remove(point)?;
+        self.by_entity.remove(&entity);
+
+        Some(entity)
+    }
+
+    /// Call this when despawning a bound entity yourself.
+    pub fn unbind_entity(&mut self, entity: Entity) -> Option<Point3i> {
+        let point = self.by_entity.remove(&entity)?;
+        self.by_point.remove(&point);
+
+        Some(point)
+    }
+
+    pub fn entity_at(&self, point: &Point3i) -> Option<Entity> {
+        self.by_point.get(point).map(|(entity, _)| *entity)
+    }
+
+    pub fn point_of(&self, entity: Entity) -> Option<Point3i> {
+        self.by_entity.get(&entity).cloned()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Point3i, &Entity)> {
+        self.by_point
+            .iter()
+            .map(|(point, (entity, _))| (point, entity))
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_point.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_point.is_empty()
+    }
+}
+
+struct BlockEntitiesConfig<V> {
+    despawn_unbound: bool,
+    marker: std::marker::PhantomData<V>,
+}
+
+fn block_entities_system<V>(
+    commands: &mut Commands,
+    config: Res<BlockEntitiesConfig<V>>,
+    voxel_reader: VoxelReader<V>,
+    dirty_chunks: Res<DirtyChunks<V>>,
+    empty_chunks: Res<EmptyChunks<V>>,
+    mut block_entities: ResMut<BlockEntities<V>>,
+    mut unbound_events: ResMut<Events<BlockEntityUnbound<V>>>,
+) where
+    V: Voxel,
+{
+    if block_entities.is_empty() {
+        return;
+    }
+
+    let mut candidates: FnvHashSet<Point3i> = FnvHashSet::default();
+    let mut stale_chunks: FnvHashSet<Point3i> = FnvHashSet::default();
+    for (chunk_key, edits) in dirty_chunks.chunk_edits.iter() {
+        if edits.replaced {
+            stale_chunks.insert(*chunk_key);
+        }
+        candidates.extend(
+            edits
+                .type_changes
+                .iter()
+                .filter(|p| block_entities.by_point.contains_key(*p)),
+        );
+    }
+    stale_chunks.extend(empty_chunks.removed_chunk_keys());
+    if !stale_chunks.is_empty() {
+        let indexer = &voxel_reader.map.voxels.indexer;
+        candidates.extend(
+            block_entities
+                .by_point
+                .keys()
+                .filter(|p| stale_chunks.contains(&indexer.chunk_key_containing_point(p))),
+        );
+    }
+    if candidates.is_empty() {
+        return;
+    }
+
+    let unbound: Vec<Point3i> = voxel_reader.read(|reader| {
+        candidates
+            .into_iter()
+            .filter(|p| {
+                let (_, type_index) = block_entities.by_point[p];
+
+                reader.get(p).get_type_index() != type_index
+            })
+            .collect()
+    });
+    for point in unbound.into_iter() {
+        let entity = block_entities.unbind_point(&point).unwrap();
+        unbound_events.send(BlockEntityUnbound {
+            point,
+            entity,
+            marker: Default::default(),
+        });
+        if config.despawn_unbound {
+            commands.despawn_recursive(entity);
+        }
+    }
+}
@@ -0,0 +1,358 @@
+use crate::{observer::transform_voxel_point, DirtyChunks, ThreadLocalVoxelCache, Voxel, VoxelMap};
+
+use bevy::{prelude::*, render::camera::Camera};
+use building_blocks::prelude::*;
+use fnv::FnvHashMap;
+
+/// Maintains a `BrickAtlas` of the chunks surrounding the camera, intended to be consumed by
+/// ray-marching or voxel cone tracing renderers. Depends on the `MapIoPlugin`.
+///
+/// Bricks have the same shape as the chunks of the `VoxelMap`, so the atlas texture is allocated
+/// the first time the system runs.
+pub struct BrickAtlasPlugin<V> {
+    pub config: BrickAtlasConfig,
+    marker: std::marker::PhantomData<V>,
+}
+
+impl<V> BrickAtlasPlugin<V> {
+    pub fn new(config: BrickAtlasConfig) -> Self {
+        Self {
+            config,
+            marker: Default::default(),
+        }
+    }
+}
+
+impl<V> Plugin for BrickAtlasPlugin<V>
+where
+    V: Voxel,
+{
+    fn build(&self, app: &mut AppBuilder) {
+        app.insert_resource(BrickAtlas::<V>::new(&self.config))
+            .add_system_to_stage(stage::POST_UPDATE, brick_atlas_system::<V>.system());
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct BrickAtlasConfig {
+    /// The number of brick slots along each axis of the atlas texture.
+    pub atlas_shape_in_bricks: Point3i,
+    /// Chunks within this Chebyshev distance (in chunks) of the camera's chunk are kept in the
+    /// atlas.
+    pub radius_in_chunks: i32,
+    /// Avoid hitches from writing too many bricks in one frame.
+    pub max_bricks_written_per_frame: usize,
+}
+
+impl Default for BrickAtlasConfig {
+    fn default() -> Self {
+        Self {
+            atlas_shape_in_bricks: PointN([16; 3]),
+            radius_in_chunks: 6,
+            max_bricks_written_per_frame: 64,
+        }
+    }
+}
+
+/// The value written into the indirection table for chunks that don't have a brick in the atlas.
+pub const EMPTY_BRICK: u32 = std::u32::MAX;
+
+/// A sparse 3D texture atlas of the chunks near the camera, plus the indirection table that maps
+/// chunk coordinates to brick slots.
+///
+/// Each texel stores the voxel's type index, truncated to a `u8`, so a shader can look up any
+/// material data from its own copy of the palette.
+///
+/// The indirection table is a dense `(2 * radius + 1)^3` grid in chunk coordinates, starting at
+/// `indirection_origin`, with X varying fastest. Entries hold a brick slot index or `EMPTY_BRICK`.
+/// Slot `s` occupies brick coordinates `(s % ax, (s / ax) % ay, s / (ax * ay))` in the atlas, where
+/// `(ax, ay, az)` is the atlas shape in bricks.
+pub struct BrickAtlas<V> {
+    /// Avoid hitches from writing too many bricks in one frame.
+    pub max_bricks_written_per_frame: usize,
+    // Copied from the map; zero until the atlas is allocated.
+    chunk_shape: Point3i,
+    atlas_shape_in_bricks: Point3i,
+    radius: i32,
+    center: Option<Point3i>,
+    slots: FnvHashMap<Point3i, u32>,
+    free_slots: Vec<u32>,
+    texels: Vec<u8>,
+    indirection: Vec<u32>,
+    written_slots: Vec<u32>,
+    indirection_changed: bool,
+    marker: std::marker::PhantomData<V>,
+}
+
+impl<V> BrickAtlas<V> {
+    pub fn new(config: &BrickAtlasConfig) -> Self {
+        let table_edge = 2 * config.radius_in_chunks + 1;
+
+        Self {
+            max_bricks_written_per_frame: config.max_bricks_written_per_frame,
+            chunk_shape: PointN([0; 3]),
+            atlas_shape_in_bricks: config.atlas_shape_in_bricks,
+            radius: config.radius_in_chunks,
+            center: None,
+            slots: Default::default(),
+            free_slots: Vec::new(),
+            texels: Vec::new(),
+            indirection: vec![EMPTY_BRICK; (table_edge * table_edge * table_edge) as usize],
+            written_slots: Vec::new(),
+            indirection_changed: true,
+            marker: Default::default(),
+        }
+    }
+
+    /// The shape of the atlas texture in texels, which is zero until the atlas is allocated.
+    pub fn texture_shape(&self) -> Point3i {
+        self.atlas_shape_in_bricks * self.chunk_shape
+    }
+
+    /// The shape of a single brick in texels, which is also the shape of a chunk.
+    pub fn brick_shape(&self) -> Point3i {
+        self.chunk_shape
+    }
+
+    /// All texels of the atlas texture, with X varying fastest.
+    pub fn texels(&self) -> &[u8] {
+        &self.texels
+    }
+
+    /// The shape of the indirection table, in chunks.
+    pub fn indirection_shape(&self) -> Point3i {
+        PointN([2 * self.radius + 1; 3])
+    }
+
+    /// The chunk coordinates of the first entry in the indirection table.
+    pub fn indirection_origin(&self) -> Option<Point3i> {
+        self.center.map(|c| c - PointN([self.radius; 3]))
+    }
+
+    pub fn indirection(&self) -> &[u32] {
+        &self.indirection
+    }
+
+    /// The brick slot holding the chunk at `chunk_key`, if it's resident.
+    pub fn slot_for_chunk(&self, chunk_key: Point3i) -> Option<u32> {
+        self.slots.get(&chunk_key).cloned()
+    }
+
+    /// The keys of all resident chunks.
+    pub fn chunk_keys(&self) -> impl Iterator<Item = &Point3i> {
+        self.slots.keys()
+    }
+
+    /// The minimum texel of brick slot `slot` in the atlas texture.
+    pub fn slot_min_texel(&self, slot: u32) -> Point3i {
+        let slot = slot as i32;
+        let [ax, ay, _] = self.atlas_shape_in_bricks.0;
+
+        PointN([slot % ax, (slot / ax) % ay, slot / (ax * ay)]) * self.chunk_shape
+    }
+
+    /// Takes the slots that were written since the last call. Useful for incremental uploads.
+    pub fn take_written_slots(&mut self) -> Vec<u32> {
+        std::mem::replace(&mut self.written_slots, Vec::new())
+    }
+
+    /// Returns `true` (once) if the indirection table changed since the last call.
+    pub fn take_indirection_changed(&mut self) -> bool {
+        std::mem::replace(&mut self.indirection_changed, false)
+    }
+
+    /// (Re)allocates the atlas for bricks of `chunk_shape`, dropping every resident brick.
+    fn allocate(&mut self, chunk_shape: Point3i) {
+        if self.chunk_shape == chunk_shape {
+            return;
+        }
+        self.chunk_shape = chunk_shape;
+        let num_slots = volume(self.atlas_shape_in_bricks) as u32;
+        self.slots.clear();
+        // Pop from the back so slots are handed out in ascending order.
+        self.free_slots = (0..num_slots).rev().collect();
+        self.texels = vec![0; volume(self.texture_shape())];
+        self.written_slots.clear();
+        for entry in self.indirection.iter_mut() {
+            *entry = EMPTY_BRICK;
+        }
+        self.indirection_changed = true;
+    }
+
+    fn indirection_index(&self, coords: Point3i) -> Option<usize> {
+        let origin = self.indirection_origin()?;
+        let local = coords - origin;
+        let edge = 2 * self.radius + 1;
+        if local.0.iter().any(|&c| c < 0 || c >= edge) {
+            return None;
+        }
+
+        Some((local.x() + edge * (local.y() + edge * local.z())) as usize)
+    }
+
+    fn in_range(&self, coords: Point3i) -> bool {
+        self.indirection_index(coords).is_some()
+    }
+
+    fn recenter(&mut self, center: Point3i, chunk_coords: impl Fn(Point3i) -> Point3i) {
+        if self.center == Some(center) {
+            return;
+        }
+        self.center = Some(center);
+
+        // Release any bricks that fell out of range.
+        let out_of_range: Vec<Point3i> = self
+            .slots
+            .keys()
+            .cloned()
+            .filter(|key| !self.in_range(chunk_coords(*key)))
+            .collect();
+        for key in out_of_range.into_iter() {
+            self.release(&key);
+        }
+
+        self.rebuild_indirection(chunk_coords);
+    }
+
+    fn rebuild_indirection(&mut self, chunk_coords: impl Fn(Point3i) -> Point3i) {
+        for entry in self.indirection.iter_mut() {
+            *entry = EMPTY_BRICK;
+        }
+        let entries: Vec<(usize, u32)> = self
+            .slots
+            .iter()
+            .filter_map(|(key, slot)| {
+                self.indirection_index(chunk_coords(*key))
+                    .map(|i| (i, *slot))
+            })
+            .collect();
+        for (i, slot) in entries.into_iter() {
+            self.indirection[i] = slot;
+        }
+        self.indirection_changed = true;
+    }
+
+    fn release(&mut self, chunk_key: &Point3i) {
+        if let Some(slot) = self.slots.remove(chunk_key) {
+            self.free_slots.push(slot);
+            for entry in self.indirection.iter_mut().filter(|e| **e == slot) {
+                *entry = EMPTY_BRICK;
+            }
+            self.indirection_changed = true;
+        }
+    }
+
+    fn write_brick(&mut self, chunk_key: Point3i, coords: Point3i, chunk: &Array3<V>) -> bool
+    where
+        V: Voxel,
+    {
+        let slot = match self.slots.get(&chunk_key) {
+            Some(slot) => *slot,
+            None => match self.free_slots.pop() {
+                Some(slot) => {
+                    self.slots.insert(chunk_key, slot);
+                    if let Some(i) = self.indirection_index(coords) {
+                        self.indirection[i] = slot;
+                        self.indirection_changed = true;
+                    }
+
+                    slot
+                }
+                // The atlas is full.
+                None => return false,
+            },
+        };
+
+        let chunk_min = chunk.extent().minimum;
+        let slot_min = self.slot_min_texel(slot);
+        let texture_shape = self.texture_shape();
+        let texels = &mut self.texels;
+        chunk.for_each(chunk.extent(), |p: Point3i, voxel: V| {
+            let t = slot_min + (p - chunk_min);
+            let i = t.x() + texture_shape.x() * (t.y() + texture_shape.y() * t.z());
+            texels[i as usize] = voxel.get_type_index() as u8;
+        });
+        self.written_slots.push(slot);
+
+        true
+    }
+}
+
+fn camera_voxel_point(cameras: &Query<&GlobalTransform, With<Camera>>) -> Option<Point3i> {
+    cameras.iter().next().map(transform_voxel_point)
+}
+
+/// Keeps the `BrickAtlas` centered on the camera, writing bricks for chunks that come into range
+/// and rewriting bricks for any edited chunks.
+fn brick_atlas_system<V>(
+    cameras: Query<&GlobalTransform, With<Camera>>,
+    voxel_map: Res<VoxelMap<V>>,
+    local_caches: Res<ThreadLocalVoxelCache<V>>,
+    dirty_chunks: Res<DirtyChunks<V>>,
+    mut atlas: ResMut<BrickAtlas<V>>,
+) where
+    V: Voxel,
+{
+    let camera_point = match camera_voxel_point(&cameras) {
+        Some(p) => p,
+        None => return,
+    };
+
+    let indexer = &voxel_map.voxels.indexer;
+    atlas.allocate(indexer.chunk_shape());
+    let chunk_coords = |key: Point3i| {
+        let chunk_shape = indexer.chunk_shape();
+        let min = indexer.extent_for_chunk_at_key(key).minimum;
+
+        PointN([
+            min.x().div_euclid(chunk_shape.x()),
+            min.y().div_euclid(chunk_shape.y()),
+            min.z().div_euclid(chunk_shape.z()),
+        ])
+    };
+
+    let center_key = indexer.chunk_key_containing_point(&camera_point);
+    atlas.recenter(chunk_coords(center_key), chunk_coords);
+
+    let tls = local_caches.get();
+    let reader = voxel_map.reader(&tls);
+
+    let mut budget = atlas.max_bricks_written_per_frame;
+
+    // Edited chunks that are already resident must be rewritten, or released if they were removed.
+    // These don't count against the budget, since the edits would otherwise be lost.
+    for &chunk_key in dirty_chunks.edited_chunk_keys.iter() {
+        if atlas.slot_for_chunk(chunk_key).is_none() {
+            continue;
+        }
+        if let Some(chunk) = reader.get_chunk(chunk_key) {
+            atlas.write_brick(chunk_key, chunk_coords(chunk_key), &chunk.array);
+        } else {
+            atlas.release(&chunk_key);
+        }
+    }
+
+    // Fill in any chunks that are in range but not resident yet.
+    let center_extent = indexer.extent_for_chunk_at_key(center_key);
+    let pad = indexer.chunk_shape() * PointN([atlas.radius; 3]);
+    let region = Extent3i::from_min_and_max(center_extent.minimum - pad, center_extent.max() + pad);
+    for chunk_key in indexer.chunk_keys_for_extent(&region) {
+        if budget == 0 {
+            return;
+        }
+        if atlas.slot_for_chunk(chunk_key).is_some() {
+            continue;
+        }
+        if let Some(chunk) = reader.get_chunk(chunk_key) {
+            if !atlas.write_brick(chunk_key, chunk_coords(chunk_key), &chunk.array) {
+                // The atlas is full.
+                return;
+            }
+            budget -= 1;
+        }
+    }
+}
+
+fn volume(shape: Point3i) -> usize {
+    (shape.x() * shape.y() * shape.z()) as usize
+}
@@ -0,0 +1,139 @@
+use crate::{BrickAtlas, Voxel};
+
+use bevy::{
+    prelude::*,
+    render::texture::{Extent3d, FilterMode, TextureDimension, TextureFormat},
+};
+use building_blocks::prelude::*;
+
+/// Uploads the `BrickAtlas` to the GPU as a pair of 3D textures, available through the
+/// `BrickAtlasTextures` resource. Depends on the `BrickAtlasPlugin`.
+///
+/// Only the bricks written since the previous frame are copied into the atlas texture, so this
+/// plugin consumes `BrickAtlas::take_written_slots` and `BrickAtlas::take_indirection_changed`.
+pub struct BrickAtlasTexturesPlugin<V> {
+    marker: std::marker::PhantomData<V>,
+}
+
+impl<V> Default for BrickAtlasTexturesPlugin<V> {
+    fn default() -> Self {
+        Self {
+            marker: Default::default(),
+        }
+    }
+}
+
+impl<V> Plugin for BrickAtlasTexturesPlugin<V>
+where
+    V: Voxel,
+{
+    fn build(&self, app: &mut AppBuilder) {
+        app.add_startup_system(create_brick_atlas_textures_system::<V>.system())
+            // The atlas is updated in POST_UPDATE.
+            .add_system_to_stage(stage::LAST, brick_atlas_upload_system::<V>.system());
+    }
+}
+
+/// Handles to the GPU copies of the `BrickAtlas`.
+///
+/// - `atlas` is an `R8Uint` texture holding the type index of every voxel in the atlas.
+/// - `indirection` is an `R32Uint` texture holding the brick slot of each chunk near the camera, or
+///   `EMPTY_BRICK`.
+///
+/// Both use nearest-neighbor sampling, since they hold integers.
+pub struct BrickAtlasTextures<V> {
+    pub atlas: Handle<Texture>,
+    pub indirection: Handle<Texture>,
+    marker: std::marker::PhantomData<V>,
+}
+
+fn extent_3d(shape: Point3i) -> Extent3d {
+    Extent3d::new(shape.x() as u32, shape.y() as u32, shape.z() as u32)
+}
+
+fn new_integer_texture(shape: Point3i, data: Vec<u8>, format: TextureFormat) -> Texture {
+    let mut texture = Texture::new(extent_3d(shape), TextureDimension::D3, data, format);
+    texture.sampler.mag_filter = FilterMode::Nearest;
+    texture.sampler.min_filter = FilterMode::Nearest;
+
+    texture
+}
+
+fn indirection_bytes<V>(atlas: &BrickAtlas<V>) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(4 * atlas.indirection().len());
+    for entry in atlas.indirection().iter() {
+        bytes.extend_from_slice(&entry.to_le_bytes());
+    }
+
+    bytes
+}
+
+fn create_brick_atlas_textures_system<V>(
+    commands: &mut Commands,
+    atlas: Res<BrickAtlas<V>>,
+    mut textures: ResMut<Assets<Texture>>,
+) where
+    V: Voxel,
+{
+    // The atlas is allocated once the BrickAtlasPlugin sees the map, so start with a placeholder.
+    let atlas_texture = new_integer_texture(PointN([1; 3]), vec![0], TextureFormat::R8Uint);
+    let indirection_texture = new_integer_texture(
+        atlas.indirection_shape(),
+        indirection_bytes(&*atlas),
+        TextureFormat::R32Uint,
+    );
+
+    commands.insert_resource(BrickAtlasTextures::<V> {
+        atlas: textures.add(atlas_texture),
+        indirection: textures.add(indirection_texture),
+        marker: Default::default(),
+    });
+}
+
+fn brick_atlas_upload_system<V>(
+    mut atlas: ResMut<BrickAtlas<V>>,
+    atlas_textures: Res<BrickAtlasTextures<V>>,
+    mut textures: ResMut<Assets<Texture>>,
+) where
+    V: Voxel,
+{
+    let written_slots = atlas.take_written_slots();
+    let reallocated = !atlas.texels().is_empty()
+        && textures
+            .get(&atlas_textures.atlas)
+            .map_or(false, |texture| texture.data.len() != atlas.texels().len());
+    if reallocated {
+        // The BrickAtlasPlugin (re)allocated the atlas, so replace the whole texture.
+        if let Some(texture) = textures.get_mut(&atlas_textures.atlas) {
+            *texture = new_integer_texture(
+                atlas.texture_shape(),
+                atlas.texels().to_vec(),
+                TextureFormat::R8Uint,
+            );
+        }
+    } else if !written_slots.is_empty() {
+        if let Some(texture) = textures.get_mut(&atlas_textures.atlas) {
+            let texture_shape = atlas.texture_shape();
+            let brick_shape = atlas.brick_shape();
+            let row_length = brick_shape.x() as usize;
+            let texels = atlas.texels();
+            for slot in written_slots.into_iter() {
+                let min = atlas.slot_min_texel(slot);
+                for z in min.z()..min.z() + brick_shape.z() {
+                    for y in min.y()..min.y() + brick_shape.y() {
+                        let row_start =
+                            (min.x() + texture_shape.x() * (y + texture_shape.y() * z)) as usize;
+                        let row = row_start..row_start + row_length;
+                        texture.data[row.clone()].copy_from_slice(&texels[row]);
+                    }
+                }
+            }
+        }
+    }
+
+    if atlas.take_indirection_changed() {
+        if let Some(texture) = textures.get_mut(&atlas_textures.indirection) {
+            texture.data = indirection_bytes(&*atlas);
+        }
+    }
+}
@@ -1,14 +1,17 @@
-use crate::{DirtyChunks, EmptyChunks, ThreadLocalVoxelCache, Voxel, VoxelMap};
+use crate::{
+    tasks::map_in_pool, DirtyChunks, EmptyChunks, ThreadLocalVoxelCache, Voxel, VoxelMap,
+    VoxelTaskPool,
+};
 
 use building_blocks::{prelude::*, search::OctreeDBVT, storage::octree::OctreeSet};
 
-use bevy::{
-    prelude::*,
-    tasks::{ComputeTaskPool, TaskPool},
-};
+use bevy::{prelude::*, tasks::TaskPool};
 
 /// Manages the `VoxelBVT` resource by generating `OctreeSet`s for any edited chunks. Depends on the
 /// `MapIoPlugin`.
+///
+/// ncollide is no longer maintained, and the whole tree is rebuilt on every change. Prefer the
+/// `ChunkBvhPlugin`, behind the `parry` feature.
 #[derive(Default)]
 pub struct BVTPlugin<V> {
     marker: std::marker::PhantomData<V>,
@@ -33,12 +36,12 @@ pub type VoxelBVT = OctreeDBVT<Point3i>;
 
 /// Generates new octrees for all edited chunks.
 fn octree_generator_system<V>(
-    pool: Res<ComputeTaskPool>,
+    pool: Res<VoxelTaskPool>,
     voxel_map: Res<VoxelMap<V>>,
     local_caches: Res<ThreadLocalVoxelCache<V>>,
-    dirty_chunks: Res<DirtyChunks>,
+    dirty_chunks: Res<DirtyChunks<V>>,
     mut voxel_bvt: ResMut<VoxelBVT>,
-    mut empty_chunks: ResMut<EmptyChunks>,
+    mut empty_chunks: ResMut<EmptyChunks<V>>,
 ) where
     V: Voxel,
     for<'r> &'r V::TypeInfo: IsEmpty,
@@ -57,7 +60,7 @@ fn octree_generator_system<V>(
 }
 
 fn generate_octree_for_each_chunk<V>(
-    dirty_chunks: &DirtyChunks,
+    dirty_chunks: &DirtyChunks<V>,
     map: &VoxelMap<V>,
     local_caches: &ThreadLocalVoxelCache<V>,
     pool: &TaskPool,
@@ -66,19 +69,19 @@ where
     V: Voxel,
     for<'r> &'r V::TypeInfo: IsEmpty,
 {
-    pool.scope(|s| {
-        for chunk_key in dirty_chunks.edited_chunk_keys.clone().into_iter() {
-            s.spawn(async move {
-                let cache_tls = local_caches.get();
-                let reader = map.reader(&cache_tls);
-                let chunk = reader.get_chunk(chunk_key).unwrap();
-                let transform_chunk = TransformMap::new(&chunk.array, map.voxel_info_transform());
+    map_in_pool(
+        pool,
+        dirty_chunks.edited_chunk_keys.iter().cloned(),
+        |chunk_key| {
+            let cache_tls = local_caches.get();
+            let reader = map.reader(&cache_tls);
+            let chunk = reader.get_chunk(chunk_key).unwrap();
+            let transform_chunk = TransformMap::new(&chunk.array, map.voxel_info_transform());
 
-                (
-                    chunk_key,
-                    OctreeSet::from_array3(&transform_chunk, *chunk.array.extent()),
-                )
-            })
-        }
-    })
+            (
+                chunk_key,
+                OctreeSet::from_array3(&transform_chunk, *chunk.array.extent()),
+            )
+        },
+    )
 }
@@ -0,0 +1,284 @@
+use crate::{
+    tasks::map_in_pool, DirtyChunks, EmptyChunks, ThreadLocalVoxelCache, Voxel, VoxelMap,
+    VoxelTaskPool,
+};
+
+use bevy::{prelude::*, tasks::TaskPool};
+use building_blocks::prelude::*;
+use fnv::{FnvHashMap, FnvHashSet};
+use parry3d::{
+    bounding_volume::{Aabb, BoundingVolume},
+    math::Point,
+    partitioning::{Qbvh, QbvhUpdateWorkspace},
+    query::{
+        visitors::{PointIntersectionsVisitor, RayIntersectionsVisitor},
+        Ray, RayCast,
+    },
+};
+
+/// Manages the `ChunkBvh` resource, a parry bounding volume hierarchy over the occupied voxels of
+/// every chunk. Depends on the `MapIoPlugin`.
+///
+/// The hierarchy is updated incrementally from `DirtyChunks`: only the bounds of edited chunks are
+/// recomputed, and the tree is refit and rebalanced around them instead of being rebuilt. This is
+/// the replacement for the ncollide `BVTPlugin`.
+pub struct ChunkBvhPlugin<V> {
+    marker: std::marker::PhantomData<V>,
+}
+
+impl<V> Default for ChunkBvhPlugin<V> {
+    fn default() -> Self {
+        Self {
+            marker: Default::default(),
+        }
+    }
+}
+
+impl<V> Plugin for ChunkBvhPlugin<V>
+where
+    V: Voxel,
+    for<'r> &'r V::TypeInfo: IsEmpty,
+{
+    fn build(&self, app: &mut AppBuilder) {
+        app.insert_resource(ChunkBvh::<V>::default())
+            .add_system(chunk_bvh_system::<V>.system());
+    }
+}
+
+/// A bounding volume hierarchy whose leaves are chunks, each bounded by the smallest `Aabb` around
+/// its non-empty voxels. Voxel `p` occupies the unit cube from `p` to `p + 1`, so the bounds are in
+/// the same space as `Transform`s.
+///
+/// Chunks without a leaf are either entirely empty or haven't been edited since the plugin was
+/// added. Queries return chunk keys; testing the voxels inside of those chunks is up to the caller.
+pub struct ChunkBvh<V> {
+    qbvh: Qbvh<u32>,
+    workspace: QbvhUpdateWorkspace,
+    leaves: Vec<Option<ChunkLeaf>>,
+    leaf_ids: FnvHashMap<Point3i, u32>,
+    free_ids: Vec<u32>,
+    marker: std::marker::PhantomData<V>,
+}
+
+struct ChunkLeaf {
+    chunk_key: Point3i,
+    aabb: Aabb,
+}
+
+impl<V> Default for ChunkBvh<V> {
+    fn default() -> Self {
+        Self {
+            qbvh: Qbvh::new(),
+            workspace: Default::default(),
+            leaves: Vec::new(),
+            leaf_ids: Default::default(),
+            free_ids: Vec::new(),
+            marker: Default::default(),
+        }
+    }
+}
+
+impl<V> ChunkBvh<V> {
+    /// The bounds of the non-empty voxels in the chunk at `chunk_key`.
+    pub fn chunk_aabb(&self, chunk_key: &Point3i) -> Option<&Aabb> {
+        let id = self.leaf_ids.get(chunk_key)?;
+
+        self.leaves[*id as usize].as_ref().map(|leaf| &leaf.aabb)
+    }
+
+    pub fn contains_chunk(&self, chunk_key: &Point3i) -> bool {
+        self.leaf_ids.contains_key(chunk_key)
+    }
+
+    pub fn chunk_keys(&self) -> impl Iterator<Item = &Point3i> {
+        self.leaf_ids.keys()
+    }
+
+    pub fn len(&self) -> usize {
+        self.leaf_ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.leaf_ids.is_empty()
+    }
+
+    /// The chunks whose bounds are hit by `ray` within `max_toi`, along with the time of impact
+    /// where the ray enters them, nearest first.
+    pub fn cast_ray(&self, ray: &Ray, max_toi: f32) -> Vec<(Point3i, f32)> {
+        let leaves = &self.leaves;
+        let mut hits = Vec::new();
+        let mut callback = |id: &u32| {
+            if let Some(leaf) = &leaves[*id as usize] {
+                if let Some(toi) = leaf.aabb.cast_local_ray(ray, max_toi, true) {
+                    hits.push((leaf.chunk_key, toi));
+                }
+            }
+
+            true
+        };
+        let mut visitor = RayIntersectionsVisitor::new(ray, max_toi, &mut callback);
+        self.qbvh.traverse_depth_first(&mut visitor);
+        hits.sort_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+
+        hits
+    }
+
+    /// The chunks whose bounds contain `point`. There can be more than one when `point` is on the
+    /// boundary between chunks.
+    pub fn chunks_containing_point(&self, point: &Point<f32>) -> Vec<Point3i> {
+        let leaves = &self.leaves;
+        let mut chunk_keys = Vec::new();
+        let mut callback = |id: &u32| {
+            if let Some(leaf) = &leaves[*id as usize] {
+                if leaf.aabb.contains_local_point(point) {
+                    chunk_keys.push(leaf.chunk_key);
+                }
+            }
+
+            true
+        };
+        let mut visitor = PointIntersectionsVisitor::new(point, &mut callback);
+        self.qbvh.traverse_depth_first(&mut visitor);
+
+        chunk_keys
+    }
+
+    /// The chunks whose bounds intersect `aabb`.
+    pub fn chunks_intersecting_aabb(&self, aabb: &Aabb) -> Vec<Point3i> {
+        let mut ids = Vec::new();
+        self.qbvh.intersect_aabb(aabb, &mut ids);
+
+        ids.into_iter()
+            .filter_map(|id| self.leaves[id as usize].as_ref())
+            .filter(|leaf| leaf.aabb.intersects(aabb))
+            .map(|leaf| leaf.chunk_key)
+            .collect()
+    }
+
+    fn set_chunk_aabb(&mut self, chunk_key: Point3i, aabb: Aabb) {
+        let id = match self.leaf_ids.get(&chunk_key) {
+            Some(id) => *id,
+            None => {
+                let id = self.free_ids.pop().unwrap_or_else(|| {
+                    self.leaves.push(None);
+
+                    (self.leaves.len() - 1) as u32
+                });
+                self.leaf_ids.insert(chunk_key, id);
+
+                id
+            }
+        };
+        self.leaves[id as usize] = Some(ChunkLeaf { chunk_key, aabb });
+        self.qbvh.pre_update_or_insert(id);
+    }
+
+    // Returns the freed ID, which can't be reused until the tree is refit.
+    fn remove_chunk(&mut self, chunk_key: &Point3i) -> Option<u32> {
+        let id = self.leaf_ids.remove(chunk_key)?;
+        self.qbvh.remove(id);
+        self.leaves[id as usize] = None;
+
+        Some(id)
+    }
+
+    fn refit(&mut self, freed_ids: Vec<u32>) {
+        let leaves = &self.leaves;
+        self.qbvh.refit(0.0, &mut self.workspace, |id: &u32| {
+            leaves[*id as usize]
+                .as_ref()
+                .map_or_else(Aabb::new_invalid, |leaf| leaf.aabb)
+        });
+        self.qbvh.rebalance(0.0, &mut self.workspace);
+        self.free_ids.extend(freed_ids);
+    }
+}
+
+/// Recomputes the bounds of all edited chunks and refits the hierarchy.
+fn chunk_bvh_system<V>(
+    pool: Res<VoxelTaskPool>,
+    voxel_map: Res<VoxelMap<V>>,
+    local_caches: Res<ThreadLocalVoxelCache<V>>,
+    dirty_chunks: Res<DirtyChunks<V>>,
+    empty_chunks: Res<EmptyChunks<V>>,
+    mut chunk_bvh: ResMut<ChunkBvh<V>>,
+) where
+    V: Voxel,
+    for<'r> &'r V::TypeInfo: IsEmpty,
+{
+    // These chunks will be gone by the end of the frame.
+    let removed: FnvHashSet<Point3i> = empty_chunks.chunk_keys().cloned().collect();
+    if dirty_chunks.edited_chunk_keys.is_empty() && removed.is_empty() {
+        return;
+    }
+
+    let mut freed_ids = Vec::new();
+    for chunk_key in removed.iter() {
+        freed_ids.extend(chunk_bvh.remove_chunk(chunk_key));
+    }
+
+    let new_bounds =
+        occupied_bounds_for_each_chunk(&*dirty_chunks, &*voxel_map, &*local_caches, &*pool);
+    for (chunk_key, bounds) in new_bounds.into_iter() {
+        if removed.contains(&chunk_key) {
+            continue;
+        }
+        match bounds {
+            Some(aabb) => chunk_bvh.set_chunk_aabb(chunk_key, aabb),
+            // The chunk is empty or was already removed.
+            None => freed_ids.extend(chunk_bvh.remove_chunk(&chunk_key)),
+        }
+    }
+
+    chunk_bvh.refit(freed_ids);
+}
+
+fn occupied_bounds_for_each_chunk<V>(
+    dirty_chunks: &DirtyChunks<V>,
+    map: &VoxelMap<V>,
+    local_caches: &ThreadLocalVoxelCache<V>,
+    pool: &TaskPool,
+) -> Vec<(Point3i, Option<Aabb>)>
+where
+    V: Voxel,
+    for<'r> &'r V::TypeInfo: IsEmpty,
+{
+    map_in_pool(
+        pool,
+        dirty_chunks.edited_chunk_keys.iter().cloned(),
+        |chunk_key| {
+            let cache_tls = local_caches.get();
+            let reader = map.reader(&cache_tls);
+            let voxel_info = map.voxel_info_transform();
+            let bounds = reader.get_chunk(chunk_key).and_then(|chunk| {
+                let mut min_max: Option<([i32; 3], [i32; 3])> = None;
+                chunk
+                    .array
+                    .for_each(chunk.array.extent(), |p: Point3i, voxel: V| {
+                        if voxel_info(voxel).is_empty() {
+                            return;
+                        }
+                        let (mut min, mut max) = min_max.unwrap_or((p.0, p.0));
+                        for i in 0..3 {
+                            min[i] = min[i].min(p.0[i]);
+                            max[i] = max[i].max(p.0[i]);
+                        }
+                        min_max = Some((min, max));
+                    });
+
+                min_max.map(|(min, max)| {
+                    Aabb::new(
+                        Point::new(min[0] as f32, min[1] as f32, min[2] as f32),
+                        Point::new(
+                            (max[0] + 1) as f32,
+                            (max[1] + 1) as f32,
+                            (max[2] + 1) as f32,
+                        ),
+                    )
+                })
+            });
+
+            (chunk_key, bounds)
+        },
+    )
+}
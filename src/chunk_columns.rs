@@ -0,0 +1,146 @@
+use crate::{DirtyChunks, EmptyChunks, Voxel, VoxelMap};
+
+use bevy::prelude::*;
+use building_blocks::prelude::*;
+use fnv::FnvHashMap;
+use std::collections::BTreeMap;
+
+/// Manages the `ChunkColumns` resource, which groups vertically stacked chunks by their 2D chunk
+/// key. Depends on the `MapIoPlugin`.
+pub struct ChunkColumnsPlugin<V> {
+    marker: std::marker::PhantomData<V>,
+}
+
+impl<V> Default for ChunkColumnsPlugin<V> {
+    fn default() -> Self {
+        Self {
+            marker: Default::default(),
+        }
+    }
+}
+
+impl<V> Plugin for ChunkColumnsPlugin<V>
+where
+    V: Voxel,
+{
+    fn build(&self, app: &mut AppBuilder) {
+        app.insert_resource(ChunkColumns::<V>::default())
+            // Runs after chunks are marked as empty in UPDATE, but before they're removed in LAST.
+            .add_system_to_stage(stage::POST_UPDATE, chunk_columns_system::<V>.system());
+    }
+}
+
+/// The 2D key of the column containing the chunk at `chunk_key`.
+pub fn column_key(chunk_key: Point3i) -> Point2i {
+    PointN([chunk_key.x(), chunk_key.z()])
+}
+
+/// The chunks that exist in a single column, ordered from bottom to top.
+#[derive(Clone, Debug, Default)]
+pub struct ChunkColumn {
+    // Maps the minimum voxel Y of each chunk to its chunk key.
+    chunks: BTreeMap<i32, Point3i>,
+    chunk_height: i32,
+}
+
+impl ChunkColumn {
+    /// All chunk keys in this column, from bottom to top.
+    pub fn chunk_keys(&self) -> impl DoubleEndedIterator<Item = &Point3i> {
+        self.chunks.values()
+    }
+
+    pub fn num_chunks(&self) -> usize {
+        self.chunks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chunks.is_empty()
+    }
+
+    /// The lowest voxel Y coordinate covered by any chunk in this column.
+    pub fn min_chunk_y(&self) -> Option<i32> {
+        self.chunks.keys().next().cloned()
+    }
+
+    /// The highest voxel Y coordinate covered by any chunk in this column.
+    pub fn max_chunk_y(&self) -> Option<i32> {
+        self.chunks
+            .keys()
+            .next_back()
+            .map(|min_y| min_y + self.chunk_height - 1)
+    }
+}
+
+/// Tracks which chunks exist in each column of the `VoxelMap`, so tall worlds can be managed a
+/// whole column at a time.
+///
+/// Chunks are added when they're edited and removed when they're marked in `EmptyChunks`.
+#[derive(Default)]
+pub struct ChunkColumns<V> {
+    columns: FnvHashMap<Point2i, ChunkColumn>,
+    marker: std::marker::PhantomData<V>,
+}
+
+impl<V> ChunkColumns<V> {
+    pub fn get(&self, column_key: &Point2i) -> Option<&ChunkColumn> {
+        self.columns.get(column_key)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Point2i, &ChunkColumn)> {
+        self.columns.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.columns.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.columns.is_empty()
+    }
+
+    /// Marks every chunk in the column for removal, so the whole column is streamed out at the end
+    /// of the frame.
+    pub fn remove_column(&self, column_key: &Point2i, empty_chunks: &mut EmptyChunks<V>) {
+        if let Some(column) = self.columns.get(column_key) {
+            for &chunk_key in column.chunk_keys() {
+                empty_chunks.mark_for_removal(chunk_key);
+            }
+        }
+    }
+
+    fn insert_chunk(&mut self, chunk_key: Point3i, chunk_extent: &Extent3i) {
+        let column = self.columns.entry(column_key(chunk_key)).or_default();
+        column.chunk_height = chunk_extent.shape.y();
+        column.chunks.insert(chunk_extent.minimum.y(), chunk_key);
+    }
+
+    fn remove_chunk(&mut self, chunk_key: Point3i, chunk_extent: &Extent3i) {
+        let key = column_key(chunk_key);
+        if let Some(column) = self.columns.get_mut(&key) {
+            column.chunks.remove(&chunk_extent.minimum.y());
+            if column.is_empty() {
+                self.columns.remove(&key);
+            }
+        }
+    }
+}
+
+fn chunk_columns_system<V>(
+    voxel_map: Res<VoxelMap<V>>,
+    dirty_chunks: Res<DirtyChunks<V>>,
+    empty_chunks: Res<EmptyChunks<V>>,
+    mut columns: ResMut<ChunkColumns<V>>,
+) where
+    V: Voxel,
+{
+    let indexer = &voxel_map.voxels.indexer;
+
+    // Edits were merged at the end of the previous frame, so they happened before any removals
+    // that were marked this frame.
+    for &chunk_key in dirty_chunks.edited_chunk_keys.iter() {
+        columns.insert_chunk(chunk_key, &indexer.extent_for_chunk_at_key(chunk_key));
+    }
+    for &chunk_key in empty_chunks.chunk_keys() {
+        columns.remove_chunk(chunk_key, &indexer.extent_for_chunk_at_key(chunk_key));
+    }
+}
@@ -0,0 +1,293 @@
+use crate::{ChunkEntities, ChunkExtent, ChunkKey, DirtyChunks, Voxel, VoxelReader};
+
+use bevy::{prelude::*, render::camera::Camera};
+use building_blocks::prelude::*;
+
+/// Attaches culling data to the chunk entities of the `ChunkEntitiesPlugin`, and hides the chunks
+/// outside of the view frustum of every `ChunkCullingCamera`. Depends on the `ChunkEntitiesPlugin`.
+///
+/// Every chunk entity gets a `ChunkAabb`. With `with_occupancy_masks`, the voxels of edited chunks
+/// are also read to compute a coarse `ChunkOccupancy`, and the `ChunkAabb` is shrunk to the occupied
+/// cells, so mostly empty chunks are culled more often.
+///
+/// Culling sets `Visible::is_visible` on each chunk entity and its children that have a `Visible`
+/// component, so chunk meshes can be spawned as children of the chunk entity. The frustum test is
+/// conservative, and chunks are only hidden when their `ChunkAabb` is entirely outside.
+pub struct ChunkCullingPlugin<V> {
+    pub occupancy_masks: bool,
+    marker: std::marker::PhantomData<V>,
+}
+
+impl<V> Default for ChunkCullingPlugin<V> {
+    fn default() -> Self {
+        Self {
+            occupancy_masks: false,
+            marker: Default::default(),
+        }
+    }
+}
+
+impl<V> ChunkCullingPlugin<V> {
+    pub fn with_occupancy_masks(mut self) -> Self {
+        self.occupancy_masks = true;
+
+        self
+    }
+}
+
+impl<V> Plugin for ChunkCullingPlugin<V>
+where
+    V: Voxel,
+    for<'r> &'r V::TypeInfo: IsEmpty,
+{
+    fn build(&self, app: &mut AppBuilder) {
+        app.insert_resource(ChunkCullingConfig::<V> {
+            occupancy_masks: self.occupancy_masks,
+            marker: Default::default(),
+        })
+        .add_system(chunk_culling_data_system::<V>.system())
+        // After transforms are propagated in POST_UPDATE.
+        .add_system_to_stage(stage::LAST, frustum_culling_system.system());
+    }
+}
+
+struct ChunkCullingConfig<V> {
+    occupancy_masks: bool,
+    marker: std::marker::PhantomData<V>,
+}
+
+/// Marks a camera whose view frustum is used to cull chunks. A chunk is visible if it's inside the
+/// frustum of any of these cameras.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ChunkCullingCamera;
+
+/// The bounds of a chunk entity in world space.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ChunkAabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl ChunkAabb {
+    pub fn from_extent(extent: &Extent3i) -> Self {
+        let min = extent.minimum;
+        let max = extent.minimum + extent.shape;
+
+        Self {
+            min: Vec3::new(min.x() as f32, min.y() as f32, min.z() as f32),
+            max: Vec3::new(max.x() as f32, max.y() as f32, max.z() as f32),
+        }
+    }
+}
+
+/// Which cells of a chunk contain non-empty voxels, where the chunk is divided into 4x4x4 cells.
+/// Cell `(x, y, z)` is bit `x + 4 * y + 16 * z`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ChunkOccupancy(pub u64);
+
+impl ChunkOccupancy {
+    pub const CELLS_PER_AXIS: i32 = 4;
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn cell_is_occupied(&self, cell: Point3i) -> bool {
+        self.0 & (1 << Self::cell_bit(cell)) != 0
+    }
+
+    /// The extent of `cell` within the chunk at `chunk_extent`.
+    pub fn cell_extent(chunk_extent: &Extent3i, cell: Point3i) -> Extent3i {
+        let cell_shape = Self::cell_shape(chunk_extent);
+
+        Extent3i::from_min_and_shape(chunk_extent.minimum + cell * cell_shape, cell_shape)
+            .intersection(chunk_extent)
+    }
+
+    fn cell_shape(chunk_extent: &Extent3i) -> Point3i {
+        let n = Self::CELLS_PER_AXIS;
+        let s = chunk_extent.shape;
+
+        PointN([
+            ((s.x() + n - 1) / n).max(1),
+            ((s.y() + n - 1) / n).max(1),
+            ((s.z() + n - 1) / n).max(1),
+        ])
+    }
+
+    fn cell_bit(cell: Point3i) -> u64 {
+        let n = Self::CELLS_PER_AXIS;
+
+        (cell.x() + n * cell.y() + n * n * cell.z()) as u64
+    }
+
+    // The bounds of the occupied cells.
+    fn occupied_extent(&self, chunk_extent: &Extent3i) -> Option<Extent3i> {
+        let n = Self::CELLS_PER_AXIS;
+        let mut bounds: Option<Extent3i> = None;
+        for z in 0..n {
+            for y in 0..n {
+                for x in 0..n {
+                    let cell = PointN([x, y, z]);
+                    if !self.cell_is_occupied(cell) {
+                        continue;
+                    }
+                    let cell_extent = Self::cell_extent(chunk_extent, cell);
+                    bounds = Some(match bounds {
+                        Some(b) => bounding_extent(&b, &cell_extent),
+                        None => cell_extent,
+                    });
+                }
+            }
+        }
+
+        bounds
+    }
+}
+
+fn bounding_extent(a: &Extent3i, b: &Extent3i) -> Extent3i {
+    let (a_end, b_end) = (a.minimum + a.shape, b.minimum + b.shape);
+    let min = PointN([
+        a.minimum.x().min(b.minimum.x()),
+        a.minimum.y().min(b.minimum.y()),
+        a.minimum.z().min(b.minimum.z()),
+    ]);
+    let end = PointN([
+        a_end.x().max(b_end.x()),
+        a_end.y().max(b_end.y()),
+        a_end.z().max(b_end.z()),
+    ]);
+
+    Extent3i::from_min_and_shape(min, end - min)
+}
+
+/// Attaches a `ChunkAabb`, and optionally a `ChunkOccupancy`, to new chunk entities and to the
+/// entities of edited chunks.
+fn chunk_culling_data_system<V>(
+    commands: &mut Commands,
+    config: Res<ChunkCullingConfig<V>>,
+    voxel_reader: VoxelReader<V>,
+    dirty_chunks: Res<DirtyChunks<V>>,
+    chunk_entities: Res<ChunkEntities<V>>,
+    new_chunks: Query<(Entity, &ChunkKey, &ChunkExtent), Added<ChunkKey>>,
+) where
+    V: Voxel,
+    for<'r> &'r V::TypeInfo: IsEmpty,
+{
+    let indexer = &voxel_reader.map.voxels.indexer;
+    let mut updated: Vec<(Entity, Extent3i)> = new_chunks
+        .iter()
+        .map(|(entity, _, extent)| (entity, extent.0))
+        .collect();
+    if config.occupancy_masks {
+        for chunk_key in dirty_chunks.edited_chunk_keys.iter() {
+            if let Some(entity) = chunk_entities.get(chunk_key) {
+                updated.push((entity, indexer.extent_for_chunk_at_key(*chunk_key)));
+            }
+        }
+    }
+
+    for (entity, chunk_extent) in updated.into_iter() {
+        if !config.occupancy_masks {
+            commands.insert_one(entity, ChunkAabb::from_extent(&chunk_extent));
+            continue;
+        }
+
+        let occupancy = chunk_occupancy(&voxel_reader, &chunk_extent);
+        // An empty chunk can't be seen, but it keeps a degenerate box until it's removed.
+        let bounds = occupancy
+            .occupied_extent(&chunk_extent)
+            .unwrap_or_else(|| Extent3i::from_min_and_shape(chunk_extent.minimum, PointN([0; 3])));
+        commands.insert(entity, (ChunkAabb::from_extent(&bounds), occupancy));
+    }
+}
+
+fn chunk_occupancy<V>(voxel_reader: &VoxelReader<V>, chunk_extent: &Extent3i) -> ChunkOccupancy
+where
+    V: Voxel,
+    for<'r> &'r V::TypeInfo: IsEmpty,
+{
+    let cell_shape = ChunkOccupancy::cell_shape(chunk_extent);
+    let mut mask = 0;
+    voxel_reader.for_each_info(chunk_extent, |p: Point3i, info: &V::TypeInfo| {
+        if info.is_empty() {
+            return;
+        }
+        let local = p - chunk_extent.minimum;
+        let cell = PointN([
+            local.x() / cell_shape.x(),
+            local.y() / cell_shape.y(),
+            local.z() / cell_shape.z(),
+        ]);
+        mask |= 1 << ChunkOccupancy::cell_bit(cell);
+    });
+
+    ChunkOccupancy(mask)
+}
+
+/// Hides the chunks outside of the view frustums of all `ChunkCullingCamera`s.
+fn frustum_culling_system(
+    cameras: Query<(&Camera, &GlobalTransform), With<ChunkCullingCamera>>,
+    chunks: Query<(Entity, &ChunkAabb, Option<&Children>)>,
+    mut visibles: Query<&mut Visible>,
+) {
+    let frustums: Vec<[Vec4; 6]> = cameras
+        .iter()
+        .map(|(camera, transform)| {
+            frustum_planes(&(camera.projection_matrix * transform.compute_matrix().inverse()))
+        })
+        .collect();
+    if frustums.is_empty() {
+        return;
+    }
+
+    for (entity, aabb, children) in chunks.iter() {
+        let is_visible = frustums
+            .iter()
+            .any(|planes| aabb_intersects_frustum(aabb, planes));
+        if let Ok(mut visible) = visibles.get_mut(entity) {
+            visible.is_visible = is_visible;
+        }
+        for child in children.iter().flat_map(|c| c.iter()) {
+            if let Ok(mut visible) = visibles.get_mut(*child) {
+                visible.is_visible = is_visible;
+            }
+        }
+    }
+}
+
+// The planes of the frustum as `(normal, distance)`, with normals pointing inward. The near plane
+// assumes a clip space depth of [-w, w], which is conservative for projections with a depth of
+// [0, w].
+fn frustum_planes(view_projection: &Mat4) -> [Vec4; 6] {
+    let m = view_projection.to_cols_array();
+    let row = |i: usize| Vec4::new(m[i], m[4 + i], m[8 + i], m[12 + i]);
+    let (r0, r1, r2, r3) = (row(0), row(1), row(2), row(3));
+
+    [r3 + r0, r3 - r0, r3 + r1, r3 - r1, r3 + r2, r3 - r2]
+}
+
+fn aabb_intersects_frustum(aabb: &ChunkAabb, planes: &[Vec4; 6]) -> bool {
+    planes.iter().all(|plane| {
+        // The corner furthest along the plane normal.
+        let corner = Vec3::new(
+            if plane.x >= 0.0 {
+                aabb.max.x
+            } else {
+                aabb.min.x
+            },
+            if plane.y >= 0.0 {
+                aabb.max.y
+            } else {
+                aabb.min.y
+            },
+            if plane.z >= 0.0 {
+                aabb.max.z
+            } else {
+                aabb.min.z
+            },
+        );
+
+        plane.x * corner.x + plane.y * corner.y + plane.z * corner.z + plane.w >= 0.0
+    })
+}
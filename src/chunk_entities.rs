@@ -0,0 +1,137 @@
+use crate::{DirtyChunks, EmptyChunks, Voxel, VoxelReader};
+
+use bevy::prelude::*;
+use building_blocks::prelude::*;
+use fnv::FnvHashMap;
+
+/// Manages the `ChunkEntities` resource by spawning an entity for every chunk in the `VoxelMap` and
+/// despawning it when the chunk is removed. Depends on the `MapIoPlugin`.
+///
+/// Each chunk entity has a `ChunkKey` and a `ChunkExtent`. Downstream plugins can attach their own
+/// components to it, like meshes or colliders, or spawn children under it; children are despawned
+/// along with the chunk.
+///
+/// Chunks that already exist when the plugin starts get entities on the first frame. After that,
+/// the mapping is kept up to date from the `DirtyChunks` and `EmptyChunks` resources in the
+/// `PRE_UPDATE` stage, so entities for the previous frame's edits exist by `UPDATE`.
+pub struct ChunkEntitiesPlugin<V> {
+    marker: std::marker::PhantomData<V>,
+}
+
+impl<V> Default for ChunkEntitiesPlugin<V> {
+    fn default() -> Self {
+        Self {
+            marker: Default::default(),
+        }
+    }
+}
+
+impl<V> Plugin for ChunkEntitiesPlugin<V>
+where
+    V: Voxel,
+{
+    fn build(&self, app: &mut AppBuilder) {
+        app.insert_resource(ChunkEntities::<V>::default())
+            .add_system_to_stage(stage::PRE_UPDATE, chunk_entities_system::<V>.system());
+    }
+}
+
+/// The key of the chunk that an entity represents.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct ChunkKey(pub Point3i);
+
+/// The voxels covered by the chunk that an entity represents.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ChunkExtent(pub Extent3i);
+
+/// Maps the key of every chunk in the `VoxelMap` to its entity.
+pub struct ChunkEntities<V> {
+    entities: FnvHashMap<Point3i, Entity>,
+    initialized: bool,
+    marker: std::marker::PhantomData<V>,
+}
+
+impl<V> Default for ChunkEntities<V> {
+    fn default() -> Self {
+        Self {
+            entities: Default::default(),
+            initialized: false,
+            marker: Default::default(),
+        }
+    }
+}
+
+impl<V> ChunkEntities<V> {
+    pub fn get(&self, chunk_key: &Point3i) -> Option<Entity> {
+        self.entities.get(chunk_key).cloned()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Point3i, &Entity)> {
+        self.entities.iter()
+    }
+
+    pub fn chunk_keys(&self) -> impl Iterator<Item = &Point3i> {
+        self.entities.keys()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entities.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entities.is_empty()
+    }
+}
+
+fn chunk_entities_system<V>(
+    commands: &mut Commands,
+    voxel_reader: VoxelReader<V>,
+    dirty_chunks: Res<DirtyChunks<V>>,
+    empty_chunks: Res<EmptyChunks<V>>,
+    mut chunk_entities: ResMut<ChunkEntities<V>>,
+) where
+    V: Voxel,
+{
+    let chunk_entities = &mut *chunk_entities;
+
+    let indexer = &voxel_reader.map.voxels.indexer;
+    let mut spawn = |entities: &mut FnvHashMap<Point3i, Entity>, chunk_key: Point3i| {
+        if entities.contains_key(&chunk_key) {
+            return;
+        }
+        let extent = indexer.extent_for_chunk_at_key(chunk_key);
+        let entity = commands
+            .spawn((ChunkKey(chunk_key), ChunkExtent(extent)))
+            .current_entity()
+            .unwrap();
+        entities.insert(chunk_key, entity);
+    };
+
+    if !chunk_entities.initialized {
+        chunk_entities.initialized = true;
+        let chunk_keys: Vec<Point3i> = voxel_reader
+            .map
+            .voxels
+            .storage()
+            .chunk_keys()
+            .cloned()
+            .collect();
+        for chunk_key in chunk_keys.into_iter() {
+            spawn(&mut chunk_entities.entities, chunk_key);
+        }
+    }
+
+    // Removed chunks are never among the edited chunks of the same frame.
+    for &chunk_key in dirty_chunks.edited_chunk_keys.iter() {
+        spawn(&mut chunk_entities.entities, chunk_key);
+    }
+    let mut despawned = Vec::new();
+    for chunk_key in empty_chunks.removed_chunk_keys() {
+        if let Some(entity) = chunk_entities.entities.remove(chunk_key) {
+            despawned.push(entity);
+        }
+    }
+    for entity in despawned.into_iter() {
+        commands.despawn_recursive(entity);
+    }
+}
@@ -0,0 +1,154 @@
+use crate::{
+    tasks::map_in_pool, DirtyChunks, EmptyChunks, ThreadLocalVoxelCache, Voxel, VoxelMap,
+    VoxelReader,
+};
+
+use bevy::{prelude::*, tasks::TaskPool};
+use building_blocks::prelude::*;
+use fnv::FnvHashMap;
+use std::hash::{Hash, Hasher};
+use twox_hash::XxHash64;
+
+/// Manages the `ChunkHashes` resource, a cache of content hashes for the chunks of the `VoxelMap`.
+/// Depends on the `MapIoPlugin`.
+///
+/// Hashes are computed lazily and cached until the chunk is edited or removed, so comparing the
+/// hashes of a server and a client only costs a full pass over the chunks that changed. A client
+/// that finds a mismatch can request a resend of just that chunk, and a save system can skip chunks
+/// whose hash matches the one it last wrote.
+///
+/// Cached hashes are invalidated from the previous frame's `DirtyChunks` in the `FIRST` stage.
+pub struct ChunkHashesPlugin<V> {
+    marker: std::marker::PhantomData<V>,
+}
+
+impl<V> Default for ChunkHashesPlugin<V> {
+    fn default() -> Self {
+        Self {
+            marker: Default::default(),
+        }
+    }
+}
+
+impl<V> Plugin for ChunkHashesPlugin<V>
+where
+    V: Voxel + Hash,
+{
+    fn build(&self, app: &mut AppBuilder) {
+        app.insert_resource(ChunkHashes::<V>::default())
+            .add_system_to_stage(stage::FIRST, chunk_hashes_system::<V>.system());
+    }
+}
+
+/// The xxHash of every voxel in `chunk`, fed to the hasher in array order. Both sides of a
+/// comparison need the same `Hash` implementation for `V`, and the same endianness.
+pub fn chunk_hash<V>(chunk: &Array3<V>) -> u64
+where
+    V: Voxel + Hash,
+{
+    let mut hasher = XxHash64::with_seed(0);
+    chunk.for_each(chunk.extent(), |_p: Point3i, voxel: V| {
+        voxel.hash(&mut hasher)
+    });
+
+    hasher.finish()
+}
+
+/// Content hashes of chunks, from `chunk_hash`, cached until the chunk changes.
+pub struct ChunkHashes<V> {
+    hashes: FnvHashMap<Point3i, u64>,
+    marker: std::marker::PhantomData<V>,
+}
+
+impl<V> Default for ChunkHashes<V> {
+    fn default() -> Self {
+        Self {
+            hashes: Default::default(),
+            marker: Default::default(),
+        }
+    }
+}
+
+impl<V> ChunkHashes<V>
+where
+    V: Voxel + Hash,
+{
+    /// The hash of the chunk at `chunk_key`, if it was already computed.
+    pub fn cached(&self, chunk_key: &Point3i) -> Option<u64> {
+        self.hashes.get(chunk_key).cloned()
+    }
+
+    /// The hash of the chunk at `chunk_key`, computing it if it isn't cached. `None` if the chunk
+    /// doesn't exist.
+    pub fn hash_chunk(&mut self, voxel_reader: &VoxelReader<V>, chunk_key: Point3i) -> Option<u64> {
+        if let Some(hash) = self.cached(&chunk_key) {
+            return Some(hash);
+        }
+        let hash = voxel_reader.read(|reader| {
+            reader
+                .get_chunk(chunk_key)
+                .map(|chunk| chunk_hash(&chunk.array))
+        })?;
+        self.hashes.insert(chunk_key, hash);
+
+        Some(hash)
+    }
+
+    /// Like `hash_chunk`, but computes the missing hashes in parallel. The results are in no
+    /// particular order.
+    pub fn hash_chunks(
+        &mut self,
+        voxel_map: &VoxelMap<V>,
+        local_caches: &ThreadLocalVoxelCache<V>,
+        pool: &TaskPool,
+        chunk_keys: impl IntoIterator<Item = Point3i>,
+    ) -> Vec<(Point3i, Option<u64>)> {
+        let mut results = Vec::new();
+        let mut missing = Vec::new();
+        for chunk_key in chunk_keys.into_iter() {
+            match self.cached(&chunk_key) {
+                Some(hash) => results.push((chunk_key, Some(hash))),
+                None => missing.push(chunk_key),
+            }
+        }
+
+        let computed = map_in_pool(pool, missing, |chunk_key| {
+            let cache_tls = local_caches.get();
+            let reader = voxel_map.reader(&cache_tls);
+            let hash = reader
+                .get_chunk(chunk_key)
+                .map(|chunk| chunk_hash(&chunk.array));
+
+            (chunk_key, hash)
+        });
+        for (chunk_key, hash) in computed.into_iter() {
+            if let Some(hash) = hash {
+                self.hashes.insert(chunk_key, hash);
+            }
+            results.push((chunk_key, hash));
+        }
+
+        results
+    }
+
+    /// Forgets the hash of a chunk that was modified without going through the `MapIoPlugin`.
+    pub fn invalidate(&mut self, chunk_key: &Point3i) {
+        self.hashes.remove(chunk_key);
+    }
+}
+
+fn chunk_hashes_system<V>(
+    dirty_chunks: Res<DirtyChunks<V>>,
+    empty_chunks: Res<EmptyChunks<V>>,
+    mut chunk_hashes: ResMut<ChunkHashes<V>>,
+) where
+    V: Voxel + Hash,
+{
+    for chunk_key in dirty_chunks
+        .edited_chunk_keys
+        .iter()
+        .chain(empty_chunks.removed_chunk_keys())
+    {
+        chunk_hashes.invalidate(chunk_key);
+    }
+}
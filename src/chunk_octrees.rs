@@ -0,0 +1,149 @@
+use crate::{
+    tasks::map_in_pool, DirtyChunks, EmptyChunks, ThreadLocalVoxelCache, Voxel, VoxelMap,
+    VoxelTaskPool,
+};
+
+use bevy::{prelude::*, tasks::TaskPool};
+use building_blocks::{prelude::*, storage::octree::OctreeSet};
+use fnv::{FnvHashMap, FnvHashSet};
+
+/// Manages the `ChunkOctrees` resource by generating an `OctreeSet` for each edited chunk. Depends
+/// on the `MapIoPlugin`.
+///
+/// Any chunk whose octree turns out to be empty is marked for removal in the `EmptyChunks`
+/// resource.
+pub struct ChunkOctreesPlugin<V> {
+    marker: std::marker::PhantomData<V>,
+}
+
+impl<V> Default for ChunkOctreesPlugin<V> {
+    fn default() -> Self {
+        Self {
+            marker: Default::default(),
+        }
+    }
+}
+
+impl<V> Plugin for ChunkOctreesPlugin<V>
+where
+    V: Voxel,
+    for<'r> &'r V::TypeInfo: IsEmpty,
+{
+    fn build(&self, app: &mut AppBuilder) {
+        app.insert_resource(ChunkOctrees::<V>::default())
+            .add_system(chunk_octrees_system::<V>.system());
+    }
+}
+
+/// An occupancy `OctreeSet` for every non-empty chunk in the `VoxelMap`, keyed by chunk key.
+///
+/// Chunks without an octree are either entirely empty or haven't been edited since the plugin was
+/// added; `chunk_is_empty` tells them apart.
+#[derive(Default)]
+pub struct ChunkOctrees<V> {
+    octrees: FnvHashMap<Point3i, OctreeSet>,
+    // Chunks that were found to be empty, or removed, since the plugin was added.
+    empty_chunk_keys: FnvHashSet<Point3i>,
+    marker: std::marker::PhantomData<V>,
+}
+
+impl<V> ChunkOctrees<V> {
+    pub fn get(&self, chunk_key: &Point3i) -> Option<&OctreeSet> {
+        self.octrees.get(chunk_key)
+    }
+
+    pub fn contains_chunk(&self, chunk_key: &Point3i) -> bool {
+        self.octrees.contains_key(chunk_key)
+    }
+
+    /// Returns `Some(true)` if the chunk at `chunk_key` is known to contain no occupied voxels,
+    /// `Some(false)` if it has an octree, and `None` if it hasn't been edited since the plugin was
+    /// added.
+    pub fn chunk_is_empty(&self, chunk_key: &Point3i) -> Option<bool> {
+        if self.contains_chunk(chunk_key) {
+            Some(false)
+        } else if self.empty_chunk_keys.contains(chunk_key) {
+            Some(true)
+        } else {
+            None
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Point3i, &OctreeSet)> {
+        self.octrees.iter()
+    }
+
+    pub fn chunk_keys(&self) -> impl Iterator<Item = &Point3i> {
+        self.octrees.keys()
+    }
+
+    pub fn len(&self) -> usize {
+        self.octrees.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.octrees.is_empty()
+    }
+}
+
+/// Generates new octrees for all edited chunks.
+fn chunk_octrees_system<V>(
+    pool: Res<VoxelTaskPool>,
+    voxel_map: Res<VoxelMap<V>>,
+    local_caches: Res<ThreadLocalVoxelCache<V>>,
+    dirty_chunks: Res<DirtyChunks<V>>,
+    mut chunk_octrees: ResMut<ChunkOctrees<V>>,
+    mut empty_chunks: ResMut<EmptyChunks<V>>,
+) where
+    V: Voxel,
+    for<'r> &'r V::TypeInfo: IsEmpty,
+{
+    let new_chunk_octrees =
+        generate_octree_for_each_chunk(&*dirty_chunks, &*voxel_map, &*local_caches, &*pool);
+
+    for (chunk_key, octree) in new_chunk_octrees.into_iter() {
+        match octree {
+            Some(octree) if !octree.is_empty() => {
+                chunk_octrees.empty_chunk_keys.remove(&chunk_key);
+                chunk_octrees.octrees.insert(chunk_key, octree);
+            }
+            Some(_) => {
+                chunk_octrees.octrees.remove(&chunk_key);
+                chunk_octrees.empty_chunk_keys.insert(chunk_key);
+                empty_chunks.mark_for_removal(chunk_key);
+            }
+            // The chunk was already removed.
+            None => {
+                chunk_octrees.octrees.remove(&chunk_key);
+                chunk_octrees.empty_chunk_keys.insert(chunk_key);
+            }
+        }
+    }
+}
+
+fn generate_octree_for_each_chunk<V>(
+    dirty_chunks: &DirtyChunks<V>,
+    map: &VoxelMap<V>,
+    local_caches: &ThreadLocalVoxelCache<V>,
+    pool: &TaskPool,
+) -> Vec<(Point3i, Option<OctreeSet>)>
+where
+    V: Voxel,
+    for<'r> &'r V::TypeInfo: IsEmpty,
+{
+    map_in_pool(
+        pool,
+        dirty_chunks.edited_chunk_keys.iter().cloned(),
+        |chunk_key| {
+            let cache_tls = local_caches.get();
+            let reader = map.reader(&cache_tls);
+            let octree = reader.get_chunk(chunk_key).map(|chunk| {
+                let transform_chunk = TransformMap::new(&chunk.array, map.voxel_info_transform());
+
+                OctreeSet::from_array3(&transform_chunk, *chunk.array.extent())
+            });
+
+            (chunk_key, octree)
+        },
+    )
+}
@@ -0,0 +1,175 @@
+use crate::{ChunkEdits, DirtyChunks, EmptyChunks, Voxel};
+
+use bevy::prelude::*;
+use building_blocks::prelude::*;
+use fnv::{FnvHashMap, FnvHashSet};
+
+/// Manages the `CoalescedDirtyChunks` resource, a debounced alternative to `DirtyChunks` for
+/// expensive post-processing. Depends on the `MapIoPlugin`.
+///
+/// A chunk that's edited every frame, e.g. while a player keeps digging, would otherwise be
+/// re-processed every frame. Instead, a chunk is held back until it hasn't been dirtied for
+/// `quiet_frames` frames, or until `max_latency_frames` frames have passed since it was first
+/// dirtied, whichever comes first. Its `ChunkEdits` from all of those frames are combined.
+///
+/// Removed chunks aren't debounced. When a chunk is removed, its held back notifications are
+/// dropped, since the removal is published in `EmptyChunks` right away.
+pub struct CoalescedDirtyChunksPlugin<V> {
+    pub quiet_frames: u64,
+    pub max_latency_frames: u64,
+    marker: std::marker::PhantomData<V>,
+}
+
+impl<V> CoalescedDirtyChunksPlugin<V> {
+    pub fn new(quiet_frames: u64, max_latency_frames: u64) -> Self {
+        Self {
+            quiet_frames,
+            max_latency_frames,
+            marker: Default::default(),
+        }
+    }
+}
+
+impl<V> Default for CoalescedDirtyChunksPlugin<V> {
+    fn default() -> Self {
+        Self::new(3, 30)
+    }
+}
+
+impl<V> Plugin for CoalescedDirtyChunksPlugin<V>
+where
+    V: Voxel,
+{
+    fn build(&self, app: &mut AppBuilder) {
+        app.insert_resource(CoalescedDirtyChunks::<V>::new(
+            self.quiet_frames,
+            self.max_latency_frames,
+        ))
+        // Before consumers in PRE_UPDATE or UPDATE.
+        .add_system_to_stage(stage::FIRST, coalesced_dirty_chunks_system::<V>.system());
+    }
+}
+
+/// The dirty chunks released by the debouncer this frame, in the same shape as `DirtyChunks`.
+pub struct CoalescedDirtyChunks<V> {
+    pub edited_chunk_keys: Vec<Point3i>,
+    pub dirty_chunk_keys: FnvHashSet<Point3i>,
+    /// The edits to each of the `edited_chunk_keys`, combined over every frame it was held back.
+    pub chunk_edits: FnvHashMap<Point3i, ChunkEdits>,
+    quiet_frames: u64,
+    max_latency_frames: u64,
+    frame: u64,
+    flush_requested: bool,
+    pending: FnvHashMap<Point3i, PendingChunk>,
+    marker: std::marker::PhantomData<V>,
+}
+
+struct PendingChunk {
+    first_dirtied: u64,
+    last_dirtied: u64,
+    // Only for edited chunks.
+    edits: Option<ChunkEdits>,
+}
+
+impl<V> CoalescedDirtyChunks<V> {
+    fn new(quiet_frames: u64, max_latency_frames: u64) -> Self {
+        Self {
+            edited_chunk_keys: Vec::new(),
+            dirty_chunk_keys: Default::default(),
+            chunk_edits: Default::default(),
+            quiet_frames,
+            max_latency_frames,
+            frame: 0,
+            flush_requested: false,
+            pending: Default::default(),
+            marker: Default::default(),
+        }
+    }
+
+    /// Whether `chunk_key` is dirty but held back.
+    pub fn is_pending(&self, chunk_key: &Point3i) -> bool {
+        self.pending.contains_key(chunk_key)
+    }
+
+    pub fn num_pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Releases every held back chunk on the next frame, e.g. before saving.
+    pub fn flush(&mut self) {
+        self.flush_requested = true;
+    }
+
+    fn pend(&mut self, chunk_key: Point3i) -> &mut PendingChunk {
+        let frame = self.frame;
+        let pending = self.pending.entry(chunk_key).or_insert(PendingChunk {
+            first_dirtied: frame,
+            last_dirtied: frame,
+            edits: None,
+        });
+        pending.last_dirtied = frame;
+
+        pending
+    }
+}
+
+fn coalesced_dirty_chunks_system<V>(
+    dirty_chunks: Res<DirtyChunks<V>>,
+    empty_chunks: Res<EmptyChunks<V>>,
+    mut coalesced: ResMut<CoalescedDirtyChunks<V>>,
+) where
+    V: Voxel,
+{
+    let coalesced = &mut *coalesced;
+
+    coalesced.frame += 1;
+    coalesced.edited_chunk_keys.clear();
+    coalesced.dirty_chunk_keys.clear();
+    coalesced.chunk_edits.clear();
+
+    for chunk_key in dirty_chunks.dirty_chunk_keys.iter() {
+        coalesced.pend(*chunk_key);
+    }
+    for chunk_key in dirty_chunks.edited_chunk_keys.iter() {
+        let edits = dirty_chunks
+            .chunk_edits
+            .get(chunk_key)
+            .cloned()
+            .unwrap_or_default();
+        let pending = coalesced.pend(*chunk_key);
+        pending.edits = Some(match pending.edits.take() {
+            Some(mut pending_edits) => {
+                pending_edits.extend(edits);
+
+                pending_edits
+            }
+            None => edits,
+        });
+    }
+    for chunk_key in empty_chunks.removed_chunk_keys() {
+        coalesced.pending.remove(chunk_key);
+    }
+
+    let frame = coalesced.frame;
+    let quiet_frames = coalesced.quiet_frames;
+    let max_latency_frames = coalesced.max_latency_frames;
+    let flushing = std::mem::take(&mut coalesced.flush_requested);
+    let released: Vec<Point3i> = coalesced
+        .pending
+        .iter()
+        .filter(|(_, p)| {
+            flushing
+                || frame - p.last_dirtied >= quiet_frames
+                || frame - p.first_dirtied >= max_latency_frames
+        })
+        .map(|(k, _)| *k)
+        .collect();
+    for chunk_key in released.into_iter() {
+        let pending = coalesced.pending.remove(&chunk_key).unwrap();
+        coalesced.dirty_chunk_keys.insert(chunk_key);
+        if let Some(edits) = pending.edits {
+            coalesced.edited_chunk_keys.push(chunk_key);
+            coalesced.chunk_edits.insert(chunk_key, edits);
+        }
+    }
+}
@@ -0,0 +1,354 @@
+use crate::Voxel;
+
+use building_blocks::prelude::*;
+use std::{convert::TryInto, fmt};
+
+/// Controls how a chunk of voxels is converted to and from bytes. Persistence, replication, and
+/// prefab baking all go through this trait, so exotic voxel layouts only need to define their
+/// format in one place.
+///
+/// Implementations only deal with the voxel payload; use `encode_chunk` and `decode_chunk` to also
+/// store the format version and the chunk's extent.
+pub trait VoxelCodec<V>: Send + Sync
+where
+    V: Voxel,
+{
+    /// The format version written by `encode_voxels`. This is stored with every encoded chunk, so
+    /// `decode_voxels` can continue to read data written by older versions.
+    fn version(&self) -> u32;
+
+    /// Appends the encoded voxels of `chunk` to `out`.
+    fn encode_voxels(&self, chunk: &Array3<V>, out: &mut Vec<u8>);
+
+    /// Decodes the voxels in `extent` from `bytes`, which were written by `encode_voxels` with
+    /// format `version`.
+    fn decode_voxels(
+        &self,
+        version: u32,
+        bytes: &[u8],
+        extent: Extent3i,
+    ) -> Result<Array3<V>, CodecError>;
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum CodecError {
+    /// The data was written with a format version that this codec can't read.
+    UnsupportedVersion(u32),
+    /// The data ended before the whole chunk was decoded.
+    Truncated,
+    /// The data is corrupt or was written by a different codec.
+    InvalidData(String),
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CodecError::UnsupportedVersion(v) => write!(f, "unsupported format version {}", v),
+            CodecError::Truncated => write!(f, "encoded chunk is truncated"),
+            CodecError::InvalidData(reason) => write!(f, "invalid chunk data: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+const HEADER_LEN: usize = 4 * 7;
+
+/// Encodes `chunk` with a header containing the codec's format version and the chunk's extent.
+pub fn encode_chunk<V>(codec: &dyn VoxelCodec<V>, chunk: &Array3<V>) -> Vec<u8>
+where
+    V: Voxel,
+{
+    let extent = chunk.extent();
+    let mut bytes = Vec::with_capacity(HEADER_LEN);
+    bytes.extend_from_slice(&codec.version().to_le_bytes());
+    for c in extent.minimum.0.iter().chain(extent.shape.0.iter()) {
+        bytes.extend_from_slice(&c.to_le_bytes());
+    }
+    codec.encode_voxels(chunk, &mut bytes);
+
+    bytes
+}
+
+/// Decodes a chunk that was written by `encode_chunk`.
+pub fn decode_chunk<V>(codec: &dyn VoxelCodec<V>, bytes: &[u8]) -> Result<Array3<V>, CodecError>
+where
+    V: Voxel,
+{
+    let (version, extent, payload) = decode_header(bytes)?;
+
+    codec.decode_voxels(version, payload, extent)
+}
+
+/// Decodes a chunk that was written by `encode_chunk`, failing if it doesn't cover exactly
+/// `expected_extent`. Use this when the chunk is loaded into a map at a known chunk key, since
+/// corrupt or misplaced data could otherwise overwrite the wrong voxels.
+pub fn decode_chunk_at<V>(
+    codec: &dyn VoxelCodec<V>,
+    bytes: &[u8],
+    expected_extent: Extent3i,
+) -> Result<Array3<V>, CodecError>
+where
+    V: Voxel,
+{
+    let (version, extent, payload) = decode_header(bytes)?;
+    // Check the extent before decoding, so a corrupt header doesn't allocate.
+    if extent != expected_extent {
+        return Err(CodecError::InvalidData(format!(
+            "chunk extent at {:?} with shape {:?} doesn't match the expected extent at {:?} with shape {:?}",
+            extent.minimum.0, extent.shape.0, expected_extent.minimum.0, expected_extent.shape.0
+        )));
+    }
+
+    codec.decode_voxels(version, payload, extent)
+}
+
+/// Splits the format version, extent, and encoded voxels of a chunk written by `encode_chunk`.
+fn decode_header(bytes: &[u8]) -> Result<(u32, Extent3i, &[u8]), CodecError> {
+    if bytes.len() < HEADER_LEN {
+        return Err(CodecError::Truncated);
+    }
+    let (header, payload) = bytes.split_at(HEADER_LEN);
+    let mut words = header
+        .chunks_exact(4)
+        .map(|w| w.try_into().map(u32::from_le_bytes).unwrap());
+    let version = words.next().unwrap();
+    let mut next_i32 = || words.next().unwrap() as i32;
+    let minimum = PointN([next_i32(), next_i32(), next_i32()]);
+    let shape = PointN([next_i32(), next_i32(), next_i32()]);
+    if shape.0.iter().any(|&s| s <= 0) {
+        return Err(CodecError::InvalidData(format!(
+            "chunk shape {:?} is not positive",
+            shape.0
+        )));
+    }
+    let fits = minimum
+        .0
+        .iter()
+        .zip(shape.0.iter())
+        .all(|(m, s)| m.checked_add(*s).is_some());
+    if !fits || checked_num_points(shape).is_none() {
+        return Err(CodecError::InvalidData(format!(
+            "chunk extent at {:?} with shape {:?} is too large",
+            minimum.0, shape.0
+        )));
+    }
+
+    Ok((
+        version,
+        Extent3i::from_min_and_shape(minimum, shape),
+        payload,
+    ))
+}
+
+/// The number of points in `shape`, or `None` if it overflows an `i32`.
+fn checked_num_points(shape: Point3i) -> Option<usize> {
+    let n = shape.0.iter().try_fold(1i32, |n, &s| n.checked_mul(s))?;
+
+    n.try_into().ok()
+}
+
+/// A `VoxelCodec` for voxels that are always encoded with the same number of bytes. Voxels are
+/// written in array order, i.e. with X varying fastest.
+///
+/// ```
+/// use bevy_building_blocks::{FixedSizeCodec, Voxel};
+///
+/// #[derive(Copy, Clone, Default)]
+/// struct MyVoxel {
+///     voxel_type: u8,
+/// }
+///
+/// impl Voxel for MyVoxel {
+///     type TypeInfo = ();
+///
+///     fn get_type_index(&self) -> usize {
+///         self.voxel_type as usize
+///     }
+/// }
+///
+/// let codec = FixedSizeCodec::<MyVoxel> {
+///     version: 1,
+///     voxel_size: 1,
+///     encode: |v, out| out.push(v.voxel_type),
+///     decode: |bytes| Some(MyVoxel { voxel_type: bytes[0] }),
+/// };
+/// ```
+pub struct FixedSizeCodec<V> {
+    pub version: u32,
+    /// Must not be zero.
+    pub voxel_size: usize,
+    /// Must append exactly `voxel_size` bytes.
+    pub encode: fn(&V, &mut Vec<u8>),
+    /// Receives exactly `voxel_size` bytes. Returns `None` if they don't represent a valid voxel.
+    pub decode: fn(&[u8]) -> Option<V>,
+}
+
+impl<V> VoxelCodec<V> for FixedSizeCodec<V>
+where
+    V: Voxel,
+{
+    fn version(&self) -> u32 {
+        self.version
+    }
+
+    fn encode_voxels(&self, chunk: &Array3<V>, out: &mut Vec<u8>) {
+        out.reserve(self.voxel_size * chunk.extent().num_points());
+        let encode = self.encode;
+        chunk.for_each(chunk.extent(), |_p: Point3i, voxel: V| encode(&voxel, out));
+    }
+
+    fn decode_voxels(
+        &self,
+        version: u32,
+        bytes: &[u8],
+        extent: Extent3i,
+    ) -> Result<Array3<V>, CodecError> {
+        if version != self.version {
+            return Err(CodecError::UnsupportedVersion(version));
+        }
+        if self.voxel_size == 0 {
+            return Err(CodecError::InvalidData("voxel size is zero".to_string()));
+        }
+        // Check the length before allocating the chunk, since the extent may be corrupt.
+        let num_bytes = checked_num_points(extent.shape)
+            .and_then(|n| n.checked_mul(self.voxel_size))
+            .ok_or_else(|| {
+                CodecError::InvalidData(format!("chunk shape {:?} is too large", extent.shape.0))
+            })?;
+        if bytes.len() < num_bytes {
+            return Err(CodecError::Truncated);
+        }
+
+        let mut chunk = Array3::fill(extent, V::default());
+        let mut voxel_bytes = bytes.chunks_exact(self.voxel_size);
+        let decode = self.decode;
+        let mut error = None;
+        chunk.for_each_mut(&extent, |p: Point3i, voxel: &mut V| {
+            if error.is_some() {
+                return;
+            }
+            match voxel_bytes.next().and_then(decode) {
+                Some(v) => *voxel = v,
+                None => {
+                    error = Some(CodecError::InvalidData(format!(
+                        "invalid voxel at {:?}",
+                        p.0
+                    )))
+                }
+            }
+        });
+
+        match error {
+            Some(e) => Err(e),
+            None => Ok(chunk),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::*;
+
+    fn encode(chunk: &Array3<TestVoxel>) -> Vec<u8> {
+        encode_chunk::<TestVoxel>(&test_codec(), chunk)
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Array3<TestVoxel>, CodecError> {
+        decode_chunk::<TestVoxel>(&test_codec(), bytes)
+    }
+
+    fn decode_at(bytes: &[u8], extent: Extent3i) -> Result<Array3<TestVoxel>, CodecError> {
+        decode_chunk_at::<TestVoxel>(&test_codec(), bytes, extent)
+    }
+
+    fn test_extent() -> Extent3i {
+        Extent3i::from_min_and_shape(PointN([-16, 0, 16]), PointN([4, 2, 3]))
+    }
+
+    fn encoded_test_chunk() -> Vec<u8> {
+        encode(&numbered_array(test_extent()))
+    }
+
+    fn set_header_i32(bytes: &mut [u8], word: usize, value: i32) {
+        bytes[4 * word..4 * (word + 1)].copy_from_slice(&value.to_le_bytes());
+    }
+
+    #[test]
+    fn round_trip_keeps_extent_and_voxels() {
+        let chunk = numbered_array(test_extent());
+        let bytes = encode(&chunk);
+        assert_eq!(bytes.len(), HEADER_LEN + test_extent().num_points());
+
+        assert_same_voxels(&decode(&bytes).unwrap(), &chunk);
+        assert_same_voxels(&decode_at(&bytes, test_extent()).unwrap(), &chunk);
+    }
+
+    #[test]
+    fn decode_at_rejects_other_extents() {
+        let bytes = encoded_test_chunk();
+        let moved = Extent3i::from_min_and_shape(PointN([-12, 0, 16]), PointN([4, 2, 3]));
+        let reshaped = Extent3i::from_min_and_shape(PointN([-16, 0, 16]), PointN([4, 3, 2]));
+
+        for extent in [moved, reshaped].iter() {
+            match decode_at(&bytes, *extent) {
+                Err(CodecError::InvalidData(_)) => (),
+                other => panic!("expected invalid data, got {:?}", other.map(|_| ())),
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_chunks_are_rejected() {
+        let bytes = encoded_test_chunk();
+
+        for len in [0, HEADER_LEN - 1, HEADER_LEN, bytes.len() - 1].iter() {
+            assert_eq!(
+                decode(&bytes[..*len]).map(|_| ()),
+                Err(CodecError::Truncated)
+            );
+        }
+    }
+
+    #[test]
+    fn corrupt_shapes_are_rejected_before_decoding() {
+        // The shape is in words 4 to 6 of the header.
+        for (word, value) in [(4, 0), (5, -2), (6, i32::MAX), (1, i32::MAX)].iter() {
+            let mut bytes = encoded_test_chunk();
+            set_header_i32(&mut bytes, *word, *value);
+
+            match decode(&bytes) {
+                Err(CodecError::InvalidData(_)) => (),
+                other => panic!(
+                    "expected invalid data for word {} = {}, got {:?}",
+                    word,
+                    value,
+                    other.map(|_| ())
+                ),
+            }
+        }
+    }
+
+    #[test]
+    fn other_versions_are_unsupported() {
+        let mut bytes = encoded_test_chunk();
+        set_header_i32(&mut bytes, 0, 2);
+
+        assert_eq!(
+            decode(&bytes).map(|_| ()),
+            Err(CodecError::UnsupportedVersion(2))
+        );
+    }
+
+    #[test]
+    fn invalid_voxels_are_rejected() {
+        let mut bytes = encoded_test_chunk();
+        bytes[HEADER_LEN + 5] = MAX_TEST_VOXEL + 1;
+
+        match decode(&bytes) {
+            Err(CodecError::InvalidData(_)) => (),
+            other => panic!("expected invalid data, got {:?}", other.map(|_| ())),
+        }
+    }
+}
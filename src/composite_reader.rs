@@ -0,0 +1,168 @@
+use crate::{Voxel, VoxelReader};
+
+use building_blocks::prelude::*;
+
+/// Reads several `VoxelMap`s as one, e.g. terrain, then fluid, then decoration, each usually a
+/// `Layered` map.
+///
+/// Every layer maps its voxels to a common type `T`, or to `None` where the layer has nothing, like
+/// at an empty voxel. Layers are added in priority order, highest first. By default, the value of
+/// the highest-priority layer that has one wins, and `get` doesn't read the layers below it. With
+/// `with_combine`, the values of every layer are folded from the highest priority down.
+///
+/// ```
+/// use bevy_building_blocks::{bb::prelude::*, CompositeReader, Layered, Voxel, VoxelReader};
+///
+/// #[derive(Clone, Copy, Default)]
+/// struct Block(u8);
+///
+/// impl Voxel for Block {
+///     type TypeInfo = ();
+///
+///     fn get_type_index(&self) -> usize {
+///         self.0 as usize
+///     }
+/// }
+///
+/// struct Terrain;
+/// struct Water;
+///
+/// fn solid<K>(voxel: Layered<K, Block>) -> Option<Block> {
+///     Some(voxel.voxel).filter(|b| b.0 != 0)
+/// }
+///
+/// fn composite_system(
+///     terrain: VoxelReader<Layered<Terrain, Block>>,
+///     water: VoxelReader<Layered<Water, Block>>,
+/// ) {
+///     let composite = CompositeReader::new()
+///         .with_layer(&terrain, solid)
+///         .with_layer(&water, solid);
+///
+///     let block: Option<Block> = composite.get(&PointN([0, 0, 0]));
+/// }
+/// ```
+pub struct CompositeReader<'r, T> {
+    layers: Vec<Box<dyn CompositeLayer<T> + 'r>>,
+    combine: Option<Box<dyn Fn(T, T) -> T + 'r>>,
+}
+
+impl<'r, T> Default for CompositeReader<'r, T> {
+    fn default() -> Self {
+        Self {
+            layers: Vec::new(),
+            combine: None,
+        }
+    }
+}
+
+impl<'r, T> CompositeReader<'r, T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a layer below the existing ones, reading `reader` and mapping its voxels with `map_fn`.
+    pub fn with_layer<'w, V>(
+        mut self,
+        reader: &'r VoxelReader<'w, V>,
+        map_fn: impl Fn(V) -> Option<T> + 'r,
+    ) -> Self
+    where
+        V: Voxel,
+        'w: 'r,
+    {
+        self.layers.push(Box::new(MappedLayer { reader, map_fn }));
+
+        self
+    }
+
+    /// Combines the values of all layers with `combine(higher, lower)`, instead of taking the
+    /// highest.
+    pub fn with_combine(mut self, combine: impl Fn(T, T) -> T + 'r) -> Self {
+        self.combine = Some(Box::new(combine));
+
+        self
+    }
+
+    pub fn num_layers(&self) -> usize {
+        self.layers.len()
+    }
+
+    pub fn get(&self, p: &Point3i) -> Option<T> {
+        let mut values = self.layers.iter().filter_map(|layer| layer.get(*p));
+        let first = values.next()?;
+
+        Some(match &self.combine {
+            Some(combine) => values.fold(first, combine),
+            None => first,
+        })
+    }
+
+    /// Visits every point in `extent` with its composite value. Each layer is read with a single
+    /// `for_each`, which is much faster than calling `get` for every point.
+    pub fn for_each(&self, extent: &Extent3i, mut f: impl FnMut(Point3i, Option<T>)) {
+        let mut values: Vec<Option<T>> = (0..extent.num_points()).map(|_| None).collect();
+        for layer in self.layers.iter() {
+            layer.for_each(extent, &mut |p, lower| {
+                let lower = match lower {
+                    Some(lower) => lower,
+                    None => return,
+                };
+                let value = &mut values[linear_index(extent, &p)];
+                *value = match (value.take(), &self.combine) {
+                    (None, _) => Some(lower),
+                    (Some(higher), Some(combine)) => Some(combine(higher, lower)),
+                    (Some(higher), None) => Some(higher),
+                };
+            });
+        }
+
+        let min = extent.minimum;
+        let max = extent.max();
+        let mut values = values.into_iter();
+        for z in min.z()..=max.z() {
+            for y in min.y()..=max.y() {
+                for x in min.x()..=max.x() {
+                    f(PointN([x, y, z]), values.next().unwrap());
+                }
+            }
+        }
+    }
+}
+
+/// The index of `p` in `extent`, with X varying fastest.
+fn linear_index(extent: &Extent3i, p: &Point3i) -> usize {
+    let local = *p - extent.minimum;
+    let shape = extent.shape;
+
+    (local.x() + shape.x() * (local.y() + shape.y() * local.z())) as usize
+}
+
+trait CompositeLayer<T> {
+    fn get(&self, p: Point3i) -> Option<T>;
+
+    fn for_each(&self, extent: &Extent3i, f: &mut dyn FnMut(Point3i, Option<T>));
+}
+
+struct MappedLayer<'r, 'w, V, F>
+where
+    V: Voxel,
+{
+    reader: &'r VoxelReader<'w, V>,
+    map_fn: F,
+}
+
+impl<'r, 'w, V, F, T> CompositeLayer<T> for MappedLayer<'r, 'w, V, F>
+where
+    V: Voxel,
+    F: Fn(V) -> Option<T>,
+{
+    fn get(&self, p: Point3i) -> Option<T> {
+        (self.map_fn)(self.reader.get(p))
+    }
+
+    fn for_each(&self, extent: &Extent3i, f: &mut dyn FnMut(Point3i, Option<T>)) {
+        self.reader
+            .for_each(extent, |p: Point3i, voxel: V| f(p, (self.map_fn)(voxel)));
+    }
+}
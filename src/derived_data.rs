@@ -0,0 +1,205 @@
+use crate::{
+    tasks::map_in_pool, DirtyChunks, EmptyChunks, ThreadLocalVoxelCache, Voxel, VoxelMap,
+    VoxelTaskPool,
+};
+
+use bevy::prelude::*;
+use building_blocks::prelude::*;
+use fnv::{FnvHashMap, FnvHashSet};
+use std::{collections::VecDeque, sync::Arc};
+
+/// Computes a value of `T` for a chunk from the map, or `None` if the chunk has none, e.g. an empty
+/// mesh. It's given the map, a reader, and the key of the chunk.
+pub type DerivedChunkFn<V, T> =
+    dyn Fn(&VoxelMap<V>, &CompressibleChunkMapReader3<V>, Point3i) -> Option<T> + Send + Sync;
+
+/// Manages a `DerivedChunkData<V, T>` resource, which keeps one value of `T` per chunk, like a
+/// mesh, collider, light field, or navmesh, up to date with the map. Depends on the `MapIoPlugin`.
+///
+/// Every chunk in the previous frame's `DirtyChunks` is queued for recomputation, including the
+/// neighbors of edited chunks, since derived data usually depends on them. Queued chunks are
+/// computed on the `VoxelTaskPool`, up to `chunks_per_frame` per frame, in the `PRE_UPDATE` stage
+/// unless another stage is given. Values of removed chunks are dropped.
+///
+/// Several datasets can be derived from the same map by adding one plugin per type `T`.
+pub struct DerivedChunkDataPlugin<V, T> {
+    pub chunks_per_frame: usize,
+    pub stage: &'static str,
+    compute: Arc<DerivedChunkFn<V, T>>,
+}
+
+impl<V, T> DerivedChunkDataPlugin<V, T> {
+    pub fn new(
+        compute: impl Fn(&VoxelMap<V>, &CompressibleChunkMapReader3<V>, Point3i) -> Option<T>
+            + Send
+            + Sync
+            + 'static,
+    ) -> Self {
+        Self {
+            chunks_per_frame: 64,
+            stage: stage::PRE_UPDATE,
+            compute: Arc::new(compute),
+        }
+    }
+
+    pub fn with_chunks_per_frame(mut self, chunks_per_frame: usize) -> Self {
+        self.chunks_per_frame = chunks_per_frame;
+
+        self
+    }
+
+    pub fn with_stage(mut self, stage: &'static str) -> Self {
+        self.stage = stage;
+
+        self
+    }
+}
+
+impl<V, T> Plugin for DerivedChunkDataPlugin<V, T>
+where
+    V: Voxel,
+    T: 'static + Send + Sync,
+{
+    fn build(&self, app: &mut AppBuilder) {
+        app.insert_resource(DerivedChunkData::<V, T>::new(
+            self.compute.clone(),
+            self.chunks_per_frame,
+        ))
+        .add_system_to_stage(self.stage, derived_chunk_data_system::<V, T>.system());
+    }
+}
+
+/// One value of `T` per chunk, derived from the map by the `DerivedChunkDataPlugin`.
+///
+/// Values are double-buffered: a chunk's old value stays readable while it's queued for
+/// recomputation, and is replaced all at once when the new value is ready. Check `is_stale` to
+/// tell the two apart, and `changed_chunk_keys` for the values replaced this frame.
+pub struct DerivedChunkData<V, T> {
+    /// The most chunks computed in a single frame.
+    pub chunks_per_frame: usize,
+    compute: Arc<DerivedChunkFn<V, T>>,
+    values: FnvHashMap<Point3i, T>,
+    queue: VecDeque<Point3i>,
+    queued: FnvHashSet<Point3i>,
+    changed_chunk_keys: Vec<Point3i>,
+    removed_chunk_keys: Vec<Point3i>,
+}
+
+impl<V, T> DerivedChunkData<V, T> {
+    fn new(compute: Arc<DerivedChunkFn<V, T>>, chunks_per_frame: usize) -> Self {
+        Self {
+            chunks_per_frame,
+            compute,
+            values: Default::default(),
+            queue: VecDeque::new(),
+            queued: Default::default(),
+            changed_chunk_keys: Vec::new(),
+            removed_chunk_keys: Vec::new(),
+        }
+    }
+
+    pub fn get(&self, chunk_key: &Point3i) -> Option<&T> {
+        self.values.get(chunk_key)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Point3i, &T)> {
+        self.values.iter()
+    }
+
+    /// `true` if the chunk's value is waiting to be recomputed.
+    pub fn is_stale(&self, chunk_key: &Point3i) -> bool {
+        self.queued.contains(chunk_key)
+    }
+
+    /// Queues the chunk at `chunk_key` for recomputation, e.g. because something else the value
+    /// depends on changed.
+    pub fn recompute(&mut self, chunk_key: Point3i) {
+        if self.queued.insert(chunk_key) {
+            self.queue.push_back(chunk_key);
+        }
+    }
+
+    /// The chunks whose value was computed or dropped this frame, including those that no longer
+    /// have a value.
+    pub fn changed_chunk_keys(&self) -> impl Iterator<Item = &Point3i> {
+        self.changed_chunk_keys.iter()
+    }
+
+    /// The chunks whose value was dropped this frame because the chunk was removed.
+    pub fn removed_chunk_keys(&self) -> impl Iterator<Item = &Point3i> {
+        self.removed_chunk_keys.iter()
+    }
+
+    /// The number of chunks waiting to be recomputed.
+    pub fn num_queued(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+}
+
+fn derived_chunk_data_system<V, T>(
+    pool: Res<VoxelTaskPool>,
+    voxel_map: Res<VoxelMap<V>>,
+    local_caches: Res<ThreadLocalVoxelCache<V>>,
+    dirty_chunks: Res<DirtyChunks<V>>,
+    empty_chunks: Res<EmptyChunks<V>>,
+    mut data: ResMut<DerivedChunkData<V, T>>,
+) where
+    V: Voxel,
+    T: 'static + Send + Sync,
+{
+    let data = &mut *data;
+    data.changed_chunk_keys.clear();
+    data.removed_chunk_keys.clear();
+
+    for &chunk_key in empty_chunks.removed_chunk_keys() {
+        if data.queued.remove(&chunk_key) {
+            data.queue.retain(|k| *k != chunk_key);
+        }
+        if data.values.remove(&chunk_key).is_some() {
+            data.changed_chunk_keys.push(chunk_key);
+            data.removed_chunk_keys.push(chunk_key);
+        }
+    }
+    for &chunk_key in dirty_chunks.dirty_chunk_keys.iter() {
+        data.recompute(chunk_key);
+    }
+
+    let num_chunks = data.chunks_per_frame.min(data.queue.len());
+    if num_chunks == 0 {
+        return;
+    }
+    let batch: Vec<Point3i> = data.queue.drain(..num_chunks).collect();
+    for chunk_key in batch.iter() {
+        data.queued.remove(chunk_key);
+    }
+
+    let map = &*voxel_map;
+    let local_caches = &*local_caches;
+    let compute = &*data.compute;
+    let computed = map_in_pool(&*pool, batch.into_iter(), |chunk_key| {
+        let cache_tls = local_caches.get();
+        let reader = map.reader(&cache_tls);
+
+        (chunk_key, compute(map, &reader, chunk_key))
+    });
+
+    for (chunk_key, value) in computed.into_iter() {
+        match value {
+            Some(value) => {
+                data.values.insert(chunk_key, value);
+            }
+            None => {
+                data.values.remove(&chunk_key);
+            }
+        }
+        data.changed_chunk_keys.push(chunk_key);
+    }
+}
@@ -0,0 +1,178 @@
+use crate::{observer::transform_voxel_point, DirtyChunks, Observer, Voxel, VoxelMap};
+
+use bevy::prelude::*;
+use building_blocks::prelude::*;
+use fnv::FnvHashSet;
+
+/// Manages the `DirtyChunkQueue` resource, which collects dirty chunks across frames and hands
+/// them out most urgent first. Depends on the `MapIoPlugin`.
+///
+/// This is useful when a burst of edits dirties more chunks than can be post-processed in one
+/// frame. Drain a budget's worth of chunks every frame, and the chunks near `Observer`s are
+/// processed first:
+///
+/// ```
+/// use bevy::prelude::*;
+/// use bevy_building_blocks::{DirtyChunkQueue, Voxel};
+///
+/// fn remesh_system<V: Voxel>(mut queue: ResMut<DirtyChunkQueue<V>>) {
+///     for chunk_key in queue.drain(16) {
+///         // Re-mesh the chunk.
+///     }
+/// }
+/// ```
+pub struct DirtyChunkQueuePlugin<V> {
+    marker: std::marker::PhantomData<V>,
+}
+
+impl<V> Default for DirtyChunkQueuePlugin<V> {
+    fn default() -> Self {
+        Self {
+            marker: Default::default(),
+        }
+    }
+}
+
+impl<V> Plugin for DirtyChunkQueuePlugin<V>
+where
+    V: Voxel,
+{
+    fn build(&self, app: &mut AppBuilder) {
+        app.insert_resource(DirtyChunkQueue::<V>::default())
+            // Order the queue before consumers run in UPDATE.
+            .add_system_to_stage(stage::PRE_UPDATE, dirty_chunk_queue_system::<V>.system());
+    }
+}
+
+/// Scores a dirty chunk given its extent and the voxel positions of all `Observer`s. Chunks with
+/// lower scores are drained first.
+pub type DirtyChunkScoreFn = dyn Fn(&Extent3i, &[Point3i]) -> f32 + Send + Sync;
+
+/// Dirty chunks waiting to be post-processed, ordered by score. A chunk is only queued once, no
+/// matter how many times it's dirtied before being drained.
+///
+/// By default, chunks are scored by the squared distance from their centers to the nearest
+/// `Observer`, so with no observers the order is arbitrary. The queue is re-ordered every frame, in
+/// `PRE_UPDATE`.
+pub struct DirtyChunkQueue<V> {
+    score_fn: Box<DirtyChunkScoreFn>,
+    queued: FnvHashSet<Point3i>,
+    // Sorted so the most urgent chunk is last.
+    ordered: Vec<Point3i>,
+    marker: std::marker::PhantomData<V>,
+}
+
+impl<V> Default for DirtyChunkQueue<V> {
+    fn default() -> Self {
+        Self {
+            score_fn: Box::new(nearest_observer_distance_sq),
+            queued: Default::default(),
+            ordered: Vec::new(),
+            marker: Default::default(),
+        }
+    }
+}
+
+impl<V> DirtyChunkQueue<V> {
+    /// Replaces the default scoring by distance to observers. Takes effect when the queue is next
+    /// re-ordered.
+    pub fn set_score_fn(
+        &mut self,
+        score_fn: impl Fn(&Extent3i, &[Point3i]) -> f32 + Send + Sync + 'static,
+    ) {
+        self.score_fn = Box::new(score_fn);
+    }
+
+    /// Removes and returns up to `max_chunks` of the most urgent chunk keys, most urgent first.
+    pub fn drain(&mut self, max_chunks: usize) -> Vec<Point3i> {
+        let num_drained = max_chunks.min(self.ordered.len());
+        let drained: Vec<Point3i> = self
+            .ordered
+            .drain(self.ordered.len() - num_drained..)
+            .rev()
+            .collect();
+        for chunk_key in drained.iter() {
+            self.queued.remove(chunk_key);
+        }
+
+        drained
+    }
+
+    /// Queues a chunk manually, e.g. to re-process it after a failure.
+    pub fn push(&mut self, chunk_key: Point3i) {
+        if self.queued.insert(chunk_key) {
+            // Until the next re-ordering, manually queued chunks are the least urgent.
+            self.ordered.insert(0, chunk_key);
+        }
+    }
+
+    pub fn contains(&self, chunk_key: &Point3i) -> bool {
+        self.queued.contains(chunk_key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.ordered.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ordered.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.queued.clear();
+        self.ordered.clear();
+    }
+}
+
+fn nearest_observer_distance_sq(chunk_extent: &Extent3i, observers: &[Point3i]) -> f32 {
+    let shape = chunk_extent.shape;
+    let center = chunk_extent.minimum + PointN([shape.x() / 2, shape.y() / 2, shape.z() / 2]);
+
+    observers
+        .iter()
+        .map(|o| {
+            let d = *o - center;
+
+            (d.x() as f32).powi(2) + (d.y() as f32).powi(2) + (d.z() as f32).powi(2)
+        })
+        .fold(None, |nearest: Option<f32>, d| {
+            Some(nearest.map_or(d, |n| n.min(d)))
+        })
+        .unwrap_or(0.0)
+}
+
+fn dirty_chunk_queue_system<V>(
+    observers: Query<&GlobalTransform, With<Observer>>,
+    voxel_map: Res<VoxelMap<V>>,
+    dirty_chunks: Res<DirtyChunks<V>>,
+    mut queue: ResMut<DirtyChunkQueue<V>>,
+) where
+    V: Voxel,
+{
+    let queue = &mut *queue;
+
+    for chunk_key in dirty_chunks.dirty_chunk_keys.iter() {
+        if queue.queued.insert(*chunk_key) {
+            queue.ordered.push(*chunk_key);
+        }
+    }
+    if queue.ordered.is_empty() {
+        return;
+    }
+
+    let indexer = &voxel_map.voxels.indexer;
+    let observer_points: Vec<Point3i> = observers.iter().map(transform_voxel_point).collect();
+    let score_fn = &queue.score_fn;
+    let mut scored: Vec<(f32, Point3i)> = queue
+        .ordered
+        .iter()
+        .map(|k| {
+            let score = score_fn(&indexer.extent_for_chunk_at_key(*k), &observer_points);
+
+            (score, *k)
+        })
+        .collect();
+    // Descending, so the lowest score is last.
+    scored.sort_by(|(a, _), (b, _)| b.partial_cmp(a).unwrap_or(std::cmp::Ordering::Equal));
+    queue.ordered = scored.into_iter().map(|(_, k)| k).collect();
+}
@@ -0,0 +1,351 @@
+use crate::{
+    decode_chunk, encode_chunk, CodecError, DirtyChunks, EmptyChunks, Voxel, VoxelCodec,
+    VoxelEditor, VoxelReader,
+};
+
+use bevy::prelude::*;
+use building_blocks::prelude::*;
+use std::{convert::TryInto, sync::Arc};
+
+/// Manages the `EditRecorder` and `EditPlayback` resources, which capture the edits merged into the
+/// `VoxelMap` and re-apply them later, e.g. for demo replays, debugging desyncs, or regression tests
+/// of world mutation logic. Depends on the `MapIoPlugin`.
+///
+/// Voxels are encoded with `codec`, so the playback needs a codec that can read what the recorder
+/// wrote.
+pub struct EditRecordingPlugin<V>
+where
+    V: Voxel,
+{
+    codec: Arc<dyn VoxelCodec<V>>,
+}
+
+impl<V> EditRecordingPlugin<V>
+where
+    V: Voxel,
+{
+    pub fn new(codec: impl VoxelCodec<V> + 'static) -> Self {
+        Self {
+            codec: Arc::new(codec),
+        }
+    }
+}
+
+impl<V> Plugin for EditRecordingPlugin<V>
+where
+    V: Voxel,
+{
+    fn build(&self, app: &mut AppBuilder) {
+        app.insert_resource(EditRecorder::<V>::new(self.codec.clone()))
+            .insert_resource(EditPlayback::<V>::new(self.codec.clone()))
+            // The previous frame's merge is complete in FIRST.
+            .add_system_to_stage(stage::FIRST, edit_recorder_system::<V>.system())
+            // Played back edits are merged at the end of the same frame, like the recorded ones.
+            .add_system(edit_playback_system::<V>.system());
+    }
+}
+
+/// A recording of the edits merged into the `VoxelMap`, frame by frame.
+///
+/// Each frame stores the final contents of every edited extent, rather than the edit functions, so
+/// playing it back reproduces the recorded voxels exactly, whatever state the map is in.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct EditLog {
+    frames: Vec<RecordedFrame>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct RecordedFrame {
+    // Counted from the frame on which the recording started.
+    frame: u64,
+    // Each encoded with `encode_chunk`, which stores the extent.
+    extents: Vec<Vec<u8>>,
+    removed_chunk_keys: Vec<Point3i>,
+}
+
+impl EditLog {
+    /// The number of frames with edits.
+    pub fn num_frames(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// The number of frames from the start of the recording to its last edit.
+    pub fn duration_frames(&self) -> u64 {
+        self.frames.last().map_or(0, |f| f.frame + 1)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&(self.frames.len() as u32).to_le_bytes());
+        for frame in self.frames.iter() {
+            bytes.extend_from_slice(&frame.frame.to_le_bytes());
+            bytes.extend_from_slice(&(frame.removed_chunk_keys.len() as u32).to_le_bytes());
+            for chunk_key in frame.removed_chunk_keys.iter() {
+                for c in chunk_key.0.iter() {
+                    bytes.extend_from_slice(&c.to_le_bytes());
+                }
+            }
+            bytes.extend_from_slice(&(frame.extents.len() as u32).to_le_bytes());
+            for encoded in frame.extents.iter() {
+                bytes.extend_from_slice(&(encoded.len() as u32).to_le_bytes());
+                bytes.extend_from_slice(encoded);
+            }
+        }
+
+        bytes
+    }
+
+    /// Reads a log written by `to_bytes`. The voxels aren't decoded until they're played back.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, CodecError> {
+        let mut reader = ByteReader { bytes };
+        let num_frames = reader.read_u32()?;
+        let mut frames = Vec::new();
+        for _ in 0..num_frames {
+            let frame = reader.read_u64()?;
+            let num_removed = reader.read_u32()?;
+            let mut removed_chunk_keys = Vec::new();
+            for _ in 0..num_removed {
+                let x = reader.read_u32()? as i32;
+                let y = reader.read_u32()? as i32;
+                let z = reader.read_u32()? as i32;
+                removed_chunk_keys.push(PointN([x, y, z]));
+            }
+            let num_extents = reader.read_u32()?;
+            let mut extents = Vec::new();
+            for _ in 0..num_extents {
+                let len = reader.read_u32()? as usize;
+                extents.push(reader.take(len)?.to_vec());
+            }
+            frames.push(RecordedFrame {
+                frame,
+                extents,
+                removed_chunk_keys,
+            });
+        }
+        if !reader.bytes.is_empty() {
+            return Err(CodecError::InvalidData(format!(
+                "{} trailing bytes after the last frame",
+                reader.bytes.len()
+            )));
+        }
+
+        Ok(Self { frames })
+    }
+}
+
+struct ByteReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> ByteReader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], CodecError> {
+        if self.bytes.len() < len {
+            return Err(CodecError::Truncated);
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+
+        Ok(taken)
+    }
+
+    fn read_u32(&mut self) -> Result<u32, CodecError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn read_u64(&mut self) -> Result<u64, CodecError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+}
+
+/// Records the edits merged into the `VoxelMap` into an `EditLog`, while started.
+///
+/// Each frame's edits are captured from `DirtyChunks` and `EmptyChunks` at the start of the next
+/// frame, so a recording that's started mid-frame includes everything merged at the end of that
+/// frame.
+pub struct EditRecorder<V>
+where
+    V: Voxel,
+{
+    codec: Arc<dyn VoxelCodec<V>>,
+    log: Option<EditLog>,
+    frame: u64,
+}
+
+impl<V> EditRecorder<V>
+where
+    V: Voxel,
+{
+    fn new(codec: Arc<dyn VoxelCodec<V>>) -> Self {
+        Self {
+            codec,
+            log: None,
+            frame: 0,
+        }
+    }
+
+    /// Starts a new recording, discarding the current one, if any.
+    pub fn start(&mut self) {
+        self.log = Some(EditLog::default());
+        self.frame = 0;
+    }
+
+    /// Stops recording and returns the log, or `None` if nothing was being recorded.
+    pub fn stop(&mut self) -> Option<EditLog> {
+        self.log.take()
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.log.is_some()
+    }
+
+    /// The log recorded so far.
+    pub fn log(&self) -> Option<&EditLog> {
+        self.log.as_ref()
+    }
+}
+
+/// Plays back an `EditLog` through the `VoxelEditor`, one recorded frame per frame. All edited
+/// chunks and their neighbors will be marked as dirty.
+///
+/// Removed chunks are marked in `EmptyChunks`, so they're subject to its per-frame removal budget.
+///
+/// If a recorded extent fails to decode, the playback stops, and the error is kept in `error`.
+pub struct EditPlayback<V>
+where
+    V: Voxel,
+{
+    codec: Arc<dyn VoxelCodec<V>>,
+    log: Option<EditLog>,
+    frame: u64,
+    next_index: usize,
+    error: Option<CodecError>,
+}
+
+impl<V> EditPlayback<V>
+where
+    V: Voxel,
+{
+    fn new(codec: Arc<dyn VoxelCodec<V>>) -> Self {
+        Self {
+            codec,
+            log: None,
+            frame: 0,
+            next_index: 0,
+            error: None,
+        }
+    }
+
+    /// Starts playing `log` on this frame, replacing the current playback, if any.
+    pub fn play(&mut self, log: EditLog) {
+        self.log = Some(log);
+        self.frame = 0;
+        self.next_index = 0;
+        self.error = None;
+    }
+
+    pub fn stop(&mut self) {
+        self.log = None;
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.log.is_some()
+    }
+
+    /// Why the last playback stopped early, if it did.
+    pub fn error(&self) -> Option<&CodecError> {
+        self.error.as_ref()
+    }
+}
+
+fn edit_recorder_system<V>(
+    dirty_chunks: Res<DirtyChunks<V>>,
+    empty_chunks: Res<EmptyChunks<V>>,
+    voxel_reader: VoxelReader<V>,
+    mut recorder: ResMut<EditRecorder<V>>,
+) where
+    V: Voxel,
+{
+    let recorder = &mut *recorder;
+
+    let log = match recorder.log.as_mut() {
+        Some(log) => log,
+        None => return,
+    };
+    let frame = recorder.frame;
+    recorder.frame += 1;
+
+    let removed_chunk_keys: Vec<Point3i> = empty_chunks.removed_chunk_keys().cloned().collect();
+    let mut extents = Vec::new();
+    for chunk_key in dirty_chunks.edited_chunk_keys.iter() {
+        let chunk_extent = voxel_reader
+            .map
+            .voxels
+            .indexer
+            .extent_for_chunk_at_key(*chunk_key);
+        match dirty_chunks.chunk_edits.get(chunk_key) {
+            Some(edits) if !edits.replaced => extents.extend(edits.extents.iter().cloned()),
+            _ => extents.push(chunk_extent),
+        }
+    }
+    if extents.is_empty() && removed_chunk_keys.is_empty() {
+        return;
+    }
+
+    let codec = &*recorder.codec;
+    let extents = extents
+        .iter()
+        .map(|extent| encode_chunk(codec, &voxel_reader.copy_extent(extent)))
+        .collect();
+    log.frames.push(RecordedFrame {
+        frame,
+        extents,
+        removed_chunk_keys,
+    });
+}
+
+fn edit_playback_system<V>(
+    mut voxel_editor: VoxelEditor<V>,
+    mut empty_chunks: ResMut<EmptyChunks<V>>,
+    mut playback: ResMut<EditPlayback<V>>,
+) where
+    V: Voxel,
+{
+    let playback = &mut *playback;
+
+    let log = match playback.log.take() {
+        Some(log) => log,
+        None => return,
+    };
+    let frame = playback.frame;
+    playback.frame += 1;
+
+    while let Some(recorded) = log.frames.get(playback.next_index) {
+        if recorded.frame > frame {
+            break;
+        }
+        playback.next_index += 1;
+
+        for chunk_key in recorded.removed_chunk_keys.iter() {
+            empty_chunks.mark_for_removal(*chunk_key);
+        }
+        for encoded in recorded.extents.iter() {
+            match decode_chunk(&*playback.codec, encoded) {
+                Ok(voxels) => voxel_editor
+                    .overwrite_extent_and_touch_neighbors(*voxels.extent(), |p: Point3i| {
+                        voxels.get(&p)
+                    }),
+                Err(e) => {
+                    playback.error = Some(e);
+                    return;
+                }
+            }
+        }
+    }
+    if playback.next_index < log.frames.len() {
+        playback.log = Some(log);
+    }
+}
@@ -0,0 +1,272 @@
+use crate::{DirtyChunks, Voxel, VoxelEditor};
+
+use bevy::prelude::*;
+use building_blocks::prelude::*;
+use fnv::{FnvHashMap, FnvHashSet};
+
+/// Runs a cellular simulation of finite fluid volumes, like water and lava, in the `VoxelMap`.
+/// Depends on the `MapIoPlugin`.
+///
+/// Only chunks with active fluid are simulated. A chunk becomes active whenever it's dirty, and the
+/// simulation writes its flows with the `VoxelEditor`, touching neighbors, so fluid that crosses
+/// into a chunk activates it on the next frame. A chunk goes back to sleep after a tick in which
+/// none of its fluid moved.
+///
+/// Every tick, each fluid voxel first flows down as far as it can, then gives one unit of its level
+/// to each horizontal neighbor that is at least two units lower. Fluid is never created or
+/// destroyed, and different kinds of fluid don't mix.
+pub struct FluidSimPlugin<V> {
+    pub config: FluidSimConfig,
+    marker: std::marker::PhantomData<V>,
+}
+
+impl<V> FluidSimPlugin<V> {
+    pub fn new(config: FluidSimConfig) -> Self {
+        Self {
+            config,
+            marker: Default::default(),
+        }
+    }
+}
+
+impl<V> Plugin for FluidSimPlugin<V>
+where
+    V: FluidVoxel,
+{
+    fn build(&self, app: &mut AppBuilder) {
+        app.insert_resource(FluidSim::<V>::new(self.config))
+            .add_system(fluid_sim_system::<V>.system());
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct FluidSimConfig {
+    /// The number of simulation ticks per second. At most one tick runs per frame.
+    pub ticks_per_second: f32,
+    /// The maximum number of active chunks simulated per tick. The rest wait for later ticks.
+    pub max_chunks_per_tick: usize,
+    /// The level of a voxel that's completely full of fluid.
+    pub max_level: u8,
+}
+
+impl Default for FluidSimConfig {
+    fn default() -> Self {
+        Self {
+            ticks_per_second: 10.0,
+            max_chunks_per_tick: 64,
+            max_level: 8,
+        }
+    }
+}
+
+/// Some amount of a kind of fluid.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Fluid {
+    /// Identifies the fluid, e.g. 0 for water and 1 for lava.
+    pub kind: u8,
+    /// From 1 to `FluidSimConfig::max_level`.
+    pub level: u8,
+}
+
+/// A `Voxel` that can hold fluids.
+pub trait FluidVoxel: Voxel {
+    /// The fluid in this voxel, if any.
+    fn fluid(&self) -> Option<Fluid>;
+
+    /// Whether fluid can flow into this voxel, i.e. it's empty or already holds fluid.
+    fn can_hold_fluid(&self) -> bool;
+
+    /// This voxel with its fluid replaced. `None` means the fluid drained away, leaving an empty
+    /// voxel.
+    fn with_fluid(&self, fluid: Option<Fluid>) -> Self;
+}
+
+/// The state of the fluid simulation.
+pub struct FluidSim<V> {
+    pub config: FluidSimConfig,
+    active_chunks: FnvHashSet<Point3i>,
+    time_since_tick: f32,
+    marker: std::marker::PhantomData<V>,
+}
+
+impl<V> FluidSim<V> {
+    pub fn new(config: FluidSimConfig) -> Self {
+        Self {
+            config,
+            active_chunks: Default::default(),
+            time_since_tick: 0.0,
+            marker: Default::default(),
+        }
+    }
+
+    /// Wakes up the chunk at `chunk_key`, e.g. after placing fluid with a direct write to the map.
+    pub fn activate_chunk(&mut self, chunk_key: Point3i) {
+        self.active_chunks.insert(chunk_key);
+    }
+
+    pub fn is_active(&self, chunk_key: &Point3i) -> bool {
+        self.active_chunks.contains(chunk_key)
+    }
+
+    pub fn num_active_chunks(&self) -> usize {
+        self.active_chunks.len()
+    }
+}
+
+const HORIZONTAL_OFFSETS: [Point3i; 4] = [
+    PointN([-1, 0, 0]),
+    PointN([1, 0, 0]),
+    PointN([0, 0, -1]),
+    PointN([0, 0, 1]),
+];
+
+fn fluid_sim_system<V>(
+    time: Res<Time>,
+    dirty_chunks: Res<DirtyChunks<V>>,
+    mut fluid_sim: ResMut<FluidSim<V>>,
+    mut voxel_editor: VoxelEditor<V>,
+) where
+    V: FluidVoxel,
+{
+    let fluid_sim = &mut *fluid_sim;
+
+    fluid_sim
+        .active_chunks
+        .extend(dirty_chunks.dirty_chunk_keys.iter().cloned());
+
+    fluid_sim.time_since_tick += time.delta_seconds();
+    let tick_interval = 1.0 / fluid_sim.config.ticks_per_second;
+    if fluid_sim.time_since_tick < tick_interval {
+        return;
+    }
+    fluid_sim.time_since_tick = (fluid_sim.time_since_tick - tick_interval).min(tick_interval);
+
+    let chunk_keys: Vec<Point3i> = fluid_sim
+        .active_chunks
+        .iter()
+        .take(fluid_sim.config.max_chunks_per_tick)
+        .cloned()
+        .collect();
+    for chunk_key in chunk_keys.iter() {
+        fluid_sim.active_chunks.remove(chunk_key);
+    }
+
+    let changes = {
+        let tls = voxel_editor.local_cache.get();
+        let reader = voxel_editor.map.reader(&tls);
+        let mut cells = FluidCells {
+            voxels: Default::default(),
+            changed: Default::default(),
+            max_level: fluid_sim.config.max_level,
+            read: |p: Point3i| reader.get(&p),
+        };
+
+        let mut sources = Vec::new();
+        for chunk_key in chunk_keys.iter() {
+            let extent = reader.indexer.extent_for_chunk_at_key(*chunk_key);
+            reader.for_each(&extent, |p: Point3i, voxel: V| {
+                if voxel.fluid().is_some() {
+                    sources.push(p);
+                }
+            });
+        }
+        // Settle the lowest fluid first, so a column doesn't fall more than once per tick.
+        sources.sort_by_key(|p| p.y());
+        for p in sources.into_iter() {
+            cells.flow(p);
+        }
+
+        cells.into_changes()
+    };
+
+    for (p, voxel) in changes.into_iter() {
+        voxel_editor.edit_extent_and_touch_neighbors(
+            Extent3i::from_min_and_shape(p, PointN([1; 3])),
+            |_p, v: &mut V| *v = voxel,
+        );
+    }
+}
+
+// A working copy of the voxels touched by one tick.
+struct FluidCells<V, R> {
+    voxels: FnvHashMap<Point3i, V>,
+    changed: FnvHashSet<Point3i>,
+    max_level: u8,
+    read: R,
+}
+
+impl<V, R> FluidCells<V, R>
+where
+    V: FluidVoxel,
+    R: Fn(Point3i) -> V,
+{
+    fn get(&mut self, p: Point3i) -> V {
+        let read = &self.read;
+
+        *self.voxels.entry(p).or_insert_with(|| read(p))
+    }
+
+    // The level of `kind` at `p`, or `None` if it can't hold that kind of fluid.
+    fn level(&mut self, p: Point3i, kind: u8) -> Option<u8> {
+        let voxel = self.get(p);
+        if !voxel.can_hold_fluid() {
+            return None;
+        }
+
+        match voxel.fluid() {
+            None => Some(0),
+            Some(fluid) if fluid.kind == kind => Some(fluid.level),
+            Some(_) => None,
+        }
+    }
+
+    fn set_level(&mut self, p: Point3i, kind: u8, level: u8) {
+        let voxel = self.get(p);
+        let fluid = if level > 0 {
+            Some(Fluid { kind, level })
+        } else {
+            None
+        };
+        if voxel.fluid() != fluid {
+            self.voxels.insert(p, voxel.with_fluid(fluid));
+            self.changed.insert(p);
+        }
+    }
+
+    fn flow(&mut self, p: Point3i) {
+        let Fluid { kind, mut level } = match self.get(p).fluid() {
+            Some(fluid) => fluid,
+            None => return,
+        };
+
+        let below = p - PointN([0, 1, 0]);
+        if let Some(below_level) = self.level(below, kind) {
+            let moved = level.min(self.max_level.saturating_sub(below_level));
+            if moved > 0 {
+                self.set_level(below, kind, below_level + moved);
+                level -= moved;
+            }
+        }
+
+        for offset in HORIZONTAL_OFFSETS.iter() {
+            if level < 2 {
+                break;
+            }
+            let neighbor = p + *offset;
+            if let Some(neighbor_level) = self.level(neighbor, kind) {
+                if neighbor_level + 1 < level {
+                    self.set_level(neighbor, kind, neighbor_level + 1);
+                    level -= 1;
+                }
+            }
+        }
+
+        self.set_level(p, kind, level);
+    }
+
+    fn into_changes(self) -> Vec<(Point3i, V)> {
+        let voxels = self.voxels;
+
+        self.changed.into_iter().map(|p| (p, voxels[&p])).collect()
+    }
+}
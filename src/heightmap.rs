@@ -0,0 +1,276 @@
+use crate::{tasks::map_in_pool, Voxel, VoxelEditor, VoxelTaskPool};
+
+use bevy::{prelude::*, render::texture::TextureFormat};
+use building_blocks::prelude::*;
+use std::{collections::VecDeque, convert::TryInto, sync::Arc};
+
+/// A greyscale image of terrain heights, normalized to `[0, 1]`.
+#[derive(Clone, Debug)]
+pub struct Heightmap {
+    pub width: usize,
+    pub height: usize,
+    /// One sample per pixel, row by row.
+    pub samples: Vec<f32>,
+}
+
+impl Heightmap {
+    /// Reads 8-bit greyscale pixels.
+    pub fn from_luma8(width: usize, height: usize, pixels: &[u8]) -> Self {
+        assert_eq!(pixels.len(), width * height);
+
+        Self {
+            width,
+            height,
+            samples: pixels.iter().map(|p| *p as f32 / 255.0).collect(),
+        }
+    }
+
+    /// Reads a loaded image. 8-bit and 16-bit greyscale images are supported, and only the red
+    /// channel of RGBA images is used. Returns `None` for other formats.
+    pub fn from_texture(texture: &Texture) -> Option<Self> {
+        let width = texture.size.width as usize;
+        let height = texture.size.height as usize;
+        let samples = match texture.format {
+            TextureFormat::R8Unorm => texture.data.iter().map(|p| *p as f32 / 255.0).collect(),
+            TextureFormat::Rgba8Unorm | TextureFormat::Rgba8UnormSrgb => texture
+                .data
+                .chunks_exact(4)
+                .map(|p| p[0] as f32 / 255.0)
+                .collect(),
+            TextureFormat::R16Uint => texture
+                .data
+                .chunks_exact(2)
+                .map(|p| u16::from_le_bytes(p.try_into().unwrap()) as f32 / 65535.0)
+                .collect(),
+            _ => return None,
+        };
+
+        Some(Self {
+            width,
+            height,
+            samples,
+        })
+    }
+
+    /// Bilinearly interpolates the heightmap at pixel coordinates `(u, v)`, clamping to the edges.
+    pub fn sample(&self, u: f32, v: f32) -> f32 {
+        let max_u = (self.width - 1) as f32;
+        let max_v = (self.height - 1) as f32;
+        let u = u.max(0.0).min(max_u);
+        let v = v.max(0.0).min(max_v);
+        let (u0, v0) = (u.floor() as usize, v.floor() as usize);
+        let (u1, v1) = ((u0 + 1).min(self.width - 1), (v0 + 1).min(self.height - 1));
+        let (fu, fv) = (u - u0 as f32, v - v0 as f32);
+        let at = |u: usize, v: usize| self.samples[u + self.width * v];
+        let top = at(u0, v0) * (1.0 - fu) + at(u1, v0) * fu;
+        let bottom = at(u0, v1) * (1.0 - fu) + at(u1, v1) * fu;
+
+        top * (1.0 - fv) + bottom * fv
+    }
+}
+
+/// How a `Heightmap` is placed in the world. The image's X axis maps to the world's X axis, and
+/// the image's Y axis maps to the world's Z axis.
+#[derive(Clone, Copy, Debug)]
+pub struct HeightmapTerrain {
+    /// The voxel at the top-left corner of the image, at height 0.
+    pub origin: Point3i,
+    /// The number of voxels covered by each pixel along X and Z.
+    pub horizontal_scale: f32,
+    /// The height of a white pixel, in voxels.
+    pub vertical_scale: f32,
+}
+
+impl Default for HeightmapTerrain {
+    fn default() -> Self {
+        Self {
+            origin: PointN([0; 3]),
+            horizontal_scale: 1.0,
+            vertical_scale: 64.0,
+        }
+    }
+}
+
+impl HeightmapTerrain {
+    /// The extent covered by the terrain, from the origin up to the height of a white pixel.
+    pub fn extent(&self, heightmap: &Heightmap) -> Extent3i {
+        let shape = PointN([
+            (heightmap.width as f32 * self.horizontal_scale).ceil() as i32,
+            self.vertical_scale.ceil() as i32 + 1,
+            (heightmap.height as f32 * self.horizontal_scale).ceil() as i32,
+        ]);
+
+        Extent3i::from_min_and_shape(self.origin, shape)
+    }
+
+    /// The world Y coordinate of the terrain surface above `(x, z)`.
+    pub fn surface_height(&self, heightmap: &Heightmap, x: i32, z: i32) -> i32 {
+        let u = (x - self.origin.x()) as f32 / self.horizontal_scale;
+        let v = (z - self.origin.z()) as f32 / self.horizontal_scale;
+
+        self.origin.y() + (heightmap.sample(u, v) * self.vertical_scale).round() as i32
+    }
+
+    /// Fills `extent` with the voxels returned by `voxel_at`, which receives each point and the
+    /// surface height of its column.
+    pub fn generate<V>(
+        &self,
+        heightmap: &Heightmap,
+        extent: Extent3i,
+        voxel_at: impl Fn(Point3i, i32) -> V,
+    ) -> Array3<V>
+    where
+        V: Voxel,
+    {
+        let mut array = Array3::fill(extent, V::default());
+        array.for_each_mut(&extent, |p: Point3i, voxel: &mut V| {
+            *voxel = voxel_at(p, self.surface_height(heightmap, p.x(), p.z()));
+        });
+
+        array
+    }
+}
+
+/// Turns heightmap images into terrain in the `VoxelMap`, using the `HeightmapImports` resource.
+/// Depends on the `MapIoPlugin`.
+pub struct HeightmapImportPlugin<V> {
+    marker: std::marker::PhantomData<V>,
+}
+
+impl<V> Default for HeightmapImportPlugin<V> {
+    fn default() -> Self {
+        Self {
+            marker: Default::default(),
+        }
+    }
+}
+
+impl<V> Plugin for HeightmapImportPlugin<V>
+where
+    V: Voxel,
+{
+    fn build(&self, app: &mut AppBuilder) {
+        app.insert_resource(HeightmapImports::<V>::default())
+            .add_system(heightmap_import_system::<V>.system());
+    }
+}
+
+/// Receives the voxel point and the surface height of its column.
+pub type HeightmapVoxelFn<V> = Arc<dyn Fn(Point3i, i32) -> V + Send + Sync>;
+
+/// Queued heightmap imports. Each import waits for its image to load, then generates a few chunks
+/// per frame in parallel and inserts them with `VoxelEditor::insert_chunks`.
+pub struct HeightmapImports<V> {
+    /// The most chunks generated in a single frame.
+    pub max_chunks_per_frame: usize,
+    imports: VecDeque<HeightmapImport<V>>,
+    failed: Vec<Handle<Texture>>,
+}
+
+struct HeightmapImport<V> {
+    image: Handle<Texture>,
+    terrain: HeightmapTerrain,
+    voxel_at: HeightmapVoxelFn<V>,
+    heightmap: Option<Arc<Heightmap>>,
+    remaining_chunk_keys: Vec<Point3i>,
+}
+
+impl<V> Default for HeightmapImports<V> {
+    fn default() -> Self {
+        Self {
+            max_chunks_per_frame: 32,
+            imports: VecDeque::new(),
+            failed: Vec::new(),
+        }
+    }
+}
+
+impl<V> HeightmapImports<V>
+where
+    V: Voxel,
+{
+    /// Queues the terrain for `image`. Chunks in the terrain's extent are replaced entirely.
+    pub fn import(
+        &mut self,
+        image: Handle<Texture>,
+        terrain: HeightmapTerrain,
+        voxel_at: impl Fn(Point3i, i32) -> V + Send + Sync + 'static,
+    ) {
+        self.imports.push_back(HeightmapImport {
+            image,
+            terrain,
+            voxel_at: Arc::new(voxel_at),
+            heightmap: None,
+            remaining_chunk_keys: Vec::new(),
+        });
+    }
+
+    /// Returns `true` when every import has finished.
+    pub fn is_idle(&self) -> bool {
+        self.imports.is_empty()
+    }
+
+    /// Takes the images that couldn't be imported because their format isn't supported.
+    pub fn take_failed(&mut self) -> Vec<Handle<Texture>> {
+        std::mem::replace(&mut self.failed, Vec::new())
+    }
+}
+
+fn heightmap_import_system<V>(
+    pool: Res<VoxelTaskPool>,
+    textures: Res<Assets<Texture>>,
+    mut imports: ResMut<HeightmapImports<V>>,
+    mut voxel_editor: VoxelEditor<V>,
+) where
+    V: Voxel,
+{
+    let max_chunks = imports.max_chunks_per_frame;
+    let imports = &mut *imports;
+    let import = match imports.imports.front_mut() {
+        Some(i) => i,
+        None => return,
+    };
+
+    if import.heightmap.is_none() {
+        // Wait for the image to load.
+        let texture = match textures.get(&import.image) {
+            Some(t) => t,
+            None => return,
+        };
+        let heightmap = match Heightmap::from_texture(texture) {
+            Some(h) => h,
+            None => {
+                let failed = imports.imports.pop_front().unwrap();
+                imports.failed.push(failed.image);
+                return;
+            }
+        };
+        import.remaining_chunk_keys = voxel_editor
+            .map
+            .voxels
+            .indexer
+            .chunk_keys_for_extent(&import.terrain.extent(&heightmap))
+            .collect();
+        import.heightmap = Some(Arc::new(heightmap));
+    }
+
+    let num_chunks = max_chunks.min(import.remaining_chunk_keys.len());
+    let batch: Vec<Point3i> = import.remaining_chunk_keys.drain(..num_chunks).collect();
+    let heightmap = import.heightmap.as_ref().unwrap();
+    let terrain = import.terrain;
+    let voxel_at = &import.voxel_at;
+    let indexer = &voxel_editor.map.voxels.indexer;
+    let chunks = map_in_pool(&*pool, batch, |chunk_key| {
+        let chunk_extent = indexer.extent_for_chunk_at_key(chunk_key);
+
+        (
+            chunk_key,
+            terrain.generate(heightmap, chunk_extent, |p, h| voxel_at(p, h)),
+        )
+    });
+    voxel_editor.insert_chunks(chunks);
+
+    if import.remaining_chunk_keys.is_empty() {
+        imports.imports.pop_front();
+    }
+}
@@ -0,0 +1,192 @@
+use crate::{
+    ChunkCacheConfig, DirtyChunks, EvictionPolicy, PinnedChunks, PrefetchQueue, Voxel, VoxelMap,
+};
+
+use bevy::prelude::*;
+
+/// Registers the map IO settings with the `TypeRegistry` and mirrors them into `Reflect` resources,
+/// so they show up in reflection-based tools like `bevy-inspector-egui` and can be tweaked while
+/// the app runs. Depends on the `MapIoPlugin`.
+///
+/// The settings of the generic `ChunkCacheConfig`, `PinnedChunks`, and `PrefetchQueue` resources are
+/// mirrored into `MapIoSettings`, and edits to either side are copied to the other in the
+/// `PRE_UPDATE` stage. `DirtyChunksSummary` is refreshed from the
+/// previous frame's `DirtyChunks`; editing it has no effect.
+///
+/// The mirrors aren't generic, so only add this plugin for one voxel type.
+pub struct MapIoInspectorPlugin<V> {
+    marker: std::marker::PhantomData<V>,
+}
+
+impl<V> Default for MapIoInspectorPlugin<V> {
+    fn default() -> Self {
+        Self {
+            marker: Default::default(),
+        }
+    }
+}
+
+impl<V> Plugin for MapIoInspectorPlugin<V>
+where
+    V: Voxel,
+{
+    fn build(&self, app: &mut AppBuilder) {
+        app.register_type::<EvictionPolicy>()
+            .register_type::<MapIoSettings>()
+            .register_type::<DirtyChunksSummary>()
+            .insert_resource(MapIoSettings::default())
+            .insert_resource(DirtyChunksSummary::default())
+            .add_system_to_stage(stage::PRE_UPDATE, map_io_settings_system::<V>.system())
+            .add_system_to_stage(stage::PRE_UPDATE, dirty_chunks_summary_system::<V>.system());
+    }
+}
+
+/// Mirrors the palette of the `VoxelMap` into the `InspectablePalette` resource, so palette entries
+/// can be inspected and edited live. Edits are copied back into the `VoxelPalette` in the
+/// `PRE_UPDATE` stage. Depends on the `MapIoPlugin`.
+///
+/// The `Voxel::TypeInfo` must implement `Reflect`, which it usually can with `#[derive(Reflect)]`.
+pub struct PaletteInspectorPlugin<V> {
+    marker: std::marker::PhantomData<V>,
+}
+
+impl<V> Default for PaletteInspectorPlugin<V> {
+    fn default() -> Self {
+        Self {
+            marker: Default::default(),
+        }
+    }
+}
+
+impl<V> Plugin for PaletteInspectorPlugin<V>
+where
+    V: Voxel,
+    V::TypeInfo: Reflect + Clone + PartialEq,
+{
+    fn build(&self, app: &mut AppBuilder) {
+        app.register_type::<InspectablePalette<V::TypeInfo>>()
+            .insert_resource(InspectablePalette::<V::TypeInfo> {
+                entries: Vec::new(),
+            })
+            .add_system_to_stage(stage::PRE_UPDATE, palette_inspector_system::<V>.system());
+    }
+}
+
+/// The settings of the `ChunkCacheConfig`, `PinnedChunks`, and `PrefetchQueue` resources.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Reflect)]
+pub struct MapIoSettings {
+    /// `ChunkCacheConfig::max_cached_chunks`
+    pub max_cached_chunks: usize,
+    /// `ChunkCacheConfig::max_chunks_compressed_per_frame_per_thread`
+    pub max_chunks_compressed_per_frame_per_thread: usize,
+    /// `ChunkCacheConfig::eviction_policy`
+    pub eviction_policy: EvictionPolicy,
+    /// `ChunkCacheConfig::compression_level`
+    pub compression_level: u32,
+    /// `PinnedChunks::observer_radius_in_chunks`
+    pub pinned_radius_in_chunks: i32,
+    /// `PrefetchQueue::observer_radius_in_chunks`
+    pub prefetch_radius_in_chunks: i32,
+    /// `PrefetchQueue::max_chunks_per_frame`
+    pub max_chunks_prefetched_per_frame: usize,
+}
+
+/// Counts from the `DirtyChunks` of the previous frame, along with the size of the chunk cache.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Reflect)]
+pub struct DirtyChunksSummary {
+    pub edited_chunks: usize,
+    pub dirty_chunks: usize,
+    /// The number of edited extents over all edited chunks.
+    pub edited_extents: usize,
+    pub cached_chunks: usize,
+}
+
+/// A copy of the `VoxelPalette` entries.
+#[derive(Clone, Debug, Reflect)]
+pub struct InspectablePalette<I>
+where
+    I: Reflect + Clone,
+{
+    pub entries: Vec<I>,
+}
+
+fn map_io_settings_system<V>(
+    mut settings: ResMut<MapIoSettings>,
+    mut last_synced: Local<Option<MapIoSettings>>,
+    mut cache_config: ResMut<ChunkCacheConfig<V>>,
+    mut pinned_chunks: ResMut<PinnedChunks<V>>,
+    mut prefetch_queue: ResMut<PrefetchQueue<V>>,
+) where
+    V: Voxel,
+{
+    let current = MapIoSettings {
+        max_cached_chunks: cache_config.max_cached_chunks,
+        max_chunks_compressed_per_frame_per_thread: cache_config
+            .max_chunks_compressed_per_frame_per_thread,
+        eviction_policy: cache_config.eviction_policy,
+        compression_level: cache_config.compression_level,
+        pinned_radius_in_chunks: pinned_chunks.observer_radius_in_chunks,
+        prefetch_radius_in_chunks: prefetch_queue.observer_radius_in_chunks,
+        max_chunks_prefetched_per_frame: prefetch_queue.max_chunks_per_frame,
+    };
+    if let Some(edited) = sync_mirror(&mut *settings, &mut *last_synced, current) {
+        cache_config.max_cached_chunks = edited.max_cached_chunks;
+        cache_config.max_chunks_compressed_per_frame_per_thread =
+            edited.max_chunks_compressed_per_frame_per_thread;
+        cache_config.eviction_policy = edited.eviction_policy;
+        cache_config.compression_level = edited.compression_level;
+        pinned_chunks.observer_radius_in_chunks = edited.pinned_radius_in_chunks;
+        prefetch_queue.observer_radius_in_chunks = edited.prefetch_radius_in_chunks;
+        prefetch_queue.max_chunks_per_frame = edited.max_chunks_prefetched_per_frame;
+    }
+}
+
+fn dirty_chunks_summary_system<V>(
+    mut summary: ResMut<DirtyChunksSummary>,
+    dirty_chunks: Res<DirtyChunks<V>>,
+    voxel_map: Res<VoxelMap<V>>,
+) where
+    V: Voxel,
+{
+    *summary = DirtyChunksSummary {
+        edited_chunks: dirty_chunks.edited_chunk_keys.len(),
+        dirty_chunks: dirty_chunks.dirty_chunk_keys.len(),
+        edited_extents: dirty_chunks
+            .chunk_edits
+            .values()
+            .map(|edits| edits.extents.len())
+            .sum(),
+        cached_chunks: voxel_map.voxels.storage().cache.len_cached(),
+    };
+}
+
+fn palette_inspector_system<V>(
+    mut palette: ResMut<InspectablePalette<V::TypeInfo>>,
+    mut last_synced: Local<Option<Vec<V::TypeInfo>>>,
+    mut voxel_map: ResMut<VoxelMap<V>>,
+) where
+    V: Voxel,
+    V::TypeInfo: Reflect + Clone + PartialEq,
+{
+    let current = voxel_map.palette.infos.clone();
+    if let Some(edited) = sync_mirror(&mut palette.entries, &mut *last_synced, current) {
+        voxel_map.palette.infos = edited;
+    }
+}
+
+// Copies `source` into `mirror`, unless the mirror was edited since the last sync, in which case the
+// edited value is returned so it can be written back to the source.
+fn sync_mirror<T>(mirror: &mut T, last_synced: &mut Option<T>, source: T) -> Option<T>
+where
+    T: Clone + PartialEq,
+{
+    if last_synced.as_ref().map_or(false, |last| last != mirror) {
+        *last_synced = Some(mirror.clone());
+
+        return Some(mirror.clone());
+    }
+    *mirror = source.clone();
+    *last_synced = Some(source);
+
+    None
+}
@@ -0,0 +1,99 @@
+use crate::Voxel;
+
+use std::{fmt, marker::PhantomData};
+
+/// Wraps a voxel type `V` so it's distinct for each layer type `K`.
+///
+/// All of the per-map resources (`VoxelMap`, `EditBuffer`, `DirtyChunks`, `EmptyChunks`, etc.) are
+/// keyed by voxel type, so each voxel type gets exactly one map. To run several independent maps
+/// with the same voxels, like a planet and a ship, give each one its own layer:
+///
+/// ```
+/// use bevy_building_blocks::{ChunkCacheConfig, Layered, MapIoPlugin, Voxel};
+/// use bevy_building_blocks::bb::prelude::*;
+///
+/// #[derive(Clone, Copy, Default)]
+/// struct MyVoxel(u8);
+///
+/// impl Voxel for MyVoxel {
+///     type TypeInfo = ();
+///
+///     fn get_type_index(&self) -> usize {
+///         self.0 as usize
+///     }
+/// }
+///
+/// struct Planet;
+/// struct Ship;
+///
+/// let chunk_shape = PointN([16; 3]);
+/// let planet_io =
+///     MapIoPlugin::<Layered<Planet, MyVoxel>>::new(chunk_shape, ChunkCacheConfig::default());
+/// let ship_io =
+///     MapIoPlugin::<Layered<Ship, MyVoxel>>::new(chunk_shape, ChunkCacheConfig::default());
+/// ```
+///
+/// The `VoxelTaskPool` is shared by every map, along with the few other resources that aren't
+/// generic over the voxel type, like the mirrors of the `MapIoInspectorPlugin`.
+#[repr(transparent)]
+pub struct Layered<K, V> {
+    pub voxel: V,
+    layer: PhantomData<fn() -> K>,
+}
+
+impl<K, V> Layered<K, V> {
+    pub fn new(voxel: V) -> Self {
+        Self {
+            voxel,
+            layer: PhantomData,
+        }
+    }
+}
+
+impl<K, V> From<V> for Layered<K, V> {
+    fn from(voxel: V) -> Self {
+        Self::new(voxel)
+    }
+}
+
+impl<K, V> Voxel for Layered<K, V>
+where
+    K: 'static,
+    V: Voxel,
+{
+    type TypeInfo = V::TypeInfo;
+
+    fn get_type_index(&self) -> usize {
+        self.voxel.get_type_index()
+    }
+}
+
+// These are implemented by hand so they don't require anything of `K`.
+
+impl<K, V: Clone> Clone for Layered<K, V> {
+    fn clone(&self) -> Self {
+        Self::new(self.voxel.clone())
+    }
+}
+
+impl<K, V: Copy> Copy for Layered<K, V> {}
+
+impl<K, V: Default> Default for Layered<K, V> {
+    fn default() -> Self {
+        Self::new(V::default())
+    }
+}
+
+impl<K, V: PartialEq> PartialEq for Layered<K, V> {
+    fn eq(&self, other: &Self) -> bool {
+        self.voxel == other.voxel
+    }
+}
+
+impl<K, V: Eq> Eq for Layered<K, V> {}
+
+impl<K, V: fmt::Debug> fmt::Debug for Layered<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.voxel.fmt(f)
+    }
+}
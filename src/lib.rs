@@ -2,24 +2,178 @@
 
 #[cfg(feature = "ncollide")]
 mod bvt;
+#[cfg(feature = "parry")]
+mod chunk_bvh;
+#[cfg(feature = "minecraft")]
+pub mod minecraft;
+#[cfg(feature = "parry")]
+mod voxel_collisions;
+#[cfg(feature = "parry")]
+mod voxel_picking;
 
+mod ambient_occlusion;
+mod analysis;
+mod audit;
+mod block_entities;
+mod brick_atlas;
+mod brick_atlas_textures;
+mod chunk_columns;
+mod chunk_culling;
+mod chunk_entities;
+mod chunk_hashes;
+mod chunk_octrees;
+mod coalesced_dirty_chunks;
+mod codec;
+mod composite_reader;
+mod derived_data;
+mod dirty_chunk_queue;
+mod edit_recording;
+mod fluids;
+mod heightmap;
+mod inspector;
+mod layered;
 mod map;
+mod map2;
 mod map_io;
+mod map_io_2d;
+mod map_stats;
+mod material;
+mod mesh_export;
+#[cfg(feature = "navigation")]
+mod navigation;
+mod observer;
+mod occupancy_counts;
+mod palette_migration;
+mod persistence;
+mod relight;
+#[cfg(feature = "serialize")]
+mod serialization;
+mod shared_chunk;
+mod shared_meshes;
+mod spawn_points;
+mod structures;
+mod subscriptions;
+mod tasks;
+#[cfg(test)]
+mod test_util;
 mod thread_local_resource;
+mod top_down_map;
+mod uniform_chunks;
+mod versions;
+mod view_rings;
+mod voxel_trackers;
+#[cfg(feature = "voxelize")]
+mod voxelize;
+mod worldgen;
 
 #[cfg(feature = "ncollide")]
 pub use bvt::{BVTPlugin, VoxelBVT};
+#[cfg(feature = "parry")]
+pub use chunk_bvh::{ChunkBvh, ChunkBvhPlugin};
+#[cfg(feature = "parry")]
+pub use voxel_collisions::{VoxelCollisions, VoxelRayHit, VoxelSweepHit};
+#[cfg(feature = "parry")]
+pub use voxel_picking::{
+    HoveredVoxel, HoveredVoxelChanged, VoxelPick, VoxelPickingCamera, VoxelPickingPlugin,
+};
+
+pub use ambient_occlusion::{AmbientOcclusionPlugin, ChunkAmbientOcclusion};
+pub use analysis::{MapIoAnalysis, MapIoAnalysisPlugin, MapIoRecommendation};
+pub use audit::{ChunkAudit, ChunkAuditPlugin, ChunkAuditReport, ChunkKeyMismatch, ChunkKeySource};
+pub use block_entities::{BlockEntities, BlockEntitiesPlugin, BlockEntityUnbound};
+pub use brick_atlas::{BrickAtlas, BrickAtlasConfig, BrickAtlasPlugin, EMPTY_BRICK};
+pub use brick_atlas_textures::{BrickAtlasTextures, BrickAtlasTexturesPlugin};
+pub use chunk_columns::{column_key, ChunkColumn, ChunkColumns, ChunkColumnsPlugin};
+pub use chunk_culling::{ChunkAabb, ChunkCullingCamera, ChunkCullingPlugin, ChunkOccupancy};
+pub use chunk_entities::{ChunkEntities, ChunkEntitiesPlugin, ChunkExtent, ChunkKey};
+pub use chunk_hashes::{chunk_hash, ChunkHashes, ChunkHashesPlugin};
+pub use chunk_octrees::{ChunkOctrees, ChunkOctreesPlugin};
+pub use coalesced_dirty_chunks::{CoalescedDirtyChunks, CoalescedDirtyChunksPlugin};
+pub use codec::{
+    decode_chunk, decode_chunk_at, encode_chunk, CodecError, FixedSizeCodec, VoxelCodec,
+};
+pub use composite_reader::CompositeReader;
+pub use derived_data::{DerivedChunkData, DerivedChunkDataPlugin, DerivedChunkFn};
+pub use dirty_chunk_queue::{DirtyChunkQueue, DirtyChunkQueuePlugin, DirtyChunkScoreFn};
+pub use edit_recording::{EditLog, EditPlayback, EditRecorder, EditRecordingPlugin};
+pub use fluids::{Fluid, FluidSim, FluidSimConfig, FluidSimPlugin, FluidVoxel};
+pub use heightmap::{
+    Heightmap, HeightmapImportPlugin, HeightmapImports, HeightmapTerrain, HeightmapVoxelFn,
+};
+pub use inspector::{
+    DirtyChunksSummary, InspectablePalette, MapIoInspectorPlugin, MapIoSettings,
+    PaletteInspectorPlugin,
+};
+pub use layered::Layered;
+pub use map_stats::{MapStats, MapStatsPlugin};
+pub use material::{FaceTiles, TextureAtlasLayout, VoxelMaterial};
+pub use mesh_export::{
+    ExportMesh, ExportMesher, MeshExportFinished, MeshExportFormat, MeshExportPlugin,
+    MeshExportRequest, MeshMaterial,
+};
+#[cfg(feature = "navigation")]
+pub use navigation::{NavGrid, NavGridConfig, NavGridPlugin};
+pub use observer::Observer;
+pub use occupancy_counts::{ChunkOccupancyCounts, ChunkOccupancyCountsPlugin};
+pub use palette_migration::{
+    PaletteMigration, PaletteMigrationFinished, PaletteMigrationPlugin, PaletteMigrator,
+};
+pub use persistence::{
+    Autosave, AutosaveConfig, AutosavePlugin, ChunkDirectory, ChunkStore, RegionStore,
+};
+
+#[cfg(feature = "sled")]
+pub use persistence::SledChunkStore;
+#[cfg(feature = "sqlite")]
+pub use persistence::SqliteChunkStore;
+pub use relight::{RelightBatch, RelightExtent, RelightFinished, RelightPlugin, RelightQueue};
+#[cfg(feature = "serialize")]
+pub use serialization::SerializedChunk;
+pub use shared_chunk::SharedChunk;
+pub use shared_meshes::{padded_chunk_hash, SharedChunkMeshes, SharedChunkMeshesPlugin};
+pub use spawn_points::SpawnConstraints;
+pub use structures::{PlacementError, PlacementRules, Structure, Structures, StructuresPlugin};
+pub use subscriptions::{
+    ExtentChanged, ExtentSubscriptionId, ExtentSubscriptions, ExtentSubscriptionsPlugin,
+};
+pub use tasks::{VoxelTaskPool, VoxelTaskPoolConfig};
+pub use top_down_map::{TopDownMap, TopDownMapPlugin};
+pub use uniform_chunks::{UniformChunks, UniformChunksPlugin};
+pub use versions::{MapVersions, MapVersionsPlugin};
+pub use view_rings::{
+    ObserverViewDistance, ViewRing, ViewRingChanged, ViewRings, ViewRingsConfig, ViewRingsPlugin,
+};
+pub use voxel_trackers::{VoxelTrackerId, VoxelTrackers, VoxelTrackersPlugin};
+#[cfg(feature = "voxelize")]
+pub use voxelize::{mesh_triangles, MeshTriangle, VoxelizeMode};
+pub use worldgen::{
+    BiomeId, BiomeMap, Biomes, ChunkDecorator, ChunkGenerator, ChunkRng, DecorationWriter,
+    GeneratingVoxelReader, WorldGen, WorldGenPlugin, WorldSeed,
+};
 
 pub use thread_local_resource::{ThreadLocalResource, ThreadLocalResourceHandle};
 
 // Core data structures.
 pub use map::{
-    default_array, empty_chunk_hash_map, empty_compressible_chunk_map, VoxelMap, VoxelPalette,
+    default_array, empty_chunk_hash_map, empty_compressible_chunk_map, VoxelInfoReader, VoxelMap,
+    VoxelMapStats, VoxelPalette,
 };
 
 // Systems and resources that facilitate voxel access.
 pub use map_io::{
-    ChunkCacheConfig, DirtyChunks, EmptyChunks, MapIoPlugin, ThreadLocalVoxelCache, VoxelEditor,
+    copy_chunk_without_caching, AmortizedEditFinished, AmortizedEditId, AmortizedEdits,
+    BackgroundDecompression, BoundsPolicy, ChunkCacheConfig, ChunkCacheStats, ChunkClaims,
+    ChunkEdits, ChunkRemoved, ChunkSpillConfig, ClaimPolicy, ClaimRejection, DirtyChunks,
+    EditBuffer, EmptyChunks, EvictionPolicy, MapIoFrameStats, MapIoPause, MapIoPlugin, MergeHooks,
+    NeighborDirtying, OutOfBoundsEdit, OwnerId, OwnerOnlyPolicy, PinnedChunks, PostMergeHook,
+    PreMergeHook, PrefetchQueue, SpilledChunks, ThreadLocalVoxelCache, VoxelDamage, VoxelEditQueue,
+    VoxelEditSender, VoxelEditor, VoxelHardness, VoxelReader, WorldBounds, MID_FRAME_MERGE_STAGE,
+};
+
+// 2D counterparts of the core data structures and map IO.
+pub use map2::{default_array2, empty_chunk_hash_map2, empty_compressible_chunk_map2, VoxelMap2};
+pub use map_io_2d::{
+    ChunkEdits2, DirtyChunks2, EmptyChunks2, MapIo2dPlugin, ThreadLocalVoxelCache2, VoxelEditor2,
 };
 
 /// You can use your own type of voxel, but it must implement this trait.
@@ -30,3 +184,5 @@ pub trait Voxel: 'static + Copy + Default + Send + Sync {
 }
 
 pub use building_blocks as bb;
+#[cfg(feature = "parry")]
+pub use parry3d;
@@ -1,6 +1,10 @@
-use crate::{ThreadLocalResourceHandle, Voxel};
+use crate::{
+    tasks::map_in_pool, ThreadLocalResourceHandle, ThreadLocalVoxelCache, UniformChunks, Voxel,
+};
 
+use bevy::tasks::TaskPool;
 use building_blocks::prelude::*;
+use fnv::FnvHashSet;
 
 /// The global source of truth for voxels in the current map.
 ///
@@ -35,28 +39,89 @@ use building_blocks::prelude::*;
 ///
 /// const CHUNK_SHAPE: Point3i = PointN([16; 3]);
 ///
-/// let map = VoxelMap {
-///     voxels: empty_compressible_chunk_map::<MyVoxel>(CHUNK_SHAPE),
-///     palette: VoxelPalette {
+/// let map = VoxelMap::new(
+///     empty_compressible_chunk_map::<MyVoxel>(CHUNK_SHAPE),
+///     VoxelPalette {
 ///         infos: vec![
 ///             MyVoxelTypeInfo { is_empty: true },
 ///             MyVoxelTypeInfo { is_empty: false },
 ///         ],
 ///     },
-/// };
+/// );
 /// ```
+///
+/// # Unsaved Chunks
+///
+/// The map remembers which chunks were modified since they were last saved, so save systems only
+/// need to write the chunks that differ from what's on disk. Chunks are marked unsaved when edits are
+/// merged or chunks are removed by the `MapIoPlugin`, and a save system marks them saved once they're
+/// written successfully.
 pub struct VoxelMap<V>
 where
     V: Voxel,
 {
     pub voxels: CompressibleChunkMap3<V>,
     pub palette: VoxelPalette<V::TypeInfo>,
+    /// Chunks that the `UniformChunksPlugin` collapsed to a single voxel, which aren't in `voxels`.
+    pub uniform_chunks: UniformChunks<V>,
+    unsaved_chunk_keys: FnvHashSet<Point3i>,
 }
 
 impl<V> VoxelMap<V>
 where
     V: Voxel,
 {
+    /// A map whose chunks all start out saved.
+    pub fn new(voxels: CompressibleChunkMap3<V>, palette: VoxelPalette<V::TypeInfo>) -> Self {
+        Self {
+            voxels,
+            palette,
+            uniform_chunks: Default::default(),
+            unsaved_chunk_keys: Default::default(),
+        }
+    }
+
+    /// The keys of all chunks modified since they were last marked saved, including removed chunks.
+    pub fn unsaved_chunk_keys(&self) -> impl Iterator<Item = &Point3i> {
+        self.unsaved_chunk_keys.iter()
+    }
+
+    pub fn is_saved(&self, chunk_key: &Point3i) -> bool {
+        !self.unsaved_chunk_keys.contains(chunk_key)
+    }
+
+    /// Marks a chunk that was modified without going through the `MapIoPlugin`, so it gets saved.
+    pub fn mark_unsaved(&mut self, chunk_key: Point3i) {
+        self.unsaved_chunk_keys.insert(chunk_key);
+    }
+
+    /// Call this once the current contents of the chunk have been written successfully.
+    pub fn mark_saved(&mut self, chunk_key: &Point3i) {
+        self.unsaved_chunk_keys.remove(chunk_key);
+    }
+
+    /// Counts the chunks in memory, without reading any voxels. For per-type voxel counts, see the
+    /// `MapStatsPlugin`.
+    pub fn stats(&self) -> VoxelMapStats {
+        let storage = self.voxels.storage();
+        let indexer = &self.voxels.indexer;
+        let mut total_chunks = 0;
+        let chunk_bounds = bounding_extent(storage.chunk_keys().map(|chunk_key| {
+            total_chunks += 1;
+
+            indexer.extent_for_chunk_at_key(*chunk_key)
+        }));
+        let resident_chunks = storage.cache.len_cached();
+
+        VoxelMapStats {
+            total_chunks,
+            resident_chunks,
+            compressed_chunks: total_chunks.saturating_sub(resident_chunks),
+            uniform_chunks: self.uniform_chunks.len(),
+            chunk_bounds,
+        }
+    }
+
     /// Returns a closure that transforms voxels into their type's corresponding info. This is
     /// intended to be used with a `TransformMap`.
     #[inline]
@@ -64,16 +129,184 @@ where
         move |v: V| self.palette.get_voxel_type_info(v)
     }
 
+    /// Each thread's local cache is created by the init function of the `ThreadLocalVoxelCache`
+    /// that `cache` came from, or with `LocalChunkCache3::new` if it has none.
     pub fn reader<'a>(
         &'a self,
         cache: &'a ThreadLocalResourceHandle<LocalChunkCache3<V>>,
     ) -> ChunkMap3<V, (), CompressibleChunkStorageReader3<V>> {
         self.voxels
-            .reader(cache.get_or_create_with(|| LocalChunkCache3::new()))
+            .reader(cache.get_or_init_with(LocalChunkCache3::new))
+    }
+
+    /// Like `reader`, but yields each voxel's `TypeInfo` from the `palette`.
+    pub fn info_reader<'a>(
+        &'a self,
+        cache: &'a ThreadLocalResourceHandle<LocalChunkCache3<V>>,
+    ) -> VoxelInfoReader<'a, V> {
+        VoxelInfoReader {
+            map: self,
+            reader: self.reader(cache),
+        }
+    }
+
+    /// Decompresses every chunk overlapping `extent` in parallel, so later reads don't have to. The
+    /// chunks land in the `local_caches` and move to the global cache at the end of the frame.
+    ///
+    /// To spread the work over multiple frames, use the `PrefetchQueue` instead.
+    pub fn prefetch_extent(
+        &self,
+        extent: &Extent3i,
+        local_caches: &ThreadLocalVoxelCache<V>,
+        pool: &TaskPool,
+    ) {
+        self.prefetch_chunks(
+            self.voxels.indexer.chunk_keys_for_extent(extent),
+            local_caches,
+            pool,
+        );
+    }
+
+    /// Like `prefetch_extent`, but for specific chunks.
+    pub fn prefetch_chunks(
+        &self,
+        chunk_keys: impl IntoIterator<Item = Point3i>,
+        local_caches: &ThreadLocalVoxelCache<V>,
+        pool: &TaskPool,
+    ) {
+        map_in_pool(pool, chunk_keys, |chunk_key| {
+            let cache_tls = local_caches.get();
+            // Reading the chunk is enough to decompress it into the thread-local cache.
+            self.reader(&cache_tls).get_chunk(chunk_key);
+        });
+    }
+
+    /// Runs `f` on every chunk overlapping `extent`, spreading the chunks across `pool`. Each
+    /// thread reads through its own cache in `local_caches`. Missing chunks are skipped, collapsed
+    /// uniform chunks are passed as filled arrays, and chunks on the border of `extent` are passed
+    /// whole, so `f` should clip them if it needs to.
+    ///
+    /// Chunks are not visited in any particular order.
+    pub fn par_for_each_chunk(
+        &self,
+        extent: &Extent3i,
+        local_caches: &ThreadLocalVoxelCache<V>,
+        pool: &TaskPool,
+        f: impl Fn(Point3i, &Array3<V>) + Sync,
+    ) {
+        self.par_map_chunks(extent, local_caches, pool, f);
+    }
+
+    /// Like `par_for_each_chunk`, but collects the results of `f`, e.g. for summing per-chunk
+    /// counts. Results are returned in completion order.
+    pub fn par_map_chunks<T>(
+        &self,
+        extent: &Extent3i,
+        local_caches: &ThreadLocalVoxelCache<V>,
+        pool: &TaskPool,
+        f: impl Fn(Point3i, &Array3<V>) -> T + Sync,
+    ) -> Vec<T>
+    where
+        T: 'static + Send,
+    {
+        let chunk_shape = self.voxels.indexer.chunk_shape();
+        map_in_pool(
+            pool,
+            self.voxels.indexer.chunk_keys_for_extent(extent),
+            |chunk_key| {
+                if let Some(array) = self.uniform_chunks.expanded_chunk(chunk_key, chunk_shape) {
+                    return Some(f(chunk_key, &array));
+                }
+                let cache_tls = local_caches.get();
+                let reader = self.reader(&cache_tls);
+                let chunk = reader.get_chunk(chunk_key)?;
+
+                Some(f(chunk_key, &chunk.array))
+            },
+        )
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+}
+
+/// A summary of the chunks in a `VoxelMap`, from `VoxelMap::stats`. Chunks spilled to disk aren't
+/// in memory, so they aren't counted.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct VoxelMapStats {
+    pub total_chunks: usize,
+    /// Chunks that are decompressed in the global cache.
+    pub resident_chunks: usize,
+    pub compressed_chunks: usize,
+    /// Chunks collapsed to a single voxel by the `UniformChunksPlugin`, which aren't counted in
+    /// `total_chunks`.
+    pub uniform_chunks: usize,
+    /// The smallest extent containing every chunk, or `None` if there are no chunks.
+    pub chunk_bounds: Option<Extent3i>,
+}
+
+/// The smallest extent containing all of `extents`.
+pub(crate) fn bounding_extent(mut extents: impl Iterator<Item = Extent3i>) -> Option<Extent3i> {
+    let first = extents.next()?;
+    let (min, max) = extents.fold((first.minimum, first.max()), |(min, max), e| {
+        let (e_min, e_max) = (e.minimum, e.max());
+        (
+            PointN([
+                min.x().min(e_min.x()),
+                min.y().min(e_min.y()),
+                min.z().min(e_min.z()),
+            ]),
+            PointN([
+                max.x().max(e_max.x()),
+                max.y().max(e_max.y()),
+                max.z().max(e_max.z()),
+            ]),
+        )
+    });
+
+    Some(Extent3i::from_min_and_max(min, max))
+}
+
+/// A cached reader of the `VoxelMap` that yields `&V::TypeInfo` instead of `V`. Construct it with
+/// `VoxelMap::info_reader`.
+pub struct VoxelInfoReader<'a, V>
+where
+    V: Voxel,
+{
+    map: &'a VoxelMap<V>,
+    pub reader: ChunkMap3<V, (), CompressibleChunkStorageReader3<'a, V>>,
+}
+
+impl<'a, V> VoxelInfoReader<'a, V>
+where
+    V: Voxel,
+{
+    pub fn get(&self, p: &Point3i) -> &'a V::TypeInfo {
+        self.map.palette.get_voxel_type_info(self.reader.get(p))
+    }
+
+    pub fn for_each(&self, extent: &Extent3i, mut f: impl FnMut(Point3i, &'a V::TypeInfo)) {
+        let palette = &self.map.palette;
+        self.reader.for_each(extent, |p: Point3i, voxel: V| {
+            f(p, palette.get_voxel_type_info(voxel))
+        });
+    }
+
+    /// A `TransformMap` view of the reader, for use with building-blocks algorithms that take any
+    /// map.
+    pub fn as_transform_map(
+        &self,
+    ) -> TransformMap<
+        '_,
+        ChunkMap3<V, (), CompressibleChunkStorageReader3<'a, V>>,
+        impl Fn(V) -> &'a V::TypeInfo,
+    > {
+        TransformMap::new(&self.reader, self.map.voxel_info_transform())
     }
 }
 
 #[derive(Clone, Default)]
+#[cfg_attr(feature = "serialize", derive(serde::Deserialize, serde::Serialize))]
 pub struct VoxelPalette<I> {
     pub infos: Vec<I>,
 }
@@ -0,0 +1,68 @@
+use crate::{ThreadLocalResourceHandle, Voxel, VoxelPalette};
+
+use building_blocks::prelude::*;
+
+/// The 2D counterpart of the `VoxelMap`, for tile games and heightmap layers. It shares the
+/// `Voxel` trait and `VoxelPalette` with the 3D map.
+pub struct VoxelMap2<V>
+where
+    V: Voxel,
+{
+    pub voxels: CompressibleChunkMap2<V>,
+    pub palette: VoxelPalette<V::TypeInfo>,
+}
+
+impl<V> VoxelMap2<V>
+where
+    V: Voxel,
+{
+    /// Returns a closure that transforms voxels into their type's corresponding info. This is
+    /// intended to be used with a `TransformMap`.
+    #[inline]
+    pub fn voxel_info_transform<'a>(&'a self) -> impl Fn(V) -> &'a V::TypeInfo {
+        move |v: V| self.palette.get_voxel_type_info(v)
+    }
+
+    /// Each thread's local cache is created by the init function of the `ThreadLocalVoxelCache2`
+    /// that `cache` came from, or with `LocalChunkCache2::new` if it has none.
+    pub fn reader<'a>(
+        &'a self,
+        cache: &'a ThreadLocalResourceHandle<LocalChunkCache2<V>>,
+    ) -> ChunkMap2<V, (), CompressibleChunkStorageReader2<V>> {
+        self.voxels
+            .reader(cache.get_or_init_with(LocalChunkCache2::new))
+    }
+}
+
+pub fn chunk_map_builder2<V>(chunk_shape: Point2i) -> ChunkMapBuilder2<V>
+where
+    V: Voxel,
+{
+    ChunkMapBuilder2 {
+        chunk_shape,
+        ambient_value: V::default(),
+        default_chunk_metadata: (),
+    }
+}
+
+pub fn empty_compressible_chunk_map2<V>(chunk_shape: Point2i) -> CompressibleChunkMap2<V>
+where
+    V: Voxel,
+{
+    chunk_map_builder2(chunk_shape)
+        .build_with_write_storage(CompressibleChunkStorage2::new(Lz4 { level: 10 }))
+}
+
+pub fn empty_chunk_hash_map2<V>(chunk_shape: Point2i) -> ChunkHashMap2<V>
+where
+    V: Voxel,
+{
+    chunk_map_builder2(chunk_shape).build_with_hash_map_storage()
+}
+
+pub fn default_array2<V>(extent: Extent2i) -> Array2<V>
+where
+    V: Voxel,
+{
+    Array2::fill(extent, V::default())
+}
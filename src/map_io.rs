@@ -1,15 +1,46 @@
+mod amortized_edits;
+mod background_decompression;
+mod bounds;
 mod chunk_cache_flusher;
 mod chunk_compressor;
+mod chunk_spiller;
+mod claims;
+mod damage;
 mod edit_buffer;
+mod edit_queue;
 mod editor;
 mod empty_chunk_remover;
+mod explosion;
+mod frame_stats;
+mod merge_hooks;
+mod pause;
+mod pinned_chunks;
 mod plugin;
+mod prefetch;
+mod reader;
 
-pub use chunk_compressor::ChunkCacheConfig;
-pub use edit_buffer::{double_buffering_system, DirtyChunks, EditBuffer};
+pub use amortized_edits::{AmortizedEditFinished, AmortizedEditId, AmortizedEdits};
+pub use background_decompression::BackgroundDecompression;
+pub use bounds::{BoundsPolicy, OutOfBoundsEdit, WorldBounds};
+pub use chunk_compressor::{ChunkCacheConfig, ChunkCacheStats, EvictionPolicy};
+pub use chunk_spiller::{copy_chunk_without_caching, ChunkSpillConfig, SpilledChunks};
+pub use claims::{ChunkClaims, ClaimPolicy, ClaimRejection, OwnerId, OwnerOnlyPolicy};
+pub use damage::VoxelDamage;
+pub use edit_buffer::{
+    double_buffering_system, mid_frame_merge_system, ChunkEdits, DirtyChunks, EditBuffer,
+    NeighborDirtying,
+};
+pub use edit_queue::{VoxelEditQueue, VoxelEditSender};
 pub use editor::VoxelEditor;
-pub use empty_chunk_remover::EmptyChunks;
-pub use plugin::MapIoPlugin;
+pub use empty_chunk_remover::{ChunkRemoved, EmptyChunks};
+pub use explosion::VoxelHardness;
+pub use frame_stats::MapIoFrameStats;
+pub use merge_hooks::{MergeHooks, PostMergeHook, PreMergeHook};
+pub use pause::MapIoPause;
+pub use pinned_chunks::PinnedChunks;
+pub use plugin::{MapIoPlugin, MID_FRAME_MERGE_STAGE};
+pub use prefetch::PrefetchQueue;
+pub use reader::VoxelReader;
 
 use crate::ThreadLocalResource;
 
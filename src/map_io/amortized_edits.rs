@@ -0,0 +1,195 @@
+use super::{MapIoPause, VoxelEditor};
+
+use crate::Voxel;
+
+use bevy::prelude::*;
+use building_blocks::prelude::*;
+use std::collections::VecDeque;
+
+/// Identifies an edit submitted to the `AmortizedEdits`.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct AmortizedEditId(u64);
+
+/// Sent once all of an amortized edit has been merged into the `VoxelMap`.
+pub struct AmortizedEditFinished<V> {
+    pub id: AmortizedEditId,
+    marker: std::marker::PhantomData<V>,
+}
+
+/// Edits too large to apply in a single frame, like terraforming a huge region. Each edit is split
+/// into chunk-sized pieces, and pieces are applied with the `VoxelEditor` until
+/// `voxels_per_frame` is used up. Edits are applied in the order they were submitted.
+///
+/// Between frames, the map shows a partially applied edit, so `edit_func` should only depend on
+/// the point and the voxel it's given. An `AmortizedEditFinished` event is sent once the last
+/// piece of an edit has been merged.
+pub struct AmortizedEdits<V> {
+    /// The maximum number of voxels edited per frame. At least one piece is applied every frame, so
+    /// a single chunk can go over the budget.
+    pub voxels_per_frame: usize,
+    next_id: u64,
+    edits: VecDeque<AmortizedEdit<V>>,
+    just_finished: Vec<AmortizedEditId>,
+}
+
+struct AmortizedEdit<V> {
+    id: AmortizedEditId,
+    extent: Extent3i,
+    touch_neighbors: bool,
+    edit_func: Box<dyn FnMut(Point3i, &mut V) + Send + Sync>,
+    // Split lazily, since chunk keys need the map's indexer.
+    remaining_chunk_keys: Option<Vec<Point3i>>,
+    num_chunks: usize,
+}
+
+impl<V> Default for AmortizedEdits<V> {
+    fn default() -> Self {
+        Self {
+            voxels_per_frame: 1 << 20,
+            next_id: 0,
+            edits: VecDeque::new(),
+            just_finished: Vec::new(),
+        }
+    }
+}
+
+impl<V> AmortizedEdits<V> {
+    /// Runs `edit_func` on all voxels in `extent` over as many frames as it takes. Does not mark
+    /// the neighbors of edited chunks.
+    pub fn edit_extent(
+        &mut self,
+        extent: Extent3i,
+        edit_func: impl FnMut(Point3i, &mut V) + Send + Sync + 'static,
+    ) -> AmortizedEditId {
+        self.submit(extent, false, Box::new(edit_func))
+    }
+
+    /// Runs `edit_func` on all voxels in `extent` over as many frames as it takes. All edited
+    /// chunks and their neighbors will be marked as dirty.
+    pub fn edit_extent_and_touch_neighbors(
+        &mut self,
+        extent: Extent3i,
+        edit_func: impl FnMut(Point3i, &mut V) + Send + Sync + 'static,
+    ) -> AmortizedEditId {
+        self.submit(extent, true, Box::new(edit_func))
+    }
+
+    /// Stops applying the edit. Pieces that were already applied stay in the map. Returns `false`
+    /// if the edit was already finished.
+    pub fn cancel(&mut self, id: AmortizedEditId) -> bool {
+        let num_edits = self.edits.len();
+        self.edits.retain(|e| e.id != id);
+
+        self.edits.len() != num_edits
+    }
+
+    /// The fraction of the edit's chunks that have been applied, or `None` if the edit is finished
+    /// or was cancelled.
+    pub fn progress(&self, id: AmortizedEditId) -> Option<f32> {
+        let edit = self.edits.iter().find(|e| e.id == id)?;
+
+        Some(match &edit.remaining_chunk_keys {
+            Some(remaining) if edit.num_chunks > 0 => {
+                1.0 - remaining.len() as f32 / edit.num_chunks as f32
+            }
+            _ => 0.0,
+        })
+    }
+
+    /// The number of unfinished edits.
+    pub fn len(&self) -> usize {
+        self.edits.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.edits.is_empty()
+    }
+
+    fn submit(
+        &mut self,
+        extent: Extent3i,
+        touch_neighbors: bool,
+        edit_func: Box<dyn FnMut(Point3i, &mut V) + Send + Sync>,
+    ) -> AmortizedEditId {
+        let id = AmortizedEditId(self.next_id);
+        self.next_id += 1;
+        self.edits.push_back(AmortizedEdit {
+            id,
+            extent,
+            touch_neighbors,
+            edit_func,
+            remaining_chunk_keys: None,
+            num_chunks: 0,
+        });
+
+        id
+    }
+}
+
+/// Applies pieces of the amortized edits with the `VoxelEditor`, up to the frame's budget.
+pub fn amortized_edits_system<V>(
+    mut amortized_edits: ResMut<AmortizedEdits<V>>,
+    mut voxel_editor: VoxelEditor<V>,
+    pause: Res<MapIoPause<V>>,
+) where
+    V: Voxel,
+{
+    // Pieces are only finished once they're merged.
+    if pause.is_paused() {
+        return;
+    }
+
+    let amortized_edits = &mut *amortized_edits;
+
+    let indexer = voxel_editor.map.voxels.indexer.clone();
+    let mut voxels_edited = 0;
+    while let Some(edit) = amortized_edits.edits.front_mut() {
+        if edit.remaining_chunk_keys.is_none() {
+            // Reversed so pieces can be popped in order.
+            let mut chunk_keys: Vec<_> = indexer.chunk_keys_for_extent(&edit.extent).collect();
+            chunk_keys.reverse();
+            edit.num_chunks = chunk_keys.len();
+            edit.remaining_chunk_keys = Some(chunk_keys);
+        }
+        let remaining = edit.remaining_chunk_keys.as_mut().unwrap();
+
+        while let Some(chunk_key) = remaining.last().copied() {
+            let piece = edit
+                .extent
+                .intersection(&indexer.extent_for_chunk_at_key(chunk_key));
+            let piece_voxels = piece.num_points();
+            if voxels_edited > 0 && voxels_edited + piece_voxels > amortized_edits.voxels_per_frame
+            {
+                return;
+            }
+            remaining.pop();
+
+            let edit_func = &mut *edit.edit_func;
+            if edit.touch_neighbors {
+                voxel_editor.edit_extent_and_touch_neighbors(piece, edit_func);
+            } else {
+                voxel_editor.edit_extent(piece, edit_func);
+            }
+            voxels_edited += piece_voxels;
+        }
+
+        let id = edit.id;
+        amortized_edits.edits.pop_front();
+        amortized_edits.just_finished.push(id);
+    }
+}
+
+/// Sends `AmortizedEditFinished` for the edits whose last pieces were just merged.
+pub fn amortized_edits_finished_system<V>(
+    mut amortized_edits: ResMut<AmortizedEdits<V>>,
+    mut finished_events: ResMut<Events<AmortizedEditFinished<V>>>,
+) where
+    V: Voxel,
+{
+    for id in amortized_edits.just_finished.drain(..) {
+        finished_events.send(AmortizedEditFinished {
+            id,
+            marker: Default::default(),
+        });
+    }
+}
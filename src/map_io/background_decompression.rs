@@ -0,0 +1,144 @@
+use super::DirtyChunks;
+
+use crate::{tasks::spawn_detached, EmptyChunks, Voxel, VoxelMap, VoxelTaskPool};
+
+use bevy::prelude::*;
+use building_blocks::{prelude::*, storage::MaybeCompressed};
+use crossbeam_channel::{Receiver, Sender};
+use fnv::FnvHashSet;
+use std::sync::{Arc, Mutex};
+
+/// Decompresses chunks for the non-blocking reads of the `VoxelReader`, like
+/// `VoxelReader::get_nonblocking`, on the `VoxelTaskPool` instead of the reading thread. Enabled
+/// with `MapIoPlugin::with_background_decompression`.
+///
+/// A non-blocking read of a chunk that was compressed by the `MapIoPlugin` gets the ambient value
+/// and queues the chunk. Queued chunks are decompressed in the background after the edits are
+/// merged, and they enter the cache at the start of a later frame, usually the next one.
+///
+/// A chunk that was decompressed by a blocking read still reads as ambient until the queue catches
+/// up, which takes a frame.
+pub struct BackgroundDecompression<V> {
+    // Chunks that are believed to be compressed, including those in flight.
+    compressed: FnvHashSet<Point3i>,
+    sender: Sender<Point3i>,
+    receiver: Receiver<Point3i>,
+    in_flight: FnvHashSet<Point3i>,
+    // In-flight chunks that were edited or removed, so their decompressed copies are stale.
+    stale: FnvHashSet<Point3i>,
+    finished: Arc<Mutex<Vec<(Point3i, Chunk3<V>)>>>,
+}
+
+impl<V> Default for BackgroundDecompression<V> {
+    fn default() -> Self {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+
+        Self {
+            compressed: Default::default(),
+            sender,
+            receiver,
+            in_flight: Default::default(),
+            stale: Default::default(),
+            finished: Default::default(),
+        }
+    }
+}
+
+impl<V> BackgroundDecompression<V> {
+    /// The number of chunks being decompressed.
+    pub fn num_in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    /// Queues the chunk if it's compressed. Returns `true` if it was queued, in which case the read
+    /// shouldn't touch it.
+    pub(crate) fn request(&self, chunk_key: Point3i) -> bool {
+        if !self.compressed.contains(&chunk_key) {
+            return false;
+        }
+        // The receiver lives as long as the sender.
+        let _ = self.sender.send(chunk_key);
+
+        true
+    }
+
+    pub(crate) fn record_compressed(&mut self, chunk_key: Point3i) {
+        self.compressed.insert(chunk_key);
+    }
+}
+
+/// Puts finished chunks into the `VoxelMap`, unless they changed since they were copied. This runs
+/// before any reads, so it can write directly into the map.
+pub fn background_decompression_finished_system<V>(
+    dirty_chunks: Res<DirtyChunks<V>>,
+    empty_chunks: Res<EmptyChunks<V>>,
+    mut voxel_map: ResMut<VoxelMap<V>>,
+    mut decompression: ResMut<BackgroundDecompression<V>>,
+) where
+    V: Voxel,
+{
+    let decompression = &mut *decompression;
+
+    // Merged chunks are decompressed, and removed chunks are gone.
+    for chunk_key in dirty_chunks
+        .edited_chunk_keys
+        .iter()
+        .chain(empty_chunks.removed_chunk_keys())
+    {
+        decompression.compressed.remove(chunk_key);
+        if decompression.in_flight.contains(chunk_key) {
+            decompression.stale.insert(*chunk_key);
+        }
+    }
+
+    let finished = std::mem::replace(&mut *decompression.finished.lock().unwrap(), Vec::new());
+    for (chunk_key, chunk) in finished.into_iter() {
+        decompression.in_flight.remove(&chunk_key);
+        decompression.compressed.remove(&chunk_key);
+        if !decompression.stale.remove(&chunk_key) {
+            voxel_map.voxels.write_chunk(chunk_key, chunk);
+        }
+    }
+}
+
+/// Starts decompressing the chunks that were queued this frame. This runs after the edits are
+/// merged, so the copies are up to date.
+pub fn background_decompression_system<V>(
+    pool: Res<VoxelTaskPool>,
+    voxel_map: Res<VoxelMap<V>>,
+    mut decompression: ResMut<BackgroundDecompression<V>>,
+) where
+    V: Voxel,
+{
+    let decompression = &mut *decompression;
+
+    let mut chunks = Vec::new();
+    let requested: FnvHashSet<Point3i> = decompression.receiver.try_iter().collect();
+    for chunk_key in requested.into_iter() {
+        if decompression.in_flight.contains(&chunk_key) {
+            continue;
+        }
+        match voxel_map.voxels.storage().copy_without_caching(chunk_key) {
+            Some(chunk @ MaybeCompressed::Compressed(_)) => {
+                decompression.in_flight.insert(chunk_key);
+                chunks.push((chunk_key, chunk));
+            }
+            // Already decompressed or missing.
+            _ => {
+                decompression.compressed.remove(&chunk_key);
+            }
+        }
+    }
+    if chunks.is_empty() {
+        return;
+    }
+
+    let finished = decompression.finished.clone();
+    spawn_detached(&*pool, move || {
+        let decompressed: Vec<(Point3i, Chunk3<V>)> = chunks
+            .into_iter()
+            .map(|(chunk_key, chunk)| (chunk_key, chunk.as_decompressed()))
+            .collect();
+        finished.lock().unwrap().extend(decompressed);
+    });
+}
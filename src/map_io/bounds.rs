@@ -0,0 +1,89 @@
+use building_blocks::prelude::*;
+
+/// What happens to an edit that reaches outside the `WorldBounds`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BoundsPolicy {
+    /// The whole edit is dropped.
+    Reject,
+    /// Only the part of the edit inside the bounds is applied.
+    Clamp,
+}
+
+/// Limits the map to `extent`, e.g. for arena-style games with hard borders. Configure it with
+/// `MapIoPlugin::with_world_bounds`, or change it at runtime.
+///
+/// The `VoxelEditor` enforces the bounds on every edit, including queued, amortized, and generated
+/// ones, and sends an `OutOfBoundsEdit` event for each edit that reached outside, whether it was
+/// rejected or clamped. Flood fills stop at the border without an event. Chunks outside the bounds
+/// are neither generated by the `WorldGenPlugin` nor prefetched.
+///
+/// With `BoundsPolicy::Reject`, chunks that straddle the border can't be generated, so the bounds
+/// should be aligned to chunks.
+pub struct WorldBounds<V> {
+    /// `None` means unbounded.
+    pub extent: Option<Extent3i>,
+    pub policy: BoundsPolicy,
+    marker: std::marker::PhantomData<V>,
+}
+
+impl<V> Default for WorldBounds<V> {
+    fn default() -> Self {
+        Self {
+            extent: None,
+            policy: BoundsPolicy::Reject,
+            marker: Default::default(),
+        }
+    }
+}
+
+impl<V> WorldBounds<V> {
+    pub fn new(extent: Extent3i, policy: BoundsPolicy) -> Self {
+        Self {
+            extent: Some(extent),
+            policy,
+            marker: Default::default(),
+        }
+    }
+
+    pub fn contains(&self, p: &Point3i) -> bool {
+        self.extent.map_or(true, |bounds| bounds.contains(p))
+    }
+
+    /// The part of `extent` inside the bounds, if any.
+    pub fn clip(&self, extent: &Extent3i) -> Option<Extent3i> {
+        let clipped = match self.extent {
+            Some(bounds) => extent.intersection(&bounds),
+            None => return Some(*extent),
+        };
+
+        if clipped.num_points() > 0 {
+            Some(clipped)
+        } else {
+            None
+        }
+    }
+
+    /// Whether an edit of the whole `extent` would be applied, at least in part.
+    pub fn allows(&self, extent: &Extent3i) -> bool {
+        match self.policy {
+            BoundsPolicy::Reject => self.clip(extent) == Some(*extent),
+            BoundsPolicy::Clamp => self.clip(extent).is_some(),
+        }
+    }
+}
+
+/// Sent when an edit reaches outside the `WorldBounds`.
+pub struct OutOfBoundsEdit<V> {
+    /// The extent of the attempted edit.
+    pub extent: Extent3i,
+    marker: std::marker::PhantomData<V>,
+}
+
+impl<V> OutOfBoundsEdit<V> {
+    pub(crate) fn new(extent: Extent3i) -> Self {
+        Self {
+            extent,
+            marker: Default::default(),
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use super::ThreadLocalVoxelCache;
+use super::{ChunkCacheStats, ThreadLocalVoxelCache};
 
 use crate::{Voxel, VoxelMap};
 
@@ -8,11 +8,25 @@ use bevy::prelude::*;
 pub fn chunk_cache_flusher_system<V>(
     mut local_caches: ResMut<ThreadLocalVoxelCache<V>>,
     mut voxel_map: ResMut<VoxelMap<V>>,
+    mut cache_stats: ResMut<ChunkCacheStats<V>>,
 ) where
     V: Voxel,
 {
-    let taken_caches = std::mem::replace(&mut *local_caches, ThreadLocalVoxelCache::new());
-    for cache in taken_caches.into_iter() {
+    flush_local_caches(&mut local_caches, &mut voxel_map, &mut cache_stats);
+    cache_stats.finish_frame();
+}
+
+/// Counts the lookups of the readers in the `cache_stats` before the chunks they decompressed
+/// enter the global cache.
+pub(crate) fn flush_local_caches<V>(
+    local_caches: &mut ThreadLocalVoxelCache<V>,
+    voxel_map: &mut VoxelMap<V>,
+    cache_stats: &mut ChunkCacheStats<V>,
+) where
+    V: Voxel,
+{
+    cache_stats.count_lookups(voxel_map);
+    for cache in local_caches.drain() {
         voxel_map.voxels.storage_mut().flush_local_cache(cache);
     }
 }
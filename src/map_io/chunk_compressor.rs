@@ -1,16 +1,41 @@
-use crate::{Voxel, VoxelMap};
+use super::{
+    BackgroundDecompression, DirtyChunks, EmptyChunks, MapIoFrameStats, MapIoPause, PinnedChunks,
+    SpilledChunks,
+};
 
-use bevy::{prelude::*, tasks::ComputeTaskPool};
-use building_blocks::storage::{Compression, FastChunkCompression, Lz4};
+use crate::{
+    observer::transform_voxel_point, tasks::map_in_pool, uniform_chunks::Uniformity, Observer,
+    Voxel, VoxelMap, VoxelTaskPool,
+};
 
+use bevy::prelude::*;
+use building_blocks::{
+    prelude::*,
+    storage::{Compression, FastChunkCompression, Lz4, MaybeCompressed},
+};
+use crossbeam_channel::{Receiver, Sender};
+use fnv::{FnvHashMap, FnvHashSet};
+use std::{
+    cmp::Reverse,
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// The chunk cache limits of the map with voxel type `V`.
 #[derive(Clone, Copy)]
-pub struct ChunkCacheConfig {
+#[cfg_attr(feature = "serialize", derive(serde::Deserialize, serde::Serialize))]
+pub struct ChunkCacheConfig<V> {
     // These constants should be correlated with the size of a chunk.
     pub max_cached_chunks: usize,
     pub max_chunks_compressed_per_frame_per_thread: usize,
+    pub eviction_policy: EvictionPolicy,
+    /// The `Lz4` level of evicted chunks. Lower levels compress faster, but not as well.
+    pub compression_level: u32,
+    #[cfg_attr(feature = "serialize", serde(skip))]
+    pub marker: std::marker::PhantomData<V>,
 }
 
-impl Default for ChunkCacheConfig {
+impl<V> Default for ChunkCacheConfig<V> {
     fn default() -> Self {
         Self {
             // Assuming 8192-byte chunks, we'll reserve a little under a gigabyte for the cache.
@@ -18,20 +43,242 @@ impl Default for ChunkCacheConfig {
             // Avoid high latency from compressing too many chunks in one frame. 8192-byte chunk
             // compression latency is around 0.01 ms.
             max_chunks_compressed_per_frame_per_thread: 50,
+            eviction_policy: EvictionPolicy::Lru,
+            compression_level: 10,
+            marker: Default::default(),
+        }
+    }
+}
+
+// Implemented by hand, so the voxel type doesn't need to implement them too.
+impl<V> fmt::Debug for ChunkCacheConfig<V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ChunkCacheConfig")
+            .field("max_cached_chunks", &self.max_cached_chunks)
+            .field(
+                "max_chunks_compressed_per_frame_per_thread",
+                &self.max_chunks_compressed_per_frame_per_thread,
+            )
+            .field("eviction_policy", &self.eviction_policy)
+            .field("compression_level", &self.compression_level)
+            .finish()
+    }
+}
+
+impl<V> PartialEq for ChunkCacheConfig<V> {
+    fn eq(&self, other: &Self) -> bool {
+        self.max_cached_chunks == other.max_cached_chunks
+            && self.max_chunks_compressed_per_frame_per_thread
+                == other.max_chunks_compressed_per_frame_per_thread
+            && self.eviction_policy == other.eviction_policy
+            && self.compression_level == other.compression_level
+    }
+}
+
+/// Chooses which cached chunks are compressed when the cache is full.
+///
+/// Except for `Lru`, policies pick from a window of the least recently used chunks, so recently
+/// used chunks are never evicted. Chunks from the window that aren't evicted are treated as
+/// recently used. 2D maps always use `Lru`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Reflect)]
+#[reflect_value(PartialEq)]
+#[cfg_attr(feature = "serialize", derive(serde::Deserialize, serde::Serialize))]
+pub enum EvictionPolicy {
+    /// Evicts the least recently used chunks. Good for streaming games, where the player's
+    /// surroundings are always the most recently used.
+    Lru,
+    /// Evicts the chunks that were used least often, counting every time a chunk was edited or had
+    /// to be decompressed again after being evicted. Good for editors, where the same areas are
+    /// revisited.
+    Lfu,
+    /// Evicts the chunks farthest from any `Observer`.
+    DistanceWeighted,
+}
+
+impl Default for EvictionPolicy {
+    fn default() -> Self {
+        EvictionPolicy::Lru
+    }
+}
+
+/// Counters for the global chunk cache, accumulated since the app started or the last `reset`.
+///
+/// Hits and misses are counted for the reads of the `VoxelReader`, where every `get` is one chunk
+/// lookup, and so is every chunk visited by `for_each`. They're sorted out when the local caches
+/// are flushed. Reads through a raw `VoxelMap::reader` aren't counted.
+pub struct ChunkCacheStats<V> {
+    /// Chunk lookups that found the chunk decompressed, or didn't find it at all.
+    pub hits: u64,
+    /// Chunk lookups that had to decompress the chunk. Each chunk counts once per flush, even if
+    /// several threads decompressed it.
+    pub misses: u64,
+    /// Chunks that were evicted and compressed, or collapsed by the `UniformChunksPlugin`.
+    pub evictions: u64,
+    /// Evicted chunks that had to be decompressed again. Only counted when they're next considered
+    /// for eviction, and only with the `Lfu` policy, which tracks evicted chunks.
+    pub reloads: u64,
+    // The readers only have shared access, so they count lookups atomically, and send the ones of
+    // chunks that are believed to be compressed.
+    lookups: AtomicU64,
+    compressed: FnvHashSet<Point3i>,
+    missed_chunk_keys: Vec<Point3i>,
+    last_frame_missed_chunk_keys: Vec<Point3i>,
+    sender: Sender<Point3i>,
+    receiver: Receiver<Point3i>,
+    marker: std::marker::PhantomData<V>,
+}
+
+impl<V> Default for ChunkCacheStats<V> {
+    fn default() -> Self {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+
+        Self {
+            hits: 0,
+            misses: 0,
+            evictions: 0,
+            reloads: 0,
+            lookups: AtomicU64::new(0),
+            compressed: Default::default(),
+            missed_chunk_keys: Vec::new(),
+            last_frame_missed_chunk_keys: Vec::new(),
+            sender,
+            receiver,
+            marker: Default::default(),
+        }
+    }
+}
+
+impl<V> ChunkCacheStats<V> {
+    pub fn reset(&mut self) {
+        self.hits = 0;
+        self.misses = 0;
+        self.evictions = 0;
+        self.reloads = 0;
+    }
+
+    /// The chunks that lookups missed on the previous frame, each once per flush.
+    pub fn last_frame_missed_chunk_keys(&self) -> impl Iterator<Item = &Point3i> {
+        self.last_frame_missed_chunk_keys.iter()
+    }
+
+    pub(crate) fn record_lookup(&self, chunk_key: Point3i) {
+        self.lookups.fetch_add(1, Ordering::Relaxed);
+        if self.compressed.contains(&chunk_key) {
+            // The receiver lives as long as the sender.
+            let _ = self.sender.send(chunk_key);
+        }
+    }
+
+    /// Counts the lookups since the last flush as hits or misses. This must run right before the
+    /// local caches are flushed, while the chunks that readers decompressed are still compressed
+    /// in the global cache.
+    pub(crate) fn count_lookups(&mut self, voxel_map: &VoxelMap<V>)
+    where
+        V: Voxel,
+    {
+        let lookups = std::mem::replace(self.lookups.get_mut(), 0);
+        let maybe_missed: FnvHashSet<Point3i> = self.receiver.try_iter().collect();
+        let storage = voxel_map.voxels.storage();
+        let mut misses = 0;
+        for chunk_key in maybe_missed.into_iter() {
+            // Either it's cached already, or it will be once it's flushed.
+            self.compressed.remove(&chunk_key);
+            if let Some(MaybeCompressed::Compressed(_)) = storage.copy_without_caching(chunk_key) {
+                misses += 1;
+                self.missed_chunk_keys.push(chunk_key);
+            }
+        }
+        self.misses += misses;
+        self.hits += lookups.saturating_sub(misses);
+    }
+
+    /// Called by the last flush of the frame.
+    pub(crate) fn finish_frame(&mut self) {
+        self.last_frame_missed_chunk_keys =
+            std::mem::replace(&mut self.missed_chunk_keys, Vec::new());
+    }
+}
+
+/// How many times more chunks than will be evicted are considered by the non-LRU policies.
+const EVICTION_WINDOW: usize = 4;
+
+/// The use counts for the `Lfu` policy, kept up to date by the `chunk_compressor_system`.
+pub struct ChunkUseCounts<V> {
+    uses: FnvHashMap<Point3i, u32>,
+    // Chunks that were evicted, so we know they were reloaded when they come up again.
+    evicted: FnvHashSet<Point3i>,
+    marker: std::marker::PhantomData<V>,
+}
+
+impl<V> Default for ChunkUseCounts<V> {
+    fn default() -> Self {
+        Self {
+            uses: Default::default(),
+            evicted: Default::default(),
+            marker: Default::default(),
         }
     }
 }
 
-/// A system that evicts and compresses the least recently used voxel chunks when the cache gets too
-/// big.
+impl<V> ChunkUseCounts<V> {
+    fn forget_chunk(&mut self, chunk_key: &Point3i) {
+        self.uses.remove(chunk_key);
+        self.evicted.remove(chunk_key);
+    }
+}
+
+/// A system that evicts and compresses voxel chunks according to the `EvictionPolicy` when the cache
+/// gets too big. Pinned chunks are never compressed.
+///
+/// With the `UniformChunksPlugin`, evicted chunks that are entirely one voxel are collapsed to that
+/// voxel instead of compressed, and the ones full of the ambient value are marked for removal.
+#[allow(clippy::too_many_arguments)]
 pub fn chunk_compressor_system<V>(
-    cache_config: Res<ChunkCacheConfig>,
-    pool: Res<ComputeTaskPool>,
+    cache_config: Res<ChunkCacheConfig<V>>,
+    pool: Res<VoxelTaskPool>,
+    pinned_chunks: Res<PinnedChunks<V>>,
+    dirty_chunks: Res<DirtyChunks<V>>,
+    observers: Query<&GlobalTransform, With<Observer>>,
+    mut use_counts: ResMut<ChunkUseCounts<V>>,
     mut voxel_map: ResMut<VoxelMap<V>>,
+    mut frame_stats: ResMut<MapIoFrameStats<V>>,
+    mut cache_stats: ResMut<ChunkCacheStats<V>>,
+    mut empty_chunks: ResMut<EmptyChunks<V>>,
+    mut spilled_chunks: Option<ResMut<SpilledChunks<V>>>,
+    mut background_decompression: Option<ResMut<BackgroundDecompression<V>>>,
+    pause: Res<MapIoPause<V>>,
 ) where
     V: Voxel,
 {
+    if pause.is_paused() {
+        frame_stats.compressed_chunks = 0;
+        return;
+    }
+
+    // Merged chunks are decompressed.
+    for chunk_key in dirty_chunks.edited_chunk_keys.iter() {
+        cache_stats.compressed.remove(chunk_key);
+    }
+    // Forget the chunks that left the map, so the use counts don't grow without bound.
+    let spilled_chunk_keys = spilled_chunks
+        .as_deref()
+        .into_iter()
+        .flat_map(|s| s.recently_spilled_chunk_keys());
+    for chunk_key in empty_chunks.removed_chunk_keys().chain(spilled_chunk_keys) {
+        use_counts.forget_chunk(chunk_key);
+        cache_stats.compressed.remove(chunk_key);
+    }
+
+    let policy = cache_config.eviction_policy;
+    if policy == EvictionPolicy::Lfu {
+        for chunk_key in dirty_chunks.edited_chunk_keys.iter() {
+            *use_counts.uses.entry(*chunk_key).or_insert(0) += 1;
+        }
+    }
+
     let num_cached = voxel_map.voxels.storage().cache.len_cached();
+    frame_stats.cached_chunks = num_cached;
+    frame_stats.compressed_chunks = 0;
     if num_cached < cache_config.max_cached_chunks {
         return;
     }
@@ -41,26 +288,132 @@ pub fn chunk_compressor_system<V>(
     let num_to_compress =
         overgrowth.min(pool.thread_num() * cache_config.max_chunks_compressed_per_frame_per_thread);
 
+    let num_candidates = match policy {
+        EvictionPolicy::Lru => num_to_compress,
+        _ => num_to_compress * EVICTION_WINDOW,
+    };
+
     let mut chunks_to_compress = Vec::new();
-    for _ in 0..num_to_compress {
-        if let Some(key_and_chunk) = voxel_map.voxels.storage_mut().remove_lru() {
-            chunks_to_compress.push(key_and_chunk);
+    // Each pinned chunk is put back as the most recently used, so we visit every chunk at most once.
+    for _ in 0..num_cached {
+        if chunks_to_compress.len() == num_candidates {
+            break;
+        }
+        if let Some((key, chunk)) = voxel_map.voxels.storage_mut().remove_lru() {
+            if pinned_chunks.is_pinned(&key) {
+                voxel_map.voxels.write_chunk(key, chunk);
+            } else {
+                chunks_to_compress.push((key, chunk));
+            }
         } else {
             break;
         }
     }
 
-    let compression = FastChunkCompression::new(Lz4 { level: 10 });
-    let compressed_chunks = pool.scope(|s| {
-        for (key, chunk) in chunks_to_compress.into_iter() {
-            s.spawn(async move { (key, compression.compress(&chunk)) });
+    // The sorts are stable, so ties are broken by recency.
+    match policy {
+        EvictionPolicy::Lru => (),
+        EvictionPolicy::Lfu => {
+            let use_counts = &mut *use_counts;
+            for (key, _) in chunks_to_compress.iter() {
+                if use_counts.evicted.remove(key) {
+                    *use_counts.uses.entry(*key).or_insert(0) += 1;
+                    cache_stats.reloads += 1;
+                }
+            }
+            chunks_to_compress
+                .sort_by_key(|(key, _)| use_counts.uses.get(key).copied().unwrap_or(0));
+        }
+        EvictionPolicy::DistanceWeighted => {
+            let observer_points: Vec<Point3i> =
+                observers.iter().map(transform_voxel_point).collect();
+            let indexer = &voxel_map.voxels.indexer;
+            chunks_to_compress.sort_by_key(|(key, _)| {
+                let extent = indexer.extent_for_chunk_at_key(*key);
+                let center = extent.minimum
+                    + PointN([
+                        extent.shape.x() / 2,
+                        extent.shape.y() / 2,
+                        extent.shape.z() / 2,
+                    ]);
+                let nearest = observer_points
+                    .iter()
+                    .map(|p| {
+                        let d = *p - center;
+
+                        d.x() as i64 * d.x() as i64
+                            + d.y() as i64 * d.y() as i64
+                            + d.z() as i64 * d.z() as i64
+                    })
+                    .min()
+                    .unwrap_or(0);
+
+                Reverse(nearest)
+            });
         }
+    }
+    if chunks_to_compress.len() > num_to_compress {
+        for (key, chunk) in chunks_to_compress.drain(num_to_compress..) {
+            voxel_map.voxels.write_chunk(key, chunk);
+        }
+    }
+    if policy == EvictionPolicy::Lfu {
+        for (key, _) in chunks_to_compress.iter() {
+            use_counts.evicted.insert(*key);
+        }
+    }
+
+    let compression = FastChunkCompression::new(Lz4 {
+        level: cache_config.compression_level,
+    });
+    let uniform_chunks = &voxel_map.uniform_chunks;
+    let evicted_chunks = map_in_pool(&*pool, chunks_to_compress, |(key, chunk)| {
+        let evicted = match uniform_chunks.uniformity(&chunk.array) {
+            Uniformity::Mixed => EvictedChunk::Compressed(compression.compress(&chunk)),
+            Uniformity::Ambient => EvictedChunk::Empty(compression.compress(&chunk)),
+            Uniformity::Uniform(value) => EvictedChunk::Collapsed(value),
+        };
+
+        (key, evicted)
     });
 
-    for (key, compressed_chunk) in compressed_chunks.into_iter() {
+    frame_stats.compressed_chunks = evicted_chunks
+        .iter()
+        .filter(|(_, evicted)| !matches!(evicted, EvictedChunk::Collapsed(_)))
+        .count();
+    cache_stats.evictions += evicted_chunks.len() as u64;
+    frame_stats.cached_chunks = num_cached - evicted_chunks.len();
+
+    for (key, evicted) in evicted_chunks.into_iter() {
+        let compressed_chunk = match evicted {
+            EvictedChunk::Compressed(compressed_chunk) => compressed_chunk,
+            EvictedChunk::Empty(compressed_chunk) => {
+                empty_chunks.mark_for_removal(key);
+
+                compressed_chunk
+            }
+            EvictedChunk::Collapsed(value) => {
+                voxel_map.uniform_chunks.collapse(key, value);
+                continue;
+            }
+        };
+        cache_stats.compressed.insert(key);
+        if let Some(spilled_chunks) = spilled_chunks.as_mut() {
+            spilled_chunks.record_compressed(key);
+        }
+        if let Some(background_decompression) = background_decompression.as_mut() {
+            background_decompression.record_compressed(key);
+        }
         voxel_map
             .voxels
             .storage_mut()
             .insert_compressed(key, compressed_chunk);
     }
 }
+
+enum EvictedChunk<C, V> {
+    Compressed(C),
+    // Full of the ambient value, so it's only compressed until it's removed.
+    Empty(C),
+    Collapsed(V),
+}
@@ -0,0 +1,339 @@
+use super::{DirtyChunks, MapIoPause, PinnedChunks, PrefetchQueue};
+
+use crate::{
+    decode_chunk_at, encode_chunk, tasks::map_in_pool, ChunkStore, Voxel, VoxelCodec, VoxelMap,
+    VoxelTaskPool,
+};
+
+use bevy::prelude::*;
+use building_blocks::{prelude::*, storage::MaybeCompressed};
+use fnv::FnvHashSet;
+use std::{
+    collections::VecDeque,
+    io,
+    sync::{Arc, Mutex},
+};
+
+#[derive(Clone)]
+pub struct ChunkSpillConfig {
+    /// Where spilled chunks are written. This should not be the store used for saving the map.
+    pub store: Arc<dyn ChunkStore>,
+    /// When there are more compressed chunks than this in memory, the least recently compressed
+    /// chunks are spilled to disk. Like the `ChunkCacheConfig` limits, this should be correlated
+    /// with the size of a chunk.
+    pub max_compressed_chunks: usize,
+    /// The most chunks that will be spilled or reloaded in a single frame.
+    pub max_chunks_per_frame: usize,
+}
+
+impl ChunkSpillConfig {
+    pub fn new(store: impl ChunkStore + 'static) -> Self {
+        Self {
+            store: Arc::new(store),
+            // Assuming 8192-byte chunks compress to around 1 KB, this is about a gigabyte.
+            max_compressed_chunks: 1000000,
+            max_chunks_per_frame: 50,
+        }
+    }
+}
+
+/// The disk tier of the chunk cache, enabled with `MapIoPlugin::with_disk_spill`.
+///
+/// Chunks move from the decompressed cache to compressed memory to disk as they get colder.
+/// Spilled chunks aren't in the `VoxelMap` storage, so a `VoxelReader` sees the ambient value
+/// until they're reloaded. Chunks are reloaded at the start of every frame if they are:
+///
+/// - requested with `reload_chunk` or `reload_extent`
+/// - pinned in `PinnedChunks`, which includes chunks near `Observer` entities
+/// - waiting in the `PrefetchQueue`
+///
+/// The `VoxelEditor` reads spilled chunks from disk by itself before editing them, and
+/// `copy_chunk_without_caching` reads them for whole-chunk copies, like saving, versioning, and
+/// palette migration. If reading a spilled chunk fails, the edit is skipped and the error is
+/// reported by `take_errors`.
+pub struct SpilledChunks<V> {
+    pub config: ChunkSpillConfig,
+    codec: Arc<dyn VoxelCodec<V>>,
+    chunk_shape: Point3i,
+    // Compressed chunks, least recently compressed first. Chunks that were decompressed or removed
+    // since are skipped when they come up for spilling.
+    compressed_order: VecDeque<Point3i>,
+    compressed: FnvHashSet<Point3i>,
+    spilled: FnvHashSet<Point3i>,
+    // Spilled by the last run of the `chunk_spiller_system`.
+    recently_spilled: Vec<Point3i>,
+    reload_chunk_keys: Vec<Point3i>,
+    reload_extents: Vec<Extent3i>,
+    // Behind a lock so the `VoxelEditor` can report read errors without exclusive access.
+    errors: Mutex<Vec<(Point3i, io::Error)>>,
+}
+
+impl<V> SpilledChunks<V>
+where
+    V: Voxel,
+{
+    pub(crate) fn new(
+        config: ChunkSpillConfig,
+        codec: Arc<dyn VoxelCodec<V>>,
+        chunk_shape: Point3i,
+    ) -> Self {
+        Self {
+            config,
+            codec,
+            chunk_shape,
+            compressed_order: VecDeque::new(),
+            compressed: Default::default(),
+            spilled: Default::default(),
+            recently_spilled: Vec::new(),
+            reload_chunk_keys: Vec::new(),
+            reload_extents: Vec::new(),
+            errors: Default::default(),
+        }
+    }
+
+    pub fn is_spilled(&self, chunk_key: &Point3i) -> bool {
+        self.spilled.contains(chunk_key)
+    }
+
+    pub fn chunk_keys(&self) -> impl Iterator<Item = &Point3i> {
+        self.spilled.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.spilled.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.spilled.is_empty()
+    }
+
+    /// Reloads the chunk at the start of the next frame, if it's spilled.
+    pub fn reload_chunk(&mut self, chunk_key: Point3i) {
+        self.reload_chunk_keys.push(chunk_key);
+    }
+
+    /// Reloads every spilled chunk overlapping `extent` at the start of the next frame.
+    pub fn reload_extent(&mut self, extent: Extent3i) {
+        self.reload_extents.push(extent);
+    }
+
+    /// Reads a spilled chunk from disk without reloading it into the map. Returns `None` if the
+    /// chunk isn't spilled.
+    pub fn read_chunk(&self, chunk_key: Point3i) -> io::Result<Option<Array3<V>>> {
+        if !self.is_spilled(&chunk_key) {
+            return Ok(None);
+        }
+
+        read_spilled_chunk(
+            &*self.config.store,
+            &*self.codec,
+            chunk_key,
+            self.chunk_shape,
+        )
+    }
+
+    /// Takes the errors from failed spills, reloads, and reads. Chunks that failed to spill stay in
+    /// memory, and chunks that failed to reload or be read stay on disk.
+    pub fn take_errors(&mut self) -> Vec<(Point3i, io::Error)> {
+        std::mem::replace(self.errors.get_mut().unwrap(), Vec::new())
+    }
+
+    pub(crate) fn record_error(&self, chunk_key: Point3i, error: io::Error) {
+        self.errors.lock().unwrap().push((chunk_key, error));
+    }
+
+    pub(crate) fn recently_spilled_chunk_keys(&self) -> impl Iterator<Item = &Point3i> {
+        self.recently_spilled.iter()
+    }
+
+    pub(crate) fn record_compressed(&mut self, chunk_key: Point3i) {
+        if self.compressed.insert(chunk_key) {
+            self.compressed_order.push_back(chunk_key);
+        }
+    }
+
+    /// Forgets a chunk that was replaced or removed in memory, so its spilled copy is never
+    /// reloaded.
+    pub(crate) fn forget_chunk(&mut self, chunk_key: &Point3i) {
+        self.spilled.remove(chunk_key);
+    }
+}
+
+/// Copies the chunk at `chunk_key` out of the `VoxelMap` without caching it, expands it if it was
+/// collapsed by the `UniformChunksPlugin`, or reads it from disk if it was spilled. Returns
+/// `Ok(None)` if the chunk doesn't exist.
+pub fn copy_chunk_without_caching<V>(
+    map: &VoxelMap<V>,
+    spilled_chunks: Option<&SpilledChunks<V>>,
+    chunk_key: Point3i,
+) -> io::Result<Option<Array3<V>>>
+where
+    V: Voxel,
+{
+    if let Some(chunk) = map.voxels.storage().copy_without_caching(chunk_key) {
+        return Ok(Some(chunk.as_decompressed().array));
+    }
+    let chunk_shape = map.voxels.indexer.chunk_shape();
+    if let Some(chunk) = map.uniform_chunks.expanded_chunk(chunk_key, chunk_shape) {
+        return Ok(Some(chunk));
+    }
+
+    match spilled_chunks {
+        Some(spilled_chunks) => spilled_chunks.read_chunk(chunk_key),
+        None => Ok(None),
+    }
+}
+
+/// Reads the chunk at `chunk_key` from `store`, failing if the decoded chunk isn't the one at
+/// `chunk_key`.
+fn read_spilled_chunk<V>(
+    store: &dyn ChunkStore,
+    codec: &dyn VoxelCodec<V>,
+    chunk_key: Point3i,
+    chunk_shape: Point3i,
+) -> io::Result<Option<Array3<V>>>
+where
+    V: Voxel,
+{
+    let extent = Extent3i::from_min_and_shape(chunk_key, chunk_shape);
+    match store.get(chunk_key)? {
+        Some(bytes) => decode_chunk_at(codec, &bytes, extent)
+            .map(Some)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+        None => Ok(None),
+    }
+}
+
+/// A system that writes the least recently compressed chunks to disk when there are too many
+/// compressed chunks in memory.
+pub fn chunk_spiller_system<V>(
+    pool: Res<VoxelTaskPool>,
+    dirty_chunks: Res<DirtyChunks<V>>,
+    mut voxel_map: ResMut<VoxelMap<V>>,
+    spilled_chunks: Option<ResMut<SpilledChunks<V>>>,
+    pause: Res<MapIoPause<V>>,
+) where
+    V: Voxel,
+{
+    let mut spilled_chunks = match spilled_chunks {
+        Some(s) if !pause.is_paused() => s,
+        _ => return,
+    };
+
+    spilled_chunks.recently_spilled.clear();
+    // Chunks merged from the edit buffer this frame replace their spilled copies.
+    for chunk_key in dirty_chunks.edited_chunk_keys.iter() {
+        spilled_chunks.forget_chunk(chunk_key);
+    }
+
+    let mut chunks_to_spill = Vec::new();
+    while spilled_chunks.compressed.len() > spilled_chunks.config.max_compressed_chunks
+        && chunks_to_spill.len() < spilled_chunks.config.max_chunks_per_frame
+    {
+        let chunk_key = match spilled_chunks.compressed_order.pop_front() {
+            Some(k) => k,
+            None => break,
+        };
+        spilled_chunks.compressed.remove(&chunk_key);
+        // Only spill chunks that are still compressed.
+        if let Some(chunk @ MaybeCompressed::Compressed(_)) =
+            voxel_map.voxels.storage().copy_without_caching(chunk_key)
+        {
+            chunks_to_spill.push((chunk_key, chunk));
+        }
+    }
+    if chunks_to_spill.is_empty() {
+        return;
+    }
+
+    let store = &*spilled_chunks.config.store;
+    let codec = &*spilled_chunks.codec;
+    let results = map_in_pool(&*pool, chunks_to_spill, |(chunk_key, chunk)| {
+        let bytes = encode_chunk(codec, &chunk.as_decompressed().array);
+
+        (chunk_key, store.put(chunk_key, &bytes))
+    });
+
+    for (chunk_key, result) in results.into_iter() {
+        match result {
+            Ok(()) => {
+                voxel_map.voxels.storage_mut().remove(chunk_key);
+                spilled_chunks.spilled.insert(chunk_key);
+                spilled_chunks.recently_spilled.push(chunk_key);
+            }
+            Err(e) => {
+                spilled_chunks.record_compressed(chunk_key);
+                spilled_chunks.record_error(chunk_key, e);
+            }
+        }
+    }
+}
+
+/// A system that reloads requested spilled chunks into the `VoxelMap`. This runs before any reads,
+/// so it can write directly into the map.
+pub fn chunk_reload_system<V>(
+    pool: Res<VoxelTaskPool>,
+    pinned_chunks: Res<PinnedChunks<V>>,
+    prefetch_queue: Res<PrefetchQueue<V>>,
+    mut voxel_map: ResMut<VoxelMap<V>>,
+    spilled_chunks: Option<ResMut<SpilledChunks<V>>>,
+    pause: Res<MapIoPause<V>>,
+) where
+    V: Voxel,
+{
+    // Requested reloads wait until the map is resumed.
+    let mut spilled_chunks = match spilled_chunks {
+        Some(s) if !pause.is_paused() => s,
+        _ => return,
+    };
+    if spilled_chunks.is_empty() {
+        spilled_chunks.reload_chunk_keys.clear();
+        spilled_chunks.reload_extents.clear();
+        return;
+    }
+
+    let mut requested = std::mem::replace(&mut spilled_chunks.reload_chunk_keys, Vec::new());
+    for extent in std::mem::replace(&mut spilled_chunks.reload_extents, Vec::new()).iter() {
+        requested.extend(voxel_map.voxels.indexer.chunk_keys_for_extent(extent));
+    }
+    requested.extend(pinned_chunks.chunk_keys().cloned());
+    requested.extend(prefetch_queue.pending_chunk_keys().cloned());
+
+    let mut chunks_to_reload = Vec::new();
+    let mut seen = FnvHashSet::default();
+    for chunk_key in requested.into_iter() {
+        if !spilled_chunks.is_spilled(&chunk_key) || !seen.insert(chunk_key) {
+            continue;
+        }
+        if chunks_to_reload.len() < spilled_chunks.config.max_chunks_per_frame {
+            chunks_to_reload.push(chunk_key);
+        } else {
+            // Try again next frame.
+            spilled_chunks.reload_chunk_keys.push(chunk_key);
+        }
+    }
+
+    let store = &*spilled_chunks.config.store;
+    let codec = &*spilled_chunks.codec;
+    let chunk_shape = spilled_chunks.chunk_shape;
+    let results = map_in_pool(&*pool, chunks_to_reload, |chunk_key| {
+        (
+            chunk_key,
+            read_spilled_chunk(store, codec, chunk_key, chunk_shape),
+        )
+    });
+
+    for (chunk_key, result) in results.into_iter() {
+        match result {
+            Ok(chunk) => {
+                if let Some(chunk) = chunk {
+                    voxel_map
+                        .voxels
+                        .write_chunk(chunk_key, Chunk3::with_array(chunk));
+                }
+                spilled_chunks.spilled.remove(&chunk_key);
+            }
+            Err(e) => spilled_chunks.record_error(chunk_key, e),
+        }
+    }
+}
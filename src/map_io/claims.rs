@@ -0,0 +1,307 @@
+use super::VoxelEditor;
+
+use crate::Voxel;
+
+use building_blocks::prelude::*;
+use fnv::FnvHashMap;
+
+/// Identifies a player, team, or anything else that can own parts of the map.
+pub type OwnerId = u64;
+
+/// Decides whether `editor` may edit a chunk or extent claimed by `owner`. Edits made outside of
+/// `VoxelEditor::with_owner`, and queued edits sent without `VoxelEditSender::with_owner`, have no
+/// editor.
+pub trait ClaimPolicy: Send + Sync {
+    fn allows(&self, editor: Option<OwnerId>, owner: OwnerId) -> bool;
+}
+
+impl<F> ClaimPolicy for F
+where
+    F: Fn(Option<OwnerId>, OwnerId) -> bool + Send + Sync,
+{
+    fn allows(&self, editor: Option<OwnerId>, owner: OwnerId) -> bool {
+        (self)(editor, owner)
+    }
+}
+
+/// The default `ClaimPolicy`. Owners may only edit their own claims. Edits without an editor come
+/// from the game's own systems, like world generation or fluids, so they're allowed everywhere,
+/// unless `reject_ownerless` is set.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct OwnerOnlyPolicy {
+    pub reject_ownerless: bool,
+}
+
+impl OwnerOnlyPolicy {
+    /// Also rejects edits without an editor in every claim.
+    pub fn strict() -> Self {
+        Self {
+            reject_ownerless: true,
+        }
+    }
+}
+
+impl ClaimPolicy for OwnerOnlyPolicy {
+    fn allows(&self, editor: Option<OwnerId>, owner: OwnerId) -> bool {
+        editor.map_or(!self.reject_ownerless, |editor| editor == owner)
+    }
+}
+
+/// Claims on chunks and extents of the map, e.g. for server-side region protection in multiplayer
+/// building games. Enabled with `MapIoPlugin::with_chunk_claims`.
+///
+/// The `VoxelEditor` checks every edit against the claims on all chunks and extents it touches,
+/// and drops the whole edit if the `ClaimPolicy` rejects any of them, sending a `ClaimRejection`
+/// event. Flood fills stop at rejected claims without an event. Edits are attributed to an owner
+/// with `VoxelEditor::with_owner`, or `VoxelEditSender::with_owner` for queued edits; amortized and
+/// generated edits have no owner.
+pub struct ChunkClaims<V> {
+    chunks: FnvHashMap<Point3i, OwnerId>,
+    extents: Vec<(Extent3i, OwnerId)>,
+    policy: Box<dyn ClaimPolicy>,
+    editor: Option<OwnerId>,
+    marker: std::marker::PhantomData<V>,
+}
+
+impl<V> Default for ChunkClaims<V> {
+    fn default() -> Self {
+        Self {
+            chunks: Default::default(),
+            extents: Vec::new(),
+            policy: Box::new(OwnerOnlyPolicy::default()),
+            editor: None,
+            marker: Default::default(),
+        }
+    }
+}
+
+impl<V> ChunkClaims<V> {
+    pub fn set_policy(&mut self, policy: impl ClaimPolicy + 'static) {
+        self.policy = Box::new(policy);
+    }
+
+    /// Gives the chunk at `chunk_key` to `owner`, returning the previous owner, if any.
+    pub fn claim_chunk(&mut self, chunk_key: Point3i, owner: OwnerId) -> Option<OwnerId> {
+        self.chunks.insert(chunk_key, owner)
+    }
+
+    pub fn release_chunk(&mut self, chunk_key: &Point3i) -> Option<OwnerId> {
+        self.chunks.remove(chunk_key)
+    }
+
+    pub fn chunk_owner(&self, chunk_key: &Point3i) -> Option<OwnerId> {
+        self.chunks.get(chunk_key).cloned()
+    }
+
+    /// Gives the voxels in `extent` to `owner`, for claims that aren't aligned to chunks. Extent
+    /// claims may overlap each other and chunk claims, in which case an edit must be allowed by
+    /// all of them.
+    pub fn claim_extent(&mut self, extent: Extent3i, owner: OwnerId) {
+        self.extents.push((extent, owner));
+    }
+
+    /// Removes every extent claim of `owner` that intersects `extent`.
+    pub fn release_extents(&mut self, extent: &Extent3i, owner: OwnerId) {
+        self.extents
+            .retain(|(e, o)| *o != owner || e.intersection(extent).num_points() == 0);
+    }
+
+    /// Removes all chunk and extent claims of `owner`.
+    pub fn release_all(&mut self, owner: OwnerId) {
+        self.chunks.retain(|_, o| *o != owner);
+        self.extents.retain(|(_, o)| *o != owner);
+    }
+
+    pub fn extent_claims(&self) -> impl Iterator<Item = &(Extent3i, OwnerId)> {
+        self.extents.iter()
+    }
+
+    /// The owner that edits are being made as, if any.
+    pub(crate) fn editor(&self) -> Option<OwnerId> {
+        self.editor
+    }
+
+    /// Replaces the owner that edits are being made as, returning the previous one.
+    pub(crate) fn set_editor(&mut self, editor: Option<OwnerId>) -> Option<OwnerId> {
+        std::mem::replace(&mut self.editor, editor)
+    }
+
+    /// The owner of the first claim that rejects an edit of `extent`, which covers the chunks at
+    /// `chunk_keys`.
+    pub(crate) fn rejecting_owner(
+        &self,
+        extent: &Extent3i,
+        mut chunk_keys: impl Iterator<Item = Point3i>,
+    ) -> Option<OwnerId> {
+        let allows = |owner: &OwnerId| self.policy.allows(self.editor, *owner);
+        let chunk_owner = chunk_keys.find_map(|chunk_key| {
+            self.chunks
+                .get(&chunk_key)
+                .filter(|owner| !allows(owner))
+                .cloned()
+        });
+
+        chunk_owner.or_else(|| {
+            self.extents
+                .iter()
+                .find(|(e, owner)| !allows(owner) && e.intersection(extent).num_points() > 0)
+                .map(|(_, owner)| *owner)
+        })
+    }
+}
+
+/// Sent when an edit is dropped because it touches a claim that its editor isn't allowed to edit.
+pub struct ClaimRejection<V> {
+    /// The extent of the attempted edit.
+    pub extent: Extent3i,
+    /// The owner the edit was made as, if any.
+    pub editor: Option<OwnerId>,
+    /// The owner of the claim that rejected the edit.
+    pub owner: OwnerId,
+    marker: std::marker::PhantomData<V>,
+}
+
+impl<V> ClaimRejection<V> {
+    pub(crate) fn new(extent: Extent3i, editor: Option<OwnerId>, owner: OwnerId) -> Self {
+        Self {
+            extent,
+            editor,
+            owner,
+            marker: Default::default(),
+        }
+    }
+}
+
+impl<'a, V> VoxelEditor<'a, V>
+where
+    V: Voxel,
+{
+    /// Makes the edits in `f` as `owner`, e.g. a client whose edits are being applied by the
+    /// server. Without `ChunkClaims`, this is the same as calling `f` directly.
+    pub fn with_owner<T>(&mut self, owner: OwnerId, f: impl FnOnce(&mut Self) -> T) -> T {
+        let previous = self.claims_mut().and_then(|c| c.set_editor(Some(owner)));
+        let result = f(self);
+        if let Some(claims) = self.claims_mut() {
+            claims.set_editor(previous);
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::*;
+
+    const ALICE: OwnerId = 1;
+    const BOB: OwnerId = 2;
+
+    fn unit_extent(p: Point3i) -> Extent3i {
+        Extent3i::from_min_and_shape(p, PointN([1; 3]))
+    }
+
+    #[test]
+    fn owner_only_policy_allows_ownerless_edits_unless_strict() {
+        let policy = OwnerOnlyPolicy::default();
+        assert!(policy.allows(Some(ALICE), ALICE));
+        assert!(!policy.allows(Some(BOB), ALICE));
+        assert!(policy.allows(None, ALICE));
+
+        let strict = OwnerOnlyPolicy::strict();
+        assert!(strict.allows(Some(ALICE), ALICE));
+        assert!(!strict.allows(Some(BOB), ALICE));
+        assert!(!strict.allows(None, ALICE));
+    }
+
+    #[test]
+    fn chunk_claims_reject_other_owners() {
+        let mut claims = ChunkClaims::<TestVoxel>::default();
+        let chunk_key = PointN([16, 0, 0]);
+        let extent = unit_extent(chunk_key);
+        assert_eq!(claims.claim_chunk(chunk_key, ALICE), None);
+        assert_eq!(claims.chunk_owner(&chunk_key), Some(ALICE));
+
+        assert_eq!(
+            claims.rejecting_owner(&extent, std::iter::once(chunk_key)),
+            None
+        );
+        claims.set_editor(Some(ALICE));
+        assert_eq!(
+            claims.rejecting_owner(&extent, std::iter::once(chunk_key)),
+            None
+        );
+        claims.set_editor(Some(BOB));
+        assert_eq!(
+            claims.rejecting_owner(&extent, std::iter::once(chunk_key)),
+            Some(ALICE)
+        );
+        // Unclaimed chunks are open to everyone.
+        let other_key = PointN([0, 0, 0]);
+        assert_eq!(
+            claims.rejecting_owner(&unit_extent(other_key), std::iter::once(other_key)),
+            None
+        );
+
+        assert_eq!(claims.release_chunk(&chunk_key), Some(ALICE));
+        assert_eq!(
+            claims.rejecting_owner(&extent, std::iter::once(chunk_key)),
+            None
+        );
+    }
+
+    #[test]
+    fn extent_claims_reject_intersecting_edits() {
+        let mut claims = ChunkClaims::<TestVoxel>::default();
+        claims.set_policy(OwnerOnlyPolicy::strict());
+        let claimed = Extent3i::from_min_and_shape(PointN([2; 3]), PointN([4; 3]));
+        claims.claim_extent(claimed, ALICE);
+        let chunk_key = PointN([0; 3]);
+
+        assert_eq!(
+            claims.rejecting_owner(&unit_extent(PointN([3; 3])), std::iter::once(chunk_key)),
+            Some(ALICE)
+        );
+        assert_eq!(
+            claims.rejecting_owner(&unit_extent(PointN([1; 3])), std::iter::once(chunk_key)),
+            None
+        );
+        claims.set_editor(Some(ALICE));
+        assert_eq!(
+            claims.rejecting_owner(&unit_extent(PointN([3; 3])), std::iter::once(chunk_key)),
+            None
+        );
+
+        claims.release_extents(&unit_extent(PointN([5; 3])), ALICE);
+        assert_eq!(claims.extent_claims().count(), 0);
+    }
+
+    #[test]
+    fn custom_policies_and_release_all() {
+        let mut claims = ChunkClaims::<TestVoxel>::default();
+        // Anyone may edit the claims of owner 0.
+        claims.set_policy(|editor: Option<OwnerId>, owner: OwnerId| {
+            owner == 0 || editor == Some(owner)
+        });
+        claims.set_editor(Some(BOB));
+        let public_key = PointN([0; 3]);
+        let private_key = PointN([16, 0, 0]);
+        claims.claim_chunk(public_key, 0);
+        claims.claim_chunk(private_key, ALICE);
+        claims.claim_extent(unit_extent(PointN([20, 0, 0])), ALICE);
+
+        let extent = Extent3i::from_min_and_shape(public_key, PointN([32, 16, 16]));
+        assert_eq!(
+            claims.rejecting_owner(&extent, vec![public_key, private_key].into_iter()),
+            Some(ALICE)
+        );
+
+        claims.release_all(ALICE);
+        assert_eq!(claims.chunk_owner(&private_key), None);
+        assert_eq!(claims.extent_claims().count(), 0);
+        assert_eq!(
+            claims.rejecting_owner(&extent, vec![public_key, private_key].into_iter()),
+            None
+        );
+    }
+}
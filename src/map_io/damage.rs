@@ -0,0 +1,206 @@
+use super::{DirtyChunks, EmptyChunks, VoxelEditor, VoxelHardness};
+
+use crate::{Voxel, VoxelReader};
+
+use bevy::prelude::*;
+use building_blocks::prelude::*;
+use fnv::{FnvHashMap, FnvHashSet};
+
+/// Damage dealt to individual voxels, e.g. by mining, stored sparsely per chunk. Enabled with
+/// `MapIoPlugin::with_voxel_damage`, and dealt with `VoxelEditor::damage_voxel`.
+///
+/// Damage decays by `decay_per_second` every frame, so a voxel that's only hit once eventually
+/// heals. It's also forgotten when the voxel changes type or its chunk is removed, as seen in the
+/// previous frame's `DirtyChunks` and `EmptyChunks`.
+pub struct VoxelDamage<V> {
+    pub decay_per_second: f32,
+    voxels: FnvHashMap<Point3i, DamagedVoxel>,
+    chunks: FnvHashMap<Point3i, FnvHashSet<Point3i>>,
+    changed_chunk_keys: FnvHashSet<Point3i>,
+    marker: std::marker::PhantomData<V>,
+}
+
+struct DamagedVoxel {
+    damage: f32,
+    chunk_key: Point3i,
+    // The type that was damaged, so the damage doesn't carry over to a replacement.
+    type_index: usize,
+}
+
+impl<V> VoxelDamage<V> {
+    pub fn new(decay_per_second: f32) -> Self {
+        Self {
+            decay_per_second,
+            voxels: Default::default(),
+            chunks: Default::default(),
+            changed_chunk_keys: Default::default(),
+            marker: Default::default(),
+        }
+    }
+
+    /// The damage of the voxel at `p`, 0 if it's undamaged.
+    pub fn get(&self, p: &Point3i) -> f32 {
+        self.voxels.get(p).map_or(0.0, |v| v.damage)
+    }
+
+    /// The damaged voxels in the chunk at `chunk_key`, e.g. for drawing cracks.
+    pub fn chunk_damage<'b>(
+        &'b self,
+        chunk_key: &Point3i,
+    ) -> impl Iterator<Item = (Point3i, f32)> + 'b {
+        let voxels = &self.voxels;
+
+        self.chunks
+            .get(chunk_key)
+            .into_iter()
+            .flat_map(move |points| points.iter().map(move |p| (*p, voxels[p].damage)))
+    }
+
+    /// The chunks whose damage changed since the start of the frame.
+    pub fn changed_chunk_keys(&self) -> impl Iterator<Item = &Point3i> {
+        self.changed_chunk_keys.iter()
+    }
+
+    pub fn num_damaged_voxels(&self) -> usize {
+        self.voxels.len()
+    }
+
+    /// Adds `amount` to the damage of the voxel at `p`, and returns the total.
+    fn add(&mut self, p: Point3i, chunk_key: Point3i, type_index: usize, amount: f32) -> f32 {
+        let damaged = self.voxels.entry(p).or_insert(DamagedVoxel {
+            damage: 0.0,
+            chunk_key,
+            type_index,
+        });
+        if damaged.type_index != type_index {
+            damaged.damage = 0.0;
+            damaged.type_index = type_index;
+        }
+        damaged.damage += amount;
+        self.chunks.entry(chunk_key).or_default().insert(p);
+        self.changed_chunk_keys.insert(chunk_key);
+
+        damaged.damage
+    }
+
+    fn remove(&mut self, p: &Point3i) {
+        if let Some(damaged) = self.voxels.remove(p) {
+            if let Some(points) = self.chunks.get_mut(&damaged.chunk_key) {
+                points.remove(p);
+                if points.is_empty() {
+                    self.chunks.remove(&damaged.chunk_key);
+                }
+            }
+            self.changed_chunk_keys.insert(damaged.chunk_key);
+        }
+    }
+
+    fn remove_chunk(&mut self, chunk_key: &Point3i) {
+        if let Some(points) = self.chunks.remove(chunk_key) {
+            for p in points.iter() {
+                self.voxels.remove(p);
+            }
+            self.changed_chunk_keys.insert(*chunk_key);
+        }
+    }
+}
+
+impl<'a, V> VoxelEditor<'a, V>
+where
+    V: Voxel,
+    V::TypeInfo: VoxelHardness,
+    for<'r> &'r V::TypeInfo: IsEmpty,
+{
+    /// Adds `amount` of damage to the voxel at `p`. Once its total damage exceeds the hardness of
+    /// its type, the voxel is replaced with `V::default()` through the normal edit path, marking its
+    /// chunk and the neighbors as dirty. Returns the original value of the destroyed voxel, if any.
+    ///
+    /// Empty voxels and voxels outside the `WorldBounds` can't be damaged. Without
+    /// `MapIoPlugin::with_voxel_damage`, damage doesn't accumulate, so a voxel is only destroyed if
+    /// `amount` alone exceeds its hardness.
+    pub fn damage_voxel(&mut self, p: Point3i, amount: f32) -> Option<V> {
+        if !self.point_in_bounds(&p) {
+            return None;
+        }
+
+        let chunk_key = self.map.voxels.indexer.chunk_key_containing_point(&p);
+        let voxel = self.current_voxel(p);
+        let info = self.map.palette.get_voxel_type_info(voxel);
+        if info.is_empty() {
+            return None;
+        }
+        let hardness = info.hardness();
+
+        let type_index = voxel.get_type_index();
+        let total = match self.voxel_damage_mut() {
+            Some(damage) => damage.add(p, chunk_key, type_index, amount),
+            None => amount,
+        };
+        if total <= hardness {
+            return None;
+        }
+
+        if let Some(damage) = self.voxel_damage_mut() {
+            damage.remove(&p);
+        }
+        self.edit_extent_and_touch_neighbors(
+            Extent3i::from_min_and_shape(p, PointN([1; 3])),
+            |_p: Point3i, voxel: &mut V| *voxel = V::default(),
+        );
+
+        Some(voxel)
+    }
+}
+
+/// Decays damage, and forgets the damage of voxels that changed type or were removed. This runs
+/// before the UPDATE stage, where damage is dealt.
+pub fn voxel_damage_system<V>(
+    time: Res<Time>,
+    dirty_chunks: Res<DirtyChunks<V>>,
+    empty_chunks: Res<EmptyChunks<V>>,
+    voxel_reader: VoxelReader<V>,
+    mut damage: ResMut<VoxelDamage<V>>,
+) where
+    V: Voxel,
+{
+    let damage = &mut *damage;
+
+    damage.changed_chunk_keys.clear();
+
+    for chunk_key in empty_chunks.removed_chunk_keys() {
+        damage.remove_chunk(chunk_key);
+    }
+    let mut replaced = Vec::new();
+    voxel_reader.read(|reader| {
+        for chunk_key in dirty_chunks.edited_chunk_keys.iter() {
+            if let Some(points) = damage.chunks.get(chunk_key) {
+                for p in points.iter() {
+                    if reader.get(p).get_type_index() != damage.voxels[p].type_index {
+                        replaced.push(*p);
+                    }
+                }
+            }
+        }
+    });
+    for p in replaced.iter() {
+        damage.remove(p);
+    }
+
+    let decay = damage.decay_per_second * time.delta_seconds();
+    if decay <= 0.0 {
+        return;
+    }
+    let mut healed = Vec::new();
+    for (p, damaged) in damage.voxels.iter_mut() {
+        damaged.damage -= decay;
+        if damaged.damage <= 0.0 {
+            healed.push(*p);
+        }
+    }
+    damage
+        .changed_chunk_keys
+        .extend(damage.chunks.keys().cloned());
+    for p in healed.iter() {
+        damage.remove(p);
+    }
+}
@@ -1,11 +1,17 @@
+use super::{
+    chunk_cache_flusher::flush_local_caches, ChunkCacheStats, ChunkRemoved, EmptyChunks,
+    MapIoFrameStats, MapIoPause, MergeHooks, SpilledChunks, ThreadLocalVoxelCache,
+};
+
 use crate::{
     map::{default_array, empty_chunk_hash_map},
-    Voxel, VoxelMap,
+    MapVersions, SharedChunk, Voxel, VoxelMap,
 };
 
 use bevy::prelude::*;
 use building_blocks::prelude::*;
-use fnv::FnvHashSet;
+use fnv::{FnvHashMap, FnvHashSet};
+use std::io;
 
 /// For the sake of pipelining, all voxels edits are first written out of place here. They can later be merged into another
 /// chunk map by overwriting the dirty chunks.
@@ -16,31 +22,158 @@ where
     edited_voxels: ChunkHashMap3<V>,
     // Includes the edited chunks as well as their neighbors, all of which need to be re-meshed.
     dirty_chunk_keys: FnvHashSet<Point3i>,
+    chunk_edits: FnvHashMap<Point3i, ChunkEdits>,
+    track_type_changes: bool,
+    // Whether each voxel type index is empty, if occupancy changes are being counted.
+    empty_types: Option<Vec<bool>>,
+    count_type_deltas: bool,
+    num_voxels_edited: usize,
+    // Whether merges keep the chunks they replace in `DirtyChunks::replaced_chunks`.
+    keep_replaced_chunks: bool,
+    // The result of a mid-frame merge, if there was one this frame.
+    earlier_merge: Option<DirtyChunks<V>>,
 }
 
 impl<V> EditBuffer<V>
 where
     V: Voxel,
 {
-    pub fn new(chunk_shape: Point3i) -> Self {
+    /// If `track_type_changes`, then every voxel whose type index is changed by an edit will be
+    /// recorded in `ChunkEdits::type_changes`.
+    pub fn new(chunk_shape: Point3i, track_type_changes: bool) -> Self {
         Self {
             edited_voxels: empty_chunk_hash_map(chunk_shape),
             dirty_chunk_keys: Default::default(),
+            chunk_edits: Default::default(),
+            track_type_changes,
+            empty_types: None,
+            count_type_deltas: false,
+            num_voxels_edited: 0,
+            keep_replaced_chunks: false,
+            earlier_merge: None,
         }
     }
 
+    pub fn tracks_type_changes(&self) -> bool {
+        self.track_type_changes
+    }
+
+    /// Takes the edits made so far to merge them, leaving an empty buffer with the same settings.
+    fn take_edits(&mut self) -> EditBuffer<V> {
+        let next = EditBuffer {
+            empty_types: self.empty_types.take(),
+            count_type_deltas: self.count_type_deltas,
+            keep_replaced_chunks: self.keep_replaced_chunks,
+            ..EditBuffer::new(
+                self.edited_voxels.indexer.chunk_shape(),
+                self.track_type_changes,
+            )
+        };
+
+        std::mem::replace(self, next)
+    }
+
+    /// Sets whether merges move the chunks they replace into `DirtyChunks`, so they can be saved
+    /// without copying them.
+    pub(crate) fn keep_replaced_chunks(&mut self, keep: bool) {
+        self.keep_replaced_chunks = keep;
+    }
+
+    /// Starts counting `ChunkEdits::occupancy_delta`, where `empty_types[i]` says whether voxels of
+    /// type index `i` are empty. Types beyond the end of `empty_types` count as occupied.
+    pub(crate) fn count_occupancy_changes(&mut self, empty_types: Vec<bool>) {
+        self.empty_types = Some(empty_types);
+    }
+
+    /// Starts counting `ChunkEdits::type_deltas`.
+    pub(crate) fn count_type_deltas(&mut self) {
+        self.count_type_deltas = true;
+    }
+
+    /// Whether edits need to compare each voxel with the one it replaces.
+    pub(crate) fn compares_old_voxels(&self) -> bool {
+        self.track_type_changes || self.empty_types.is_some() || self.count_type_deltas
+    }
+
+    /// The number of voxels covered by all edits so far, counting overlapping edits multiple times.
+    pub fn num_voxels_edited(&self) -> usize {
+        self.num_voxels_edited
+    }
+
+    /// The keys of all chunks that have been written to the backbuffer so far.
+    pub fn edited_chunk_keys(&self) -> impl Iterator<Item = &Point3i> {
+        self.edited_voxels.storage().keys()
+    }
+
+    /// The voxel at `p`, if its chunk has already been copied into the backbuffer.
+    pub fn get_edited_voxel(&self, p: Point3i) -> Option<V> {
+        let chunk_key = self.edited_voxels.indexer.chunk_key_containing_point(&p);
+
+        self.edited_voxels
+            .get_chunk(chunk_key)
+            .map(|chunk| chunk.array.get(&p))
+    }
+
+    pub(crate) fn contains_chunk(&self, chunk_key: &Point3i) -> bool {
+        self.edited_voxels.get_chunk(*chunk_key).is_some()
+    }
+
+    pub(crate) fn edited_chunk(&self, chunk_key: Point3i) -> Option<&Array3<V>> {
+        self.edited_voxels
+            .get_chunk(chunk_key)
+            .map(|chunk| &chunk.array)
+    }
+
+    /// Copies `chunk` into the backbuffer as the current contents of the chunk at `chunk_key`,
+    /// unless the chunk is already there. This doesn't count as an edit.
+    ///
+    /// Fails if `chunk` doesn't cover exactly the extent of the chunk at `chunk_key`, since it would
+    /// otherwise be merged over the wrong voxels.
+    pub(crate) fn insert_unedited_chunk(
+        &mut self,
+        chunk_key: Point3i,
+        chunk: Array3<V>,
+    ) -> io::Result<()> {
+        let expected_extent = self
+            .edited_voxels
+            .indexer
+            .extent_for_chunk_at_key(chunk_key);
+        let extent = *chunk.extent();
+        if extent != expected_extent {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "chunk extent at {:?} with shape {:?} doesn't match the extent of chunk {:?}",
+                    extent.minimum.0, extent.shape.0, chunk_key.0
+                ),
+            ));
+        }
+        self.edited_voxels
+            .get_mut_chunk_or_insert_with(chunk_key, || Chunk3::with_array(chunk));
+
+        Ok(())
+    }
+
+    /// Drops any edits to the chunk at `chunk_key`, so it won't be written into the map. The chunk
+    /// and its neighbors stay dirty.
+    pub fn discard_chunk(&mut self, chunk_key: Point3i) {
+        self.edited_voxels.storage_mut().remove(&chunk_key);
+        self.chunk_edits.remove(&chunk_key);
+    }
+
     /// This function does read-modify-write of the voxels in `extent`. If a chunk is missing from the backbuffer, it will be
     /// copied from the `reader` before being written.
     ///
-    /// If `touch_neighbors`, then all chunks in the Moore Neighborhood of any edited chunk will be marked as dirty. This is
-    /// useful when there are dependencies between adjacent chunks that must be considered during post-processing (e.g. during
-    /// mesh generation).
+    /// The chunks around each edited chunk are marked as dirty according to `neighbors`, which is
+    /// `NeighborDirtying::Moore` for `true` and `NeighborDirtying::EditedOnly` for `false`. This is
+    /// useful when there are dependencies between adjacent chunks that must be considered during
+    /// post-processing (e.g. during mesh generation).
     pub fn edit_voxels_out_of_place(
         &mut self,
         reader: &CompressibleChunkMapReader3<V>,
         extent: Extent3i,
         edit_func: impl FnMut(Point3i, &mut V),
-        touch_neighbors: bool,
+        neighbors: impl Into<NeighborDirtying>,
     ) {
         debug_assert!(reader
             .indexer
@@ -65,81 +198,868 @@ where
                 });
         }
 
-        self.dirty_chunks_for_extent(touch_neighbors, extent);
+        self.dirty_chunks_for_extent(neighbors.into(), extent);
+        self.record_edited_extent(extent);
+        self.num_voxels_edited += extent.num_points();
 
         // Edit the backbuffer.
-        self.edited_voxels.for_each_mut(&extent, edit_func);
+        if self.compares_old_voxels() {
+            let indexer = self.edited_voxels.indexer.clone();
+            let changes = VoxelChanges {
+                track_type_changes: self.track_type_changes,
+                count_type_deltas: self.count_type_deltas,
+                empty_types: self.empty_types.as_deref(),
+            };
+            let chunk_edits = &mut self.chunk_edits;
+            let mut edit_func = edit_func;
+            self.edited_voxels
+                .for_each_mut(&extent, |p: Point3i, voxel: &mut V| {
+                    let old_voxel = *voxel;
+                    edit_func(p, voxel);
+                    if old_voxel.get_type_index() == voxel.get_type_index() {
+                        return;
+                    }
+                    let edits = chunk_edits
+                        .entry(indexer.chunk_key_containing_point(&p))
+                        .or_default();
+                    changes.record(edits, p, old_voxel, *voxel);
+                });
+        } else {
+            self.edited_voxels.for_each_mut(&extent, edit_func);
+        }
+    }
+
+    /// Like `edit_voxels_out_of_place`, but every voxel in `extent` is overwritten with the value
+    /// returned by `write_func`, so the chunks entirely covered by `extent` aren't copied from the
+    /// `reader` first.
+    pub fn overwrite_voxels_out_of_place(
+        &mut self,
+        reader: &CompressibleChunkMapReader3<V>,
+        extent: Extent3i,
+        mut write_func: impl FnMut(Point3i) -> V,
+        neighbors: impl Into<NeighborDirtying>,
+    ) {
+        let neighbors = neighbors.into();
+        let covered = self.covered_chunk_keys(&extent);
+        for &chunk_key in covered.iter() {
+            let chunk_extent = self
+                .edited_voxels
+                .indexer
+                .extent_for_chunk_at_key(chunk_key);
+            let mut chunk = default_array(chunk_extent);
+            chunk.for_each_mut(&chunk_extent, |p: Point3i, voxel: &mut V| {
+                *voxel = write_func(p)
+            });
+            self.write_covered_chunk(reader, chunk_key, chunk, neighbors);
+        }
+
+        for partial_extent in self.partial_extents(&extent, &covered).into_iter() {
+            self.edit_voxels_out_of_place(
+                reader,
+                partial_extent,
+                |p: Point3i, voxel: &mut V| *voxel = write_func(p),
+                neighbors,
+            );
+        }
     }
 
-    pub fn insert_chunk(&mut self, touch_neighbors: bool, chunk_key: Point3i, chunk: Array3<V>) {
-        // PERF: this could be more efficient if we just took the moore neighborhood in chunk space
+    /// Sets every voxel in `extent` to `value`. Chunks entirely covered by `extent` are written as
+    /// whole arrays, without copying them from the `reader` or calling a closure per voxel.
+    pub fn fill_extent(
+        &mut self,
+        reader: &CompressibleChunkMapReader3<V>,
+        extent: Extent3i,
+        value: V,
+        neighbors: impl Into<NeighborDirtying>,
+    ) {
+        let neighbors = neighbors.into();
+        let covered = self.covered_chunk_keys(&extent);
+        for &chunk_key in covered.iter() {
+            let chunk_extent = self
+                .edited_voxels
+                .indexer
+                .extent_for_chunk_at_key(chunk_key);
+            let chunk = Array3::fill(chunk_extent, value);
+            self.write_covered_chunk(reader, chunk_key, chunk, neighbors);
+        }
+
+        for partial_extent in self.partial_extents(&extent, &covered).into_iter() {
+            self.edit_voxels_out_of_place(
+                reader,
+                partial_extent,
+                |_p: Point3i, voxel: &mut V| *voxel = value,
+                neighbors,
+            );
+        }
+    }
+
+    pub fn insert_chunk(
+        &mut self,
+        neighbors: impl Into<NeighborDirtying>,
+        chunk_key: Point3i,
+        chunk: Array3<V>,
+    ) {
+        let chunk_shape = self.edited_voxels.indexer.chunk_shape();
+        let dirty_chunk_keys = &mut self.dirty_chunk_keys;
+        neighbors
+            .into()
+            .for_each_dirty_chunk_key(chunk_shape, chunk_key, |k| {
+                dirty_chunk_keys.insert(k);
+            });
+        self.write_inserted_chunk(chunk_key, chunk);
+    }
+
+    /// Like `insert_chunk` for a whole batch of chunks, e.g. freshly generated terrain. Space for
+    /// the batch is reserved up front, and neighbors shared by several chunks of the batch are only
+    /// marked as dirty once.
+    pub fn insert_chunks(
+        &mut self,
+        neighbors: impl Into<NeighborDirtying>,
+        chunks: impl IntoIterator<Item = (Point3i, Array3<V>)>,
+    ) {
+        let neighbors = neighbors.into();
+        let chunks: Vec<(Point3i, Array3<V>)> = chunks.into_iter().collect();
+        if chunks.is_empty() {
+            return;
+        }
+        self.edited_voxels.storage_mut().reserve(chunks.len());
+        self.chunk_edits.reserve(chunks.len());
+        self.dirty_chunk_keys.reserve(chunks.len());
+
+        let batch_keys: FnvHashSet<Point3i> =
+            chunks.iter().map(|(chunk_key, _)| *chunk_key).collect();
+        if neighbors != NeighborDirtying::EditedOnly {
+            let chunk_shape = self.edited_voxels.indexer.chunk_shape();
+            let dirty_chunk_keys = &mut self.dirty_chunk_keys;
+            for &chunk_key in batch_keys.iter() {
+                neighbors.for_each_dirty_chunk_key(chunk_shape, chunk_key, |neighbor| {
+                    // Neighbors in the batch are marked as themselves.
+                    if !batch_keys.contains(&neighbor) {
+                        dirty_chunk_keys.insert(neighbor);
+                    }
+                });
+            }
+        }
+        self.dirty_chunk_keys.extend(batch_keys);
+
+        for (chunk_key, chunk) in chunks.into_iter() {
+            self.write_inserted_chunk(chunk_key, chunk);
+        }
+    }
+
+    // Replaces the chunk at `chunk_key` with `chunk`. The caller marks the dirty chunks.
+    fn write_inserted_chunk(&mut self, chunk_key: Point3i, chunk: Array3<V>) {
         let extent = self
             .edited_voxels
             .indexer
             .extent_for_chunk_at_key(chunk_key);
-        self.dirty_chunks_for_extent(touch_neighbors, extent);
+        self.num_voxels_edited += extent.num_points();
+        self.chunk_edits.insert(
+            chunk_key,
+            ChunkEdits {
+                extents: vec![extent],
+                replaced: true,
+                type_changes: Vec::new(),
+                occupancy_delta: 0,
+                type_deltas: Default::default(),
+            },
+        );
         self.edited_voxels
             .write_chunk(chunk_key, Chunk3::with_array(chunk));
     }
 
     /// Write all of the edited chunks into `dst_map`. Returns the dirty chunks.
-    pub fn merge_edits(self, dst_map: &mut CompressibleChunkMap3<V>) -> DirtyChunks {
+    pub fn merge_edits(self, dst_map: &mut CompressibleChunkMap3<V>) -> DirtyChunks<V> {
         let EditBuffer {
             edited_voxels,
             dirty_chunk_keys,
+            chunk_edits,
+            keep_replaced_chunks,
+            earlier_merge,
+            ..
         } = self;
 
         let chunk_storage = edited_voxels.take_storage();
         let edited_chunk_keys = chunk_storage.chunk_keys().cloned().collect();
 
+        let mut replaced_chunks = FnvHashMap::default();
         for (chunk_key, chunk) in chunk_storage.into_iter() {
+            if keep_replaced_chunks {
+                // The chunk is about to be overwritten, so take it instead of copying it.
+                let replaced = dst_map.storage_mut().remove(chunk_key);
+                replaced_chunks.insert(
+                    chunk_key,
+                    replaced.map(|c| SharedChunk::from(c.as_decompressed().array)),
+                );
+            }
             dst_map.write_chunk(chunk_key, chunk);
         }
 
-        DirtyChunks {
+        let dirty_chunks = DirtyChunks {
             edited_chunk_keys,
             dirty_chunk_keys,
+            chunk_edits,
+            replaced_chunks,
+            marker: Default::default(),
+        };
+
+        match earlier_merge {
+            Some(mut earlier) => {
+                earlier.extend(dirty_chunks);
+
+                earlier
+            }
+            None => dirty_chunks,
         }
     }
 
-    fn dirty_chunks_for_extent(&mut self, touch_neighbors: bool, extent: Extent3i) {
-        // Mark the chunks and maybe their neighbors as dirty.
-        let dirty_extent = if touch_neighbors {
-            let chunk_shape = self.edited_voxels.indexer.chunk_shape();
+    /// The keys of the chunks that are entirely covered by `extent`, and can be overwritten without
+    /// copying them first.
+    fn covered_chunk_keys(&self, extent: &Extent3i) -> Vec<Point3i> {
+        let indexer = &self.edited_voxels.indexer;
 
-            Extent3i::from_min_and_max(extent.minimum - chunk_shape, extent.max() + chunk_shape)
-        } else {
-            extent
+        indexer
+            .chunk_keys_for_extent(extent)
+            .filter(|chunk_key| {
+                let chunk_extent = indexer.extent_for_chunk_at_key(*chunk_key);
+
+                extent.intersection(&chunk_extent) == chunk_extent
+            })
+            .collect()
+    }
+
+    /// The parts of `extent` in the chunks that aren't `covered` by it.
+    fn partial_extents(&self, extent: &Extent3i, covered: &[Point3i]) -> Vec<Extent3i> {
+        let indexer = &self.edited_voxels.indexer;
+
+        indexer
+            .chunk_keys_for_extent(extent)
+            .filter(|chunk_key| !covered.contains(chunk_key))
+            .map(|chunk_key| extent.intersection(&indexer.extent_for_chunk_at_key(chunk_key)))
+            .collect()
+    }
+
+    /// Writes `chunk` over the whole chunk at `chunk_key` as an edit. If edits compare old voxels,
+    /// the changes are counted against the chunk it replaces, from the backbuffer or else the
+    /// `reader`.
+    fn write_covered_chunk(
+        &mut self,
+        reader: &CompressibleChunkMapReader3<V>,
+        chunk_key: Point3i,
+        chunk: Array3<V>,
+        neighbors: NeighborDirtying,
+    ) {
+        let chunk_extent = *chunk.extent();
+        if self.compares_old_voxels() {
+            let old_chunk = match self.edited_voxels.storage_mut().remove(&chunk_key) {
+                Some(old_chunk) => Some(old_chunk.array),
+                None => reader
+                    .storage()
+                    .storage
+                    .copy_without_caching(chunk_key)
+                    .map(|c| c.as_decompressed().array),
+            };
+            let changes = VoxelChanges {
+                track_type_changes: self.track_type_changes,
+                count_type_deltas: self.count_type_deltas,
+                empty_types: self.empty_types.as_deref(),
+            };
+            let edits = self.chunk_edits.entry(chunk_key).or_default();
+            chunk.for_each(&chunk_extent, |p: Point3i, voxel: V| {
+                let old_voxel = old_chunk.as_ref().map_or(V::default(), |c| c.get(&p));
+                changes.record(edits, p, old_voxel, voxel);
+            });
+        }
+        self.edited_voxels
+            .write_chunk(chunk_key, Chunk3::with_array(chunk));
+        self.dirty_chunks_for_extent(neighbors, chunk_extent);
+        self.record_edited_extent(chunk_extent);
+        self.num_voxels_edited += chunk_extent.num_points();
+    }
+
+    fn record_edited_extent(&mut self, extent: Extent3i) {
+        for chunk_key in self.edited_voxels.indexer.chunk_keys_for_extent(&extent) {
+            let chunk_extent = self
+                .edited_voxels
+                .indexer
+                .extent_for_chunk_at_key(chunk_key);
+            let edits = self.chunk_edits.entry(chunk_key).or_default();
+            if !edits.replaced {
+                edits.extents.push(extent.intersection(&chunk_extent));
+            }
+        }
+    }
+
+    fn dirty_chunks_for_extent(&mut self, neighbors: NeighborDirtying, extent: Extent3i) {
+        let indexer = &self.edited_voxels.indexer;
+        let chunk_shape = indexer.chunk_shape();
+        let radius = match neighbors {
+            NeighborDirtying::EditedOnly => 0,
+            NeighborDirtying::Moore => 1,
+            NeighborDirtying::Radius(radius) => radius.max(0),
+            NeighborDirtying::Faces => {
+                let dirty_chunk_keys = &mut self.dirty_chunk_keys;
+                for chunk_key in indexer.chunk_keys_for_extent(&extent) {
+                    neighbors.for_each_dirty_chunk_key(chunk_shape, chunk_key, |k| {
+                        dirty_chunk_keys.insert(k);
+                    });
+                }
+                return;
+            }
         };
-        for chunk_key in self
-            .edited_voxels
-            .indexer
-            .chunk_keys_for_extent(&dirty_extent)
-        {
+        // Cubic neighborhoods of all chunks in the extent are covered by a single padded extent.
+        let padding = chunk_shape * PointN([radius; 3]);
+        let dirty_extent =
+            Extent3i::from_min_and_max(extent.minimum - padding, extent.max() + padding);
+        for chunk_key in indexer.chunk_keys_for_extent(&dirty_extent) {
             self.dirty_chunk_keys.insert(chunk_key);
         }
     }
 }
 
+/// Counts the voxel changes that the edit buffer was configured to track.
+struct VoxelChanges<'a> {
+    track_type_changes: bool,
+    count_type_deltas: bool,
+    empty_types: Option<&'a [bool]>,
+}
+
+impl<'a> VoxelChanges<'a> {
+    fn record<V>(&self, edits: &mut ChunkEdits, p: Point3i, old_voxel: V, new_voxel: V)
+    where
+        V: Voxel,
+    {
+        let old_type = old_voxel.get_type_index();
+        let new_type = new_voxel.get_type_index();
+        if new_type == old_type {
+            return;
+        }
+        if self.track_type_changes {
+            edits.type_changes.push(p);
+        }
+        if let Some(empty_types) = self.empty_types {
+            let is_empty = |t: usize| empty_types.get(t).cloned().unwrap_or(false);
+            match (is_empty(old_type), is_empty(new_type)) {
+                (true, false) => edits.occupancy_delta += 1,
+                (false, true) => edits.occupancy_delta -= 1,
+                _ => (),
+            }
+        }
+        if self.count_type_deltas {
+            *edits.type_deltas.entry(old_type).or_insert(0) -= 1;
+            *edits.type_deltas.entry(new_type).or_insert(0) += 1;
+        }
+    }
+}
+
+/// Which chunks around an edited chunk are marked as dirty along with it, for consumers of
+/// `DirtyChunks` that depend on neighboring chunks. Picking the smallest neighborhood a consumer
+/// needs avoids invalidating more chunks than necessary.
+///
+/// `true` and `false` convert to `Moore` and `EditedOnly`, which are what the
+/// `*_and_touch_neighbors` methods of the `VoxelEditor` and their counterparts use.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum NeighborDirtying {
+    /// Only the edited chunks.
+    EditedOnly,
+    /// The 6 chunks sharing a face with an edited chunk, e.g. for meshers that only look at the
+    /// voxels across each face.
+    Faces,
+    /// The 26 chunks sharing a face, edge, or corner with an edited chunk.
+    Moore,
+    /// Every chunk within this many chunks of an edited chunk along each axis, e.g. 2 for lighting
+    /// that spreads farther than a chunk. `Radius(1)` is the same as `Moore`.
+    Radius(i32),
+}
+
+impl From<bool> for NeighborDirtying {
+    fn from(touch_neighbors: bool) -> Self {
+        if touch_neighbors {
+            NeighborDirtying::Moore
+        } else {
+            NeighborDirtying::EditedOnly
+        }
+    }
+}
+
+impl NeighborDirtying {
+    /// Calls `f` with the key of every chunk dirtied by an edit of the chunk at `chunk_key`,
+    /// including that chunk.
+    fn for_each_dirty_chunk_key(
+        self,
+        chunk_shape: Point3i,
+        chunk_key: Point3i,
+        mut f: impl FnMut(Point3i),
+    ) {
+        let radius = match self {
+            NeighborDirtying::EditedOnly => 0,
+            NeighborDirtying::Faces => {
+                f(chunk_key);
+                for offset in FACE_OFFSETS.iter() {
+                    f(chunk_key + chunk_shape * *offset);
+                }
+                return;
+            }
+            NeighborDirtying::Moore => 1,
+            NeighborDirtying::Radius(radius) => radius.max(0),
+        };
+        for z in -radius..=radius {
+            for y in -radius..=radius {
+                for x in -radius..=radius {
+                    f(chunk_key + chunk_shape * PointN([x, y, z]));
+                }
+            }
+        }
+    }
+}
+
+pub(crate) const FACE_OFFSETS: [Point3i; 6] = [
+    PointN([-1, 0, 0]),
+    PointN([1, 0, 0]),
+    PointN([0, -1, 0]),
+    PointN([0, 1, 0]),
+    PointN([0, 0, -1]),
+    PointN([0, 0, 1]),
+];
+
 /// The sets of chunk keys that have either been edited directly or marked as dirty, by virtue of neighboring an edited chunk.
 #[derive(Default)]
-pub struct DirtyChunks {
+pub struct DirtyChunks<V> {
     pub edited_chunk_keys: Vec<Point3i>,
     pub dirty_chunk_keys: FnvHashSet<Point3i>,
+    /// What exactly was edited in each of the `edited_chunk_keys`, so consumers can do minimal updates.
+    pub chunk_edits: FnvHashMap<Point3i, ChunkEdits>,
+    // The contents of the edited chunks from before the frame's first merge that kept them, or
+    // `None` for chunks that didn't exist.
+    pub(crate) replaced_chunks: FnvHashMap<Point3i, Option<SharedChunk<V>>>,
+    marker: std::marker::PhantomData<V>,
+}
+
+impl<V> DirtyChunks<V> {
+    /// The contents of the edited chunk at `chunk_key` from before this frame's edits, if the merge
+    /// kept it. Merges only keep the chunks they replace while the `MapVersions` hold a version.
+    pub fn replaced_chunk(&self, chunk_key: &Point3i) -> Option<&SharedChunk<V>> {
+        self.replaced_chunks
+            .get(chunk_key)
+            .and_then(|chunk| chunk.as_ref())
+    }
+
+    /// Adds the chunks from a later merge in the same frame.
+    fn extend(&mut self, later: DirtyChunks<V>) {
+        let DirtyChunks {
+            edited_chunk_keys,
+            dirty_chunk_keys,
+            chunk_edits,
+            replaced_chunks,
+            ..
+        } = later;
+
+        // An earlier merge already replaced what was there at the start of the frame.
+        for (chunk_key, replaced) in replaced_chunks.into_iter() {
+            self.replaced_chunks.entry(chunk_key).or_insert(replaced);
+        }
+        for chunk_key in edited_chunk_keys.into_iter() {
+            if !self.chunk_edits.contains_key(&chunk_key) {
+                self.edited_chunk_keys.push(chunk_key);
+            }
+        }
+        self.dirty_chunk_keys.extend(dirty_chunk_keys);
+        for (chunk_key, later_edits) in chunk_edits.into_iter() {
+            self.chunk_edits
+                .entry(chunk_key)
+                .or_default()
+                .extend(later_edits);
+        }
+    }
+}
+
+/// The parts of a single chunk that were edited during one frame.
+#[derive(Clone, Debug, Default)]
+pub struct ChunkEdits {
+    /// Every edited extent, clipped to the chunk. These may overlap.
+    pub extents: Vec<Extent3i>,
+    /// `true` if the whole chunk was replaced with `insert_chunk`. Type changes made by the
+    /// replacement itself are not tracked.
+    pub replaced: bool,
+    /// The points whose voxel type index changed. Only recorded if the `MapIoPlugin` was configured
+    /// to track type changes.
+    pub type_changes: Vec<Point3i>,
+    /// The change in the number of non-empty voxels, not counting a replacement. Only counted when
+    /// the `ChunkOccupancyCountsPlugin` is added.
+    pub occupancy_delta: i64,
+    /// The change in the number of voxels of each type index, not counting a replacement. Only
+    /// counted when the `MapStatsPlugin` is added.
+    pub type_deltas: FnvHashMap<usize, i64>,
+}
+
+impl ChunkEdits {
+    /// Adds the edits made to the same chunk after these ones.
+    pub(crate) fn extend(&mut self, later: ChunkEdits) {
+        if later.replaced {
+            *self = later;
+        } else {
+            if !self.replaced {
+                self.extents.extend(later.extents);
+            }
+            self.type_changes.extend(later.type_changes);
+            self.occupancy_delta += later.occupancy_delta;
+            for (type_index, delta) in later.type_deltas.into_iter() {
+                *self.type_deltas.entry(type_index).or_insert(0) += delta;
+            }
+        }
+    }
 }
 
 /// Merges edits from the `EditBuffer` into the `VoxelMap`. By setting the `DirtyChunks` resource, the `chunk_processor_system`
 /// will be notified to process dirty chunks on the next frame.
+#[allow(clippy::too_many_arguments)]
 pub fn double_buffering_system<V>(
     mut voxel_map: ResMut<VoxelMap<V>>,
     mut edit_buffer: ResMut<EditBuffer<V>>,
-    mut dirty_chunks: ResMut<DirtyChunks>,
+    mut dirty_chunks: ResMut<DirtyChunks<V>>,
+    mut empty_chunks: ResMut<EmptyChunks<V>>,
+    mut removed_events: ResMut<Events<ChunkRemoved<V>>>,
+    mut frame_stats: ResMut<MapIoFrameStats<V>>,
+    mut merge_hooks: ResMut<MergeHooks<V>>,
+    mut versions: Option<ResMut<MapVersions<V>>>,
+    mut spilled_chunks: Option<ResMut<SpilledChunks<V>>>,
+    pause: Res<MapIoPause<V>>,
+) where
+    V: Voxel,
+{
+    // Keep the edits for the first frame after resuming.
+    if pause.is_paused() {
+        *dirty_chunks = DirtyChunks::default();
+        frame_stats.edited_voxels = 0;
+        frame_stats.edited_chunks = 0;
+        frame_stats.dirty_chunks = 0;
+        return;
+    }
+
+    let mut edit_buffer = edit_buffer.take_edits();
+    merge_hooks.run_pre_merge(&mut edit_buffer, &*voxel_map);
+    // Versions need the chunks from before the merge, including the edits made by the hooks.
+    if let Some(versions) = versions.as_mut() {
+        versions.prepare_merge(
+            &mut edit_buffer,
+            &mut *voxel_map,
+            &mut *empty_chunks,
+            spilled_chunks.as_deref_mut(),
+        );
+    }
+    frame_stats.edited_voxels = edit_buffer.num_voxels_edited();
+    // Chunks were removed before this merge, so only the chunks it writes can bring them back.
+    let rewritten: FnvHashSet<Point3i> = edit_buffer.edited_chunk_keys().cloned().collect();
+    *dirty_chunks = merge_with_post_hooks(edit_buffer, &mut voxel_map, &mut merge_hooks);
+    empty_chunks.reconcile_with_merge(&rewritten, &mut dirty_chunks);
+    if let Some(versions) = versions.as_mut() {
+        versions.absorb_merge(&dirty_chunks);
+    }
+    for chunk_key in empty_chunks.removed_chunk_keys() {
+        removed_events.send(ChunkRemoved::new(*chunk_key));
+    }
+    for chunk_key in dirty_chunks.edited_chunk_keys.iter() {
+        voxel_map.mark_unsaved(*chunk_key);
+    }
+    frame_stats.edited_chunks = dirty_chunks.edited_chunk_keys.len();
+    frame_stats.dirty_chunks = dirty_chunks.dirty_chunk_keys.len();
+}
+
+/// Merges `edit_buffer` into the map and runs the post-merge hooks with the `DirtyChunks` of this
+/// merge alone, before combining them with any earlier merge from the same frame.
+fn merge_with_post_hooks<V>(
+    mut edit_buffer: EditBuffer<V>,
+    voxel_map: &mut VoxelMap<V>,
+    merge_hooks: &mut MergeHooks<V>,
+) -> DirtyChunks<V>
+where
+    V: Voxel,
+{
+    let earlier_merge = edit_buffer.earlier_merge.take();
+    let dirty_chunks = edit_buffer.merge_edits(&mut voxel_map.voxels);
+    // The merged chunks replace any that were collapsed.
+    for chunk_key in dirty_chunks.edited_chunk_keys.iter() {
+        voxel_map.uniform_chunks.forget_chunk(chunk_key);
+    }
+    merge_hooks.run_post_merge(&dirty_chunks, voxel_map);
+
+    match earlier_merge {
+        Some(mut earlier) => {
+            earlier.extend(dirty_chunks);
+
+            earlier
+        }
+        None => dirty_chunks,
+    }
+}
+
+/// Merges the edits made so far this frame into the `VoxelMap`, so readers in later stages can see
+/// them. Added by `MapIoPlugin::with_mid_frame_merge`.
+///
+/// The `DirtyChunks` from this merge are held back and combined with the end-of-frame merge, so
+/// consumers still see all of a frame's changes at once.
+pub fn mid_frame_merge_system<V>(
+    mut voxel_map: ResMut<VoxelMap<V>>,
+    mut local_caches: ResMut<ThreadLocalVoxelCache<V>>,
+    mut edit_buffer: ResMut<EditBuffer<V>>,
+    mut cache_stats: ResMut<ChunkCacheStats<V>>,
+    mut merge_hooks: ResMut<MergeHooks<V>>,
+    mut versions: Option<ResMut<MapVersions<V>>>,
+    spilled_chunks: Option<Res<SpilledChunks<V>>>,
+    pause: Res<MapIoPause<V>>,
 ) where
     V: Voxel,
 {
-    let edit_buffer = std::mem::replace(
-        &mut *edit_buffer,
-        EditBuffer::new(voxel_map.voxels.indexer.chunk_shape()),
-    );
-    *dirty_chunks = edit_buffer.merge_edits(&mut voxel_map.voxels);
+    if pause.is_paused() || edit_buffer.edited_voxels.storage().is_empty() {
+        return;
+    }
+
+    merge_hooks.run_pre_merge(&mut edit_buffer, &*voxel_map);
+
+    // Locally cached chunks would overwrite the merged edits when they're flushed, so flush them
+    // first, like at the end of the frame.
+    flush_local_caches(&mut local_caches, &mut voxel_map, &mut cache_stats);
+
+    // Versions need the chunks from before the merge. A pending rollback waits for the merge at
+    // the end of the frame.
+    if let Some(versions) = versions.as_mut() {
+        versions.save_originals(&mut *edit_buffer, &*voxel_map, spilled_chunks.as_deref());
+    }
+
+    let merged_buffer = edit_buffer.take_edits();
+    // Keep counting edits from the start of the frame.
+    edit_buffer.num_voxels_edited = merged_buffer.num_voxels_edited;
+    let dirty_chunks = merge_with_post_hooks(merged_buffer, &mut voxel_map, &mut merge_hooks);
+    if let Some(versions) = versions.as_mut() {
+        versions.absorb_merge(&dirty_chunks);
+    }
+    edit_buffer.earlier_merge = Some(dirty_chunks);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{empty_compressible_chunk_map, test_util::*};
+
+    use building_blocks::storage::LocalChunkCache3;
+
+    const CHUNK_SHAPE: Point3i = PointN([4; 3]);
+    const A: Point3i = PointN([0; 3]);
+    const B: Point3i = PointN([4, 0, 0]);
+
+    /// Chunk `A` is filled with 1.
+    fn test_chunk_map() -> CompressibleChunkMap3<TestVoxel> {
+        let mut map = empty_compressible_chunk_map(CHUNK_SHAPE);
+        map.write_chunk(A, Chunk3::with_array(filled_chunk(A, 1)));
+
+        map
+    }
+
+    fn filled_chunk(chunk_key: Point3i, value: u8) -> Array3<TestVoxel> {
+        Array3::fill(
+            Extent3i::from_min_and_shape(chunk_key, CHUNK_SHAPE),
+            TestVoxel(value),
+        )
+    }
+
+    fn edit(
+        edit_buffer: &mut EditBuffer<TestVoxel>,
+        map: &CompressibleChunkMap3<TestVoxel>,
+        extent: Extent3i,
+        value: u8,
+        touch_neighbors: bool,
+    ) {
+        let cache = LocalChunkCache3::new();
+        let reader = map.reader(&cache);
+        edit_buffer.edit_voxels_out_of_place(
+            &reader,
+            extent,
+            |_p: Point3i, v: &mut TestVoxel| *v = TestVoxel(value),
+            touch_neighbors,
+        );
+    }
+
+    fn voxel_at(map: &CompressibleChunkMap3<TestVoxel>, p: Point3i) -> Option<u8> {
+        let chunk_key = map.indexer.chunk_key_containing_point(&p);
+
+        map.storage()
+            .copy_without_caching(chunk_key)
+            .map(|c| c.as_decompressed().array.get(&p).0)
+    }
+
+    fn sorted_edited_keys(dirty_chunks: &DirtyChunks<TestVoxel>) -> Vec<[i32; 3]> {
+        let mut keys: Vec<[i32; 3]> = dirty_chunks.edited_chunk_keys.iter().map(|k| k.0).collect();
+        keys.sort_unstable();
+
+        keys
+    }
+
+    #[test]
+    fn merge_writes_edits_across_chunks() {
+        let mut map = test_chunk_map();
+        let mut edit_buffer = EditBuffer::new(CHUNK_SHAPE, false);
+        let extent = Extent3i::from_min_and_shape(PointN([2, 1, 1]), PointN([4, 1, 1]));
+        edit(&mut edit_buffer, &map, extent, 5, false);
+        assert_eq!(edit_buffer.num_voxels_edited(), 4);
+        assert_eq!(
+            edit_buffer.get_edited_voxel(PointN([5, 1, 1])),
+            Some(TestVoxel(5))
+        );
+
+        let dirty_chunks = edit_buffer.merge_edits(&mut map);
+        assert_eq!(sorted_edited_keys(&dirty_chunks), vec![A.0, B.0]);
+        assert_eq!(dirty_chunks.dirty_chunk_keys.len(), 2);
+        let clipped = Extent3i::from_min_and_shape(PointN([2, 1, 1]), PointN([2, 1, 1]));
+        assert_eq!(dirty_chunks.chunk_edits[&A].extents, vec![clipped]);
+        assert!(!dirty_chunks.chunk_edits[&B].replaced);
+        // The rest of each chunk is kept.
+        assert_eq!(voxel_at(&map, PointN([1, 1, 1])), Some(1));
+        assert_eq!(voxel_at(&map, PointN([3, 1, 1])), Some(5));
+        assert_eq!(voxel_at(&map, PointN([4, 1, 1])), Some(5));
+        assert_eq!(voxel_at(&map, PointN([7, 1, 1])), Some(0));
+    }
+
+    #[test]
+    fn touching_neighbors_dirties_the_surrounding_chunks() {
+        let mut map = test_chunk_map();
+        let mut edit_buffer = EditBuffer::new(CHUNK_SHAPE, false);
+        let extent = Extent3i::from_min_and_shape(A, PointN([1; 3]));
+        edit(&mut edit_buffer, &map, extent, 2, true);
+
+        let dirty_chunks = edit_buffer.merge_edits(&mut map);
+        assert_eq!(sorted_edited_keys(&dirty_chunks), vec![A.0]);
+        assert_eq!(dirty_chunks.dirty_chunk_keys.len(), 27);
+    }
+
+    #[test]
+    fn type_and_occupancy_changes_are_counted() {
+        let mut map = test_chunk_map();
+        let mut edit_buffer = EditBuffer::new(CHUNK_SHAPE, true);
+        edit_buffer.count_occupancy_changes(vec![true]);
+        edit_buffer.count_type_deltas();
+        let cache = LocalChunkCache3::new();
+        let reader = map.reader(&cache);
+        edit_buffer.edit_voxels_out_of_place(
+            &reader,
+            Extent3i::from_min_and_shape(A, PointN([3, 1, 1])),
+            |p: Point3i, v: &mut TestVoxel| *v = TestVoxel((p.x() != 0) as u8),
+            false,
+        );
+        drop(reader);
+
+        let dirty_chunks = edit_buffer.merge_edits(&mut map);
+        let edits = &dirty_chunks.chunk_edits[&A];
+        assert_eq!(edits.type_changes, vec![A]);
+        assert_eq!(edits.occupancy_delta, -1);
+        assert_eq!(edits.type_deltas.get(&1), Some(&-1));
+        assert_eq!(edits.type_deltas.get(&0), Some(&1));
+    }
+
+    #[test]
+    fn fill_extent_keeps_the_uncovered_parts_of_chunks() {
+        let mut map = test_chunk_map();
+        map.write_chunk(B, Chunk3::with_array(filled_chunk(B, 2)));
+        let mut edit_buffer = EditBuffer::new(CHUNK_SHAPE, false);
+        let cache = LocalChunkCache3::new();
+        let reader = map.reader(&cache);
+        let extent = Extent3i::from_min_and_shape(A, PointN([6, 4, 4]));
+        edit_buffer.fill_extent(&reader, extent, TestVoxel(7), false);
+        drop(reader);
+        assert_eq!(edit_buffer.num_voxels_edited(), extent.num_points());
+
+        edit_buffer.merge_edits(&mut map);
+        assert_eq!(voxel_at(&map, PointN([0, 3, 3])), Some(7));
+        assert_eq!(voxel_at(&map, PointN([5, 0, 0])), Some(7));
+        assert_eq!(voxel_at(&map, PointN([6, 0, 0])), Some(2));
+    }
+
+    #[test]
+    fn covered_chunks_count_changes_against_the_old_chunk() {
+        let mut map = test_chunk_map();
+        let mut edit_buffer = EditBuffer::new(CHUNK_SHAPE, true);
+        edit_buffer.count_occupancy_changes(vec![true]);
+        edit_buffer.count_type_deltas();
+        let cache = LocalChunkCache3::new();
+        let reader = map.reader(&cache);
+        // Covers chunk `A`, and half of the missing chunk `B`.
+        let extent = Extent3i::from_min_and_shape(A, PointN([6, 4, 4]));
+        edit_buffer.fill_extent(&reader, extent, TestVoxel(2), false);
+        drop(reader);
+
+        let dirty_chunks = edit_buffer.merge_edits(&mut map);
+        let a_edits = &dirty_chunks.chunk_edits[&A];
+        assert_eq!(a_edits.type_changes.len(), 64);
+        assert_eq!(a_edits.occupancy_delta, 0);
+        assert_eq!(a_edits.type_deltas.get(&1), Some(&-64));
+        assert_eq!(a_edits.type_deltas.get(&2), Some(&64));
+        let b_edits = &dirty_chunks.chunk_edits[&B];
+        assert_eq!(b_edits.type_changes.len(), 32);
+        assert_eq!(b_edits.occupancy_delta, 32);
+        assert_eq!(b_edits.type_deltas.get(&0), Some(&-32));
+        assert_eq!(voxel_at(&map, PointN([3, 3, 3])), Some(2));
+    }
+
+    #[test]
+    fn later_merges_extend_earlier_ones() {
+        let mut map = test_chunk_map();
+        let voxel = |x| Extent3i::from_min_and_shape(PointN([x, 0, 0]), PointN([1; 3]));
+
+        let mut first = EditBuffer::new(CHUNK_SHAPE, false);
+        first.keep_replaced_chunks(true);
+        edit(&mut first, &map, voxel(0), 2, false);
+        let earlier = first.merge_edits(&mut map);
+
+        let mut second = EditBuffer::new(CHUNK_SHAPE, false);
+        second.keep_replaced_chunks(true);
+        second.earlier_merge = Some(earlier);
+        edit(&mut second, &map, voxel(1), 3, false);
+        edit(&mut second, &map, voxel(4), 3, false);
+        let dirty_chunks = second.merge_edits(&mut map);
+
+        assert_eq!(sorted_edited_keys(&dirty_chunks), vec![A.0, B.0]);
+        assert_eq!(
+            dirty_chunks.chunk_edits[&A].extents,
+            vec![voxel(0), voxel(1)]
+        );
+        // The chunk from before the first merge is the one that's kept.
+        let replaced = dirty_chunks.replaced_chunk(&A).unwrap();
+        assert_eq!(replaced.get(&PointN([0, 0, 0])), TestVoxel(1));
+        assert!(dirty_chunks.replaced_chunks[&B].is_none());
+        assert_eq!(voxel_at(&map, PointN([0, 0, 0])), Some(2));
+        assert_eq!(voxel_at(&map, PointN([1, 0, 0])), Some(3));
+    }
+
+    #[test]
+    fn discarded_chunks_are_not_merged() {
+        let mut map = test_chunk_map();
+        let mut edit_buffer = EditBuffer::new(CHUNK_SHAPE, false);
+        edit_buffer.insert_chunk(false, A, filled_chunk(A, 2));
+        edit_buffer.insert_chunk(false, B, filled_chunk(B, 2));
+        edit_buffer.discard_chunk(B);
+
+        let dirty_chunks = edit_buffer.merge_edits(&mut map);
+        assert_eq!(sorted_edited_keys(&dirty_chunks), vec![A.0]);
+        assert!(dirty_chunks.dirty_chunk_keys.contains(&B));
+        assert_eq!(voxel_at(&map, PointN([0, 0, 0])), Some(2));
+        assert_eq!(voxel_at(&map, B), None);
+    }
+
+    #[test]
+    fn unedited_chunks_must_match_their_chunk_key() {
+        let mut map = test_chunk_map();
+        let mut edit_buffer = EditBuffer::new(CHUNK_SHAPE, false);
+        let error = edit_buffer
+            .insert_unedited_chunk(B, filled_chunk(A, 2))
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(edit_buffer.edited_chunk(B).is_none());
+
+        edit_buffer
+            .insert_unedited_chunk(B, filled_chunk(B, 2))
+            .unwrap();
+        assert_eq!(edit_buffer.num_voxels_edited(), 0);
+        let dirty_chunks = edit_buffer.merge_edits(&mut map);
+        assert_eq!(voxel_at(&map, B), Some(2));
+        assert!(dirty_chunks.edited_chunk_keys.contains(&B));
+    }
 }
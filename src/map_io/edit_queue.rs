@@ -0,0 +1,191 @@
+use super::{OwnerId, VoxelEditor};
+
+use crate::Voxel;
+
+use bevy::prelude::*;
+use building_blocks::prelude::*;
+use crossbeam_channel::{Receiver, Sender};
+
+/// A queue of edits that can be sent from any thread or async task, without access to the
+/// `VoxelEditor`. Get a `VoxelEditSender` with `sender`.
+///
+/// Queued edits are applied in the order they were sent, in the `LAST` stage just before the edit
+/// buffer is merged, so they land in the `VoxelMap` on the same frame as the `VoxelEditor`'s edits.
+/// Edits sent after that point are applied on the next frame.
+pub struct VoxelEditQueue<V> {
+    sender: VoxelEditSender<V>,
+    receiver: Receiver<(Option<OwnerId>, VoxelEdit<V>)>,
+}
+
+impl<V> Default for VoxelEditQueue<V> {
+    fn default() -> Self {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+
+        Self {
+            sender: VoxelEditSender {
+                sender,
+                owner: None,
+            },
+            receiver,
+        }
+    }
+}
+
+impl<V> VoxelEditQueue<V> {
+    pub fn sender(&self) -> VoxelEditSender<V> {
+        self.sender.clone()
+    }
+
+    /// The number of edits waiting to be applied.
+    pub fn len(&self) -> usize {
+        self.receiver.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.receiver.is_empty()
+    }
+}
+
+/// Sends edits to the `VoxelEditQueue`. Cheap to clone, and usable from any thread. The edits
+/// mirror the methods of the `VoxelEditor`.
+pub struct VoxelEditSender<V> {
+    sender: Sender<(Option<OwnerId>, VoxelEdit<V>)>,
+    owner: Option<OwnerId>,
+}
+
+impl<V> Clone for VoxelEditSender<V> {
+    fn clone(&self) -> Self {
+        Self {
+            sender: self.sender.clone(),
+            owner: self.owner,
+        }
+    }
+}
+
+impl<V> VoxelEditSender<V>
+where
+    V: Voxel,
+{
+    /// Edits sent through the returned sender are applied as `owner`, like
+    /// `VoxelEditor::with_owner`, e.g. one sender per connected client.
+    pub fn with_owner(mut self, owner: OwnerId) -> Self {
+        self.owner = Some(owner);
+
+        self
+    }
+
+    /// The owner that edits are sent as, if any.
+    pub fn owner(&self) -> Option<OwnerId> {
+        self.owner
+    }
+
+    /// Runs `edit_func` on all voxels in `extent`. Does not mark the neighbors of edited chunks.
+    pub fn edit_extent(
+        &self,
+        extent: Extent3i,
+        edit_func: impl FnMut(Point3i, &mut V) + Send + 'static,
+    ) {
+        self.send(VoxelEdit::Extent {
+            extent,
+            touch_neighbors: false,
+            edit_func: Box::new(edit_func),
+        });
+    }
+
+    /// Runs `edit_func` on all voxels in `extent`. All edited chunks and their neighbors will be
+    /// marked as dirty.
+    pub fn edit_extent_and_touch_neighbors(
+        &self,
+        extent: Extent3i,
+        edit_func: impl FnMut(Point3i, &mut V) + Send + 'static,
+    ) {
+        self.send(VoxelEdit::Extent {
+            extent,
+            touch_neighbors: true,
+            edit_func: Box::new(edit_func),
+        });
+    }
+
+    /// Sets a single voxel. The edited chunk and its neighbors will be marked as dirty.
+    pub fn set_voxel(&self, p: Point3i, voxel: V) {
+        self.edit_extent_and_touch_neighbors(
+            Extent3i::from_min_and_shape(p, PointN([1; 3])),
+            move |_p, v: &mut V| *v = voxel,
+        );
+    }
+
+    pub fn insert_chunk(&self, chunk_key: Point3i, chunk: Array3<V>) {
+        self.send(VoxelEdit::Chunk {
+            chunk_key,
+            chunk,
+            touch_neighbors: false,
+        });
+    }
+
+    pub fn insert_chunk_and_touch_neighbors(&self, chunk_key: Point3i, chunk: Array3<V>) {
+        self.send(VoxelEdit::Chunk {
+            chunk_key,
+            chunk,
+            touch_neighbors: true,
+        });
+    }
+
+    fn send(&self, edit: VoxelEdit<V>) {
+        // The receiver lives as long as the queue resource, so a failure means the app is gone.
+        let _ = self.sender.send((self.owner, edit));
+    }
+}
+
+enum VoxelEdit<V> {
+    Extent {
+        extent: Extent3i,
+        touch_neighbors: bool,
+        edit_func: Box<dyn FnMut(Point3i, &mut V) + Send>,
+    },
+    Chunk {
+        chunk_key: Point3i,
+        chunk: Array3<V>,
+        touch_neighbors: bool,
+    },
+}
+
+/// Applies the queued edits with the `VoxelEditor`, as the owner of their sender.
+pub fn edit_queue_system<V>(queue: Res<VoxelEditQueue<V>>, mut voxel_editor: VoxelEditor<V>)
+where
+    V: Voxel,
+{
+    for (owner, edit) in queue.receiver.try_iter() {
+        match owner {
+            Some(owner) => voxel_editor.with_owner(owner, |editor| apply_edit(editor, edit)),
+            None => apply_edit(&mut voxel_editor, edit),
+        }
+    }
+}
+
+fn apply_edit<V>(voxel_editor: &mut VoxelEditor<V>, edit: VoxelEdit<V>)
+where
+    V: Voxel,
+{
+    match edit {
+        VoxelEdit::Extent {
+            extent,
+            touch_neighbors: false,
+            edit_func,
+        } => voxel_editor.edit_extent(extent, edit_func),
+        VoxelEdit::Extent {
+            extent,
+            touch_neighbors: true,
+            edit_func,
+        } => voxel_editor.edit_extent_and_touch_neighbors(extent, edit_func),
+        VoxelEdit::Chunk {
+            chunk_key,
+            chunk,
+            touch_neighbors: false,
+        } => voxel_editor.insert_chunk(chunk_key, chunk),
+        VoxelEdit::Chunk {
+            chunk_key,
+            chunk,
+            touch_neighbors: true,
+        } => voxel_editor.insert_chunk_and_touch_neighbors(chunk_key, chunk),
+    }
+}
@@ -1,18 +1,40 @@
 use crate::{
-    map_io::{EditBuffer, ThreadLocalVoxelCache},
+    map_io::{
+        edit_buffer::FACE_OFFSETS, BoundsPolicy, ChunkClaims, ClaimRejection, EditBuffer,
+        NeighborDirtying, OutOfBoundsEdit, SpilledChunks, ThreadLocalVoxelCache, VoxelDamage,
+        WorldBounds,
+    },
     Voxel, VoxelMap,
 };
-use bevy::ecs::{prelude::*, SystemParam};
+use bevy::{
+    app::Events,
+    ecs::{prelude::*, SystemParam},
+};
 use building_blocks::prelude::*;
+use fnv::{FnvHashMap, FnvHashSet};
+use std::collections::VecDeque;
 
 /// A `SystemParam` that double-buffers writes to the `VoxelMap` and detects which chunks are
 /// changed each frame. On the subsequent frame, the set of dirty and edited chunk keys will be
 /// available in the `DirtyChunks` resource.
+///
+/// Chunks that were spilled to disk are read back before they're edited. If that fails, the edit
+/// is skipped and the error is recorded in the `SpilledChunks`. Chunks collapsed by the
+/// `UniformChunksPlugin` are expanded before they're edited.
+///
+/// Edits are limited to the `WorldBounds`, if there are any, and checked against the
+/// `ChunkClaims`, if they're enabled.
 #[derive(SystemParam)]
 pub struct VoxelEditor<'a, V: Voxel> {
     pub map: Res<'a, VoxelMap<V>>,
     pub local_cache: Res<'a, ThreadLocalVoxelCache<V>>,
     edit_buffer: ResMut<'a, EditBuffer<V>>,
+    spilled_chunks: Option<Res<'a, SpilledChunks<V>>>,
+    bounds: Res<'a, WorldBounds<V>>,
+    out_of_bounds_events: ResMut<'a, Events<OutOfBoundsEdit<V>>>,
+    damage: Option<ResMut<'a, VoxelDamage<V>>>,
+    claims: Option<ResMut<'a, ChunkClaims<V>>>,
+    claim_rejection_events: Option<ResMut<'a, Events<ClaimRejection<V>>>>,
 }
 
 impl<'a, V> VoxelEditor<'a, V>
@@ -21,7 +43,7 @@ where
 {
     /// Run `edit_func` on all voxels in `extent`. Does not mark the neighbors of edited chunks.
     pub fn edit_extent(&mut self, extent: Extent3i, edit_func: impl FnMut(Point3i, &mut V)) {
-        self._edit_extent(false, extent, edit_func);
+        self._edit_extent(NeighborDirtying::EditedOnly, extent, edit_func);
     }
 
     /// Run `edit_func` on all voxels in `extent`. All edited chunks and their neighbors will be
@@ -31,26 +53,551 @@ where
         extent: Extent3i,
         edit_func: impl FnMut(Point3i, &mut V),
     ) {
-        self._edit_extent(true, extent, edit_func);
+        self._edit_extent(NeighborDirtying::Moore, extent, edit_func);
+    }
+
+    /// Run `edit_func` on all voxels in `extent`. All edited chunks and the chunks around them
+    /// selected by `neighbors` will be marked as dirty.
+    pub fn edit_extent_and_dirty_neighbors(
+        &mut self,
+        extent: Extent3i,
+        neighbors: NeighborDirtying,
+        edit_func: impl FnMut(Point3i, &mut V),
+    ) {
+        self._edit_extent(neighbors, extent, edit_func);
     }
 
     fn _edit_extent(
         &mut self,
-        touch_neighbors: bool,
+        neighbors: NeighborDirtying,
         extent: Extent3i,
         edit_func: impl FnMut(Point3i, &mut V),
     ) {
+        let extent = match self.bounded_extent(extent) {
+            Some(e) => e,
+            None => return,
+        };
+        if !self.copy_offloaded_chunks(&extent) {
+            return;
+        }
+        let tls = self.local_cache.get();
+        let reader = self.map.reader(&tls);
+        self.edit_buffer
+            .edit_voxels_out_of_place(&reader, extent, edit_func, neighbors);
+    }
+
+    /// Sets every voxel in `extent` to the value returned by `write_func`. Unlike `edit_extent`,
+    /// chunks entirely covered by `extent` aren't copied first, so this is faster when the old
+    /// voxels don't matter. They're only read to count the changes, if type changes, occupancy, or
+    /// type counts are tracked. Does not mark the neighbors of edited chunks.
+    pub fn overwrite_extent(&mut self, extent: Extent3i, write_func: impl FnMut(Point3i) -> V) {
+        self._overwrite_extent(NeighborDirtying::EditedOnly, extent, write_func);
+    }
+
+    /// Like `overwrite_extent`, but all edited chunks and their neighbors will be marked as dirty.
+    pub fn overwrite_extent_and_touch_neighbors(
+        &mut self,
+        extent: Extent3i,
+        write_func: impl FnMut(Point3i) -> V,
+    ) {
+        self._overwrite_extent(NeighborDirtying::Moore, extent, write_func);
+    }
+
+    /// Like `overwrite_extent`, but the chunks around edited chunks selected by `neighbors` will
+    /// be marked as dirty.
+    pub fn overwrite_extent_and_dirty_neighbors(
+        &mut self,
+        extent: Extent3i,
+        neighbors: NeighborDirtying,
+        write_func: impl FnMut(Point3i) -> V,
+    ) {
+        self._overwrite_extent(neighbors, extent, write_func);
+    }
+
+    fn _overwrite_extent(
+        &mut self,
+        neighbors: NeighborDirtying,
+        extent: Extent3i,
+        write_func: impl FnMut(Point3i) -> V,
+    ) {
+        let extent = match self.bounded_extent(extent) {
+            Some(e) => e,
+            None => return,
+        };
+        if !self.copy_offloaded_boundary_chunks(&extent) {
+            return;
+        }
+        let tls = self.local_cache.get();
+        let reader = self.map.reader(&tls);
+        self.edit_buffer
+            .overwrite_voxels_out_of_place(&reader, extent, write_func, neighbors);
+    }
+
+    /// Sets every voxel in `extent` to `value`. Chunks entirely covered by `extent` are written as
+    /// constant arrays without being copied first, like with `overwrite_extent`. Does not mark the
+    /// neighbors of edited chunks.
+    pub fn fill_extent(&mut self, extent: Extent3i, value: V) {
+        self._fill_extent(NeighborDirtying::EditedOnly, extent, value);
+    }
+
+    /// Like `fill_extent`, but all edited chunks and their neighbors will be marked as dirty.
+    pub fn fill_extent_and_touch_neighbors(&mut self, extent: Extent3i, value: V) {
+        self._fill_extent(NeighborDirtying::Moore, extent, value);
+    }
+
+    /// Like `fill_extent`, but the chunks around edited chunks selected by `neighbors` will be
+    /// marked as dirty.
+    pub fn fill_extent_and_dirty_neighbors(
+        &mut self,
+        extent: Extent3i,
+        neighbors: NeighborDirtying,
+        value: V,
+    ) {
+        self._fill_extent(neighbors, extent, value);
+    }
+
+    fn _fill_extent(&mut self, neighbors: NeighborDirtying, extent: Extent3i, value: V) {
+        let extent = match self.bounded_extent(extent) {
+            Some(e) => e,
+            None => return,
+        };
+        if !self.copy_offloaded_boundary_chunks(&extent) {
+            return;
+        }
         let tls = self.local_cache.get();
         let reader = self.map.reader(&tls);
         self.edit_buffer
-            .edit_voxels_out_of_place(&reader, extent, edit_func, touch_neighbors);
+            .fill_extent(&reader, extent, value, neighbors);
+    }
+
+    /// The part of `extent` that may be edited under the `WorldBounds` and `ChunkClaims`. Sends an
+    /// `OutOfBoundsEdit` if any of it is outside, or a `ClaimRejection` if a claim rejects it.
+    fn bounded_extent(&mut self, extent: Extent3i) -> Option<Extent3i> {
+        let clipped = self.bounds.clip(&extent);
+        if clipped != Some(extent) {
+            self.out_of_bounds_events.send(OutOfBoundsEdit::new(extent));
+        }
+        let allowed = match (clipped, self.bounds.policy) {
+            (Some(clipped), _) if clipped == extent => clipped,
+            (Some(clipped), BoundsPolicy::Clamp) => clipped,
+            _ => return None,
+        };
+
+        let claims = match self.claims.as_ref() {
+            Some(claims) => claims,
+            None => return Some(allowed),
+        };
+        let chunk_keys = self.map.voxels.indexer.chunk_keys_for_extent(&allowed);
+        match claims.rejecting_owner(&allowed, chunk_keys) {
+            Some(owner) => {
+                if let Some(events) = self.claim_rejection_events.as_mut() {
+                    events.send(ClaimRejection::new(extent, claims.editor(), owner));
+                }
+
+                None
+            }
+            None => Some(allowed),
+        }
+    }
+
+    /// `true` if the `ChunkClaims` allow editing the voxel at `p`.
+    fn point_allowed_by_claims(&self, p: Point3i) -> bool {
+        let claims = match self.claims.as_ref() {
+            Some(claims) => claims,
+            None => return true,
+        };
+        let chunk_key = self.map.voxels.indexer.chunk_key_containing_point(&p);
+
+        claims
+            .rejecting_owner(
+                &Extent3i::from_min_and_shape(p, PointN([1; 3])),
+                std::iter::once(chunk_key),
+            )
+            .is_none()
+    }
+
+    /// Returns `false` if any spilled chunk in `extent` couldn't be read.
+    fn copy_offloaded_chunks(&mut self, extent: &Extent3i) -> bool {
+        let map = &*self.map;
+        let spilled_chunks = self.spilled_chunks.as_deref();
+        let edit_buffer = &mut self.edit_buffer;
+
+        map.voxels
+            .indexer
+            .chunk_keys_for_extent(extent)
+            .all(|chunk_key| copy_offloaded_chunk(map, spilled_chunks, edit_buffer, chunk_key))
+    }
+
+    /// Like `copy_offloaded_chunks`, for edits that overwrite every voxel in `extent`. The chunks
+    /// entirely covered by `extent` are only copied if the edit buffer needs their old voxels.
+    fn copy_offloaded_boundary_chunks(&mut self, extent: &Extent3i) -> bool {
+        let map = &*self.map;
+        let spilled_chunks = self.spilled_chunks.as_deref();
+        let edit_buffer = &mut self.edit_buffer;
+        let copy_covered = edit_buffer.compares_old_voxels();
+        let indexer = &map.voxels.indexer;
+
+        indexer
+            .chunk_keys_for_extent(extent)
+            .filter(|chunk_key| {
+                let chunk_extent = indexer.extent_for_chunk_at_key(*chunk_key);
+
+                copy_covered || extent.intersection(&chunk_extent) != chunk_extent
+            })
+            .all(|chunk_key| copy_offloaded_chunk(map, spilled_chunks, edit_buffer, chunk_key))
+    }
+
+    /// Sets every voxel reachable from `seed` to `value`, moving between face-adjacent voxels that
+    /// satisfy `predicate`. At most `max_voxels` are filled, so filling an unbounded region won't
+    /// stall the frame. Returns the number of voxels filled.
+    ///
+    /// The fill sees edits made earlier in the same frame, and stops at the `WorldBounds`. All
+    /// edited chunks and their neighbors will be marked as dirty.
+    pub fn flood_fill(
+        &mut self,
+        seed: Point3i,
+        mut predicate: impl FnMut(Point3i, V) -> bool,
+        value: V,
+        max_voxels: usize,
+    ) -> usize {
+        let tls = self.local_cache.get();
+        let reader = self.map.reader(&tls);
+
+        // Group the filled points by chunk, so each chunk is copied into the edit buffer once.
+        let mut filled_points: FnvHashMap<Point3i, FnvHashSet<Point3i>> = Default::default();
+        let mut spilled_reads = FnvHashMap::default();
+        let mut num_filled = 0;
+        let mut visited = FnvHashSet::default();
+        let mut queue = VecDeque::new();
+        visited.insert(seed);
+        queue.push_back(seed);
+        while let Some(p) = queue.pop_front() {
+            if num_filled >= max_voxels {
+                break;
+            }
+            if !self.bounds.contains(&p) || !self.point_allowed_by_claims(p) {
+                continue;
+            }
+            // Chunks are only copied into the edit buffer once they're filled, since the fill may
+            // just touch the boundary of many chunks.
+            let voxel = match self.edit_buffer.get_edited_voxel(p) {
+                Some(voxel) => voxel,
+                None => match read_offloaded_voxel(
+                    &*self.map,
+                    self.spilled_chunks.as_deref(),
+                    &mut spilled_reads,
+                    p,
+                ) {
+                    Ok(Some(voxel)) => voxel,
+                    Ok(None) => reader.get(&p),
+                    Err(()) => continue,
+                },
+            };
+            if !predicate(p, voxel) {
+                continue;
+            }
+
+            filled_points
+                .entry(reader.indexer.chunk_key_containing_point(&p))
+                .or_default()
+                .insert(p);
+            num_filled += 1;
+
+            for offset in FACE_OFFSETS.iter() {
+                let neighbor = p + *offset;
+                if visited.insert(neighbor) {
+                    queue.push_back(neighbor);
+                }
+            }
+        }
+
+        for (chunk_key, points) in filled_points.iter() {
+            let copied = match spilled_reads.remove(chunk_key) {
+                Some(Ok(Some(chunk))) => insert_offloaded_chunk(
+                    self.spilled_chunks.as_deref(),
+                    &mut self.edit_buffer,
+                    *chunk_key,
+                    chunk,
+                ),
+                _ => copy_offloaded_chunk(
+                    &*self.map,
+                    self.spilled_chunks.as_deref(),
+                    &mut self.edit_buffer,
+                    *chunk_key,
+                ),
+            };
+            if !copied {
+                num_filled -= points.len();
+                continue;
+            }
+            let mut min = *points.iter().next().unwrap();
+            let mut max = min;
+            for p in points.iter() {
+                min = PointN([min.x().min(p.x()), min.y().min(p.y()), min.z().min(p.z())]);
+                max = PointN([max.x().max(p.x()), max.y().max(p.y()), max.z().max(p.z())]);
+            }
+            self.edit_buffer.edit_voxels_out_of_place(
+                &reader,
+                Extent3i::from_min_and_max(min, max),
+                |p: Point3i, voxel: &mut V| {
+                    if points.contains(&p) {
+                        *voxel = value;
+                    }
+                },
+                true,
+            );
+        }
+
+        num_filled
+    }
+
+    pub(crate) fn point_in_bounds(&self, p: &Point3i) -> bool {
+        self.bounds.contains(p)
+    }
+
+    /// `true` if all of `extent` is inside the `WorldBounds`, whatever the policy.
+    pub(crate) fn extent_in_bounds(&self, extent: &Extent3i) -> bool {
+        self.bounds.clip(extent) == Some(*extent)
+    }
+
+    /// The voxel at `p`, including the edits made so far this frame. This is the ambient value if
+    /// `p` is in a spilled chunk that couldn't be read.
+    pub(crate) fn current_voxel(&mut self, p: Point3i) -> V {
+        let tls = self.local_cache.get();
+        let reader = self.map.reader(&tls);
+        copy_offloaded_chunk(
+            &*self.map,
+            self.spilled_chunks.as_deref(),
+            &mut self.edit_buffer,
+            reader.indexer.chunk_key_containing_point(&p),
+        );
+
+        self.edit_buffer
+            .get_edited_voxel(p)
+            .unwrap_or_else(|| reader.get(&p))
+    }
+
+    pub(crate) fn voxel_damage_mut(&mut self) -> Option<&mut VoxelDamage<V>> {
+        self.damage.as_deref_mut()
+    }
+
+    pub(crate) fn claims_mut(&mut self) -> Option<&mut ChunkClaims<V>> {
+        self.claims.as_deref_mut()
+    }
+
+    /// `true` if the whole chunk at `chunk_key` could be inserted under the `WorldBounds`.
+    pub(crate) fn chunk_in_bounds(&self, chunk_key: Point3i) -> bool {
+        self.bounds
+            .allows(&self.map.voxels.indexer.extent_for_chunk_at_key(chunk_key))
+    }
+
+    /// `true` if the chunk at `chunk_key` is in the map, collapsed, spilled to disk, or was written
+    /// to the edit buffer this frame.
+    pub(crate) fn chunk_exists(&self, chunk_key: Point3i) -> bool {
+        if self.edit_buffer.contains_chunk(&chunk_key)
+            || self.map.uniform_chunks.contains_chunk(&chunk_key)
+            || self
+                .spilled_chunks
+                .as_ref()
+                .map_or(false, |s| s.is_spilled(&chunk_key))
+        {
+            return true;
+        }
+        let tls = self.local_cache.get();
+        let reader = self.map.reader(&tls);
+
+        reader.get_chunk(chunk_key).is_some()
+    }
+
+    /// The chunk at `chunk_key` as written to the edit buffer so far this frame.
+    pub(crate) fn edited_chunk(&self, chunk_key: Point3i) -> Option<&Array3<V>> {
+        self.edit_buffer.edited_chunk(chunk_key)
     }
 
     pub fn insert_chunk_and_touch_neighbors(&mut self, chunk_key: Point3i, chunk: Array3<V>) {
-        self.edit_buffer.insert_chunk(true, chunk_key, chunk);
+        self._insert_chunk(NeighborDirtying::Moore, chunk_key, chunk);
     }
 
     pub fn insert_chunk(&mut self, chunk_key: Point3i, chunk: Array3<V>) {
-        self.edit_buffer.insert_chunk(false, chunk_key, chunk);
+        self._insert_chunk(NeighborDirtying::EditedOnly, chunk_key, chunk);
+    }
+
+    /// Like `insert_chunk`, but the chunks around the inserted chunk selected by `neighbors` will
+    /// be marked as dirty.
+    pub fn insert_chunk_and_dirty_neighbors(
+        &mut self,
+        chunk_key: Point3i,
+        neighbors: NeighborDirtying,
+        chunk: Array3<V>,
+    ) {
+        self._insert_chunk(neighbors, chunk_key, chunk);
+    }
+
+    /// Inserts many chunks at once, which is much faster than calling `insert_chunk` for each of
+    /// them when thousands of chunks are generated or loaded in a single frame.
+    pub fn insert_chunks(&mut self, chunks: impl IntoIterator<Item = (Point3i, Array3<V>)>) {
+        self._insert_chunks(NeighborDirtying::EditedOnly, chunks);
+    }
+
+    pub fn insert_chunks_and_touch_neighbors(
+        &mut self,
+        chunks: impl IntoIterator<Item = (Point3i, Array3<V>)>,
+    ) {
+        self._insert_chunks(NeighborDirtying::Moore, chunks);
+    }
+
+    pub fn insert_chunks_and_dirty_neighbors(
+        &mut self,
+        neighbors: NeighborDirtying,
+        chunks: impl IntoIterator<Item = (Point3i, Array3<V>)>,
+    ) {
+        self._insert_chunks(neighbors, chunks);
+    }
+
+    /// Inserts a whole column of vertically stacked chunks at once. All inserted chunks and their
+    /// neighbors will be marked as dirty.
+    pub fn insert_column(&mut self, chunks: impl IntoIterator<Item = (Point3i, Array3<V>)>) {
+        self._insert_chunks(NeighborDirtying::Moore, chunks);
+    }
+
+    fn _insert_chunk(&mut self, neighbors: NeighborDirtying, chunk_key: Point3i, chunk: Array3<V>) {
+        let chunk_extent = self.map.voxels.indexer.extent_for_chunk_at_key(chunk_key);
+        match self.bounded_extent(chunk_extent) {
+            Some(extent) if extent == chunk_extent => {
+                self.edit_buffer.insert_chunk(neighbors, chunk_key, chunk);
+            }
+            Some(extent) => self.insert_clamped_chunk(neighbors, extent, chunk),
+            None => (),
+        }
+    }
+
+    fn _insert_chunks(
+        &mut self,
+        neighbors: NeighborDirtying,
+        chunks: impl IntoIterator<Item = (Point3i, Array3<V>)>,
+    ) {
+        let mut whole_chunks = Vec::new();
+        for (chunk_key, chunk) in chunks.into_iter() {
+            let chunk_extent = self.map.voxels.indexer.extent_for_chunk_at_key(chunk_key);
+            match self.bounded_extent(chunk_extent) {
+                Some(extent) if extent == chunk_extent => whole_chunks.push((chunk_key, chunk)),
+                Some(extent) => self.insert_clamped_chunk(neighbors, extent, chunk),
+                None => (),
+            }
+        }
+        self.edit_buffer.insert_chunks(neighbors, whole_chunks);
+    }
+
+    // Only the part of a clamped chunk inside the bounds is written.
+    fn insert_clamped_chunk(
+        &mut self,
+        neighbors: NeighborDirtying,
+        extent: Extent3i,
+        chunk: Array3<V>,
+    ) {
+        if !self.copy_offloaded_chunks(&extent) {
+            return;
+        }
+        let tls = self.local_cache.get();
+        let reader = self.map.reader(&tls);
+        self.edit_buffer.overwrite_voxels_out_of_place(
+            &reader,
+            extent,
+            |p: Point3i| chunk.get(&p),
+            neighbors,
+        );
+    }
+}
+
+/// Copies a chunk that isn't in the map storage, because it was collapsed or spilled to disk, into
+/// the edit buffer, so edits start from its real contents. Returns `false` if a spilled chunk
+/// couldn't be read, in which case editing it would lose its contents.
+/// Reads the voxel at `p` if its chunk is collapsed or spilled, without copying the chunk into the
+/// edit buffer. Each spilled chunk is only read once, into `spilled_reads`. Returns `Ok(None)` if
+/// the chunk is in the map, and `Err(())` if it couldn't be read.
+fn read_offloaded_voxel<V>(
+    map: &VoxelMap<V>,
+    spilled_chunks: Option<&SpilledChunks<V>>,
+    spilled_reads: &mut FnvHashMap<Point3i, Result<Option<Array3<V>>, ()>>,
+    p: Point3i,
+) -> Result<Option<V>, ()>
+where
+    V: Voxel,
+{
+    let chunk_key = map.voxels.indexer.chunk_key_containing_point(&p);
+    if let Some(voxel) = map.uniform_chunks.get(&chunk_key) {
+        return Ok(Some(voxel));
+    }
+    let spilled_chunks = match spilled_chunks {
+        Some(s) if s.is_spilled(&chunk_key) => s,
+        _ => return Ok(None),
+    };
+    let read = spilled_reads.entry(chunk_key).or_insert_with(|| {
+        spilled_chunks
+            .read_chunk(chunk_key)
+            .map_err(|e| spilled_chunks.record_error(chunk_key, e))
+    });
+
+    match read {
+        Ok(chunk) => Ok(chunk.as_ref().map(|chunk| chunk.get(&p))),
+        Err(()) => Err(()),
+    }
+}
+
+fn copy_offloaded_chunk<V>(
+    map: &VoxelMap<V>,
+    spilled_chunks: Option<&SpilledChunks<V>>,
+    edit_buffer: &mut EditBuffer<V>,
+    chunk_key: Point3i,
+) -> bool
+where
+    V: Voxel,
+{
+    if edit_buffer.contains_chunk(&chunk_key) {
+        return true;
+    }
+    let chunk_shape = map.voxels.indexer.chunk_shape();
+    if let Some(chunk) = map.uniform_chunks.expanded_chunk(chunk_key, chunk_shape) {
+        return insert_offloaded_chunk(spilled_chunks, edit_buffer, chunk_key, chunk);
+    }
+    let spilled_chunks = match spilled_chunks {
+        Some(s) => s,
+        None => return true,
+    };
+    if !spilled_chunks.is_spilled(&chunk_key) {
+        return true;
+    }
+    match spilled_chunks.read_chunk(chunk_key) {
+        Ok(Some(chunk)) => {
+            insert_offloaded_chunk(Some(spilled_chunks), edit_buffer, chunk_key, chunk)
+        }
+        Ok(None) => true,
+        Err(e) => {
+            spilled_chunks.record_error(chunk_key, e);
+
+            false
+        }
+    }
+}
+
+// Chunks with the wrong extent are reported like spilled chunks that can't be read.
+fn insert_offloaded_chunk<V>(
+    spilled_chunks: Option<&SpilledChunks<V>>,
+    edit_buffer: &mut EditBuffer<V>,
+    chunk_key: Point3i,
+    chunk: Array3<V>,
+) -> bool
+where
+    V: Voxel,
+{
+    match edit_buffer.insert_unedited_chunk(chunk_key, chunk) {
+        Ok(()) => true,
+        Err(e) => {
+            if let Some(spilled_chunks) = spilled_chunks {
+                spilled_chunks.record_error(chunk_key, e);
+            }
+
+            false
+        }
     }
 }
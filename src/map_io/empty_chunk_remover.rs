@@ -1,32 +1,172 @@
-use crate::{Voxel, VoxelMap};
+use super::{DirtyChunks, MapIoFrameStats, MapIoPause, SpilledChunks};
+
+use crate::{MapVersions, Voxel, VoxelMap};
 
 use bevy::ecs::prelude::*;
 use building_blocks::core::Point3i;
+use fnv::FnvHashSet;
 
 /// The resource that tracks which chunks recently became empty and should be removed. This enables
 /// multiple methods of detecting empty chunks. Chunks will be removed at the end of the frame in
 /// which they are marked as empty, but removal happens before the edit buffer is merged into the
 /// `VoxelMap`, so writes from the same frame will not be removed.
-#[derive(Default)]
-pub struct EmptyChunks {
+///
+/// The removals are published together with the `DirtyChunks` of the same frame, as
+/// `removed_chunk_keys` and `ChunkRemoved` events. A chunk that was written again by the frame's
+/// edits doesn't count as removed, and a removed chunk is never one of the `edited_chunk_keys`, so
+/// consumers can apply removals and edits in either order.
+///
+/// At most `max_removals_per_frame` chunks are removed per frame, so a large destruction event
+/// doesn't stall a single frame. The rest wait for later frames, unless they're edited first, in
+/// which case they need to be marked again if they're still empty.
+pub struct EmptyChunks<V> {
+    pub max_removals_per_frame: usize,
     chunks_to_remove: Vec<Point3i>,
+    deferred: Vec<Point3i>,
+    removed: Vec<Point3i>,
+    marker: std::marker::PhantomData<V>,
+}
+
+impl<V> Default for EmptyChunks<V> {
+    fn default() -> Self {
+        Self {
+            max_removals_per_frame: usize::MAX,
+            chunks_to_remove: Vec::new(),
+            deferred: Vec::new(),
+            removed: Vec::new(),
+            marker: Default::default(),
+        }
+    }
 }
 
-impl EmptyChunks {
+impl<V> EmptyChunks<V> {
     /// Mark the chunk at `chunk_key` as "empty" and thus ready to be removed by the
     /// `empty_chunk_remover_system`.
     pub fn mark_for_removal(&mut self, chunk_key: Point3i) {
         self.chunks_to_remove.push(chunk_key);
     }
+
+    /// The chunks that have been marked for removal so far this frame.
+    pub fn chunk_keys(&self) -> impl Iterator<Item = &Point3i> {
+        self.chunks_to_remove.iter()
+    }
+
+    /// The chunks that were marked on earlier frames, but are still waiting for their removal.
+    pub fn deferred_chunk_keys(&self) -> impl Iterator<Item = &Point3i> {
+        self.deferred.iter()
+    }
+
+    /// The chunks that were removed at the end of the previous frame, and are no longer in the map.
+    pub fn removed_chunk_keys(&self) -> impl Iterator<Item = &Point3i> {
+        self.removed.iter()
+    }
+
+    /// Removes the chunk at `chunk_key` from the map right away, publishing it with the other
+    /// removals of this frame.
+    pub(crate) fn remove_now(
+        &mut self,
+        chunk_key: Point3i,
+        voxel_map: &mut VoxelMap<V>,
+        spilled_chunks: Option<&mut SpilledChunks<V>>,
+    ) where
+        V: Voxel,
+    {
+        if let Some(spilled_chunks) = spilled_chunks {
+            spilled_chunks.forget_chunk(&chunk_key);
+        }
+        voxel_map.voxels.storage_mut().remove(chunk_key);
+        voxel_map.uniform_chunks.forget_chunk(&chunk_key);
+        voxel_map.mark_unsaved(chunk_key);
+        self.removed.push(chunk_key);
+    }
+
+    /// Forgets the removals that were undone by the final merge of the frame, and drops the rest
+    /// from the merged `dirty_chunks`, which may still contain them from a mid-frame merge.
+    pub(crate) fn reconcile_with_merge(
+        &mut self,
+        rewritten: &FnvHashSet<Point3i>,
+        dirty_chunks: &mut DirtyChunks<V>,
+    ) {
+        self.removed
+            .retain(|chunk_key| !rewritten.contains(chunk_key));
+        if self.removed.is_empty() {
+            return;
+        }
+        let removed: FnvHashSet<Point3i> = self.removed.iter().cloned().collect();
+        dirty_chunks
+            .edited_chunk_keys
+            .retain(|chunk_key| !removed.contains(chunk_key));
+        dirty_chunks
+            .chunk_edits
+            .retain(|chunk_key, _| !removed.contains(chunk_key));
+        dirty_chunks
+            .replaced_chunks
+            .retain(|chunk_key, _| !removed.contains(chunk_key));
+    }
+}
+
+/// Sent at the end of the frame for every chunk that was removed from the `VoxelMap`, e.g. so
+/// downstream systems can despawn its mesh and collider entities.
+pub struct ChunkRemoved<V> {
+    pub chunk_key: Point3i,
+    marker: std::marker::PhantomData<V>,
+}
+
+impl<V> ChunkRemoved<V> {
+    pub(crate) fn new(chunk_key: Point3i) -> Self {
+        Self {
+            chunk_key,
+            marker: Default::default(),
+        }
+    }
 }
 
 pub fn empty_chunk_remover_system<V>(
-    mut empty_chunks: ResMut<EmptyChunks>,
+    dirty_chunks: Res<DirtyChunks<V>>,
+    mut empty_chunks: ResMut<EmptyChunks<V>>,
     mut voxel_map: ResMut<VoxelMap<V>>,
+    mut frame_stats: ResMut<MapIoFrameStats<V>>,
+    mut spilled_chunks: Option<ResMut<SpilledChunks<V>>>,
+    mut versions: Option<ResMut<MapVersions<V>>>,
+    pause: Res<MapIoPause<V>>,
 ) where
     V: Voxel,
 {
-    for chunk_key in empty_chunks.chunks_to_remove.drain(..) {
-        voxel_map.voxels.storage_mut().remove(chunk_key);
+    let empty_chunks = &mut *empty_chunks;
+
+    // Marked chunks wait until the map is resumed.
+    if pause.is_paused() {
+        frame_stats.removed_chunks = 0;
+        empty_chunks.removed.clear();
+        return;
+    }
+
+    // Deferred chunks that were edited since they were marked might not be empty anymore.
+    if !empty_chunks.deferred.is_empty() {
+        let edited: FnvHashSet<Point3i> = dirty_chunks.edited_chunk_keys.iter().cloned().collect();
+        empty_chunks
+            .deferred
+            .retain(|chunk_key| !edited.contains(chunk_key));
+    }
+
+    // The oldest marks go first. A chunk can be marked more than once.
+    let mut seen = FnvHashSet::default();
+    let mut queue: Vec<Point3i> = empty_chunks
+        .deferred
+        .drain(..)
+        .chain(empty_chunks.chunks_to_remove.drain(..))
+        .filter(|chunk_key| seen.insert(*chunk_key))
+        .collect();
+    let num_to_remove = queue.len().min(empty_chunks.max_removals_per_frame);
+    empty_chunks.deferred = queue.split_off(num_to_remove);
+
+    frame_stats.removed_chunks = queue.len();
+    empty_chunks.removed.clear();
+    for chunk_key in queue.into_iter() {
+        // Versions need the chunk from before the removal.
+        if let Some(versions) = versions.as_mut() {
+            versions.save_original(chunk_key, &*voxel_map, spilled_chunks.as_deref());
+        }
+        empty_chunks.remove_now(chunk_key, &mut *voxel_map, spilled_chunks.as_deref_mut());
     }
 }
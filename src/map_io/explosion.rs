@@ -0,0 +1,91 @@
+use super::VoxelEditor;
+
+use crate::Voxel;
+
+use building_blocks::prelude::*;
+
+/// Implemented by `Voxel::TypeInfo` to say how well each voxel type resists explosions.
+pub trait VoxelHardness {
+    /// Explosions only remove voxels when their strength exceeds this. Explosion strength is at
+    /// most 1, so anything harder is indestructible.
+    fn hardness(&self) -> f32;
+}
+
+impl<'a, V> VoxelEditor<'a, V>
+where
+    V: Voxel,
+    V::TypeInfo: VoxelHardness,
+    for<'r> &'r V::TypeInfo: IsEmpty,
+{
+    /// Carves a noisy sphere of `radius` around `center`, replacing removed voxels with
+    /// `V::default()`.
+    ///
+    /// `falloff` maps the distance from the center, normalized to `[0, 1]`, to the strength of the
+    /// explosion, where 1 is the strongest. Distances are jittered per voxel so the crater isn't a
+    /// perfect sphere. A voxel is removed if the strength at its position exceeds the hardness of its
+    /// type.
+    ///
+    /// Returns the position and original value of every removed non-empty voxel, e.g. for spawning
+    /// debris or drops. All edited chunks and their neighbors will be marked as dirty.
+    pub fn explode(
+        &mut self,
+        center: Point3i,
+        radius: f32,
+        falloff: impl Fn(f32) -> f32,
+    ) -> Vec<(Point3i, V)> {
+        if radius <= 0.0 {
+            return Vec::new();
+        }
+
+        // Look up the palette up front, since the map can't be borrowed during the edit.
+        let palette = &self.map.palette.infos;
+        let hardness: Vec<f32> = palette.iter().map(|info| info.hardness()).collect();
+        let is_empty: Vec<bool> = palette.iter().map(|info| info.is_empty()).collect();
+
+        // Jitter can push distances past the radius, so pad the extent.
+        let max_radius = (radius * (1.0 + MAX_JITTER)).ceil() as i32;
+        let extent = Extent3i::from_min_and_max(
+            center - PointN([max_radius; 3]),
+            center + PointN([max_radius; 3]),
+        );
+
+        let mut removed = Vec::new();
+        self.edit_extent_and_touch_neighbors(extent, |p: Point3i, voxel: &mut V| {
+            let type_index = voxel.get_type_index();
+            if is_empty[type_index] {
+                return;
+            }
+            let offset = p - center;
+            let distance = ((offset.x() * offset.x()
+                + offset.y() * offset.y()
+                + offset.z() * offset.z()) as f32)
+                .sqrt()
+                / radius;
+            let distance = distance * (1.0 + MAX_JITTER * (2.0 * jitter(p) - 1.0));
+            if distance > 1.0 {
+                return;
+            }
+            if falloff(distance) > hardness[type_index] {
+                removed.push((p, *voxel));
+                *voxel = V::default();
+            }
+        });
+
+        removed
+    }
+}
+
+/// The largest fraction by which a distance is scaled up or down.
+const MAX_JITTER: f32 = 0.2;
+
+/// A pseudo-random number in `[0, 1]` for each point.
+fn jitter(p: Point3i) -> f32 {
+    let mut h = (p.x() as u32).wrapping_mul(0x8da6_b343)
+        ^ (p.y() as u32).wrapping_mul(0xd816_3841)
+        ^ (p.z() as u32).wrapping_mul(0xcb1a_b31f);
+    h ^= h >> 15;
+    h = h.wrapping_mul(0x2c1b_3c6d);
+    h ^= h >> 12;
+
+    (h & 0xffff) as f32 / 0xffff as f32
+}
@@ -0,0 +1,17 @@
+/// Counters describing the work done by the `MapIoPlugin` systems on the most recent frame.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MapIoFrameStats<V> {
+    /// The number of voxels covered by edits that were merged into the `VoxelMap`.
+    pub edited_voxels: usize,
+    /// The number of chunks that were merged into the `VoxelMap`.
+    pub edited_chunks: usize,
+    /// The number of chunks marked dirty by the merge, including neighbors of edited chunks.
+    pub dirty_chunks: usize,
+    /// The number of chunks evicted from the cache and compressed.
+    pub compressed_chunks: usize,
+    /// The number of chunks removed because they were marked as empty.
+    pub removed_chunks: usize,
+    /// The number of decompressed chunks in the global cache after compression.
+    pub cached_chunks: usize,
+    marker: std::marker::PhantomData<V>,
+}
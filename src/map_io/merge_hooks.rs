@@ -0,0 +1,77 @@
+use super::{DirtyChunks, EditBuffer};
+
+use crate::{Voxel, VoxelMap};
+
+/// Runs right before the `EditBuffer` is merged into the `VoxelMap`, which still has the old
+/// voxels. The hook may change the buffer, e.g. `EditBuffer::discard_chunk` to reject edits that
+/// break the game rules.
+pub type PreMergeHook<V> = dyn FnMut(&mut EditBuffer<V>, &VoxelMap<V>) + Send + Sync;
+
+/// Runs right after the `EditBuffer` is merged into the `VoxelMap`, with the `DirtyChunks` of that
+/// merge alone.
+pub type PostMergeHook<V> = dyn FnMut(&DirtyChunks<V>, &VoxelMap<V>) + Send + Sync;
+
+/// Callbacks that run in the same system as each merge of the `EditBuffer`, so they see exactly the
+/// merged edits, e.g. to validate them, record replication diffs, or update derived data in step
+/// with the map. Watching `DirtyChunks` instead only sees the edits a frame later.
+///
+/// Hooks run in the order they were added, for the end-of-frame merge and any mid-frame merge. The
+/// `DirtyChunks` given to post-merge hooks don't include the chunks removed by `EmptyChunks`.
+pub struct MergeHooks<V>
+where
+    V: Voxel,
+{
+    pre_merge: Vec<Box<PreMergeHook<V>>>,
+    post_merge: Vec<Box<PostMergeHook<V>>>,
+}
+
+impl<V> Default for MergeHooks<V>
+where
+    V: Voxel,
+{
+    fn default() -> Self {
+        Self {
+            pre_merge: Vec::new(),
+            post_merge: Vec::new(),
+        }
+    }
+}
+
+impl<V> MergeHooks<V>
+where
+    V: Voxel,
+{
+    pub fn add_pre_merge(
+        &mut self,
+        hook: impl FnMut(&mut EditBuffer<V>, &VoxelMap<V>) + Send + Sync + 'static,
+    ) {
+        self.pre_merge.push(Box::new(hook));
+    }
+
+    pub fn add_post_merge(
+        &mut self,
+        hook: impl FnMut(&DirtyChunks<V>, &VoxelMap<V>) + Send + Sync + 'static,
+    ) {
+        self.post_merge.push(Box::new(hook));
+    }
+
+    pub(crate) fn run_pre_merge(
+        &mut self,
+        edit_buffer: &mut EditBuffer<V>,
+        voxel_map: &VoxelMap<V>,
+    ) {
+        for hook in self.pre_merge.iter_mut() {
+            hook(edit_buffer, voxel_map);
+        }
+    }
+
+    pub(crate) fn run_post_merge(
+        &mut self,
+        dirty_chunks: &DirtyChunks<V>,
+        voxel_map: &VoxelMap<V>,
+    ) {
+        for hook in self.post_merge.iter_mut() {
+            hook(dirty_chunks, voxel_map);
+        }
+    }
+}
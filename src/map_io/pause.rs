@@ -0,0 +1,60 @@
+use bevy::prelude::*;
+
+/// Pauses the map streaming, compression, spilling, and merging done by the `MapIoPlugin`, e.g.
+/// during loading screens or menus.
+///
+/// While paused, edits keep accumulating in the edit buffer, and they're all merged on the first
+/// frame after resuming. The `DirtyChunks` resource is empty while paused, so consumers don't
+/// process the same chunks over and over. Reads are unaffected.
+///
+/// The map is paused if either `pause` was called, or the current `State` is one of those given to
+/// `MapIoPlugin::with_paused_states`.
+pub struct MapIoPause<V> {
+    manual: bool,
+    by_state: bool,
+    marker: std::marker::PhantomData<V>,
+}
+
+impl<V> Default for MapIoPause<V> {
+    fn default() -> Self {
+        Self {
+            manual: false,
+            by_state: false,
+            marker: Default::default(),
+        }
+    }
+}
+
+impl<V> MapIoPause<V> {
+    pub fn pause(&mut self) {
+        self.manual = true;
+    }
+
+    /// Undoes `pause`. The map stays paused while in one of the paused states.
+    pub fn resume(&mut self) {
+        self.manual = false;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.manual || self.by_state
+    }
+}
+
+/// The states that pause the map, from `MapIoPlugin::with_paused_states`.
+pub(crate) struct PausedStates<V, S> {
+    pub states: Vec<S>,
+    pub marker: std::marker::PhantomData<V>,
+}
+
+pub(crate) fn paused_states_system<V, S>(
+    state: Option<Res<State<S>>>,
+    paused_states: Res<PausedStates<V, S>>,
+    mut pause: ResMut<MapIoPause<V>>,
+) where
+    V: 'static + Send + Sync,
+    S: 'static + Clone + PartialEq + Send + Sync,
+{
+    pause.by_state = state.map_or(false, |state| {
+        paused_states.states.iter().any(|s| s == state.current())
+    });
+}
@@ -0,0 +1,84 @@
+use crate::{
+    observer::{chunk_radius_extent, transform_voxel_point},
+    Observer, Voxel, VoxelMap,
+};
+
+use bevy::prelude::*;
+use building_blocks::prelude::*;
+use fnv::{FnvHashMap, FnvHashSet};
+
+/// Chunks that the `chunk_compressor_system` must keep decompressed, even under cache pressure.
+///
+/// Chunks can be pinned manually, and every chunk within `observer_radius_in_chunks` of an
+/// `Observer` entity is pinned automatically.
+pub struct PinnedChunks<V> {
+    pub observer_radius_in_chunks: i32,
+    // Manual pins are counted, so independent systems can pin the same chunk.
+    pin_counts: FnvHashMap<Point3i, usize>,
+    near_observers: FnvHashSet<Point3i>,
+    marker: std::marker::PhantomData<V>,
+}
+
+impl<V> Default for PinnedChunks<V> {
+    fn default() -> Self {
+        Self {
+            observer_radius_in_chunks: 2,
+            pin_counts: Default::default(),
+            near_observers: Default::default(),
+            marker: Default::default(),
+        }
+    }
+}
+
+impl<V> PinnedChunks<V> {
+    /// Keeps the chunk at `chunk_key` decompressed until a matching call to `unpin`.
+    pub fn pin(&mut self, chunk_key: Point3i) {
+        *self.pin_counts.entry(chunk_key).or_insert(0) += 1;
+    }
+
+    /// Undoes one call to `pin`.
+    pub fn unpin(&mut self, chunk_key: Point3i) {
+        if let Some(count) = self.pin_counts.get_mut(&chunk_key) {
+            *count -= 1;
+            if *count == 0 {
+                self.pin_counts.remove(&chunk_key);
+            }
+        }
+    }
+
+    pub fn is_pinned(&self, chunk_key: &Point3i) -> bool {
+        self.pin_counts.contains_key(chunk_key) || self.near_observers.contains(chunk_key)
+    }
+
+    /// The keys of all chunks that are pinned manually or by observers.
+    pub fn chunk_keys(&self) -> impl Iterator<Item = &Point3i> {
+        self.pin_counts.keys().chain(
+            self.near_observers
+                .iter()
+                .filter(move |k| !self.pin_counts.contains_key(k)),
+        )
+    }
+}
+
+/// Pins the chunks near every `Observer`.
+pub fn observer_pinning_system<V>(
+    observers: Query<&GlobalTransform, With<Observer>>,
+    voxel_map: Res<VoxelMap<V>>,
+    mut pinned_chunks: ResMut<PinnedChunks<V>>,
+) where
+    V: Voxel,
+{
+    let indexer = &voxel_map.voxels.indexer;
+    let radius = pinned_chunks.observer_radius_in_chunks;
+    pinned_chunks.near_observers.clear();
+    for transform in observers.iter() {
+        let extent = chunk_radius_extent(
+            transform_voxel_point(transform),
+            indexer.chunk_shape(),
+            radius,
+        );
+        pinned_chunks
+            .near_observers
+            .extend(indexer.chunk_keys_for_extent(&extent));
+    }
+}
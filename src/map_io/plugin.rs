@@ -1,15 +1,35 @@
 use super::{
+    amortized_edits::{amortized_edits_finished_system, amortized_edits_system},
+    background_decompression::{
+        background_decompression_finished_system, background_decompression_system,
+    },
     chunk_cache_flusher::chunk_cache_flusher_system,
-    chunk_compressor::chunk_compressor_system,
-    edit_buffer::{double_buffering_system, DirtyChunks},
+    chunk_compressor::{chunk_compressor_system, ChunkUseCounts},
+    chunk_spiller::{chunk_reload_system, chunk_spiller_system},
+    damage::voxel_damage_system,
+    edit_buffer::{double_buffering_system, mid_frame_merge_system, DirtyChunks},
+    edit_queue::edit_queue_system,
     empty_chunk_remover::empty_chunk_remover_system,
-    EditBuffer, EmptyChunks, ThreadLocalVoxelCache,
+    pause::{paused_states_system, PausedStates},
+    pinned_chunks::observer_pinning_system,
+    prefetch::prefetch_system,
+    AmortizedEditFinished, AmortizedEdits, BackgroundDecompression, BoundsPolicy, ChunkCacheStats,
+    ChunkClaims, ChunkRemoved, ChunkSpillConfig, ClaimRejection, EditBuffer, EmptyChunks,
+    MapIoFrameStats, MapIoPause, MergeHooks, OutOfBoundsEdit, PinnedChunks, PrefetchQueue,
+    SpilledChunks, ThreadLocalVoxelCache, VoxelDamage, VoxelEditQueue, WorldBounds,
 };
 
-use crate::Voxel;
+use crate::{Voxel, VoxelCodec, VoxelTaskPoolConfig};
 
 use bevy::{app::prelude::*, ecs::prelude::*};
-use building_blocks::core::Point3i;
+use building_blocks::{
+    core::{Extent3i, Point3i},
+    storage::LocalChunkCache3,
+};
+use std::sync::Arc;
+
+/// The stage added by `MapIoPlugin::with_mid_frame_merge` to merge the edits made so far.
+pub const MID_FRAME_MERGE_STAGE: &str = "voxel_mid_frame_merge";
 
 pub use super::chunk_compressor::ChunkCacheConfig;
 
@@ -38,6 +58,17 @@ pub use super::chunk_compressor::ChunkCacheConfig;
 /// }
 /// ```
 ///
+/// Or, with the `VoxelReader` `SystemParam`, which manages the TLS for you:
+///
+/// ```
+/// use bevy_building_blocks::{bb::prelude::*, Voxel, VoxelReader};
+///
+/// fn reading_system<V: Voxel>(voxel_reader: VoxelReader<V>) {
+///     let extent = Extent3i::from_min_and_shape(PointN([-100; 3]), PointN([200; 3]));
+///     voxel_reader.for_each(&extent, |p: Point3i, voxel: V| {});
+/// }
+/// ```
+///
 /// If the size of the global chunk cache grows beyond a limit, one of the plugin systems will start
 /// compressing the least-recently-used chunks to save space.
 ///
@@ -59,20 +90,174 @@ pub use super::chunk_compressor::ChunkCacheConfig;
 /// **WARNING**: Cached reads will always be flushed before double-buffered writes. This means if
 /// you try to write directly into the `VoxelMap`, you risk having your changes overwritten by the
 /// flush.
-pub struct MapIoPlugin<V> {
+///
+/// Each thread's local cache is created with `LocalChunkCache3::new` the first time the thread reads
+/// from the map. Use `with_local_cache_factory` to create them differently, e.g. from configuration
+/// captured by the closure.
+///
+/// Edits too large for one frame can be spread over several with the `AmortizedEdits` resource.
+///
+/// Edits normally become visible to readers on the next frame. For lower latency,
+/// `with_mid_frame_merge` adds a second merge in another stage, so systems in later stages can read
+/// the edits made before it. The cache flush is coordinated with the extra merge. `DirtyChunks` is
+/// still only updated at the end of the frame, and it includes the chunks from both merges.
+///
+/// Code that has to run in step with the merge, like validating edits or recording replication
+/// diffs, can be registered in the `MergeHooks` resource.
+///
+/// Even compressed chunks can outgrow memory in very large worlds. With `with_disk_spill`, the
+/// coldest compressed chunks are moved to disk and reloaded when they're needed again. See
+/// `SpilledChunks` for details.
+///
+/// Streaming, compression, and merging can be paused with the `MapIoPause` resource, or while in
+/// the states given to `with_paused_states`.
+///
+/// Arena-style maps can be given hard borders with `with_world_bounds`. See `WorldBounds`.
+///
+/// Reading a compressed chunk decompresses it on the reading thread, which can cause frame spikes.
+/// With `with_background_decompression`, the non-blocking reads of the `VoxelReader` return the
+/// ambient value for compressed chunks instead, and decompress them on the `VoxelTaskPool`. See
+/// `BackgroundDecompression`.
+///
+/// Mining-style games can wear voxels down over several hits with `with_voxel_damage`. See
+/// `VoxelDamage`.
+///
+/// Multiplayer servers can protect regions of the map from other players' edits with
+/// `with_chunk_claims`. See `ChunkClaims`.
+pub struct MapIoPlugin<V>
+where
+    V: Voxel,
+{
     pub chunk_shape: Point3i,
-    pub cache_config: ChunkCacheConfig,
+    pub cache_config: ChunkCacheConfig<V>,
+    /// Record the points whose voxel type changed in `DirtyChunks::chunk_edits`. This costs an
+    /// extra comparison per edited voxel and some memory for the point lists.
+    pub track_type_changes: bool,
+    /// The threads that compress chunks and run other background voxel work.
+    pub task_pool: VoxelTaskPoolConfig,
+    spill: Option<(ChunkSpillConfig, Arc<dyn VoxelCodec<V>>)>,
+    mid_frame_merge_stage: Option<&'static str>,
+    local_cache_factory: Option<Arc<dyn Fn() -> LocalChunkCache3<V> + Send + Sync>>,
+    paused_states: Option<Arc<dyn Fn(&mut AppBuilder) + Send + Sync>>,
+    world_bounds: Option<(Extent3i, BoundsPolicy)>,
+    background_decompression: bool,
+    voxel_damage_decay: Option<f32>,
+    chunk_claims: bool,
     marker: std::marker::PhantomData<V>,
 }
 
-impl<V> MapIoPlugin<V> {
-    pub fn new(chunk_shape: Point3i, cache_config: ChunkCacheConfig) -> Self {
+impl<V> MapIoPlugin<V>
+where
+    V: Voxel,
+{
+    pub fn new(chunk_shape: Point3i, cache_config: ChunkCacheConfig<V>) -> Self {
         Self {
             chunk_shape,
             cache_config,
+            track_type_changes: false,
+            task_pool: Default::default(),
+            spill: None,
+            mid_frame_merge_stage: None,
+            local_cache_factory: None,
+            paused_states: None,
+            world_bounds: None,
+            background_decompression: false,
+            voxel_damage_decay: None,
+            chunk_claims: false,
             marker: Default::default(),
         }
     }
+
+    pub fn with_type_change_tracking(mut self) -> Self {
+        self.track_type_changes = true;
+
+        self
+    }
+
+    pub fn with_task_pool(mut self, task_pool: VoxelTaskPoolConfig) -> Self {
+        self.task_pool = task_pool;
+
+        self
+    }
+
+    /// Spills cold compressed chunks to disk, encoded with `codec`.
+    pub fn with_disk_spill(
+        mut self,
+        config: ChunkSpillConfig,
+        codec: impl VoxelCodec<V> + 'static,
+    ) -> Self {
+        self.spill = Some((config, Arc::new(codec)));
+
+        self
+    }
+
+    /// Also merges the edits made so far in the `MID_FRAME_MERGE_STAGE`, which is added right after
+    /// `stage`, e.g. `stage::UPDATE`. Edits made by any system in `stage` can then be read by
+    /// systems in later stages of the same frame.
+    ///
+    /// Every map with a mid-frame merge shares the same stage, which is placed after the `stage`
+    /// of the first plugin that adds it.
+    pub fn with_mid_frame_merge(mut self, stage: &'static str) -> Self {
+        self.mid_frame_merge_stage = Some(stage);
+
+        self
+    }
+
+    /// Creates each thread's local chunk cache with `factory`.
+    pub fn with_local_cache_factory(
+        mut self,
+        factory: impl Fn() -> LocalChunkCache3<V> + Send + Sync + 'static,
+    ) -> Self {
+        self.local_cache_factory = Some(Arc::new(factory));
+
+        self
+    }
+
+    /// Limits edits, generation, and prefetching to `extent`.
+    pub fn with_world_bounds(mut self, extent: Extent3i, policy: BoundsPolicy) -> Self {
+        self.world_bounds = Some((extent, policy));
+
+        self
+    }
+
+    /// Decompresses chunks in the background for `VoxelReader::get_nonblocking` and
+    /// `VoxelReader::for_each_nonblocking`.
+    pub fn with_background_decompression(mut self) -> Self {
+        self.background_decompression = true;
+
+        self
+    }
+
+    /// Accumulates the damage dealt with `VoxelEditor::damage_voxel`, healing `decay_per_second` per
+    /// second.
+    pub fn with_voxel_damage(mut self, decay_per_second: f32) -> Self {
+        self.voxel_damage_decay = Some(decay_per_second);
+
+        self
+    }
+
+    /// Checks every edit against the `ChunkClaims`, e.g. to protect players' builds on a server.
+    pub fn with_chunk_claims(mut self) -> Self {
+        self.chunk_claims = true;
+
+        self
+    }
+
+    /// Pauses the map while the current `State<S>` is one of `states`, e.g. a loading screen.
+    pub fn with_paused_states<S>(mut self, states: Vec<S>) -> Self
+    where
+        S: 'static + Clone + PartialEq + Send + Sync,
+    {
+        self.paused_states = Some(Arc::new(move |app: &mut AppBuilder| {
+            app.insert_resource(PausedStates::<V, S> {
+                states: states.clone(),
+                marker: Default::default(),
+            })
+            .add_system_to_stage(stage::FIRST, paused_states_system::<V, S>.system());
+        }));
+
+        self
+    }
 }
 
 impl<V> Plugin for MapIoPlugin<V>
@@ -80,19 +265,102 @@ where
     V: Voxel,
 {
     fn build(&self, app: &mut AppBuilder) {
+        let local_caches = match self.local_cache_factory.clone() {
+            Some(factory) => ThreadLocalVoxelCache::<V>::with_init(move || factory()),
+            None => ThreadLocalVoxelCache::<V>::with_init(LocalChunkCache3::new),
+        };
+
+        self.task_pool.insert_into(app);
         app.insert_resource(self.cache_config)
-            .insert_resource(EditBuffer::<V>::new(self.chunk_shape))
-            .insert_resource(DirtyChunks::default())
-            .insert_resource(EmptyChunks::default())
+            .insert_resource(EditBuffer::<V>::new(
+                self.chunk_shape,
+                self.track_type_changes,
+            ))
+            .insert_resource(DirtyChunks::<V>::default())
+            .insert_resource(EmptyChunks::<V>::default())
+            .insert_resource(MapIoFrameStats::<V>::default())
+            .insert_resource(ChunkCacheStats::<V>::default())
+            .insert_resource(ChunkUseCounts::<V>::default())
+            .insert_resource(PinnedChunks::<V>::default())
+            .insert_resource(PrefetchQueue::<V>::default())
+            .insert_resource(VoxelEditQueue::<V>::default())
+            .insert_resource(AmortizedEdits::<V>::default())
+            .insert_resource(MapIoPause::<V>::default())
+            .insert_resource(MergeHooks::<V>::default())
+            .insert_resource(match self.world_bounds {
+                Some((extent, policy)) => WorldBounds::<V>::new(extent, policy),
+                None => WorldBounds::<V>::default(),
+            })
+            .add_event::<AmortizedEditFinished<V>>()
+            .add_event::<ChunkRemoved<V>>()
+            .add_event::<OutOfBoundsEdit<V>>()
             // Each thread gets its own local chunk cache. The local caches are flushed into the
             // global cache in the chunk_cache_flusher_system.
-            .insert_resource(ThreadLocalVoxelCache::<V>::new())
+            .insert_resource(local_caches)
             // Ordering the cache flusher and double buffering is important, because we don't want
             // to overwrite edits with locally cached chunks. Similarly, empty chunks should be
             // removed before new edits are merged in.
+            // Prefetch before the UPDATE stage, where most reads happen.
+            .add_system_to_stage(stage::PRE_UPDATE, prefetch_system::<V>.system())
+            .add_system_to_stage(stage::POST_UPDATE, observer_pinning_system::<V>.system())
+            // Queued and amortized edits read through the local caches, so they're applied before
+            // the flush.
+            .add_system_to_stage(stage::LAST, edit_queue_system::<V>.system())
+            .add_system_to_stage(stage::LAST, amortized_edits_system::<V>.system())
             .add_system_to_stage(stage::LAST, chunk_cache_flusher_system::<V>.system())
             .add_system_to_stage(stage::LAST, empty_chunk_remover_system::<V>.system())
             .add_system_to_stage(stage::LAST, double_buffering_system::<V>.system())
+            .add_system_to_stage(stage::LAST, amortized_edits_finished_system::<V>.system())
             .add_system_to_stage(stage::LAST, chunk_compressor_system::<V>.system());
+
+        if let Some(add_paused_states) = &self.paused_states {
+            add_paused_states(app);
+        }
+
+        if let Some(stage) = self.mid_frame_merge_stage {
+            // Systems in the same stage run in the order they were added, so the merge needs its own
+            // stage to come after the edits of systems added later.
+            let has_merge_stage = app
+                .app
+                .schedule
+                .get_stage::<SystemStage>(MID_FRAME_MERGE_STAGE)
+                .is_some();
+            if !has_merge_stage {
+                app.add_stage_after(stage, MID_FRAME_MERGE_STAGE, SystemStage::parallel());
+            }
+            app.add_system_to_stage(MID_FRAME_MERGE_STAGE, mid_frame_merge_system::<V>.system());
+        }
+
+        if let Some((config, codec)) = &self.spill {
+            let spilled_chunks =
+                SpilledChunks::<V>::new(config.clone(), codec.clone(), self.chunk_shape);
+            app.insert_resource(spilled_chunks)
+                // Reloaded chunks are written directly into the map, so this must happen before
+                // any reads.
+                .add_system_to_stage(stage::FIRST, chunk_reload_system::<V>.system())
+                .add_system_to_stage(stage::LAST, chunk_spiller_system::<V>.system());
+        }
+
+        if let Some(decay_per_second) = self.voxel_damage_decay {
+            app.insert_resource(VoxelDamage::<V>::new(decay_per_second))
+                .add_system_to_stage(stage::PRE_UPDATE, voxel_damage_system::<V>.system());
+        }
+
+        if self.chunk_claims {
+            app.insert_resource(ChunkClaims::<V>::default())
+                .add_event::<ClaimRejection<V>>();
+        }
+
+        if self.background_decompression {
+            app.insert_resource(BackgroundDecompression::<V>::default())
+                // Finished chunks are written directly into the map, so this must happen before
+                // any reads.
+                .add_system_to_stage(
+                    stage::FIRST,
+                    background_decompression_finished_system::<V>.system(),
+                )
+                // After the merge, so the copied chunks are up to date.
+                .add_system_to_stage(stage::LAST, background_decompression_system::<V>.system());
+        }
     }
 }
@@ -0,0 +1,110 @@
+use super::{MapIoPause, WorldBounds};
+
+use crate::{
+    observer::{chunk_radius_extent, transform_voxel_point},
+    Observer, ThreadLocalVoxelCache, Voxel, VoxelMap, VoxelTaskPool,
+};
+
+use bevy::prelude::*;
+use building_blocks::prelude::*;
+use fnv::{FnvHashMap, FnvHashSet};
+use std::collections::VecDeque;
+
+/// Chunks waiting to be decompressed ahead of time by the `prefetch_system`.
+///
+/// Regions around `Observer` entities are queued automatically whenever an observer moves into a
+/// new chunk.
+pub struct PrefetchQueue<V> {
+    /// The most chunks that will be prefetched in a single frame.
+    pub max_chunks_per_frame: usize,
+    pub observer_radius_in_chunks: i32,
+    extents: Vec<Extent3i>,
+    chunk_keys: VecDeque<Point3i>,
+    queued: FnvHashSet<Point3i>,
+    marker: std::marker::PhantomData<V>,
+}
+
+impl<V> Default for PrefetchQueue<V> {
+    fn default() -> Self {
+        Self {
+            max_chunks_per_frame: 64,
+            observer_radius_in_chunks: 3,
+            extents: Vec::new(),
+            chunk_keys: VecDeque::new(),
+            queued: Default::default(),
+            marker: Default::default(),
+        }
+    }
+}
+
+impl<V> PrefetchQueue<V> {
+    /// Queues every chunk overlapping `extent` for prefetching.
+    pub fn prefetch_extent(&mut self, extent: Extent3i) {
+        self.extents.push(extent);
+    }
+
+    /// The number of chunks still waiting to be prefetched, not counting extents queued this frame.
+    pub fn num_pending_chunks(&self) -> usize {
+        self.chunk_keys.len()
+    }
+
+    pub(crate) fn pending_chunk_keys(&self) -> impl Iterator<Item = &Point3i> {
+        self.chunk_keys.iter()
+    }
+
+    fn push_chunk_keys(&mut self, chunk_keys: impl Iterator<Item = Point3i>) {
+        for chunk_key in chunk_keys {
+            if self.queued.insert(chunk_key) {
+                self.chunk_keys.push_back(chunk_key);
+            }
+        }
+    }
+}
+
+/// Queues the surroundings of observers that changed chunks, then prefetches a batch of queued
+/// chunks in parallel.
+pub fn prefetch_system<V>(
+    observers: Query<(Entity, &GlobalTransform), With<Observer>>,
+    pool: Res<VoxelTaskPool>,
+    voxel_map: Res<VoxelMap<V>>,
+    local_caches: Res<ThreadLocalVoxelCache<V>>,
+    mut queue: ResMut<PrefetchQueue<V>>,
+    mut observer_chunks: Local<FnvHashMap<Entity, Point3i>>,
+    pause: Res<MapIoPause<V>>,
+    bounds: Res<WorldBounds<V>>,
+) where
+    V: Voxel,
+{
+    if pause.is_paused() {
+        return;
+    }
+
+    let indexer = &voxel_map.voxels.indexer;
+
+    let mut seen_observers = FnvHashMap::default();
+    for (entity, transform) in observers.iter() {
+        let p = transform_voxel_point(transform);
+        let chunk_key = indexer.chunk_key_containing_point(&p);
+        if observer_chunks.get(&entity) != Some(&chunk_key) {
+            let extent =
+                chunk_radius_extent(p, indexer.chunk_shape(), queue.observer_radius_in_chunks);
+            if let Some(extent) = bounds.clip(&extent) {
+                queue.push_chunk_keys(indexer.chunk_keys_for_extent(&extent));
+            }
+        }
+        seen_observers.insert(entity, chunk_key);
+    }
+    *observer_chunks = seen_observers;
+
+    let extents = std::mem::replace(&mut queue.extents, Vec::new());
+    for extent in extents.iter().filter_map(|e| bounds.clip(e)) {
+        queue.push_chunk_keys(indexer.chunk_keys_for_extent(&extent));
+    }
+
+    let num_chunks = queue.max_chunks_per_frame.min(queue.chunk_keys.len());
+    let batch: Vec<Point3i> = queue.chunk_keys.drain(..num_chunks).collect();
+    for chunk_key in batch.iter() {
+        queue.queued.remove(chunk_key);
+    }
+    voxel_map.prefetch_chunks(batch, &*local_caches, &*pool);
+}
@@ -0,0 +1,238 @@
+use super::{BackgroundDecompression, ChunkCacheStats, ThreadLocalVoxelCache};
+
+use crate::{default_array, ChunkOctrees, Voxel, VoxelInfoReader, VoxelMap};
+
+use bevy::ecs::{prelude::*, SystemParam};
+use building_blocks::prelude::*;
+
+/// A `SystemParam` that reads the `VoxelMap` through the thread-local chunk caches, so systems don't
+/// have to keep a TLS handle alive next to their reader.
+///
+/// Each method fetches the calling thread's cache, so it's also safe to use from tasks. To amortize
+/// that lookup over many reads, use `read`.
+///
+/// Chunks collapsed by the `UniformChunksPlugin` are read from their single voxel, except through
+/// the raw readers of `read` and `read_info`. Other chunk lookups are counted in the
+/// `ChunkCacheStats`, also except through the raw readers.
+#[derive(SystemParam)]
+pub struct VoxelReader<'a, V: Voxel> {
+    pub map: Res<'a, VoxelMap<V>>,
+    pub local_cache: Res<'a, ThreadLocalVoxelCache<V>>,
+    pub background_decompression: Option<Res<'a, BackgroundDecompression<V>>>,
+    cache_stats: Res<'a, ChunkCacheStats<V>>,
+}
+
+impl<'a, V> VoxelReader<'a, V>
+where
+    V: Voxel,
+{
+    pub fn get(&self, p: Point3i) -> V {
+        self.read(|reader| self.read_voxel(reader, &p))
+    }
+
+    pub fn for_each(&self, extent: &Extent3i, mut f: impl FnMut(Point3i, V)) {
+        self.read(|reader| {
+            for chunk_key in reader.indexer.chunk_keys_for_extent(extent) {
+                let chunk_extent = reader
+                    .indexer
+                    .extent_for_chunk_at_key(chunk_key)
+                    .intersection(extent);
+                match self.map.uniform_chunks.get(&chunk_key) {
+                    Some(value) => for_each_point(&chunk_extent, |p| f(p, value)),
+                    None => {
+                        self.cache_stats.record_lookup(chunk_key);
+                        reader.for_each(&chunk_extent, |p: Point3i, voxel: V| f(p, voxel));
+                    }
+                }
+            }
+        })
+    }
+
+    /// Like `get`, but never decompresses a chunk on this thread. If the chunk is compressed, this
+    /// returns the ambient value and the chunk is decompressed in the background. Without
+    /// `MapIoPlugin::with_background_decompression`, this is the same as `get`.
+    pub fn get_nonblocking(&self, p: Point3i) -> V {
+        let chunk_key = self.map.voxels.indexer.chunk_key_containing_point(&p);
+        if let Some(value) = self.map.uniform_chunks.get(&chunk_key) {
+            return value;
+        }
+        if let Some(decompression) = self.background_decompression.as_ref() {
+            if decompression.request(chunk_key) {
+                return V::default();
+            }
+        }
+
+        self.get(p)
+    }
+
+    /// Like `for_each`, but visits the voxels of compressed chunks as the ambient value, like
+    /// `get_nonblocking`.
+    pub fn for_each_nonblocking(&self, extent: &Extent3i, mut f: impl FnMut(Point3i, V)) {
+        let decompression = match self.background_decompression.as_ref() {
+            Some(decompression) => decompression,
+            None => return self.for_each(extent, f),
+        };
+        self.read(|reader| {
+            for chunk_key in reader.indexer.chunk_keys_for_extent(extent) {
+                let chunk_extent = reader
+                    .indexer
+                    .extent_for_chunk_at_key(chunk_key)
+                    .intersection(extent);
+                let value = match self.map.uniform_chunks.get(&chunk_key) {
+                    Some(value) => value,
+                    None if decompression.request(chunk_key) => V::default(),
+                    None => {
+                        self.cache_stats.record_lookup(chunk_key);
+                        reader.for_each(&chunk_extent, |p: Point3i, voxel: V| f(p, voxel));
+                        continue;
+                    }
+                };
+                for_each_point(&chunk_extent, |p| f(p, value));
+            }
+        })
+    }
+
+    /// Copies every voxel in `extent` into a new array.
+    pub fn copy_extent(&self, extent: &Extent3i) -> Array3<V> {
+        let mut array = default_array(*extent);
+        self.for_each(extent, |p: Point3i, voxel: V| {
+            *array.get_mut(&p) = voxel;
+        });
+
+        array
+    }
+
+    /// Runs `f` with a cached reader for the calling thread. The reader sees collapsed uniform
+    /// chunks as the ambient value.
+    pub fn read<T>(
+        &self,
+        f: impl FnOnce(&ChunkMap3<V, (), CompressibleChunkStorageReader3<V>>) -> T,
+    ) -> T {
+        let tls = self.local_cache.get();
+        let reader = self.map.reader(&tls);
+
+        f(&reader)
+    }
+
+    pub fn get_info(&self, p: Point3i) -> &V::TypeInfo {
+        self.map.palette.get_voxel_type_info(self.get(p))
+    }
+
+    pub fn for_each_info(&self, extent: &Extent3i, mut f: impl FnMut(Point3i, &V::TypeInfo)) {
+        let palette = &self.map.palette;
+        self.for_each(extent, |p: Point3i, voxel: V| {
+            f(p, palette.get_voxel_type_info(voxel))
+        });
+    }
+
+    /// Finds the voxel nearest to `center` that satisfies `predicate`, e.g. the nearest water for an
+    /// AI agent. The search expands outward in cube-shaped shells, up to `max_radius` voxels along
+    /// each axis. Ties in distance are broken arbitrarily.
+    pub fn find_nearest(
+        &self,
+        center: Point3i,
+        max_radius: i32,
+        mut predicate: impl FnMut(Point3i, V) -> bool,
+    ) -> Option<Point3i> {
+        self.read(|reader| {
+            find_nearest_in_shells(center, max_radius, |p| {
+                predicate(p, self.read_voxel(reader, &p))
+            })
+        })
+    }
+
+    /// Like `find_nearest`, but skips the chunks that `octrees` knows are empty, without reading
+    /// them. Only use this if `predicate` never matches empty voxels.
+    pub fn find_nearest_occupied(
+        &self,
+        center: Point3i,
+        max_radius: i32,
+        octrees: &ChunkOctrees<V>,
+        mut predicate: impl FnMut(Point3i, V) -> bool,
+    ) -> Option<Point3i> {
+        self.read(|reader| {
+            find_nearest_in_shells(center, max_radius, |p| {
+                let chunk_key = reader.indexer.chunk_key_containing_point(&p);
+
+                octrees.chunk_is_empty(&chunk_key) != Some(true)
+                    && predicate(p, self.read_voxel(reader, &p))
+            })
+        })
+    }
+
+    /// Like `read`, but with a reader that yields each voxel's `TypeInfo`.
+    pub fn read_info<T>(&self, f: impl FnOnce(&VoxelInfoReader<V>) -> T) -> T {
+        let tls = self.local_cache.get();
+        let reader = self.map.info_reader(&tls);
+
+        f(&reader)
+    }
+
+    fn read_voxel(
+        &self,
+        reader: &ChunkMap3<V, (), CompressibleChunkStorageReader3<V>>,
+        p: &Point3i,
+    ) -> V {
+        let chunk_key = reader.indexer.chunk_key_containing_point(p);
+        if let Some(value) = self.map.uniform_chunks.get(&chunk_key) {
+            return value;
+        }
+        self.cache_stats.record_lookup(chunk_key);
+
+        reader.get(p)
+    }
+}
+
+fn for_each_point(extent: &Extent3i, mut f: impl FnMut(Point3i)) {
+    let min = extent.minimum;
+    let max = extent.max();
+    for z in min.z()..=max.z() {
+        for y in min.y()..=max.y() {
+            for x in min.x()..=max.x() {
+                f(PointN([x, y, z]));
+            }
+        }
+    }
+}
+
+fn find_nearest_in_shells(
+    center: Point3i,
+    max_radius: i32,
+    mut matches: impl FnMut(Point3i) -> bool,
+) -> Option<Point3i> {
+    let mut nearest: Option<(i32, Point3i)> = None;
+    for radius in 0..=max_radius {
+        if let Some((nearest_dist_sq, _)) = nearest {
+            // Every point in this shell is at least `radius` away.
+            if radius * radius > nearest_dist_sq {
+                break;
+            }
+        }
+        for_each_shell_offset(radius, |offset| {
+            let dist_sq =
+                offset.x() * offset.x() + offset.y() * offset.y() + offset.z() * offset.z();
+            let is_nearer = nearest.map_or(true, |(nearest_dist_sq, _)| dist_sq < nearest_dist_sq);
+            if is_nearer && matches(center + offset) {
+                nearest = Some((dist_sq, center + offset));
+            }
+        });
+    }
+
+    nearest.map(|(_, p)| p)
+}
+
+/// Visits the offsets whose largest component has magnitude `radius`.
+fn for_each_shell_offset(radius: i32, mut f: impl FnMut(Point3i)) {
+    for z in -radius..=radius {
+        for y in -radius..=radius {
+            if z.abs() == radius || y.abs() == radius {
+                for x in -radius..=radius {
+                    f(PointN([x, y, z]));
+                }
+            } else {
+                f(PointN([-radius, y, z]));
+                f(PointN([radius, y, z]));
+            }
+        }
+    }
+}
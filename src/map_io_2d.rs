@@ -0,0 +1,17 @@
+mod chunk_cache_flusher;
+mod chunk_compressor;
+mod edit_buffer;
+mod editor;
+mod empty_chunk_remover;
+mod plugin;
+
+pub use edit_buffer::{double_buffering_system, ChunkEdits2, DirtyChunks2, EditBuffer2};
+pub use editor::VoxelEditor2;
+pub use empty_chunk_remover::EmptyChunks2;
+pub use plugin::MapIo2dPlugin;
+
+use crate::ThreadLocalResource;
+
+use building_blocks::storage::LocalChunkCache2;
+
+pub type ThreadLocalVoxelCache2<V> = ThreadLocalResource<LocalChunkCache2<V>>;
@@ -0,0 +1,17 @@
+use super::ThreadLocalVoxelCache2;
+
+use crate::{Voxel, VoxelMap2};
+
+use bevy::prelude::*;
+
+/// A system that flushes thread-local voxel chunk caches into the global map's cache.
+pub fn chunk_cache_flusher_system<V>(
+    mut local_caches: ResMut<ThreadLocalVoxelCache2<V>>,
+    mut voxel_map: ResMut<VoxelMap2<V>>,
+) where
+    V: Voxel,
+{
+    for cache in local_caches.drain() {
+        voxel_map.voxels.storage_mut().flush_local_cache(cache);
+    }
+}
@@ -0,0 +1,47 @@
+use crate::{tasks::map_in_pool, ChunkCacheConfig, Voxel, VoxelMap2, VoxelTaskPool};
+
+use bevy::prelude::*;
+use building_blocks::storage::{Compression, FastChunkCompression, Lz4};
+
+/// A system that evicts and compresses the least recently used voxel chunks when the cache gets too
+/// big.
+pub fn chunk_compressor_system<V>(
+    cache_config: Res<ChunkCacheConfig<V>>,
+    pool: Res<VoxelTaskPool>,
+    mut voxel_map: ResMut<VoxelMap2<V>>,
+) where
+    V: Voxel,
+{
+    let num_cached = voxel_map.voxels.storage().cache.len_cached();
+    if num_cached < cache_config.max_cached_chunks {
+        return;
+    }
+
+    let overgrowth = num_cached - cache_config.max_cached_chunks;
+
+    let num_to_compress =
+        overgrowth.min(pool.thread_num() * cache_config.max_chunks_compressed_per_frame_per_thread);
+
+    let mut chunks_to_compress = Vec::new();
+    for _ in 0..num_to_compress {
+        if let Some(key_and_chunk) = voxel_map.voxels.storage_mut().remove_lru() {
+            chunks_to_compress.push(key_and_chunk);
+        } else {
+            break;
+        }
+    }
+
+    let compression = FastChunkCompression::new(Lz4 {
+        level: cache_config.compression_level,
+    });
+    let compressed_chunks = map_in_pool(&*pool, chunks_to_compress, |(key, chunk)| {
+        (key, compression.compress(&chunk))
+    });
+
+    for (key, compressed_chunk) in compressed_chunks.into_iter() {
+        voxel_map
+            .voxels
+            .storage_mut()
+            .insert_compressed(key, compressed_chunk);
+    }
+}
@@ -0,0 +1,237 @@
+use crate::{
+    map2::{default_array2, empty_chunk_hash_map2},
+    Voxel, VoxelMap2,
+};
+
+use bevy::prelude::*;
+use building_blocks::prelude::*;
+use fnv::{FnvHashMap, FnvHashSet};
+
+/// The 2D counterpart of the `EditBuffer`. For the sake of pipelining, all voxels edits are first written out of place
+/// here. They can later be merged into another chunk map by overwriting the dirty chunks.
+pub struct EditBuffer2<V>
+where
+    V: Voxel,
+{
+    edited_voxels: ChunkHashMap2<V>,
+    // Includes the edited chunks as well as their neighbors, all of which need to be re-meshed.
+    dirty_chunk_keys: FnvHashSet<Point2i>,
+    chunk_edits: FnvHashMap<Point2i, ChunkEdits2>,
+    track_type_changes: bool,
+    num_voxels_edited: usize,
+}
+
+impl<V> EditBuffer2<V>
+where
+    V: Voxel,
+{
+    /// If `track_type_changes`, then every voxel whose type index is changed by an edit will be
+    /// recorded in `ChunkEdits2::type_changes`.
+    pub fn new(chunk_shape: Point2i, track_type_changes: bool) -> Self {
+        Self {
+            edited_voxels: empty_chunk_hash_map2(chunk_shape),
+            dirty_chunk_keys: Default::default(),
+            chunk_edits: Default::default(),
+            track_type_changes,
+            num_voxels_edited: 0,
+        }
+    }
+
+    pub fn tracks_type_changes(&self) -> bool {
+        self.track_type_changes
+    }
+
+    /// The number of voxels covered by all edits so far, counting overlapping edits multiple times.
+    pub fn num_voxels_edited(&self) -> usize {
+        self.num_voxels_edited
+    }
+
+    /// The keys of all chunks that have been written to the backbuffer so far.
+    pub fn edited_chunk_keys(&self) -> impl Iterator<Item = &Point2i> {
+        self.edited_voxels.storage().keys()
+    }
+
+    /// Drops any edits to the chunk at `chunk_key`, so it won't be written into the map. The chunk
+    /// and its neighbors stay dirty.
+    pub fn discard_chunk(&mut self, chunk_key: Point2i) {
+        self.edited_voxels.storage_mut().remove(&chunk_key);
+        self.chunk_edits.remove(&chunk_key);
+    }
+
+    /// This function does read-modify-write of the voxels in `extent`. If a chunk is missing from the backbuffer, it will be
+    /// copied from the `reader` before being written.
+    ///
+    /// If `touch_neighbors`, then all chunks in the Moore Neighborhood of any edited chunk will be marked as dirty. This is
+    /// useful when there are dependencies between adjacent chunks that must be considered during post-processing (e.g. during
+    /// mesh generation).
+    pub fn edit_voxels_out_of_place(
+        &mut self,
+        reader: &CompressibleChunkMapReader2<V>,
+        extent: Extent2i,
+        edit_func: impl FnMut(Point2i, &mut V),
+        touch_neighbors: bool,
+    ) {
+        debug_assert!(reader
+            .indexer
+            .chunk_shape()
+            .eq(&self.edited_voxels.indexer.chunk_shape()));
+
+        // Copy any of the overlapping chunks that don't already exist in the backbuffer, i.e. those
+        // chunks which haven't been modified yet.
+        for chunk_key in reader.indexer.chunk_keys_for_extent(&extent) {
+            self.edited_voxels
+                .get_mut_chunk_or_insert_with(chunk_key, || {
+                    reader
+                        .storage()
+                        .storage
+                        // We don't cache the chunk yet, because we're just going to modify this copy
+                        // and insert back into the map later.
+                        .copy_without_caching(chunk_key)
+                        .map(|c| c.as_decompressed())
+                        .unwrap_or(Chunk2::with_array(default_array2(
+                            reader.indexer.extent_for_chunk_at_key(chunk_key),
+                        )))
+                });
+        }
+
+        self.dirty_chunks_for_extent(touch_neighbors, extent);
+        self.record_edited_extent(extent);
+        self.num_voxels_edited += extent.num_points();
+
+        // Edit the backbuffer.
+        if self.track_type_changes {
+            let indexer = self.edited_voxels.indexer.clone();
+            let chunk_edits = &mut self.chunk_edits;
+            let mut edit_func = edit_func;
+            self.edited_voxels
+                .for_each_mut(&extent, |p: Point2i, voxel: &mut V| {
+                    let old_type = voxel.get_type_index();
+                    edit_func(p, voxel);
+                    if voxel.get_type_index() != old_type {
+                        chunk_edits
+                            .entry(indexer.chunk_key_containing_point(&p))
+                            .or_default()
+                            .type_changes
+                            .push(p);
+                    }
+                });
+        } else {
+            self.edited_voxels.for_each_mut(&extent, edit_func);
+        }
+    }
+
+    pub fn insert_chunk(&mut self, touch_neighbors: bool, chunk_key: Point2i, chunk: Array2<V>) {
+        // PERF: this could be more efficient if we just took the moore neighborhood in chunk space
+        let extent = self
+            .edited_voxels
+            .indexer
+            .extent_for_chunk_at_key(chunk_key);
+        self.dirty_chunks_for_extent(touch_neighbors, extent);
+        self.num_voxels_edited += extent.num_points();
+        self.chunk_edits.insert(
+            chunk_key,
+            ChunkEdits2 {
+                extents: vec![extent],
+                replaced: true,
+                type_changes: Vec::new(),
+            },
+        );
+        self.edited_voxels
+            .write_chunk(chunk_key, Chunk2::with_array(chunk));
+    }
+
+    /// Write all of the edited chunks into `dst_map`. Returns the dirty chunks.
+    pub fn merge_edits(self, dst_map: &mut CompressibleChunkMap2<V>) -> DirtyChunks2<V> {
+        let EditBuffer2 {
+            edited_voxels,
+            dirty_chunk_keys,
+            chunk_edits,
+            ..
+        } = self;
+
+        let chunk_storage = edited_voxels.take_storage();
+        let edited_chunk_keys = chunk_storage.chunk_keys().cloned().collect();
+
+        for (chunk_key, chunk) in chunk_storage.into_iter() {
+            dst_map.write_chunk(chunk_key, chunk);
+        }
+
+        DirtyChunks2 {
+            edited_chunk_keys,
+            dirty_chunk_keys,
+            chunk_edits,
+            marker: Default::default(),
+        }
+    }
+
+    fn record_edited_extent(&mut self, extent: Extent2i) {
+        for chunk_key in self.edited_voxels.indexer.chunk_keys_for_extent(&extent) {
+            let chunk_extent = self
+                .edited_voxels
+                .indexer
+                .extent_for_chunk_at_key(chunk_key);
+            let edits = self.chunk_edits.entry(chunk_key).or_default();
+            if !edits.replaced {
+                edits.extents.push(extent.intersection(&chunk_extent));
+            }
+        }
+    }
+
+    fn dirty_chunks_for_extent(&mut self, touch_neighbors: bool, extent: Extent2i) {
+        // Mark the chunks and maybe their neighbors as dirty.
+        let dirty_extent = if touch_neighbors {
+            let chunk_shape = self.edited_voxels.indexer.chunk_shape();
+
+            Extent2i::from_min_and_max(extent.minimum - chunk_shape, extent.max() + chunk_shape)
+        } else {
+            extent
+        };
+        for chunk_key in self
+            .edited_voxels
+            .indexer
+            .chunk_keys_for_extent(&dirty_extent)
+        {
+            self.dirty_chunk_keys.insert(chunk_key);
+        }
+    }
+}
+
+/// The sets of chunk keys that have either been edited directly or marked as dirty, by virtue of neighboring an edited chunk.
+#[derive(Default)]
+pub struct DirtyChunks2<V> {
+    pub edited_chunk_keys: Vec<Point2i>,
+    pub dirty_chunk_keys: FnvHashSet<Point2i>,
+    /// What exactly was edited in each of the `edited_chunk_keys`, so consumers can do minimal updates.
+    pub chunk_edits: FnvHashMap<Point2i, ChunkEdits2>,
+    marker: std::marker::PhantomData<V>,
+}
+
+/// The parts of a single chunk that were edited during one frame.
+#[derive(Clone, Debug, Default)]
+pub struct ChunkEdits2 {
+    /// Every edited extent, clipped to the chunk. These may overlap.
+    pub extents: Vec<Extent2i>,
+    /// `true` if the whole chunk was replaced with `insert_chunk`. Type changes made by the
+    /// replacement itself are not tracked.
+    pub replaced: bool,
+    /// The points whose voxel type index changed. Only recorded if the `MapIo2dPlugin` was configured
+    /// to track type changes.
+    pub type_changes: Vec<Point2i>,
+}
+
+/// Merges edits from the `EditBuffer2` into the `VoxelMap2`. By setting the `DirtyChunks2` resource, the `chunk_processor_system`
+/// will be notified to process dirty chunks on the next frame.
+pub fn double_buffering_system<V>(
+    mut voxel_map: ResMut<VoxelMap2<V>>,
+    mut edit_buffer: ResMut<EditBuffer2<V>>,
+    mut dirty_chunks: ResMut<DirtyChunks2<V>>,
+) where
+    V: Voxel,
+{
+    let track_type_changes = edit_buffer.tracks_type_changes();
+    let edit_buffer = std::mem::replace(
+        &mut *edit_buffer,
+        EditBuffer2::new(voxel_map.voxels.indexer.chunk_shape(), track_type_changes),
+    );
+    *dirty_chunks = edit_buffer.merge_edits(&mut voxel_map.voxels);
+}
@@ -0,0 +1,64 @@
+use crate::{
+    map_io_2d::{EditBuffer2, ThreadLocalVoxelCache2},
+    Voxel, VoxelMap2,
+};
+use bevy::ecs::{prelude::*, SystemParam};
+use building_blocks::prelude::*;
+
+/// The 2D counterpart of the `VoxelEditor`. A `SystemParam` that double-buffers writes to the
+/// `VoxelMap2` and detects which chunks are changed each frame. On the subsequent frame, the set of
+/// dirty and edited chunk keys will be available in the `DirtyChunks2` resource.
+#[derive(SystemParam)]
+pub struct VoxelEditor2<'a, V: Voxel> {
+    pub map: Res<'a, VoxelMap2<V>>,
+    pub local_cache: Res<'a, ThreadLocalVoxelCache2<V>>,
+    edit_buffer: ResMut<'a, EditBuffer2<V>>,
+}
+
+impl<'a, V> VoxelEditor2<'a, V>
+where
+    V: Voxel,
+{
+    /// Run `edit_func` on all voxels in `extent`. Does not mark the neighbors of edited chunks.
+    pub fn edit_extent(&mut self, extent: Extent2i, edit_func: impl FnMut(Point2i, &mut V)) {
+        self._edit_extent(false, extent, edit_func);
+    }
+
+    /// Run `edit_func` on all voxels in `extent`. All edited chunks and their neighbors will be
+    /// marked as dirty.
+    pub fn edit_extent_and_touch_neighbors(
+        &mut self,
+        extent: Extent2i,
+        edit_func: impl FnMut(Point2i, &mut V),
+    ) {
+        self._edit_extent(true, extent, edit_func);
+    }
+
+    fn _edit_extent(
+        &mut self,
+        touch_neighbors: bool,
+        extent: Extent2i,
+        edit_func: impl FnMut(Point2i, &mut V),
+    ) {
+        let tls = self.local_cache.get();
+        let reader = self.map.reader(&tls);
+        self.edit_buffer
+            .edit_voxels_out_of_place(&reader, extent, edit_func, touch_neighbors);
+    }
+
+    pub fn insert_chunk_and_touch_neighbors(&mut self, chunk_key: Point2i, chunk: Array2<V>) {
+        self.edit_buffer.insert_chunk(true, chunk_key, chunk);
+    }
+
+    pub fn insert_chunk(&mut self, chunk_key: Point2i, chunk: Array2<V>) {
+        self.edit_buffer.insert_chunk(false, chunk_key, chunk);
+    }
+
+    /// Inserts a whole column of vertically stacked chunks at once. All inserted chunks and their
+    /// neighbors will be marked as dirty.
+    pub fn insert_column(&mut self, chunks: impl IntoIterator<Item = (Point2i, Array2<V>)>) {
+        for (chunk_key, chunk) in chunks.into_iter() {
+            self.edit_buffer.insert_chunk(true, chunk_key, chunk);
+        }
+    }
+}
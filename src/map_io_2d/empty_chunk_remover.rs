@@ -0,0 +1,38 @@
+use crate::{Voxel, VoxelMap2};
+
+use bevy::ecs::prelude::*;
+use building_blocks::core::Point2i;
+
+/// The 2D counterpart of `EmptyChunks`. The resource that tracks which chunks recently became empty and should be removed. This enables
+/// multiple methods of detecting empty chunks. Chunks will be removed at the end of the frame in
+/// which they are marked as empty, but removal happens before the edit buffer is merged into the
+/// `VoxelMap2`, so writes from the same frame will not be removed.
+#[derive(Default)]
+pub struct EmptyChunks2<V> {
+    chunks_to_remove: Vec<Point2i>,
+    marker: std::marker::PhantomData<V>,
+}
+
+impl<V> EmptyChunks2<V> {
+    /// Mark the chunk at `chunk_key` as "empty" and thus ready to be removed by the
+    /// `empty_chunk_remover_system`.
+    pub fn mark_for_removal(&mut self, chunk_key: Point2i) {
+        self.chunks_to_remove.push(chunk_key);
+    }
+
+    /// The chunks that have been marked for removal so far this frame.
+    pub fn chunk_keys(&self) -> impl Iterator<Item = &Point2i> {
+        self.chunks_to_remove.iter()
+    }
+}
+
+pub fn empty_chunk_remover_system<V>(
+    mut empty_chunks: ResMut<EmptyChunks2<V>>,
+    mut voxel_map: ResMut<VoxelMap2<V>>,
+) where
+    V: Voxel,
+{
+    for chunk_key in empty_chunks.chunks_to_remove.drain(..) {
+        voxel_map.voxels.storage_mut().remove(chunk_key);
+    }
+}
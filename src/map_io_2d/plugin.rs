@@ -0,0 +1,125 @@
+use super::{
+    chunk_cache_flusher::chunk_cache_flusher_system,
+    chunk_compressor::chunk_compressor_system,
+    edit_buffer::{double_buffering_system, DirtyChunks2},
+    empty_chunk_remover::empty_chunk_remover_system,
+    EditBuffer2, EmptyChunks2, ThreadLocalVoxelCache2,
+};
+
+use crate::{ChunkCacheConfig, Voxel, VoxelTaskPoolConfig};
+
+use bevy::{app::prelude::*, ecs::prelude::*};
+use building_blocks::{core::Point2i, storage::LocalChunkCache2};
+use std::sync::Arc;
+
+/// The 2D counterpart of the `MapIoPlugin`, for tile games and heightmap layers. It provides the
+/// same read caching, compression, and double-buffered editing for the `VoxelMap2` resource, which
+/// must exist before systems are dispatched.
+///
+/// ```
+/// use bevy::prelude::*;
+/// use bevy_building_blocks::{bb::prelude::*, ThreadLocalVoxelCache2, Voxel, VoxelEditor2, VoxelMap2};
+///
+/// fn reading_system<V: Voxel>(
+///     voxel_map: Res<VoxelMap2<V>>, caches: Res<ThreadLocalVoxelCache2<V>>
+/// ) {
+///     // The TLS has to live longer than the reader.
+///     let thread_local_cache = caches.get();
+///     let reader = voxel_map.reader(&thread_local_cache);
+///
+///     let extent = Extent2i::from_min_and_shape(PointN([-100; 2]), PointN([200; 2]));
+///     reader.for_each(&extent, |p: Point2i, voxel: V| {});
+/// }
+///
+/// fn writing_system<V: Voxel>(mut voxel_editor: VoxelEditor2<V>) {
+///     let extent = Extent2i::from_min_and_shape(PointN([-100; 2]), PointN([200; 2]));
+///     voxel_editor.edit_extent_and_touch_neighbors(extent, |p: Point2i, voxel: &mut V| {});
+/// }
+/// ```
+///
+/// Edited chunks are reported in the `DirtyChunks2` resource, and chunks marked in the
+/// `EmptyChunks2` resource are removed at the end of the frame.
+///
+/// As with the `MapIoPlugin`, `with_local_cache_factory` controls how each thread's local cache is
+/// created.
+///
+/// The `VoxelTaskPool` resource is shared with the `MapIoPlugin`. When both plugins are added for
+/// the same voxel type, they also share the `ChunkCacheConfig`, and the configuration of the last
+/// one applies to both maps.
+pub struct MapIo2dPlugin<V> {
+    pub chunk_shape: Point2i,
+    pub cache_config: ChunkCacheConfig<V>,
+    /// Record the points whose voxel type changed in `DirtyChunks2::chunk_edits`. This costs an
+    /// extra comparison per edited voxel and some memory for the point lists.
+    pub track_type_changes: bool,
+    /// The threads that compress chunks and run other background voxel work.
+    pub task_pool: VoxelTaskPoolConfig,
+    local_cache_factory: Option<Arc<dyn Fn() -> LocalChunkCache2<V> + Send + Sync>>,
+    marker: std::marker::PhantomData<V>,
+}
+
+impl<V> MapIo2dPlugin<V> {
+    pub fn new(chunk_shape: Point2i, cache_config: ChunkCacheConfig<V>) -> Self {
+        Self {
+            chunk_shape,
+            cache_config,
+            track_type_changes: false,
+            task_pool: Default::default(),
+            local_cache_factory: None,
+            marker: Default::default(),
+        }
+    }
+
+    pub fn with_type_change_tracking(mut self) -> Self {
+        self.track_type_changes = true;
+
+        self
+    }
+
+    pub fn with_task_pool(mut self, task_pool: VoxelTaskPoolConfig) -> Self {
+        self.task_pool = task_pool;
+
+        self
+    }
+
+    /// Creates each thread's local chunk cache with `factory`.
+    pub fn with_local_cache_factory(
+        mut self,
+        factory: impl Fn() -> LocalChunkCache2<V> + Send + Sync + 'static,
+    ) -> Self {
+        self.local_cache_factory = Some(Arc::new(factory));
+
+        self
+    }
+}
+
+impl<V> Plugin for MapIo2dPlugin<V>
+where
+    V: Voxel,
+{
+    fn build(&self, app: &mut AppBuilder) {
+        let local_caches = match self.local_cache_factory.clone() {
+            Some(factory) => ThreadLocalVoxelCache2::<V>::with_init(move || factory()),
+            None => ThreadLocalVoxelCache2::<V>::with_init(LocalChunkCache2::new),
+        };
+
+        self.task_pool.insert_into(app);
+        app.insert_resource(self.cache_config)
+            .insert_resource(EditBuffer2::<V>::new(
+                self.chunk_shape,
+                self.track_type_changes,
+            ))
+            .insert_resource(DirtyChunks2::<V>::default())
+            .insert_resource(EmptyChunks2::<V>::default())
+            // Each thread gets its own local chunk cache. The local caches are flushed into the
+            // global cache in the chunk_cache_flusher_system.
+            .insert_resource(local_caches)
+            // Ordering the cache flusher and double buffering is important, because we don't want
+            // to overwrite edits with locally cached chunks. Similarly, empty chunks should be
+            // removed before new edits are merged in.
+            .add_system_to_stage(stage::LAST, chunk_cache_flusher_system::<V>.system())
+            .add_system_to_stage(stage::LAST, empty_chunk_remover_system::<V>.system())
+            .add_system_to_stage(stage::LAST, double_buffering_system::<V>.system())
+            .add_system_to_stage(stage::LAST, chunk_compressor_system::<V>.system());
+    }
+}
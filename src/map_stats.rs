@@ -0,0 +1,215 @@
+use crate::{
+    map::bounding_extent, map_io::EditBuffer, tasks::map_in_pool, DirtyChunks, EmptyChunks,
+    ThreadLocalVoxelCache, Voxel, VoxelMap, VoxelMapStats, VoxelTaskPool,
+};
+
+use bevy::prelude::*;
+use building_blocks::prelude::*;
+use fnv::FnvHashMap;
+
+/// Manages the `MapStats` resource, which counts the voxels of each type in the `VoxelMap` and
+/// tracks the bounds of the occupied chunks, e.g. for HUDs, save file summaries, or balancing ore
+/// distributions. Depends on the `MapIoPlugin`.
+///
+/// Every chunk is scanned once on the first frame. After that, the `EditBuffer` counts how many
+/// voxels of each type every edit adds or removes, so keeping the counts up to date costs
+/// O(edited chunks) per frame. Chunks replaced with `insert_chunk` are scanned again.
+pub struct MapStatsPlugin<V> {
+    marker: std::marker::PhantomData<V>,
+}
+
+impl<V> Default for MapStatsPlugin<V> {
+    fn default() -> Self {
+        Self {
+            marker: Default::default(),
+        }
+    }
+}
+
+impl<V> Plugin for MapStatsPlugin<V>
+where
+    V: Voxel,
+    for<'r> &'r V::TypeInfo: IsEmpty,
+{
+    fn build(&self, app: &mut AppBuilder) {
+        app.insert_resource(MapStats::<V>::default())
+            .add_startup_system(count_type_deltas_system::<V>.system())
+            .add_system(map_stats_system::<V>.system());
+    }
+}
+
+/// Voxel counts and chunk statistics for the `VoxelMap`, as of the previous frame's merge.
+pub struct MapStats<V> {
+    /// From `VoxelMap::stats`.
+    pub chunks: VoxelMapStats,
+    type_counts: Vec<u64>,
+    occupied_bounds: Option<Extent3i>,
+    chunk_type_counts: FnvHashMap<Point3i, FnvHashMap<usize, u64>>,
+    initialized: bool,
+    marker: std::marker::PhantomData<V>,
+}
+
+impl<V> Default for MapStats<V> {
+    fn default() -> Self {
+        Self {
+            chunks: Default::default(),
+            type_counts: Vec::new(),
+            occupied_bounds: None,
+            chunk_type_counts: Default::default(),
+            initialized: false,
+            marker: Default::default(),
+        }
+    }
+}
+
+impl<V> MapStats<V> {
+    /// The number of voxels with type index `type_index` in all chunks.
+    pub fn type_count(&self, type_index: usize) -> u64 {
+        self.type_counts.get(type_index).cloned().unwrap_or(0)
+    }
+
+    /// The number of voxels of each type, indexed by type index.
+    pub fn type_counts(&self) -> &[u64] {
+        &self.type_counts
+    }
+
+    /// The smallest extent containing every chunk with a non-empty voxel, or `None` if there are no
+    /// such chunks.
+    pub fn occupied_bounds(&self) -> Option<Extent3i> {
+        self.occupied_bounds
+    }
+
+    fn add_chunk(&mut self, chunk_key: Point3i, counts: FnvHashMap<usize, u64>) {
+        self.remove_chunk(&chunk_key);
+        for (&type_index, &count) in counts.iter() {
+            if type_index >= self.type_counts.len() {
+                self.type_counts.resize(type_index + 1, 0);
+            }
+            self.type_counts[type_index] += count;
+        }
+        self.chunk_type_counts.insert(chunk_key, counts);
+    }
+
+    fn remove_chunk(&mut self, chunk_key: &Point3i) {
+        if let Some(counts) = self.chunk_type_counts.remove(chunk_key) {
+            for (type_index, count) in counts.into_iter() {
+                self.type_counts[type_index] -= count;
+            }
+        }
+    }
+
+    fn apply_deltas(&mut self, chunk_key: Point3i, deltas: &FnvHashMap<usize, i64>) {
+        let mut counts = self
+            .chunk_type_counts
+            .remove(&chunk_key)
+            .unwrap_or_default();
+        for (&type_index, &delta) in deltas.iter() {
+            let count = counts.entry(type_index).or_insert(0);
+            *count = (*count as i64 + delta).max(0) as u64;
+        }
+        counts.retain(|_, count| *count > 0);
+        self.add_chunk(chunk_key, counts);
+    }
+}
+
+fn count_type_deltas_system<V>(mut edit_buffer: ResMut<EditBuffer<V>>)
+where
+    V: Voxel,
+{
+    edit_buffer.count_type_deltas();
+}
+
+fn map_stats_system<V>(
+    pool: Res<VoxelTaskPool>,
+    voxel_map: Res<VoxelMap<V>>,
+    local_caches: Res<ThreadLocalVoxelCache<V>>,
+    dirty_chunks: Res<DirtyChunks<V>>,
+    empty_chunks: Res<EmptyChunks<V>>,
+    mut stats: ResMut<MapStats<V>>,
+) where
+    V: Voxel,
+    for<'r> &'r V::TypeInfo: IsEmpty,
+{
+    let stats = &mut *stats;
+
+    stats.chunks = voxel_map.stats();
+
+    let mut to_scan = Vec::new();
+    if !stats.initialized {
+        // The scan already sees this frame's edits.
+        stats.initialized = true;
+        to_scan.extend(voxel_map.voxels.storage().chunk_keys().cloned());
+        to_scan.extend(
+            voxel_map
+                .uniform_chunks
+                .iter()
+                .map(|(chunk_key, _)| *chunk_key),
+        );
+    } else {
+        if dirty_chunks.edited_chunk_keys.is_empty()
+            && empty_chunks.removed_chunk_keys().next().is_none()
+        {
+            return;
+        }
+        for chunk_key in empty_chunks.removed_chunk_keys() {
+            stats.remove_chunk(chunk_key);
+        }
+        for &chunk_key in dirty_chunks.edited_chunk_keys.iter() {
+            match dirty_chunks.chunk_edits.get(&chunk_key) {
+                Some(edits)
+                    if !edits.replaced && stats.chunk_type_counts.contains_key(&chunk_key) =>
+                {
+                    stats.apply_deltas(chunk_key, &edits.type_deltas)
+                }
+                _ => to_scan.push(chunk_key),
+            }
+        }
+    }
+
+    let map = &*voxel_map;
+    let local_caches = &*local_caches;
+    let scanned = map_in_pool(&*pool, to_scan.into_iter(), |chunk_key| {
+        let cache_tls = local_caches.get();
+        let reader = map.reader(&cache_tls);
+        let extent = reader.indexer.extent_for_chunk_at_key(chunk_key);
+        let mut counts: FnvHashMap<usize, u64> = FnvHashMap::default();
+        if let Some(value) = map.uniform_chunks.get(&chunk_key) {
+            counts.insert(value.get_type_index(), extent.num_points() as u64);
+
+            return (chunk_key, Some(counts));
+        }
+        if reader.get_chunk(chunk_key).is_none() {
+            return (chunk_key, None);
+        }
+        reader.for_each(&extent, |_p: Point3i, voxel: V| {
+            *counts.entry(voxel.get_type_index()).or_insert(0) += 1;
+        });
+
+        (chunk_key, Some(counts))
+    });
+    for (chunk_key, counts) in scanned.into_iter() {
+        match counts {
+            Some(counts) => stats.add_chunk(chunk_key, counts),
+            // The chunk was already removed.
+            None => stats.remove_chunk(&chunk_key),
+        }
+    }
+
+    let palette = &voxel_map.palette;
+    let indexer = &voxel_map.voxels.indexer;
+    stats.occupied_bounds = bounding_extent(
+        stats
+            .chunk_type_counts
+            .iter()
+            .filter(|(_, counts)| {
+                counts.iter().any(|(&type_index, &count)| {
+                    count > 0
+                        && palette
+                            .infos
+                            .get(type_index)
+                            .map_or(true, |info| !info.is_empty())
+                })
+            })
+            .map(|(chunk_key, _)| indexer.extent_for_chunk_at_key(*chunk_key)),
+    );
+}
@@ -0,0 +1,98 @@
+/// Standard material metadata for the entries of a `VoxelPalette`, so meshers can shade voxel types
+/// without a bespoke bridge for every palette. Implement it for `Voxel::TypeInfo`; every method has
+/// a default, so only the relevant ones need to be overridden.
+///
+/// ```
+/// use bevy_building_blocks::{FaceTiles, VoxelMaterial};
+///
+/// struct BlockInfo {
+///     name: &'static str,
+///     tiles: FaceTiles,
+/// }
+///
+/// impl VoxelMaterial for BlockInfo {
+///     fn material_name(&self) -> Option<String> {
+///         Some(self.name.to_string())
+///     }
+///
+///     fn face_tiles(&self) -> Option<FaceTiles> {
+///         Some(self.tiles)
+///     }
+/// }
+///
+/// let grass = BlockInfo {
+///     name: "grass",
+///     tiles: FaceTiles::top_bottom_sides(0, 2, 1),
+/// };
+/// assert_eq!(grass.face_tiles().unwrap().tiles, [1, 1, 2, 0, 1, 1]);
+/// ```
+pub trait VoxelMaterial {
+    /// Falls back to a name derived from the voxel type index.
+    fn material_name(&self) -> Option<String> {
+        None
+    }
+
+    /// Linear RGBA. Multiplies the texture, if there is one.
+    fn base_color(&self) -> [f32; 4] {
+        [1.0; 4]
+    }
+
+    /// The tiles of a `TextureAtlasLayout` to draw on each face. `None` means untextured.
+    fn face_tiles(&self) -> Option<FaceTiles> {
+        None
+    }
+
+    /// Transparent voxels are alpha blended, and the faces of other voxels behind them are kept.
+    fn is_transparent(&self) -> bool {
+        false
+    }
+
+    /// Linear RGB light emitted by the surface.
+    fn emissive(&self) -> [f32; 3] {
+        [0.0; 3]
+    }
+}
+
+/// Texture atlas tile indices for the faces of a voxel, in the order -X, +X, -Y, +Y, -Z, +Z.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct FaceTiles {
+    pub tiles: [u32; 6],
+}
+
+impl FaceTiles {
+    pub fn all(tile: u32) -> Self {
+        Self { tiles: [tile; 6] }
+    }
+
+    pub fn top_bottom_sides(top: u32, bottom: u32, sides: u32) -> Self {
+        Self {
+            tiles: [sides, sides, bottom, top, sides, sides],
+        }
+    }
+
+    /// The tile of the face whose normal points along `axis` (0, 1, or 2 for X, Y, or Z), in the
+    /// positive direction if `positive`.
+    pub fn face_tile(&self, axis: usize, positive: bool) -> u32 {
+        self.tiles[2 * axis + positive as usize]
+    }
+}
+
+/// A texture atlas made of equally sized tiles, indexed row by row from the top left.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TextureAtlasLayout {
+    pub columns: u32,
+    pub rows: u32,
+    /// Where exported meshes find the atlas image, relative to the mesh file.
+    pub image_uri: String,
+}
+
+impl TextureAtlasLayout {
+    /// The UV coordinates of the top left and bottom right corners of `tile`, with V pointing down.
+    pub fn tile_uv_rect(&self, tile: u32) -> ([f32; 2], [f32; 2]) {
+        let (column, row) = (tile % self.columns, tile / self.columns);
+        let (width, height) = (1.0 / self.columns as f32, 1.0 / self.rows as f32);
+        let min = [column as f32 * width, row as f32 * height];
+
+        (min, [min[0] + width, min[1] + height])
+    }
+}
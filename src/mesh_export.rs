@@ -0,0 +1,756 @@
+use crate::{
+    tasks::spawn_detached, FaceTiles, TextureAtlasLayout, ThreadLocalVoxelCache, Voxel, VoxelMap,
+    VoxelMaterial, VoxelTaskPool,
+};
+
+use bevy::prelude::*;
+use building_blocks::prelude::*;
+use fnv::FnvHashMap;
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+/// Meshes extents of the `VoxelMap` and writes them to OBJ or binary glTF files, for use in DCC
+/// tools or static bake pipelines. Depends on the `MapIoPlugin`.
+///
+/// Send a `MeshExportRequest` event to start an export. The voxels are copied out of the map on the
+/// frame of the request, then meshed and written on the `VoxelTaskPool`. A `MeshExportFinished`
+/// event is sent when the file has been written.
+///
+/// Each voxel type gets a material from the `VoxelMaterial` implementation of its palette entry, so
+/// the map's `VoxelPalette` decides how the exported mesh is shaded. With `with_atlas`, greedy quad
+/// meshes also get UVs for the `FaceTiles` of each voxel type.
+pub struct MeshExportPlugin<V> {
+    pub atlas: Option<TextureAtlasLayout>,
+    marker: std::marker::PhantomData<V>,
+}
+
+impl<V> Default for MeshExportPlugin<V> {
+    fn default() -> Self {
+        Self {
+            atlas: None,
+            marker: Default::default(),
+        }
+    }
+}
+
+impl<V> MeshExportPlugin<V> {
+    pub fn with_atlas(mut self, atlas: TextureAtlasLayout) -> Self {
+        self.atlas = Some(atlas);
+
+        self
+    }
+}
+
+impl<V> Plugin for MeshExportPlugin<V>
+where
+    V: Voxel,
+    V::TypeInfo: VoxelMaterial,
+    for<'r> &'r V::TypeInfo: IsEmpty,
+{
+    fn build(&self, app: &mut AppBuilder) {
+        app.add_event::<MeshExportRequest>()
+            .add_event::<MeshExportFinished>()
+            .insert_resource(MeshExportConfig::<V> {
+                atlas: self.atlas.clone().map(Arc::new),
+                marker: Default::default(),
+            })
+            .insert_resource(FinishedExports::default())
+            .add_system(mesh_export_system::<V>.system());
+    }
+}
+
+struct MeshExportConfig<V> {
+    atlas: Option<Arc<TextureAtlasLayout>>,
+    marker: std::marker::PhantomData<V>,
+}
+
+/// A request to mesh every voxel in `extent` and write the mesh to `path`. Voxels outside of the
+/// extent are treated as empty, so the mesh is closed.
+#[derive(Clone, Debug)]
+pub struct MeshExportRequest {
+    pub extent: Extent3i,
+    pub path: PathBuf,
+    pub mesher: ExportMesher,
+    pub format: MeshExportFormat,
+}
+
+/// Sent when an export has been written, or failed to write.
+#[derive(Debug)]
+pub struct MeshExportFinished {
+    pub path: PathBuf,
+    pub result: io::Result<()>,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ExportMesher {
+    /// Blocky faces, merged into the largest rectangles of the same material.
+    GreedyQuads,
+    /// A smooth surface with one vertex per surface cell. Each face takes the material of the
+    /// solid voxel behind it. Transparency and textures are ignored when placing faces.
+    SurfaceNets,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MeshExportFormat {
+    /// A Wavefront OBJ file, with materials written to an MTL file next to it.
+    Obj,
+    /// A binary glTF 2.0 file.
+    Glb,
+}
+
+#[derive(Clone, Debug)]
+pub struct MeshMaterial {
+    pub name: String,
+    /// Linear RGBA.
+    pub base_color: [f32; 4],
+    pub face_tiles: Option<FaceTiles>,
+    pub transparent: bool,
+    /// Linear RGB.
+    pub emissive: [f32; 3],
+}
+
+impl MeshMaterial {
+    pub fn from_voxel_material<M>(material: &M, type_index: usize) -> Self
+    where
+        M: VoxelMaterial + ?Sized,
+    {
+        Self {
+            name: material
+                .material_name()
+                .unwrap_or_else(|| format!("voxel_type_{}", type_index)),
+            base_color: material.base_color(),
+            face_tiles: material.face_tiles(),
+            transparent: material.is_transparent(),
+            emissive: material.emissive(),
+        }
+    }
+}
+
+/// A mesh with one index group per voxel type.
+#[derive(Clone, Debug, Default)]
+pub struct ExportMesh {
+    pub positions: Vec<[f32; 3]>,
+    pub normals: Vec<[f32; 3]>,
+    /// Texture coordinates into the `texture_uri` image, with V pointing down. Empty unless the
+    /// mesh was generated with a `TextureAtlasLayout`.
+    pub uvs: Vec<[f32; 2]>,
+    /// Triangle indices, grouped by voxel type index and sorted by type index.
+    pub groups: Vec<(usize, Vec<u32>)>,
+    /// The image sampled by textured materials.
+    pub texture_uri: Option<String>,
+}
+
+impl ExportMesh {
+    pub fn is_empty(&self) -> bool {
+        self.groups.is_empty()
+    }
+
+    /// Meshes `types`, which holds the voxel type index plus one for every solid voxel, and zero for
+    /// every empty voxel.
+    pub fn generate(types: &Array3<u32>, mesher: ExportMesher) -> Self {
+        Self::generate_with_materials(types, mesher, &[], None)
+    }
+
+    /// Like `generate`, but shaded by `materials`, which is indexed by voxel type index. The faces
+    /// of voxels behind a transparent voxel of another type are kept. With an `atlas`, greedy quads
+    /// get UVs for the `FaceTiles` of their material. Textured faces aren't merged, since a tile
+    /// can't repeat across a larger quad in an atlas.
+    pub fn generate_with_materials(
+        types: &Array3<u32>,
+        mesher: ExportMesher,
+        materials: &[MeshMaterial],
+        atlas: Option<&TextureAtlasLayout>,
+    ) -> Self {
+        match mesher {
+            ExportMesher::GreedyQuads => greedy_quads(types, materials, atlas),
+            ExportMesher::SurfaceNets => surface_nets(types),
+        }
+    }
+
+    /// `materials` is indexed by voxel type index.
+    pub fn write(
+        &self,
+        materials: &[MeshMaterial],
+        format: MeshExportFormat,
+        path: &Path,
+    ) -> io::Result<()> {
+        match format {
+            MeshExportFormat::Obj => self.write_obj(materials, path),
+            MeshExportFormat::Glb => self.write_glb(materials, path),
+        }
+    }
+
+    fn write_obj(&self, materials: &[MeshMaterial], path: &Path) -> io::Result<()> {
+        let mtl_path = path.with_extension("mtl");
+
+        let mut mtl = BufWriter::new(File::create(&mtl_path)?);
+        for (type_index, _) in self.groups.iter() {
+            let material = &materials[*type_index];
+            let [r, g, b, a] = material.base_color;
+            writeln!(mtl, "newmtl {}", material.name)?;
+            writeln!(mtl, "Kd {} {} {}", r, g, b)?;
+            writeln!(mtl, "d {}", a)?;
+            let [er, eg, eb] = material.emissive;
+            writeln!(mtl, "Ke {} {} {}", er, eg, eb)?;
+            if let (Some(_), Some(uri)) = (material.face_tiles, &self.texture_uri) {
+                writeln!(mtl, "map_Kd {}", uri)?;
+            }
+        }
+        mtl.flush()?;
+
+        let mut obj = BufWriter::new(File::create(path)?);
+        if let Some(mtl_name) = mtl_path.file_name() {
+            writeln!(obj, "mtllib {}", mtl_name.to_string_lossy())?;
+        }
+        for [x, y, z] in self.positions.iter() {
+            writeln!(obj, "v {} {} {}", x, y, z)?;
+        }
+        for [x, y, z] in self.normals.iter() {
+            writeln!(obj, "vn {} {} {}", x, y, z)?;
+        }
+        // OBJ texture coordinates have V pointing up.
+        for [u, v] in self.uvs.iter() {
+            writeln!(obj, "vt {} {}", u, 1.0 - v)?;
+        }
+        let has_uvs = !self.uvs.is_empty();
+        for (type_index, indices) in self.groups.iter() {
+            writeln!(obj, "usemtl {}", materials[*type_index].name)?;
+            for triangle in indices.chunks_exact(3) {
+                // OBJ indices start at 1.
+                let [a, b, c] = [triangle[0] + 1, triangle[1] + 1, triangle[2] + 1];
+                if has_uvs {
+                    writeln!(obj, "f {0}/{0}/{0} {1}/{1}/{1} {2}/{2}/{2}", a, b, c)?;
+                } else {
+                    writeln!(obj, "f {0}//{0} {1}//{1} {2}//{2}", a, b, c)?;
+                }
+            }
+        }
+
+        obj.flush()
+    }
+
+    fn write_glb(&self, materials: &[MeshMaterial], path: &Path) -> io::Result<()> {
+        let mut bin = Vec::new();
+        for p in self.positions.iter().chain(self.normals.iter()) {
+            for c in p.iter() {
+                bin.extend_from_slice(&c.to_le_bytes());
+            }
+        }
+        for uv in self.uvs.iter() {
+            for c in uv.iter() {
+                bin.extend_from_slice(&c.to_le_bytes());
+            }
+        }
+        let mut index_offsets = Vec::new();
+        for (_, indices) in self.groups.iter() {
+            index_offsets.push(bin.len());
+            for i in indices.iter() {
+                bin.extend_from_slice(&i.to_le_bytes());
+            }
+        }
+
+        let json = if self.is_empty() {
+            r#"{"asset":{"version":"2.0"},"scene":0,"scenes":[{"nodes":[]}]}"#.to_string()
+        } else {
+            self.gltf_json(materials, bin.len(), &index_offsets)
+        };
+        let mut json = json.into_bytes();
+        // Chunks must be 4-byte aligned. The JSON chunk is padded with spaces.
+        while json.len() % 4 != 0 {
+            json.push(b' ');
+        }
+
+        let has_bin = !bin.is_empty();
+        let total_length = 12 + 8 + json.len() + if has_bin { 8 + bin.len() } else { 0 };
+
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(b"glTF")?;
+        file.write_all(&2u32.to_le_bytes())?;
+        file.write_all(&(total_length as u32).to_le_bytes())?;
+        file.write_all(&(json.len() as u32).to_le_bytes())?;
+        file.write_all(b"JSON")?;
+        file.write_all(&json)?;
+        if has_bin {
+            file.write_all(&(bin.len() as u32).to_le_bytes())?;
+            file.write_all(b"BIN\0")?;
+            file.write_all(&bin)?;
+        }
+
+        file.flush()
+    }
+
+    fn gltf_json(
+        &self,
+        materials: &[MeshMaterial],
+        bin_length: usize,
+        index_offsets: &[usize],
+    ) -> String {
+        const FLOAT: u32 = 5126;
+        const UNSIGNED_INT: u32 = 5125;
+        const ARRAY_BUFFER: u32 = 34962;
+        const ELEMENT_ARRAY_BUFFER: u32 = 34963;
+        // The atlas is sampled with nearest filtering, so texels stay sharp.
+        const NEAREST: u32 = 9728;
+
+        let num_vertices = self.positions.len();
+        let vec3_bytes = 12 * num_vertices;
+        let mut min = [f32::MAX; 3];
+        let mut max = [f32::MIN; 3];
+        for p in self.positions.iter() {
+            for ((min, max), c) in min.iter_mut().zip(max.iter_mut()).zip(p.iter()) {
+                *min = min.min(*c);
+                *max = max.max(*c);
+            }
+        }
+
+        let mut buffer_views = vec![
+            format!(
+                r#"{{"buffer":0,"byteOffset":0,"byteLength":{},"target":{}}}"#,
+                vec3_bytes, ARRAY_BUFFER
+            ),
+            format!(
+                r#"{{"buffer":0,"byteOffset":{},"byteLength":{},"target":{}}}"#,
+                vec3_bytes, vec3_bytes, ARRAY_BUFFER
+            ),
+        ];
+        let mut accessors = vec![
+            format!(
+                r#"{{"bufferView":0,"componentType":{},"count":{},"type":"VEC3","min":[{},{},{}],"max":[{},{},{}]}}"#,
+                FLOAT, num_vertices, min[0], min[1], min[2], max[0], max[1], max[2]
+            ),
+            format!(
+                r#"{{"bufferView":1,"componentType":{},"count":{},"type":"VEC3"}}"#,
+                FLOAT, num_vertices
+            ),
+        ];
+        let mut attributes = r#""POSITION":0,"NORMAL":1"#.to_string();
+        if !self.uvs.is_empty() {
+            buffer_views.push(format!(
+                r#"{{"buffer":0,"byteOffset":{},"byteLength":{},"target":{}}}"#,
+                2 * vec3_bytes,
+                8 * num_vertices,
+                ARRAY_BUFFER
+            ));
+            accessors.push(format!(
+                r#"{{"bufferView":2,"componentType":{},"count":{},"type":"VEC2"}}"#,
+                FLOAT, num_vertices
+            ));
+            attributes.push_str(r#","TEXCOORD_0":2"#);
+        }
+        let mut primitives = Vec::new();
+        let mut gltf_materials = Vec::new();
+        for (group, ((type_index, indices), offset)) in
+            self.groups.iter().zip(index_offsets.iter()).enumerate()
+        {
+            let view = buffer_views.len();
+            buffer_views.push(format!(
+                r#"{{"buffer":0,"byteOffset":{},"byteLength":{},"target":{}}}"#,
+                offset,
+                4 * indices.len(),
+                ELEMENT_ARRAY_BUFFER
+            ));
+            let accessor = accessors.len();
+            accessors.push(format!(
+                r#"{{"bufferView":{},"componentType":{},"count":{},"type":"SCALAR"}}"#,
+                view,
+                UNSIGNED_INT,
+                indices.len()
+            ));
+            primitives.push(format!(
+                r#"{{"attributes":{{{}}},"indices":{},"material":{}}}"#,
+                attributes, accessor, group
+            ));
+            let material = &materials[*type_index];
+            let [r, g, b, a] = material.base_color;
+            let texture = match (material.face_tiles, &self.texture_uri) {
+                (Some(_), Some(_)) => r#","baseColorTexture":{"index":0}"#,
+                _ => "",
+            };
+            let [er, eg, eb] = material.emissive;
+            let alpha_mode = if material.transparent {
+                "BLEND"
+            } else {
+                "OPAQUE"
+            };
+            gltf_materials.push(format!(
+                r#"{{"name":"{}","pbrMetallicRoughness":{{"baseColorFactor":[{},{},{},{}]{},"metallicFactor":0,"roughnessFactor":1}},"emissiveFactor":[{},{},{}],"alphaMode":"{}"}}"#,
+                escape_json(&material.name),
+                r,
+                g,
+                b,
+                a,
+                texture,
+                er,
+                eg,
+                eb,
+                alpha_mode
+            ));
+        }
+
+        let textures = match &self.texture_uri {
+            Some(uri) => format!(
+                r#","textures":[{{"source":0,"sampler":0}}],"images":[{{"uri":"{}"}}],"samplers":[{{"magFilter":{},"minFilter":{}}}]"#,
+                escape_json(uri),
+                NEAREST,
+                NEAREST
+            ),
+            None => String::new(),
+        };
+
+        format!(
+            r#"{{"asset":{{"version":"2.0"}},"scene":0,"scenes":[{{"nodes":[0]}}],"nodes":[{{"mesh":0}}],"meshes":[{{"primitives":[{}]}}],"materials":[{}]{},"accessors":[{}],"bufferViews":[{}],"buffers":[{{"byteLength":{}}}]}}"#,
+            primitives.join(","),
+            gltf_materials.join(","),
+            textures,
+            accessors.join(","),
+            buffer_views.join(","),
+            bin_length
+        )
+    }
+}
+
+fn escape_json(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+#[derive(Default)]
+struct MeshBuilder {
+    mesh: ExportMesh,
+    groups: FnvHashMap<usize, Vec<u32>>,
+    with_uvs: bool,
+}
+
+impl MeshBuilder {
+    fn push_vertex(&mut self, position: [f32; 3], normal: [f32; 3], uv: [f32; 2]) -> u32 {
+        self.mesh.positions.push(position);
+        self.mesh.normals.push(normal);
+        if self.with_uvs {
+            self.mesh.uvs.push(uv);
+        }
+
+        (self.mesh.positions.len() - 1) as u32
+    }
+
+    /// Pushes two triangles for a quad whose corners are counter-clockwise when viewed from the
+    /// front, or clockwise if `!front_is_ccw`.
+    fn push_quad(&mut self, type_index: usize, corners: [u32; 4], front_is_ccw: bool) {
+        let [a, b, c, d] = corners;
+        let indices = self.groups.entry(type_index).or_insert_with(Vec::new);
+        if front_is_ccw {
+            indices.extend_from_slice(&[a, b, c, a, c, d]);
+        } else {
+            indices.extend_from_slice(&[a, c, b, a, d, c]);
+        }
+    }
+
+    fn build(self) -> ExportMesh {
+        let mut mesh = self.mesh;
+        mesh.groups = self.groups.into_iter().collect();
+        mesh.groups.sort_by_key(|(type_index, _)| *type_index);
+
+        mesh
+    }
+}
+
+/// The type of the voxel at `p` plus one, or zero if it's empty or outside of the array.
+fn type_at(types: &Array3<u32>, p: Point3i) -> u32 {
+    if types.extent().contains(&p) {
+        types.get(&p)
+    } else {
+        0
+    }
+}
+
+fn greedy_quads(
+    types: &Array3<u32>,
+    materials: &[MeshMaterial],
+    atlas: Option<&TextureAtlasLayout>,
+) -> ExportMesh {
+    let extent = *types.extent();
+    let min = extent.minimum.0;
+    let shape = extent.shape.0;
+
+    let material = |t: u32| materials.get(t as usize - 1);
+    let is_transparent = |t: u32| t != 0 && material(t).map_or(false, |m| m.transparent);
+
+    let mut builder = MeshBuilder {
+        with_uvs: atlas.is_some(),
+        ..Default::default()
+    };
+    builder.mesh.texture_uri = atlas.map(|atlas| atlas.image_uri.clone());
+    for a in 0..3 {
+        // (u, v, a) is a right-handed basis, so counter-clockwise quads in the (u, v) plane face +a.
+        let (u, v) = ((a + 1) % 3, (a + 2) % 3);
+        // The texture axes of a face, with the second pointing up the tile. Side faces are upright.
+        let (s, t_axis) = match a {
+            0 => (2, 1),
+            1 => (0, 2),
+            _ => (0, 1),
+        };
+        let (size_u, size_v) = (shape[u] as usize, shape[v] as usize);
+        for &sign in [-1, 1].iter() {
+            let mut normal = [0.0; 3];
+            normal[a] = sign as f32;
+            let mut mask = vec![0; size_u * size_v];
+            for d in 0..shape[a] {
+                // Find the visible faces in this slice.
+                for j in 0..size_v {
+                    for i in 0..size_u {
+                        let mut p = [0; 3];
+                        p[a] = min[a] + d;
+                        p[u] = min[u] + i as i32;
+                        p[v] = min[v] + j as i32;
+                        let t = type_at(types, PointN(p));
+                        p[a] += sign;
+                        let behind = type_at(types, PointN(p));
+                        let visible = behind == 0 || (behind != t && is_transparent(behind));
+                        mask[i + j * size_u] = if t != 0 && visible { t } else { 0 };
+                    }
+                }
+
+                // Merge them into rectangles.
+                let plane = (min[a] + d + if sign > 0 { 1 } else { 0 }) as f32;
+                for j in 0..size_v {
+                    for i in 0..size_u {
+                        let t = mask[i + j * size_u];
+                        if t == 0 {
+                            continue;
+                        }
+                        let tile_rect = atlas.and_then(|atlas| {
+                            let tiles = material(t)?.face_tiles?;
+
+                            Some(atlas.tile_uv_rect(tiles.face_tile(a, sign > 0)))
+                        });
+                        let max_size = if tile_rect.is_some() { 1 } else { usize::MAX };
+                        let mut w = 1;
+                        while w < max_size && i + w < size_u && mask[i + w + j * size_u] == t {
+                            w += 1;
+                        }
+                        let mut h = 1;
+                        while h < max_size && j + h < size_v {
+                            let row = i + (j + h) * size_u;
+                            if !mask[row..row + w].iter().all(|m| *m == t) {
+                                break;
+                            }
+                            h += 1;
+                        }
+                        for k in 0..h {
+                            let row = i + (j + k) * size_u;
+                            for m in mask[row..row + w].iter_mut() {
+                                *m = 0;
+                            }
+                        }
+
+                        let mut corner = |du: usize, dv: usize| {
+                            let mut position = [0.0; 3];
+                            position[a] = plane;
+                            position[u] = (min[u] + (i + du) as i32) as f32;
+                            position[v] = (min[v] + (j + dv) as i32) as f32;
+                            let uv = tile_rect.map_or([0.0; 2], |(uv_min, uv_max)| {
+                                // Textured quads are a single voxel, so these are 0 or 1.
+                                let along = |axis: usize| (if axis == u { du } else { dv }) as f32;
+                                let (fs, ft) = (along(s), along(t_axis));
+
+                                [
+                                    uv_min[0] + fs * (uv_max[0] - uv_min[0]),
+                                    uv_max[1] - ft * (uv_max[1] - uv_min[1]),
+                                ]
+                            });
+
+                            builder.push_vertex(position, normal, uv)
+                        };
+                        let corners = [corner(0, 0), corner(w, 0), corner(w, h), corner(0, h)];
+                        builder.push_quad(t as usize - 1, corners, sign > 0);
+                    }
+                }
+            }
+        }
+    }
+
+    builder.build()
+}
+
+fn surface_nets(types: &Array3<u32>) -> ExportMesh {
+    let extent = *types.extent();
+    // Each cell is the cube between the centers of 8 voxels, and it's keyed by its minimum voxel.
+    // Cells on the border straddle the empty voxels around the extent.
+    let cells = Extent3i::from_min_and_max(extent.minimum - PointN([1; 3]), extent.max());
+    let corner_offsets: Vec<[i32; 3]> = (0..8)
+        .map(|k| [k & 1, (k >> 1) & 1, (k >> 2) & 1])
+        .collect();
+
+    let mut builder = MeshBuilder::default();
+    let mut cell_vertices = FnvHashMap::default();
+    for_each_point(&cells, |cell| {
+        let solid: Vec<bool> = corner_offsets
+            .iter()
+            .map(|o| type_at(types, cell + PointN(*o)) != 0)
+            .collect();
+        if solid.iter().all(|s| *s) || !solid.iter().any(|s| *s) {
+            return;
+        }
+
+        // Place the vertex at the average of the edge crossings, which are the edge midpoints for
+        // binary occupancy.
+        let mut sum = [0.0; 3];
+        let mut num_crossings = 0.0;
+        for (k, o1) in corner_offsets.iter().enumerate() {
+            for &bit in [1, 2, 4].iter() {
+                if k & bit != 0 || solid[k] == solid[k | bit] {
+                    continue;
+                }
+                let o2 = &corner_offsets[k | bit];
+                for ((s, c1), c2) in sum.iter_mut().zip(o1.iter()).zip(o2.iter()) {
+                    *s += (c1 + c2) as f32 / 2.0;
+                }
+                num_crossings += 1.0;
+            }
+        }
+        // The gradient of emptiness points out of the surface.
+        let mut normal = [0.0f32; 3];
+        for (o, s) in corner_offsets.iter().zip(solid.iter()) {
+            let weight = if *s { -1.0 } else { 1.0 };
+            for (n, c) in normal.iter_mut().zip(o.iter()) {
+                *n += weight * (*c as f32 - 0.5);
+            }
+        }
+        let length = normal.iter().map(|n| n * n).sum::<f32>().sqrt();
+        if length > 0.0 {
+            for n in normal.iter_mut() {
+                *n /= length;
+            }
+        }
+        let mut position = [0.0; 3];
+        for ((p, c), s) in position.iter_mut().zip(cell.0.iter()).zip(sum.iter()) {
+            // Voxel centers are offset by half a voxel from their minimum corners.
+            *p = *c as f32 + 0.5 + s / num_crossings;
+        }
+        cell_vertices.insert(cell, builder.push_vertex(position, normal, [0.0; 2]));
+    });
+
+    // Every edge between a solid and an empty voxel center is crossed by a quad connecting the 4
+    // cells around the edge.
+    for_each_point(&cells, |p1| {
+        for a in 0..3 {
+            let (u, v) = ((a + 1) % 3, (a + 2) % 3);
+            let mut p2 = p1;
+            p2.0[a] += 1;
+            let (t1, t2) = (type_at(types, p1), type_at(types, p2));
+            if (t1 == 0) == (t2 == 0) {
+                continue;
+            }
+            let mut offset_u = PointN([0; 3]);
+            offset_u.0[u] = 1;
+            let mut offset_v = PointN([0; 3]);
+            offset_v.0[v] = 1;
+            let quad_cells = [p1, p1 - offset_u, p1 - offset_u - offset_v, p1 - offset_v];
+            let mut corners = [0; 4];
+            let mut complete = true;
+            for (corner, cell) in corners.iter_mut().zip(quad_cells.iter()) {
+                match cell_vertices.get(cell) {
+                    Some(vertex) => *corner = *vertex,
+                    None => complete = false,
+                }
+            }
+            if !complete {
+                continue;
+            }
+            // The quad faces away from the solid voxel.
+            let (t, front_is_ccw) = if t1 != 0 { (t1, true) } else { (t2, false) };
+            builder.push_quad(t as usize - 1, corners, front_is_ccw);
+        }
+    });
+
+    builder.build()
+}
+
+fn for_each_point(extent: &Extent3i, mut f: impl FnMut(Point3i)) {
+    let max = extent.max();
+    for z in extent.minimum.z()..=max.z() {
+        for y in extent.minimum.y()..=max.y() {
+            for x in extent.minimum.x()..=max.x() {
+                f(PointN([x, y, z]));
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct FinishedExports(Arc<Mutex<Vec<MeshExportFinished>>>);
+
+fn mesh_export_system<V>(
+    pool: Res<VoxelTaskPool>,
+    config: Res<MeshExportConfig<V>>,
+    voxel_map: Res<VoxelMap<V>>,
+    local_caches: Res<ThreadLocalVoxelCache<V>>,
+    requests: Res<Events<MeshExportRequest>>,
+    mut request_reader: Local<EventReader<MeshExportRequest>>,
+    finished: Res<FinishedExports>,
+    mut finished_events: ResMut<Events<MeshExportFinished>>,
+) where
+    V: Voxel,
+    V::TypeInfo: VoxelMaterial,
+    for<'r> &'r V::TypeInfo: IsEmpty,
+{
+    for event in finished.0.lock().unwrap().drain(..) {
+        finished_events.send(event);
+    }
+
+    let mut requests = request_reader.iter(&requests).peekable();
+    if requests.peek().is_none() {
+        return;
+    }
+
+    let materials: Arc<Vec<MeshMaterial>> = Arc::new(
+        voxel_map
+            .palette
+            .infos
+            .iter()
+            .enumerate()
+            .map(|(type_index, info)| MeshMaterial::from_voxel_material(info, type_index))
+            .collect(),
+    );
+    let tls = local_caches.get();
+    let reader = voxel_map.reader(&tls);
+    for request in requests {
+        // Copy the voxels now, so the export sees the map as it was on this frame.
+        let mut types = Array3::fill(request.extent, 0u32);
+        reader.for_each(&request.extent, |p: Point3i, voxel: V| {
+            if !voxel_map.palette.get_voxel_type_info(voxel).is_empty() {
+                *types.get_mut(&p) = voxel.get_type_index() as u32 + 1;
+            }
+        });
+
+        let request = request.clone();
+        let materials = materials.clone();
+        let atlas = config.atlas.clone();
+        let finished = finished.0.clone();
+        spawn_detached(&*pool, move || {
+            let mesh = ExportMesh::generate_with_materials(
+                &types,
+                request.mesher,
+                &materials,
+                atlas.as_deref(),
+            );
+            let result = mesh.write(&materials, request.format, &request.path);
+            finished.lock().unwrap().push(MeshExportFinished {
+                path: request.path,
+                result,
+            });
+        });
+    }
+}
@@ -0,0 +1,657 @@
+//! Import of Minecraft worlds saved in the Anvil region format (Minecraft 1.13 and later).
+
+use crate::{Voxel, VoxelEditor};
+
+use building_blocks::prelude::*;
+use flate2::read::{GzDecoder, ZlibDecoder};
+use fnv::FnvHashMap;
+use std::{
+    convert::TryInto,
+    fs,
+    io::{self, Read},
+    path::Path,
+};
+
+/// A block type and its properties, e.g. `minecraft:oak_stairs` with `facing=north`.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct BlockState {
+    pub name: String,
+    /// Sorted by property name.
+    pub properties: Vec<(String, String)>,
+}
+
+impl BlockState {
+    pub fn property(&self, name: &str) -> Option<&str> {
+        self.properties
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// A 16x16x16 section of a Minecraft chunk.
+#[derive(Clone, Debug)]
+pub struct AnvilSection {
+    /// The minimum voxel of the section, in world coordinates.
+    pub minimum: Point3i,
+    pub palette: Vec<BlockState>,
+    /// An index into the `palette` for every block, with X varying fastest, then Z, then Y.
+    pub blocks: Vec<u16>,
+}
+
+pub const SECTION_SHAPE: Point3i = PointN([16; 3]);
+
+impl AnvilSection {
+    pub fn extent(&self) -> Extent3i {
+        Extent3i::from_min_and_shape(self.minimum, SECTION_SHAPE)
+    }
+
+    /// Converts the section to voxels, calling `map_block` once for each entry of the palette.
+    pub fn to_array<V>(&self, mut map_block: impl FnMut(&BlockState) -> V) -> Array3<V>
+    where
+        V: Voxel,
+    {
+        let voxel_palette: Vec<V> = self.palette.iter().map(&mut map_block).collect();
+        let extent = self.extent();
+        let mut array = Array3::fill(extent, V::default());
+        array.for_each_mut(&extent, |p: Point3i, voxel: &mut V| {
+            let local = p - self.minimum;
+            let i = (local.x() + 16 * (local.z() + 16 * local.y())) as usize;
+            *voxel = voxel_palette[self.blocks[i] as usize];
+        });
+
+        array
+    }
+}
+
+/// Reads every section of every chunk in the Anvil region file at `path`.
+pub fn read_anvil_region(path: impl AsRef<Path>) -> io::Result<Vec<AnvilSection>> {
+    let bytes = fs::read(path)?;
+    if bytes.len() < 8192 {
+        return Err(invalid_data("region file is missing its header"));
+    }
+
+    let mut sections = Vec::new();
+    for location in bytes[..4096].chunks_exact(4) {
+        let sector_offset = u32::from_be_bytes([0, location[0], location[1], location[2]]) as usize;
+        if sector_offset == 0 {
+            continue;
+        }
+        let start = sector_offset * 4096;
+        let header = bytes
+            .get(start..start + 5)
+            .ok_or_else(|| invalid_data("chunk is outside of the region file"))?;
+        let len = u32::from_be_bytes(header[0..4].try_into().unwrap()) as usize;
+        let compressed = bytes
+            .get(start + 5..start + 4 + len)
+            .ok_or_else(|| invalid_data("chunk is truncated"))?;
+        let chunk_bytes = decompress_chunk(header[4], compressed)?;
+        read_chunk_sections(&chunk_bytes, &mut sections)?;
+    }
+
+    Ok(sections)
+}
+
+/// Reads the Anvil region file at `path` and inserts all of its blocks into the map, converting
+/// them with `map_block`. Returns the number of sections imported.
+///
+/// If the map's chunks are 16x16x16, each section is inserted as a whole chunk. Otherwise the
+/// sections are written with `VoxelEditor::edit_extent`.
+pub fn import_anvil_region<V>(
+    path: impl AsRef<Path>,
+    mut map_block: impl FnMut(&BlockState) -> V,
+    editor: &mut VoxelEditor<V>,
+) -> io::Result<usize>
+where
+    V: Voxel,
+{
+    let sections = read_anvil_region(path)?;
+    let aligned = editor.map.voxels.indexer.chunk_shape() == SECTION_SHAPE;
+    for section in sections.iter() {
+        let array = section.to_array(&mut map_block);
+        if aligned {
+            editor.insert_chunk(section.minimum, array);
+        } else {
+            editor.edit_extent(section.extent(), |p: Point3i, voxel: &mut V| {
+                *voxel = array.get(&p);
+            });
+        }
+    }
+
+    Ok(sections.len())
+}
+
+fn decompress_chunk(compression: u8, compressed: &[u8]) -> io::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    match compression {
+        1 => {
+            GzDecoder::new(compressed).read_to_end(&mut bytes)?;
+        }
+        2 => {
+            ZlibDecoder::new(compressed).read_to_end(&mut bytes)?;
+        }
+        3 => bytes.extend_from_slice(compressed),
+        other => {
+            return Err(invalid_data(&format!(
+                "unsupported chunk compression {}",
+                other
+            )))
+        }
+    }
+
+    Ok(bytes)
+}
+
+fn invalid_data(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason.to_string())
+}
+
+// Block states are packed without spanning longs starting with this data version (1.16).
+const NON_SPANNING_DATA_VERSION: i32 = 2529;
+
+fn read_chunk_sections(bytes: &[u8], sections: &mut Vec<AnvilSection>) -> io::Result<()> {
+    let root = NbtReader { bytes, pos: 0 }.read_root()?;
+    let data_version = root.get("DataVersion").and_then(Nbt::as_int).unwrap_or(0);
+    // Before 1.18, everything is nested in a "Level" compound.
+    let level = match root.get("Level") {
+        Some(level) => level,
+        None => &root,
+    };
+    let chunk_x = level
+        .get("xPos")
+        .and_then(Nbt::as_int)
+        .ok_or_else(|| invalid_data("chunk is missing xPos"))?;
+    let chunk_z = level
+        .get("zPos")
+        .and_then(Nbt::as_int)
+        .ok_or_else(|| invalid_data("chunk is missing zPos"))?;
+    let section_list = match level.get("Sections").or_else(|| level.get("sections")) {
+        Some(Nbt::List(list)) => list,
+        _ => return Ok(()),
+    };
+
+    for section in section_list.iter() {
+        let y = match section.get("Y") {
+            Some(Nbt::Byte(y)) => *y as i32,
+            _ => continue,
+        };
+        // 1.18 moved the palette and data into a "block_states" compound.
+        let (palette, data) = match section.get("block_states") {
+            Some(states) => (states.get("palette"), states.get("data")),
+            None => (section.get("Palette"), section.get("BlockStates")),
+        };
+        let palette = match palette {
+            Some(Nbt::List(palette)) => palette
+                .iter()
+                .map(read_block_state)
+                .collect::<io::Result<Vec<_>>>()?,
+            // Sections without a palette are empty, or from before 1.13.
+            _ => continue,
+        };
+        let data = match data {
+            Some(Nbt::LongArray(data)) => data.as_slice(),
+            _ => &[],
+        };
+        let blocks = unpack_block_indices(
+            data,
+            palette.len(),
+            data_version >= NON_SPANNING_DATA_VERSION,
+        )?;
+
+        sections.push(AnvilSection {
+            minimum: PointN([16 * chunk_x, 16 * y, 16 * chunk_z]),
+            palette,
+            blocks,
+        });
+    }
+
+    Ok(())
+}
+
+fn read_block_state(tag: &Nbt) -> io::Result<BlockState> {
+    let name = match tag.get("Name") {
+        Some(Nbt::String(name)) => name.clone(),
+        _ => return Err(invalid_data("block state is missing its name")),
+    };
+    let mut properties = Vec::new();
+    if let Some(Nbt::Compound(props)) = tag.get("Properties") {
+        for (key, value) in props.iter() {
+            if let Nbt::String(value) = value {
+                properties.push((key.clone(), value.clone()));
+            }
+        }
+    }
+    properties.sort();
+
+    Ok(BlockState { name, properties })
+}
+
+fn unpack_block_indices(
+    data: &[i64],
+    palette_len: usize,
+    non_spanning: bool,
+) -> io::Result<Vec<u16>> {
+    const NUM_BLOCKS: usize = 4096;
+
+    // A section with a single block type doesn't need any data.
+    if palette_len <= 1 || data.is_empty() {
+        return Ok(vec![0; NUM_BLOCKS]);
+    }
+
+    let mut bits = 4;
+    while (1 << bits) < palette_len {
+        bits += 1;
+    }
+    let mask = (1u64 << bits) - 1;
+    let mut blocks = Vec::with_capacity(NUM_BLOCKS);
+    for i in 0..NUM_BLOCKS {
+        let value = if non_spanning {
+            let per_long = 64 / bits;
+            let long = *data
+                .get(i / per_long)
+                .ok_or_else(|| invalid_data("block states are truncated"))?
+                as u64;
+
+            (long >> ((i % per_long) * bits)) & mask
+        } else {
+            let bit = i * bits;
+            let long = *data
+                .get(bit / 64)
+                .ok_or_else(|| invalid_data("block states are truncated"))?
+                as u64;
+            let offset = bit % 64;
+            let mut value = long >> offset;
+            if offset + bits > 64 {
+                let next = *data
+                    .get(bit / 64 + 1)
+                    .ok_or_else(|| invalid_data("block states are truncated"))?
+                    as u64;
+                value |= next << (64 - offset);
+            }
+
+            value & mask
+        };
+        if value as usize >= palette_len {
+            return Err(invalid_data("block state index is outside of the palette"));
+        }
+        blocks.push(value as u16);
+    }
+
+    Ok(blocks)
+}
+
+/// The subset of NBT needed to read chunks. Other tags are skipped.
+enum Nbt {
+    Byte(i8),
+    Short(i16),
+    Int(i32),
+    String(String),
+    List(Vec<Nbt>),
+    Compound(FnvHashMap<String, Nbt>),
+    LongArray(Vec<i64>),
+    Skipped,
+}
+
+impl Nbt {
+    fn get(&self, key: &str) -> Option<&Nbt> {
+        match self {
+            Nbt::Compound(map) => map.get(key),
+            _ => None,
+        }
+    }
+
+    fn as_int(&self) -> Option<i32> {
+        match self {
+            Nbt::Byte(v) => Some(*v as i32),
+            Nbt::Short(v) => Some(*v as i32),
+            Nbt::Int(v) => Some(*v),
+            _ => None,
+        }
+    }
+}
+
+struct NbtReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> NbtReader<'a> {
+    fn read_root(mut self) -> io::Result<Nbt> {
+        let tag_type = self.read_u8()?;
+        if tag_type != 10 {
+            return Err(invalid_data("chunk data is not an NBT compound"));
+        }
+        self.read_string()?;
+
+        self.read_payload(tag_type)
+    }
+
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        let bytes = self
+            .bytes
+            .get(self.pos..self.pos + len)
+            .ok_or_else(|| invalid_data("NBT data is truncated"))?;
+        self.pos += len;
+
+        Ok(bytes)
+    }
+
+    fn read_u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn read_i16(&mut self) -> io::Result<i16> {
+        Ok(i16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn read_i32(&mut self) -> io::Result<i32> {
+        Ok(i32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn read_i64(&mut self) -> io::Result<i64> {
+        Ok(i64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn read_len(&mut self) -> io::Result<usize> {
+        let len = self.read_i32()?;
+        if len < 0 {
+            return Err(invalid_data("NBT length is negative"));
+        }
+
+        Ok(len as usize)
+    }
+
+    fn read_string(&mut self) -> io::Result<String> {
+        let len = self.read_i16()? as u16 as usize;
+
+        // NBT strings are modified UTF-8, which only differs for unusual characters.
+        Ok(String::from_utf8_lossy(self.take(len)?).into_owned())
+    }
+
+    fn read_payload(&mut self, tag_type: u8) -> io::Result<Nbt> {
+        Ok(match tag_type {
+            1 => Nbt::Byte(self.read_u8()? as i8),
+            2 => Nbt::Short(self.read_i16()?),
+            3 => Nbt::Int(self.read_i32()?),
+            4 | 6 => {
+                self.take(8)?;
+                Nbt::Skipped
+            }
+            5 => {
+                self.take(4)?;
+                Nbt::Skipped
+            }
+            7 => {
+                let len = self.read_len()?;
+                self.take(len)?;
+                Nbt::Skipped
+            }
+            8 => Nbt::String(self.read_string()?),
+            9 => {
+                let item_type = self.read_u8()?;
+                let len = self.read_len()?;
+                let mut items = Vec::with_capacity(len.min(1024));
+                for _ in 0..len {
+                    items.push(self.read_payload(item_type)?);
+                }
+                Nbt::List(items)
+            }
+            10 => {
+                let mut map = FnvHashMap::default();
+                loop {
+                    let item_type = self.read_u8()?;
+                    if item_type == 0 {
+                        break;
+                    }
+                    let name = self.read_string()?;
+                    map.insert(name, self.read_payload(item_type)?);
+                }
+                Nbt::Compound(map)
+            }
+            11 => {
+                let len = self.read_len()?;
+                self.take(4 * len)?;
+                Nbt::Skipped
+            }
+            12 => {
+                let len = self.read_len()?;
+                let mut values = Vec::with_capacity(len.min(1024));
+                for _ in 0..len {
+                    values.push(self.read_i64()?);
+                }
+                Nbt::LongArray(values)
+            }
+            other => return Err(invalid_data(&format!("unknown NBT tag {}", other))),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::*;
+
+    use flate2::{write::ZlibEncoder, Compression};
+    use std::io::Write;
+
+    // Builders for NBT payloads. Named tags are the tag type, the name, then the payload.
+    fn named(tag_type: u8, name: &str, payload: Vec<u8>) -> Vec<u8> {
+        let mut bytes = vec![tag_type];
+        bytes.extend(string(name));
+        bytes.extend(payload);
+
+        bytes
+    }
+
+    fn string(s: &str) -> Vec<u8> {
+        let mut bytes = (s.len() as i16).to_be_bytes().to_vec();
+        bytes.extend(s.as_bytes());
+
+        bytes
+    }
+
+    fn compound(tags: Vec<Vec<u8>>) -> Vec<u8> {
+        let mut bytes: Vec<u8> = tags.into_iter().flatten().collect();
+        bytes.push(0);
+
+        bytes
+    }
+
+    fn list(item_type: u8, items: Vec<Vec<u8>>) -> Vec<u8> {
+        let mut bytes = vec![item_type];
+        bytes.extend(&(items.len() as i32).to_be_bytes());
+        bytes.extend(items.into_iter().flatten());
+
+        bytes
+    }
+
+    fn long_array(values: &[i64]) -> Vec<u8> {
+        let mut bytes = (values.len() as i32).to_be_bytes().to_vec();
+        for value in values.iter() {
+            bytes.extend(&value.to_be_bytes());
+        }
+
+        bytes
+    }
+
+    fn pack(blocks: &[u16], bits: usize, non_spanning: bool) -> Vec<i64> {
+        let num_longs = if non_spanning {
+            (blocks.len() + 64 / bits - 1) / (64 / bits)
+        } else {
+            (blocks.len() * bits + 63) / 64
+        };
+        let mut data = vec![0u64; num_longs];
+        for (i, &block) in blocks.iter().enumerate() {
+            let (long, offset) = if non_spanning {
+                let per_long = 64 / bits;
+                (i / per_long, (i % per_long) * bits)
+            } else {
+                (i * bits / 64, i * bits % 64)
+            };
+            data[long] |= (block as u64) << offset;
+            if offset + bits > 64 {
+                data[long + 1] |= (block as u64) >> (64 - offset);
+            }
+        }
+        data.into_iter().map(|long| long as i64).collect()
+    }
+
+    fn test_blocks(palette_len: usize) -> Vec<u16> {
+        (0..4096).map(|i| (i * 7 % palette_len) as u16).collect()
+    }
+
+    #[test]
+    fn unpacks_spanning_and_non_spanning_block_indices() {
+        for &palette_len in [2, 16, 17, 40].iter() {
+            let blocks = test_blocks(palette_len);
+            let bits = (4usize..).find(|bits| (1 << bits) >= palette_len).unwrap();
+            for &non_spanning in [false, true].iter() {
+                let data = pack(&blocks, bits, non_spanning);
+                assert_eq!(
+                    unpack_block_indices(&data, palette_len, non_spanning).unwrap(),
+                    blocks
+                );
+            }
+        }
+
+        // A single block type doesn't need any data.
+        assert_eq!(unpack_block_indices(&[], 1, true).unwrap(), vec![0; 4096]);
+    }
+
+    #[test]
+    fn rejects_truncated_and_out_of_palette_block_indices() {
+        let data = pack(&test_blocks(3), 4, true);
+        assert_eq!(
+            unpack_block_indices(&data[..10], 3, true)
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::InvalidData
+        );
+        assert_eq!(
+            unpack_block_indices(&data, 2, true).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    fn block_state(name: &str, properties: Vec<(&str, &str)>) -> Vec<u8> {
+        let properties = properties
+            .into_iter()
+            .map(|(key, value)| named(8, key, string(value)))
+            .collect();
+
+        compound(vec![
+            named(8, "Name", string(name)),
+            named(10, "Properties", compound(properties)),
+        ])
+    }
+
+    // A 1.18 chunk at chunk coordinates (1, -1) with one section at Y = 2.
+    fn chunk_nbt(blocks: &[u16]) -> Vec<u8> {
+        let palette = list(
+            10,
+            vec![
+                block_state("minecraft:air", vec![]),
+                block_state(
+                    "minecraft:oak_stairs",
+                    vec![("half", "bottom"), ("facing", "north")],
+                ),
+            ],
+        );
+        let section = compound(vec![
+            named(1, "Y", vec![2]),
+            named(
+                10,
+                "block_states",
+                compound(vec![
+                    named(9, "palette", palette),
+                    named(12, "data", long_array(&pack(blocks, 4, true))),
+                ]),
+            ),
+            // Skipped tags.
+            named(7, "BlockLight", vec![0, 0, 0, 2, 0, 0]),
+            named(4, "LastUpdate", vec![0; 8]),
+        ]);
+
+        named(
+            10,
+            "",
+            compound(vec![
+                named(3, "DataVersion", 2860i32.to_be_bytes().to_vec()),
+                named(3, "xPos", 1i32.to_be_bytes().to_vec()),
+                named(3, "zPos", (-1i32).to_be_bytes().to_vec()),
+                named(9, "sections", list(10, vec![section])),
+            ]),
+        )
+    }
+
+    fn region_file(chunk: &[u8]) -> Vec<u8> {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(chunk).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let mut bytes = vec![0; 8192];
+        // The chunk at index 0 starts at sector 2.
+        bytes[..4].copy_from_slice(&[0, 0, 2, 1]);
+        bytes.extend(&(compressed.len() as u32 + 1).to_be_bytes());
+        bytes.push(2);
+        bytes.extend(compressed);
+
+        bytes
+    }
+
+    #[test]
+    fn reads_sections_from_a_region_file() {
+        let blocks: Vec<u16> = (0..4096).map(|i| (i % 3 == 0) as u16).collect();
+        let dir = TempDir::new("anvil_region");
+        let path = dir.path().join("r.0.0.mca");
+        fs::write(&path, region_file(&chunk_nbt(&blocks))).unwrap();
+
+        let sections = read_anvil_region(&path).unwrap();
+        assert_eq!(sections.len(), 1);
+        let section = &sections[0];
+        assert_eq!(section.minimum, PointN([16, 32, -16]));
+        assert_eq!(section.palette[0].name, "minecraft:air");
+        let stairs = &section.palette[1];
+        assert_eq!(stairs.name, "minecraft:oak_stairs");
+        assert_eq!(
+            stairs.properties,
+            vec![
+                ("facing".to_string(), "north".to_string()),
+                ("half".to_string(), "bottom".to_string())
+            ]
+        );
+        assert_eq!(stairs.property("facing"), Some("north"));
+
+        let array = section.to_array(|state| TestVoxel((state.name != "minecraft:air") as u8));
+        assert_eq!(*array.extent(), section.extent());
+        array.for_each(array.extent(), |p: Point3i, voxel: TestVoxel| {
+            let local = p - section.minimum;
+            let i = local.x() + 16 * (local.z() + 16 * local.y());
+            assert_eq!(voxel, TestVoxel((i % 3 == 0) as u8));
+        });
+    }
+
+    #[test]
+    fn rejects_malformed_region_files() {
+        let dir = TempDir::new("anvil_malformed");
+        let path = dir.path().join("r.0.0.mca");
+
+        fs::write(&path, vec![0; 100]).unwrap();
+        assert_eq!(
+            read_anvil_region(&path).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+
+        let mut truncated = region_file(&chunk_nbt(&[0; 4096]));
+        truncated.truncate(8200);
+        fs::write(&path, truncated).unwrap();
+        assert_eq!(
+            read_anvil_region(&path).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+
+        // An empty region has no chunks.
+        fs::write(&path, vec![0; 8192]).unwrap();
+        assert!(read_anvil_region(&path).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,266 @@
+use crate::{
+    tasks::map_in_pool, DirtyChunks, ThreadLocalVoxelCache, Voxel, VoxelMap, VoxelTaskPool,
+};
+
+use bevy::prelude::*;
+use building_blocks::prelude::*;
+use fnv::{FnvHashMap, FnvHashSet};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+/// Manages the `NavGrid` resource, a grid of walkable nodes on top of the voxel surface that is
+/// kept up to date as the terrain changes. Depends on the `MapIoPlugin`.
+///
+/// The nodes of each edited chunk are regenerated every frame, along with the chunks directly
+/// above and below it, since a node depends on the floor beneath it and the headroom above it.
+pub struct NavGridPlugin<V> {
+    pub config: NavGridConfig,
+    marker: std::marker::PhantomData<V>,
+}
+
+impl<V> NavGridPlugin<V> {
+    pub fn new(config: NavGridConfig) -> Self {
+        Self {
+            config,
+            marker: Default::default(),
+        }
+    }
+}
+
+impl<V> Plugin for NavGridPlugin<V>
+where
+    V: Voxel,
+    for<'r> &'r V::TypeInfo: IsEmpty,
+{
+    fn build(&self, app: &mut AppBuilder) {
+        app.insert_resource(NavGrid::<V>::new(self.config))
+            .add_system(nav_grid_system::<V>.system());
+    }
+}
+
+/// The dimensions of the agents that walk on a `NavGrid`, in voxels.
+#[derive(Clone, Copy, Debug)]
+pub struct NavGridConfig {
+    /// The number of empty voxels an agent needs above its floor.
+    pub agent_height: i32,
+    /// The highest ledge an agent can step up onto.
+    pub max_step_up: i32,
+    /// The deepest drop an agent will step down.
+    pub max_step_down: i32,
+}
+
+impl Default for NavGridConfig {
+    fn default() -> Self {
+        Self {
+            agent_height: 2,
+            max_step_up: 1,
+            max_step_down: 3,
+        }
+    }
+}
+
+/// The walkable nodes of the voxel map. A node is an empty voxel that has a non-empty voxel right
+/// below it and `agent_height` empty voxels starting at it.
+///
+/// Nodes are connected to the nodes in the 4 horizontally adjacent columns that are within the
+/// agent's step heights. Edges are found by looking up the neighboring nodes, so the grids of
+/// adjacent chunks are connected as soon as both exist.
+pub struct NavGrid<V> {
+    config: NavGridConfig,
+    nodes: FnvHashSet<Point3i>,
+    chunk_nodes: FnvHashMap<Point3i, Vec<Point3i>>,
+    changed_chunk_keys: Vec<Point3i>,
+    marker: std::marker::PhantomData<V>,
+}
+
+const HORIZONTAL_OFFSETS: [[i32; 2]; 4] = [[1, 0], [-1, 0], [0, 1], [0, -1]];
+
+impl<V> NavGrid<V> {
+    pub fn new(config: NavGridConfig) -> Self {
+        Self {
+            config,
+            nodes: Default::default(),
+            chunk_nodes: Default::default(),
+            changed_chunk_keys: Vec::new(),
+            marker: Default::default(),
+        }
+    }
+
+    pub fn config(&self) -> &NavGridConfig {
+        &self.config
+    }
+
+    pub fn is_walkable(&self, p: &Point3i) -> bool {
+        self.nodes.contains(p)
+    }
+
+    /// The nodes in the chunk at `chunk_key`.
+    pub fn chunk_nodes(&self, chunk_key: &Point3i) -> &[Point3i] {
+        self.chunk_nodes
+            .get(chunk_key)
+            .map_or(&[], |nodes| nodes.as_slice())
+    }
+
+    /// The chunks whose nodes were regenerated this frame. Paths that cross them should be
+    /// re-planned.
+    pub fn changed_chunk_keys(&self) -> &[Point3i] {
+        &self.changed_chunk_keys
+    }
+
+    pub fn num_nodes(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// The nodes that an agent standing at `node` can move to in one step.
+    pub fn neighbors(&self, node: Point3i) -> impl Iterator<Item = Point3i> + '_ {
+        let NavGridConfig {
+            max_step_up,
+            max_step_down,
+            ..
+        } = self.config;
+
+        HORIZONTAL_OFFSETS.iter().flat_map(move |[dx, dz]| {
+            (-max_step_down..=max_step_up)
+                .map(move |dy| node + PointN([*dx, dy, *dz]))
+                .filter(move |q| self.nodes.contains(q))
+        })
+    }
+
+    /// The node at or below `p`, within `max_drop` voxels, e.g. to find where an agent is standing.
+    pub fn node_below(&self, p: Point3i, max_drop: i32) -> Option<Point3i> {
+        (0..=max_drop)
+            .map(|dy| p - PointN([0, dy, 0]))
+            .find(|q| self.nodes.contains(q))
+    }
+
+    /// Finds a shortest path from `start` to `goal` with A*, including both ends. Each step costs 1
+    /// plus the change in height. Gives up after visiting `max_visited` nodes.
+    pub fn find_path(
+        &self,
+        start: Point3i,
+        goal: Point3i,
+        max_visited: usize,
+    ) -> Option<Vec<Point3i>> {
+        if !self.is_walkable(&start) || !self.is_walkable(&goal) {
+            return None;
+        }
+
+        let heuristic = |p: Point3i| {
+            let d = goal - p;
+
+            d.x().abs() + d.y().abs() + d.z().abs()
+        };
+
+        let mut open = BinaryHeap::new();
+        let mut came_from: FnvHashMap<Point3i, Point3i> = Default::default();
+        let mut costs: FnvHashMap<Point3i, i32> = Default::default();
+        let mut closed: FnvHashSet<Point3i> = Default::default();
+        costs.insert(start, 0);
+        open.push(Reverse((heuristic(start), start.0)));
+
+        while let Some(Reverse((_, p))) = open.pop() {
+            let p = PointN(p);
+            if p == goal {
+                let mut path = vec![goal];
+                let mut current = goal;
+                while let Some(prev) = came_from.get(&current) {
+                    path.push(*prev);
+                    current = *prev;
+                }
+                path.reverse();
+
+                return Some(path);
+            }
+            if !closed.insert(p) {
+                continue;
+            }
+            if closed.len() > max_visited {
+                return None;
+            }
+
+            let cost = costs[&p];
+            for q in self.neighbors(p) {
+                let q_cost = cost + 1 + (q.y() - p.y()).abs();
+                if costs.get(&q).map_or(true, |c| q_cost < *c) {
+                    costs.insert(q, q_cost);
+                    came_from.insert(q, p);
+                    open.push(Reverse((q_cost + heuristic(q), q.0)));
+                }
+            }
+        }
+
+        None
+    }
+
+    fn set_chunk_nodes(&mut self, chunk_key: Point3i, nodes: Vec<Point3i>) {
+        if let Some(old_nodes) = self.chunk_nodes.remove(&chunk_key) {
+            for node in old_nodes.iter() {
+                self.nodes.remove(node);
+            }
+        }
+        if !nodes.is_empty() {
+            self.nodes.extend(nodes.iter().cloned());
+            self.chunk_nodes.insert(chunk_key, nodes);
+        }
+        self.changed_chunk_keys.push(chunk_key);
+    }
+}
+
+/// Regenerates the nodes of edited chunks and the chunks above and below them.
+fn nav_grid_system<V>(
+    pool: Res<VoxelTaskPool>,
+    voxel_map: Res<VoxelMap<V>>,
+    local_caches: Res<ThreadLocalVoxelCache<V>>,
+    dirty_chunks: Res<DirtyChunks<V>>,
+    mut nav_grid: ResMut<NavGrid<V>>,
+) where
+    V: Voxel,
+    for<'r> &'r V::TypeInfo: IsEmpty,
+{
+    nav_grid.changed_chunk_keys.clear();
+
+    let indexer = &voxel_map.voxels.indexer;
+    let up = PointN([0, indexer.chunk_shape().y(), 0]);
+    let mut chunk_keys: FnvHashSet<Point3i> = Default::default();
+    for &chunk_key in dirty_chunks.edited_chunk_keys.iter() {
+        let minimum = indexer.extent_for_chunk_at_key(chunk_key).minimum;
+        chunk_keys.insert(chunk_key);
+        chunk_keys.insert(indexer.chunk_key_containing_point(&(minimum + up)));
+        chunk_keys.insert(indexer.chunk_key_containing_point(&(minimum - up)));
+    }
+    if chunk_keys.is_empty() {
+        return;
+    }
+
+    let config = nav_grid.config;
+    let map = &*voxel_map;
+    let local_caches = &*local_caches;
+    let new_nodes = map_in_pool(&*pool, chunk_keys.into_iter(), |chunk_key| {
+        let cache_tls = local_caches.get();
+        let reader = map.reader(&cache_tls);
+        let is_empty = |p: Point3i| map.palette.get_voxel_type_info(reader.get(&p)).is_empty();
+
+        let extent = map.voxels.indexer.extent_for_chunk_at_key(chunk_key);
+        let (min, end) = (extent.minimum, extent.minimum + extent.shape);
+        let mut nodes = Vec::new();
+        for z in min.z()..end.z() {
+            for x in min.x()..end.x() {
+                for y in min.y()..end.y() {
+                    let p = PointN([x, y, z]);
+                    if is_empty(p - PointN([0, 1, 0])) {
+                        continue;
+                    }
+                    if (0..config.agent_height).all(|dy| is_empty(p + PointN([0, dy, 0]))) {
+                        nodes.push(p);
+                    }
+                }
+            }
+        }
+
+        (chunk_key, nodes)
+    });
+
+    for (chunk_key, nodes) in new_nodes.into_iter() {
+        nav_grid.set_chunk_nodes(chunk_key, nodes);
+    }
+}
@@ -0,0 +1,27 @@
+use bevy::prelude::*;
+use building_blocks::prelude::*;
+
+/// Marks an entity, like the player or a connected client, whose surroundings should stay ready
+/// for access. The entity must also have a `GlobalTransform`.
+///
+/// Chunks near observers are pinned in the chunk cache by the `MapIoPlugin`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Observer;
+
+/// The voxel containing the origin of `transform`.
+pub(crate) fn transform_voxel_point(transform: &GlobalTransform) -> Point3i {
+    let t = transform.translation;
+
+    PointN([t.x.floor() as i32, t.y.floor() as i32, t.z.floor() as i32])
+}
+
+/// The voxels within `radius_in_chunks` chunks of `center` in every direction.
+pub(crate) fn chunk_radius_extent(
+    center: Point3i,
+    chunk_shape: Point3i,
+    radius_in_chunks: i32,
+) -> Extent3i {
+    let padding = chunk_shape * PointN([radius_in_chunks; 3]);
+
+    Extent3i::from_min_and_max(center - padding, center + padding)
+}
@@ -0,0 +1,165 @@
+use crate::{
+    map_io::EditBuffer, tasks::map_in_pool, DirtyChunks, EmptyChunks, ThreadLocalVoxelCache, Voxel,
+    VoxelMap, VoxelTaskPool,
+};
+
+use bevy::prelude::*;
+use building_blocks::prelude::*;
+use fnv::FnvHashMap;
+
+/// Manages the `ChunkOccupancyCounts` resource, the number of non-empty voxels in each chunk, and
+/// marks chunks for removal in `EmptyChunks` when their count drops to zero. Depends on the
+/// `MapIoPlugin`.
+///
+/// The `EditBuffer` counts every voxel that an edit changes from empty to non-empty or back, so
+/// keeping the counts up to date costs O(edited chunks) per frame, rather than a scan of every
+/// edited voxel. A chunk is only scanned the first time it's edited, and whenever it's replaced
+/// with `insert_chunk`.
+///
+/// Emptiness comes from the `VoxelPalette`, and is checked again at the start of any frame where
+/// the palette changed. Changing whether a type is empty doesn't update the counts of chunks that
+/// already contain it.
+pub struct ChunkOccupancyCountsPlugin<V> {
+    marker: std::marker::PhantomData<V>,
+}
+
+impl<V> Default for ChunkOccupancyCountsPlugin<V> {
+    fn default() -> Self {
+        Self {
+            marker: Default::default(),
+        }
+    }
+}
+
+impl<V> Plugin for ChunkOccupancyCountsPlugin<V>
+where
+    V: Voxel,
+    for<'r> &'r V::TypeInfo: IsEmpty,
+{
+    fn build(&self, app: &mut AppBuilder) {
+        app.insert_resource(ChunkOccupancyCounts::<V>::default())
+            // Before any edits are made.
+            .add_system_to_stage(stage::FIRST, empty_types_system::<V>.system())
+            .add_system(occupancy_counts_system::<V>.system());
+    }
+}
+
+/// The number of non-empty voxels in each chunk that has been edited since the plugin was added.
+pub struct ChunkOccupancyCounts<V> {
+    counts: FnvHashMap<Point3i, u64>,
+    marker: std::marker::PhantomData<V>,
+}
+
+impl<V> Default for ChunkOccupancyCounts<V> {
+    fn default() -> Self {
+        Self {
+            counts: Default::default(),
+            marker: Default::default(),
+        }
+    }
+}
+
+impl<V> ChunkOccupancyCounts<V> {
+    pub fn get(&self, chunk_key: &Point3i) -> Option<u64> {
+        self.counts.get(chunk_key).cloned()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Point3i, &u64)> {
+        self.counts.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.counts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.counts.is_empty()
+    }
+}
+
+fn empty_types_system<V>(
+    voxel_map: Res<VoxelMap<V>>,
+    mut edit_buffer: ResMut<EditBuffer<V>>,
+    mut last_empty_types: Local<Option<Vec<bool>>>,
+) where
+    V: Voxel,
+    for<'r> &'r V::TypeInfo: IsEmpty,
+{
+    let empty_types: Vec<bool> = voxel_map
+        .palette
+        .infos
+        .iter()
+        .map(|info| info.is_empty())
+        .collect();
+    if last_empty_types.as_ref() == Some(&empty_types) {
+        return;
+    }
+
+    edit_buffer.count_occupancy_changes(empty_types.clone());
+    *last_empty_types = Some(empty_types);
+}
+
+fn occupancy_counts_system<V>(
+    pool: Res<VoxelTaskPool>,
+    voxel_map: Res<VoxelMap<V>>,
+    local_caches: Res<ThreadLocalVoxelCache<V>>,
+    dirty_chunks: Res<DirtyChunks<V>>,
+    mut counts: ResMut<ChunkOccupancyCounts<V>>,
+    mut empty_chunks: ResMut<EmptyChunks<V>>,
+) where
+    V: Voxel,
+    for<'r> &'r V::TypeInfo: IsEmpty,
+{
+    for chunk_key in empty_chunks.removed_chunk_keys() {
+        counts.counts.remove(chunk_key);
+    }
+
+    let mut to_scan = Vec::new();
+    for &chunk_key in dirty_chunks.edited_chunk_keys.iter() {
+        let edits = dirty_chunks.chunk_edits.get(&chunk_key);
+        let replaced = edits.map_or(false, |e| e.replaced);
+        match counts.counts.get_mut(&chunk_key) {
+            Some(count) if !replaced => {
+                let delta = edits.map_or(0, |e| e.occupancy_delta);
+                *count = (*count as i64 + delta).max(0) as u64;
+            }
+            _ => to_scan.push(chunk_key),
+        }
+    }
+
+    let map = &*voxel_map;
+    let local_caches = &*local_caches;
+    let scanned = map_in_pool(&*pool, to_scan.into_iter(), |chunk_key| {
+        let cache_tls = local_caches.get();
+        let reader = map.reader(&cache_tls);
+        if reader.get_chunk(chunk_key).is_none() {
+            return (chunk_key, None);
+        }
+        let extent = reader.indexer.extent_for_chunk_at_key(chunk_key);
+        let mut count = 0;
+        reader.for_each(&extent, |_p: Point3i, voxel: V| {
+            if !map.palette.get_voxel_type_info(voxel).is_empty() {
+                count += 1;
+            }
+        });
+
+        (chunk_key, Some(count))
+    });
+    for (chunk_key, count) in scanned.into_iter() {
+        match count {
+            Some(count) => {
+                counts.counts.insert(chunk_key, count);
+            }
+            // The chunk was already removed.
+            None => {
+                counts.counts.remove(&chunk_key);
+            }
+        }
+    }
+
+    for &chunk_key in dirty_chunks.edited_chunk_keys.iter() {
+        if counts.get(&chunk_key) == Some(0) {
+            empty_chunks.mark_for_removal(chunk_key);
+        }
+    }
+}
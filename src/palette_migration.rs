@@ -0,0 +1,362 @@
+use crate::{
+    tasks::spawn_detached, SpilledChunks, Voxel, VoxelEditor, VoxelMap, VoxelPalette, VoxelTaskPool,
+};
+
+use bevy::prelude::*;
+use building_blocks::prelude::*;
+use std::sync::{Arc, Mutex};
+
+/// Manages the `PaletteMigrator` resource, which remaps the type indices of every voxel in the map
+/// when the `VoxelPalette` changes, e.g. after a content update removes or reorders voxel types.
+/// Depends on the `MapIoPlugin`.
+pub struct PaletteMigrationPlugin<V> {
+    marker: std::marker::PhantomData<V>,
+}
+
+impl<V> Default for PaletteMigrationPlugin<V> {
+    fn default() -> Self {
+        Self {
+            marker: Default::default(),
+        }
+    }
+}
+
+impl<V> Plugin for PaletteMigrationPlugin<V>
+where
+    V: Voxel,
+{
+    fn build(&self, app: &mut AppBuilder) {
+        app.insert_resource(PaletteMigrator::<V>::default())
+            .add_event::<PaletteMigrationFinished<V>>()
+            // The last chunks were merged at the end of the previous frame.
+            .add_system_to_stage(
+                stage::FIRST,
+                palette_migration_finished_system::<V>.system(),
+            )
+            .add_system(palette_migration_system::<V>.system());
+    }
+}
+
+/// How to change the voxels of the map, and optionally the palette that goes with them.
+pub struct PaletteMigration<V>
+where
+    V: Voxel,
+{
+    remap: Arc<dyn Fn(V) -> V + Send + Sync>,
+    palette: Option<VoxelPalette<V::TypeInfo>>,
+}
+
+impl<V> PaletteMigration<V>
+where
+    V: Voxel,
+{
+    /// Replaces every voxel `v` with `remap(v)`. Only chunks where `remap` changes the type index
+    /// of at least one voxel are rewritten.
+    pub fn new(remap: impl Fn(V) -> V + Send + Sync + 'static) -> Self {
+        Self {
+            remap: Arc::new(remap),
+            palette: None,
+        }
+    }
+
+    /// Moves voxels of type index `i` to type index `new_indices[i]`, using `with_type_index` to
+    /// change a voxel's type index. Voxels with indices past the end of `new_indices` are left
+    /// alone.
+    pub fn from_index_map(
+        new_indices: Vec<usize>,
+        with_type_index: impl Fn(V, usize) -> V + Send + Sync + 'static,
+    ) -> Self {
+        Self::new(
+            move |voxel: V| match new_indices.get(voxel.get_type_index()) {
+                Some(&new_index) => with_type_index(voxel, new_index),
+                None => voxel,
+            },
+        )
+    }
+
+    /// Replaces the palette of the `VoxelMap` with `palette` once every chunk has been migrated.
+    pub fn with_palette(mut self, palette: VoxelPalette<V::TypeInfo>) -> Self {
+        self.palette = Some(palette);
+
+        self
+    }
+}
+
+/// Runs a `PaletteMigration` over every chunk of the map, including spilled ones, a few chunks per
+/// frame.
+///
+/// Chunks are first scanned on the `VoxelTaskPool` to find the ones with voxels to remap, then
+/// those are remapped with `VoxelEditor::edit_extent_and_touch_neighbors`, so `DirtyChunks`,
+/// saves, and everything else that follows edits pick up the change. Once the last chunk is
+/// merged, the new palette is installed and a `PaletteMigrationFinished` event is sent, at the
+/// start of a frame.
+///
+/// Until then, the map holds a mix of old and new type indices. Gameplay edits should be paused
+/// while a migration runs, e.g. behind a loading screen, since a chunk that was already scanned
+/// isn't scanned again.
+pub struct PaletteMigrator<V>
+where
+    V: Voxel,
+{
+    /// The most chunks scanned, and the most chunks rewritten, in a single frame.
+    pub chunks_per_frame: usize,
+    state: Option<MigrationState<V>>,
+}
+
+struct MigrationState<V>
+where
+    V: Voxel,
+{
+    migration: PaletteMigration<V>,
+    // Found lazily, since chunk keys need the map.
+    to_scan: Option<Vec<Point3i>>,
+    to_rewrite: Vec<Point3i>,
+    num_chunks: usize,
+    num_finished: usize,
+    num_rewritten: usize,
+    num_scanning: usize,
+    // Each scanned chunk key, and whether it needs to be rewritten.
+    scanned: Arc<Mutex<Vec<(Point3i, bool)>>>,
+    done: bool,
+}
+
+impl<V> Default for PaletteMigrator<V>
+where
+    V: Voxel,
+{
+    fn default() -> Self {
+        Self {
+            chunks_per_frame: 256,
+            state: None,
+        }
+    }
+}
+
+impl<V> PaletteMigrator<V>
+where
+    V: Voxel,
+{
+    /// Starts `migration` on this frame. Returns `false`, without starting it, if another migration
+    /// is still running.
+    pub fn start(&mut self, migration: PaletteMigration<V>) -> bool {
+        if self.is_running() {
+            return false;
+        }
+        self.state = Some(MigrationState {
+            migration,
+            to_scan: None,
+            to_rewrite: Vec::new(),
+            num_chunks: 0,
+            num_finished: 0,
+            num_rewritten: 0,
+            num_scanning: 0,
+            scanned: Default::default(),
+            done: false,
+        });
+
+        true
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.state.is_some()
+    }
+
+    /// The fraction of chunks that have been migrated, or `None` if no migration is running.
+    pub fn progress(&self) -> Option<f32> {
+        let state = self.state.as_ref()?;
+
+        Some(match state.to_scan {
+            Some(_) if state.num_chunks > 0 => state.num_finished as f32 / state.num_chunks as f32,
+            Some(_) => 1.0,
+            None => 0.0,
+        })
+    }
+}
+
+/// Sent at the start of the frame after a `PaletteMigration` has been merged into every chunk.
+pub struct PaletteMigrationFinished<V> {
+    /// The number of chunks that were rewritten.
+    pub num_rewritten_chunks: usize,
+    marker: std::marker::PhantomData<V>,
+}
+
+fn palette_migration_system<V>(
+    pool: Res<VoxelTaskPool>,
+    spilled_chunks: Option<Res<SpilledChunks<V>>>,
+    mut migrator: ResMut<PaletteMigrator<V>>,
+    mut voxel_editor: VoxelEditor<V>,
+) where
+    V: Voxel,
+{
+    let chunks_per_frame = migrator.chunks_per_frame;
+    let state = match migrator.state.as_mut() {
+        Some(state) if !state.done => state,
+        _ => return,
+    };
+
+    if state.to_scan.is_none() {
+        let to_scan: Vec<Point3i> = voxel_editor
+            .map
+            .voxels
+            .storage()
+            .chunk_keys()
+            .cloned()
+            .collect();
+        // Spilled chunks can't be scanned without reading them from disk, and the editor reads
+        // them anyway. Collapsed chunks are rewritten without scanning them too.
+        if let Some(spilled_chunks) = spilled_chunks.as_ref() {
+            state
+                .to_rewrite
+                .extend(spilled_chunks.chunk_keys().cloned());
+        }
+        state.to_rewrite.extend(
+            voxel_editor
+                .map
+                .uniform_chunks
+                .iter()
+                .map(|(chunk_key, _)| *chunk_key),
+        );
+        state.num_chunks = to_scan.len() + state.to_rewrite.len();
+        state.to_scan = Some(to_scan);
+    }
+
+    for (chunk_key, needs_rewrite) in state.scanned.lock().unwrap().drain(..) {
+        state.num_scanning -= 1;
+        if needs_rewrite {
+            state.to_rewrite.push(chunk_key);
+        } else {
+            state.num_finished += 1;
+        }
+    }
+
+    let num_rewrites = chunks_per_frame.min(state.to_rewrite.len());
+    let mut retries = Vec::new();
+    for chunk_key in state.to_rewrite.drain(..num_rewrites) {
+        let extent = voxel_editor
+            .map
+            .voxels
+            .indexer
+            .extent_for_chunk_at_key(chunk_key);
+        let remap = &state.migration.remap;
+        voxel_editor.edit_extent_and_touch_neighbors(extent, |_p: Point3i, voxel: &mut V| {
+            *voxel = remap(*voxel);
+        });
+        // The editor skips spilled chunks that it can't read from disk.
+        let is_spilled = spilled_chunks
+            .as_ref()
+            .map_or(false, |s| s.is_spilled(&chunk_key));
+        if is_spilled && voxel_editor.edited_chunk(chunk_key).is_none() {
+            retries.push(chunk_key);
+        }
+    }
+    state.num_finished += num_rewrites - retries.len();
+    state.num_rewritten += num_rewrites - retries.len();
+    state.to_rewrite.extend(retries);
+
+    let to_scan = state.to_scan.as_mut().unwrap();
+    let num_scans = chunks_per_frame.min(to_scan.len());
+    let storage = voxel_editor.map.voxels.storage();
+    let uniform_chunks = &voxel_editor.map.uniform_chunks;
+    let mut chunks = Vec::new();
+    for chunk_key in to_scan.drain(to_scan.len() - num_scans..) {
+        if let Some(chunk) = storage.copy_without_caching(chunk_key) {
+            chunks.push((chunk_key, chunk));
+        } else if uniform_chunks.contains_chunk(&chunk_key)
+            || spilled_chunks
+                .as_ref()
+                .map_or(false, |s| s.is_spilled(&chunk_key))
+        {
+            // Collapsed or spilled since the migration started, so rewrite it like the others.
+            state.to_rewrite.push(chunk_key);
+        } else {
+            // Removed since the migration started, so there's nothing to migrate.
+            state.num_finished += 1;
+        }
+    }
+    state.num_scanning += chunks.len();
+    if !chunks.is_empty() {
+        let remap = state.migration.remap.clone();
+        let scanned = state.scanned.clone();
+        spawn_detached(&*pool, move || {
+            let results: Vec<(Point3i, bool)> = chunks
+                .into_iter()
+                .map(|(chunk_key, chunk)| {
+                    let array = chunk.as_decompressed().array;
+                    let extent = *array.extent();
+                    let mut needs_rewrite = false;
+                    array.for_each(&extent, |_p: Point3i, voxel: V| {
+                        needs_rewrite |= remap(voxel).get_type_index() != voxel.get_type_index();
+                    });
+
+                    (chunk_key, needs_rewrite)
+                })
+                .collect();
+            scanned.lock().unwrap().extend(results);
+        });
+    }
+
+    if to_scan.is_empty() && state.num_scanning == 0 && state.to_rewrite.is_empty() {
+        state.done = true;
+    }
+}
+
+fn palette_migration_finished_system<V>(
+    mut migrator: ResMut<PaletteMigrator<V>>,
+    mut voxel_map: ResMut<VoxelMap<V>>,
+    mut finished_events: ResMut<Events<PaletteMigrationFinished<V>>>,
+) where
+    V: Voxel,
+{
+    if !migrator.state.as_ref().map_or(false, |s| s.done) {
+        return;
+    }
+    let state = migrator.state.take().unwrap();
+
+    if let Some(palette) = state.migration.palette {
+        voxel_map.palette = palette;
+    }
+    finished_events.send(PaletteMigrationFinished {
+        num_rewritten_chunks: state.num_rewritten,
+        marker: Default::default(),
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::*;
+
+    #[test]
+    fn index_maps_only_move_mapped_types() {
+        let migration =
+            PaletteMigration::from_index_map(vec![0, 2, 1], |_voxel, i| TestVoxel(i as u8))
+                .with_palette(VoxelPalette { infos: vec![(); 3] });
+        let remap = &migration.remap;
+
+        assert_eq!(remap(TestVoxel(0)), TestVoxel(0));
+        assert_eq!(remap(TestVoxel(1)), TestVoxel(2));
+        assert_eq!(remap(TestVoxel(2)), TestVoxel(1));
+        // Past the end of the index map.
+        assert_eq!(remap(TestVoxel(5)), TestVoxel(5));
+        assert_eq!(migration.palette.map(|p| p.infos.len()), Some(3));
+    }
+
+    #[test]
+    fn only_one_migration_runs_at_a_time() {
+        let mut migrator = PaletteMigrator::<TestVoxel>::default();
+        assert!(!migrator.is_running());
+        assert_eq!(migrator.progress(), None);
+
+        assert!(migrator.start(PaletteMigration::new(|v| v)));
+        assert!(migrator.is_running());
+        // The chunks aren't known until the first frame of the migration.
+        assert_eq!(migrator.progress(), Some(0.0));
+        assert!(!migrator.start(PaletteMigration::new(|v| v)));
+
+        let state = migrator.state.as_mut().unwrap();
+        state.to_scan = Some(Vec::new());
+        state.num_chunks = 4;
+        state.num_finished = 1;
+        assert_eq!(migrator.progress(), Some(0.25));
+    }
+}
@@ -0,0 +1,17 @@
+mod autosave;
+mod chunk_directory;
+mod chunk_store;
+mod region_store;
+#[cfg(feature = "sled")]
+mod sled_store;
+#[cfg(feature = "sqlite")]
+mod sqlite_store;
+
+pub use autosave::{Autosave, AutosaveConfig, AutosavePlugin};
+pub use chunk_directory::ChunkDirectory;
+pub use chunk_store::ChunkStore;
+pub use region_store::RegionStore;
+#[cfg(feature = "sled")]
+pub use sled_store::SledChunkStore;
+#[cfg(feature = "sqlite")]
+pub use sqlite_store::SqliteChunkStore;
@@ -0,0 +1,277 @@
+use super::ChunkStore;
+
+use crate::{
+    copy_chunk_without_caching, encode_chunk, tasks::spawn_detached, SpilledChunks, Voxel,
+    VoxelCodec, VoxelMap,
+};
+
+use bevy::{app::AppExit, prelude::*, tasks::IoTaskPool};
+use building_blocks::prelude::*;
+use fnv::FnvHashSet;
+use std::{
+    io,
+    sync::{Arc, Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Manages the `Autosave` resource, which periodically writes modified chunks to a `ChunkStore` on
+/// the `IoTaskPool`, and flushes everything when the app exits. Depends on the
+/// `MapIoPlugin`, and must be added after it.
+pub struct AutosavePlugin<V> {
+    store: Arc<dyn ChunkStore>,
+    codec: Arc<dyn VoxelCodec<V>>,
+    config: AutosaveConfig,
+}
+
+impl<V> AutosavePlugin<V>
+where
+    V: Voxel,
+{
+    pub fn new(
+        store: impl ChunkStore + 'static,
+        codec: impl VoxelCodec<V> + 'static,
+        config: AutosaveConfig,
+    ) -> Self {
+        Self {
+            store: Arc::new(store),
+            codec: Arc::new(codec),
+            config,
+        }
+    }
+}
+
+impl<V> Plugin for AutosavePlugin<V>
+where
+    V: Voxel,
+{
+    fn build(&self, app: &mut AppBuilder) {
+        app.insert_resource(Autosave::<V>::new(
+            self.store.clone(),
+            self.codec.clone(),
+            self.config,
+        ))
+        // Runs after the edit buffer is merged and empty chunks are removed in LAST, so every
+        // modification of the frame is already marked unsaved.
+        .add_system_to_stage(stage::LAST, autosave_system::<V>.system());
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct AutosaveConfig {
+    /// The time between saves.
+    pub interval: Duration,
+}
+
+impl Default for AutosaveConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Writes the chunks that the `VoxelMap` has marked unsaved.
+///
+/// Chunks are marked saved as soon as they're copied for writing, and marked unsaved again if the
+/// write fails, so the next save retries them. A chunk that's modified while it's being written is
+/// marked unsaved by the modification, so it's written again by the next save.
+pub struct Autosave<V> {
+    pub config: AutosaveConfig,
+    store: Arc<dyn ChunkStore>,
+    codec: Arc<dyn VoxelCodec<V>>,
+    last_save: Instant,
+    save_requested: bool,
+    writes: Arc<Writes>,
+    errors: Vec<(Point3i, io::Error)>,
+}
+
+// Shared with the background tasks.
+#[derive(Default)]
+struct Writes {
+    state: Mutex<WriteState>,
+    finished: Condvar,
+}
+
+#[derive(Default)]
+struct WriteState {
+    in_flight: FnvHashSet<Point3i>,
+    // Failed writes, whose chunks must be marked unsaved in the `VoxelMap` again.
+    errors: Vec<(Point3i, io::Error)>,
+}
+
+impl<V> Autosave<V>
+where
+    V: Voxel,
+{
+    fn new(
+        store: Arc<dyn ChunkStore>,
+        codec: Arc<dyn VoxelCodec<V>>,
+        config: AutosaveConfig,
+    ) -> Self {
+        Self {
+            config,
+            store,
+            codec,
+            last_save: Instant::now(),
+            save_requested: false,
+            writes: Default::default(),
+            errors: Vec::new(),
+        }
+    }
+
+    pub fn store(&self) -> &dyn ChunkStore {
+        &*self.store
+    }
+
+    /// Returns `true` if the chunk on disk matches the chunk in `map`.
+    pub fn is_saved(&self, map: &VoxelMap<V>, chunk_key: &Point3i) -> bool {
+        map.is_saved(chunk_key) && !self.is_writing(chunk_key)
+    }
+
+    /// Returns `true` while the chunk is being written in the background.
+    pub fn is_writing(&self, chunk_key: &Point3i) -> bool {
+        self.writes
+            .state
+            .lock()
+            .unwrap()
+            .in_flight
+            .contains(chunk_key)
+    }
+
+    /// Saves at the end of this frame, regardless of the interval.
+    pub fn request_save(&mut self) {
+        self.save_requested = true;
+    }
+
+    /// Takes the errors from failed writes. Chunks that failed to save are retried by the next save.
+    pub fn take_errors(&mut self) -> Vec<(Point3i, io::Error)> {
+        std::mem::replace(&mut self.errors, Vec::new())
+    }
+
+    /// Waits for any background writes to finish, then writes every unsaved chunk on the calling
+    /// thread. Use this when the map is about to be dropped, e.g. when leaving a world. This happens
+    /// automatically on `AppExit`.
+    ///
+    /// Chunks that were spilled to disk are read from the `spilled_chunks`, if the map has any.
+    pub fn flush(&mut self, map: &mut VoxelMap<V>, spilled_chunks: Option<&SpilledChunks<V>>) {
+        self.wait_for_writes();
+        self.collect_errors(map);
+        let unsaved: Vec<Point3i> = map.unsaved_chunk_keys().cloned().collect();
+        for chunk_key in unsaved.into_iter() {
+            let result = copy_chunk_without_caching(map, spilled_chunks, chunk_key)
+                .and_then(|chunk| write_chunk(&*self.store, &*self.codec, chunk_key, chunk));
+            match result {
+                Ok(()) => map.mark_saved(&chunk_key),
+                Err(e) => self.errors.push((chunk_key, e)),
+            }
+        }
+        self.last_save = Instant::now();
+        self.save_requested = false;
+    }
+
+    fn wait_for_writes(&self) {
+        let mut state = self.writes.state.lock().unwrap();
+        while !state.in_flight.is_empty() {
+            state = self.writes.finished.wait(state).unwrap();
+        }
+    }
+
+    /// Marks the chunks whose background writes failed as unsaved in `map` again.
+    fn collect_errors(&mut self, map: &mut VoxelMap<V>) {
+        let mut state = self.writes.state.lock().unwrap();
+        for (chunk_key, e) in state.errors.drain(..) {
+            map.mark_unsaved(chunk_key);
+            self.errors.push((chunk_key, e));
+        }
+    }
+
+    fn is_save_due(&self) -> bool {
+        self.save_requested || self.last_save.elapsed() >= self.config.interval
+    }
+
+    /// Copies every unsaved chunk that isn't already being written and marks it saved, then writes
+    /// them in the background.
+    fn start_save(
+        &mut self,
+        map: &mut VoxelMap<V>,
+        spilled_chunks: Option<&SpilledChunks<V>>,
+        pool: &IoTaskPool,
+    ) {
+        self.last_save = Instant::now();
+        self.save_requested = false;
+
+        let mut chunks: Vec<(Point3i, Option<Array3<V>>)> = Vec::new();
+        {
+            let mut state = self.writes.state.lock().unwrap();
+            // Writing the same chunk twice at once could leave the older version on disk.
+            let ready_keys: Vec<Point3i> = map
+                .unsaved_chunk_keys()
+                .filter(|chunk_key| !state.in_flight.contains(chunk_key))
+                .cloned()
+                .collect();
+            for chunk_key in ready_keys.into_iter() {
+                // A spilled chunk that can't be read stays unsaved, rather than being deleted.
+                match copy_chunk_without_caching(map, spilled_chunks, chunk_key) {
+                    Ok(chunk) => {
+                        map.mark_saved(&chunk_key);
+                        state.in_flight.insert(chunk_key);
+                        chunks.push((chunk_key, chunk));
+                    }
+                    Err(e) => self.errors.push((chunk_key, e)),
+                }
+            }
+        }
+        if chunks.is_empty() {
+            return;
+        }
+
+        let store = self.store.clone();
+        let codec = self.codec.clone();
+        let writes = self.writes.clone();
+        spawn_detached(pool, move || {
+            for (chunk_key, chunk) in chunks.into_iter() {
+                let result = write_chunk(&*store, &*codec, chunk_key, chunk);
+                let mut state = writes.state.lock().unwrap();
+                state.in_flight.remove(&chunk_key);
+                if let Err(e) = result {
+                    state.errors.push((chunk_key, e));
+                }
+            }
+            writes.finished.notify_all();
+        });
+    }
+}
+
+/// Deletes the stored chunk if the chunk no longer exists.
+fn write_chunk<V>(
+    store: &dyn ChunkStore,
+    codec: &dyn VoxelCodec<V>,
+    chunk_key: Point3i,
+    chunk: Option<Array3<V>>,
+) -> io::Result<()>
+where
+    V: Voxel,
+{
+    match chunk {
+        Some(chunk) => store.put(chunk_key, &encode_chunk(codec, &chunk)),
+        None => store.delete(chunk_key),
+    }
+}
+
+fn autosave_system<V>(
+    mut voxel_map: ResMut<VoxelMap<V>>,
+    spilled_chunks: Option<Res<SpilledChunks<V>>>,
+    pool: Res<IoTaskPool>,
+    exit_events: Res<Events<AppExit>>,
+    mut exit_reader: Local<EventReader<AppExit>>,
+    mut autosave: ResMut<Autosave<V>>,
+) where
+    V: Voxel,
+{
+    autosave.collect_errors(&mut *voxel_map);
+    if exit_reader.iter(&exit_events).next().is_some() {
+        autosave.flush(&mut *voxel_map, spilled_chunks.as_deref());
+    } else if autosave.is_save_due() {
+        autosave.start_save(&mut *voxel_map, spilled_chunks.as_deref(), &*pool);
+    }
+}
@@ -0,0 +1,145 @@
+use super::ChunkStore;
+
+use building_blocks::prelude::*;
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// A `ChunkStore` that keeps every chunk in its own file under a root directory.
+#[derive(Clone, Debug)]
+pub struct ChunkDirectory {
+    root: PathBuf,
+}
+
+impl ChunkDirectory {
+    /// Opens the directory at `root`, creating it if it doesn't exist.
+    pub fn open(root: impl Into<PathBuf>) -> io::Result<Self> {
+        let root = root.into();
+        fs::create_dir_all(&root)?;
+
+        Ok(Self { root })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn chunk_path(&self, chunk_key: Point3i) -> PathBuf {
+        let [x, y, z] = chunk_key.0;
+
+        self.root.join(format!("{}_{}_{}.chunk", x, y, z))
+    }
+}
+
+impl ChunkStore for ChunkDirectory {
+    fn get(&self, chunk_key: Point3i) -> io::Result<Option<Vec<u8>>> {
+        match fs::read(self.chunk_path(chunk_key)) {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The bytes are written to a temporary file first, so a crash during the write leaves the
+    /// previous contents intact.
+    fn put(&self, chunk_key: Point3i, bytes: &[u8]) -> io::Result<()> {
+        let path = self.chunk_path(chunk_key);
+        let temp_path = path.with_extension("chunk.tmp");
+        fs::write(&temp_path, bytes)?;
+
+        fs::rename(&temp_path, &path)
+    }
+
+    fn delete(&self, chunk_key: Point3i) -> io::Result<()> {
+        match fs::remove_file(self.chunk_path(chunk_key)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    fn chunk_keys_in_extent(&self, key_extent: &Extent3i) -> io::Result<Vec<Point3i>> {
+        let mut chunk_keys = Vec::new();
+        for entry in fs::read_dir(&self.root)? {
+            let file_name = entry?.file_name();
+            if let Some(chunk_key) = file_name.to_str().and_then(parse_chunk_file_name) {
+                if key_extent.contains(&chunk_key) {
+                    chunk_keys.push(chunk_key);
+                }
+            }
+        }
+
+        Ok(chunk_keys)
+    }
+}
+
+fn parse_chunk_file_name(file_name: &str) -> Option<Point3i> {
+    let mut coords = file_name
+        .strip_suffix(".chunk")?
+        .split('_')
+        .map(|c| c.parse::<i32>().ok());
+    let x = coords.next()??;
+    let y = coords.next()??;
+    let z = coords.next()??;
+    if coords.next().is_some() {
+        return None;
+    }
+
+    Some(PointN([x, y, z]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn put_get_and_delete() {
+        let dir = TempDir::new("chunk_directory");
+        let store = ChunkDirectory::open(dir.path().join("chunks")).unwrap();
+        let chunk_key = PointN([-16, 0, 32]);
+        assert_eq!(store.get(chunk_key).unwrap(), None);
+
+        store.put(chunk_key, &[1, 2, 3]).unwrap();
+        assert_eq!(store.get(chunk_key).unwrap(), Some(vec![1, 2, 3]));
+        store.put(chunk_key, &[4]).unwrap();
+        assert_eq!(store.get(chunk_key).unwrap(), Some(vec![4]));
+
+        store.delete(chunk_key).unwrap();
+        assert_eq!(store.get(chunk_key).unwrap(), None);
+        // Deleting a missing chunk isn't an error.
+        store.delete(chunk_key).unwrap();
+    }
+
+    #[test]
+    fn chunks_survive_reopening() {
+        let dir = TempDir::new("chunk_directory_reopen");
+        let chunk_key = PointN([0, 16, 0]);
+        ChunkDirectory::open(dir.path())
+            .unwrap()
+            .put(chunk_key, &[7; 10])
+            .unwrap();
+
+        let store = ChunkDirectory::open(dir.path()).unwrap();
+        assert_eq!(store.get(chunk_key).unwrap(), Some(vec![7; 10]));
+    }
+
+    #[test]
+    fn chunk_keys_in_extent_skips_other_files() {
+        let dir = TempDir::new("chunk_directory_keys");
+        let store = ChunkDirectory::open(dir.path()).unwrap();
+        let inside = PointN([-16, 0, 0]);
+        let outside = PointN([64, 0, 0]);
+        store.put(inside, &[1]).unwrap();
+        store.put(outside, &[1]).unwrap();
+        fs::write(dir.path().join("0_0_0.chunk.tmp"), b"1").unwrap();
+        fs::write(dir.path().join("0_0.chunk"), b"1").unwrap();
+        fs::write(dir.path().join("notes.txt"), b"1").unwrap();
+
+        let key_extent = Extent3i::from_min_and_shape(PointN([-32; 3]), PointN([64; 3]));
+        assert_eq!(
+            store.chunk_keys_in_extent(&key_extent).unwrap(),
+            vec![inside]
+        );
+    }
+}
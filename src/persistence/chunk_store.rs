@@ -0,0 +1,23 @@
+use building_blocks::prelude::*;
+use std::io;
+
+/// A database of encoded chunks, keyed by chunk key. Autosaving and spilling chunks to disk both go
+/// through this trait, so any database can be plugged in.
+///
+/// The bytes are opaque to the store. They're usually written by `encode_chunk`.
+///
+/// Implementations for sled and SQLite are available with the `sled` and `sqlite` features.
+pub trait ChunkStore: Send + Sync {
+    /// Returns `None` if there is no chunk at `chunk_key`.
+    fn get(&self, chunk_key: Point3i) -> io::Result<Option<Vec<u8>>>;
+
+    /// Replaces the chunk at `chunk_key`.
+    fn put(&self, chunk_key: Point3i, bytes: &[u8]) -> io::Result<()>;
+
+    /// Deletes the chunk at `chunk_key`, if there is one.
+    fn delete(&self, chunk_key: Point3i) -> io::Result<()>;
+
+    /// The keys of all stored chunks that lie within `key_extent`. Like the keys of the
+    /// `VoxelMap`, these are the minimum points of the chunks.
+    fn chunk_keys_in_extent(&self, key_extent: &Extent3i) -> io::Result<Vec<Point3i>>;
+}
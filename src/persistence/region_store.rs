@@ -0,0 +1,520 @@
+use super::ChunkStore;
+
+use building_blocks::prelude::*;
+use fnv::FnvHashMap;
+use std::{
+    convert::TryInto,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+const MAGIC: &[u8; 4] = b"BBRG";
+const FORMAT_VERSION: u32 = 1;
+// Magic, format version, and region size.
+const PREAMBLE_LEN: u64 = 12;
+// Offset and length of a chunk.
+const INDEX_ENTRY_LEN: u64 = 12;
+
+/// A `ChunkStore` that packs chunks into region files, so millions of chunks don't become millions
+/// of tiny files.
+///
+/// Each region file holds an NxNxN block of chunks. The file starts with an index of the offset and
+/// length of every chunk in the region, followed by the chunk data. Rewritten chunks are appended
+/// and the index is updated afterwards, so a crash mid-write leaves the previous chunk readable.
+/// The old bytes become garbage until the region is compacted with `compact_region` or `compact`.
+///
+/// Access to each region is serialized, so the store can be written from background tasks, like
+/// those of the `AutosavePlugin`, while it's being read. Only one `RegionStore` should use a
+/// directory at a time.
+pub struct RegionStore {
+    root: PathBuf,
+    chunk_shape: Point3i,
+    region_size: i32,
+    region_locks: Mutex<FnvHashMap<Point3i, Arc<Mutex<()>>>>,
+}
+
+#[derive(Clone, Copy, Default)]
+struct IndexEntry {
+    offset: u64,
+    len: u32,
+}
+
+impl RegionStore {
+    /// Opens the region files under `root`, creating the directory if it doesn't exist. Each
+    /// region is `region_size` chunks along each axis, and `chunk_shape` must match the
+    /// `VoxelMap`.
+    pub fn open(
+        root: impl Into<PathBuf>,
+        chunk_shape: Point3i,
+        region_size: i32,
+    ) -> io::Result<Self> {
+        assert!(region_size > 0);
+        let root = root.into();
+        fs::create_dir_all(&root)?;
+
+        Ok(Self {
+            root,
+            chunk_shape,
+            region_size,
+            region_locks: Default::default(),
+        })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// The key of the region containing the chunk at `chunk_key`, in units of regions.
+    pub fn region_key(&self, chunk_key: Point3i) -> Point3i {
+        let n = self.region_size;
+        let [x, y, z] = self.chunk_coords(chunk_key);
+
+        PointN([x.div_euclid(n), y.div_euclid(n), z.div_euclid(n)])
+    }
+
+    pub fn region_path(&self, region_key: Point3i) -> PathBuf {
+        let [x, y, z] = region_key.0;
+
+        self.root.join(format!("r_{}_{}_{}.region", x, y, z))
+    }
+
+    /// The keys of all regions with a file.
+    pub fn region_keys(&self) -> io::Result<Vec<Point3i>> {
+        let mut region_keys = Vec::new();
+        for entry in fs::read_dir(&self.root)? {
+            let file_name = entry?.file_name();
+            if let Some(region_key) = file_name.to_str().and_then(parse_region_file_name) {
+                region_keys.push(region_key);
+            }
+        }
+
+        Ok(region_keys)
+    }
+
+    /// The fraction of the region's data that's garbage left behind by rewritten or deleted chunks.
+    pub fn garbage_ratio(&self, region_key: Point3i) -> io::Result<f32> {
+        let lock = self.lock_region(region_key);
+        let _guard = lock.lock().unwrap();
+
+        let mut file = match self.open_region(region_key, false)? {
+            Some(f) => f,
+            None => return Ok(0.0),
+        };
+        let data_len = file.metadata()?.len() - self.header_len();
+        if data_len == 0 {
+            return Ok(0.0);
+        }
+        let live_len: u64 = self
+            .read_index(&mut file)?
+            .iter()
+            .map(|e| e.len as u64)
+            .sum();
+
+        Ok((data_len - live_len) as f32 / data_len as f32)
+    }
+
+    /// Rewrites the region file without garbage. Regions without any chunks are deleted.
+    pub fn compact_region(&self, region_key: Point3i) -> io::Result<()> {
+        let lock = self.lock_region(region_key);
+        let _guard = lock.lock().unwrap();
+
+        let mut file = match self.open_region(region_key, false)? {
+            Some(f) => f,
+            None => return Ok(()),
+        };
+        let index = self.read_index(&mut file)?;
+        let path = self.region_path(region_key);
+        if index.iter().all(|e| e.len == 0) {
+            drop(file);
+            return fs::remove_file(&path);
+        }
+
+        let mut new_index = vec![IndexEntry::default(); index.len()];
+        let mut data = Vec::new();
+        for (slot, entry) in index.iter().enumerate() {
+            if entry.len == 0 {
+                continue;
+            }
+            new_index[slot] = IndexEntry {
+                offset: self.header_len() + data.len() as u64,
+                len: entry.len,
+            };
+            data.extend(read_at(&mut file, entry.offset, entry.len as usize)?);
+        }
+        drop(file);
+
+        // Replace the file atomically, so a crash leaves either the old or the new region.
+        let temp_path = path.with_extension("region.tmp");
+        let mut temp_file = File::create(&temp_path)?;
+        temp_file.write_all(&self.encode_header(&new_index))?;
+        temp_file.write_all(&data)?;
+        temp_file.sync_all()?;
+        drop(temp_file);
+
+        fs::rename(&temp_path, &path)
+    }
+
+    /// Compacts every region whose `garbage_ratio` is at least `min_garbage_ratio`. Returns the
+    /// number of regions compacted.
+    pub fn compact(&self, min_garbage_ratio: f32) -> io::Result<usize> {
+        let mut num_compacted = 0;
+        for region_key in self.region_keys()? {
+            if self.garbage_ratio(region_key)? >= min_garbage_ratio {
+                self.compact_region(region_key)?;
+                num_compacted += 1;
+            }
+        }
+
+        Ok(num_compacted)
+    }
+
+    fn lock_region(&self, region_key: Point3i) -> Arc<Mutex<()>> {
+        self.region_locks
+            .lock()
+            .unwrap()
+            .entry(region_key)
+            .or_default()
+            .clone()
+    }
+
+    fn num_slots(&self) -> usize {
+        (self.region_size * self.region_size * self.region_size) as usize
+    }
+
+    fn header_len(&self) -> u64 {
+        PREAMBLE_LEN + INDEX_ENTRY_LEN * self.num_slots() as u64
+    }
+
+    /// The chunk key in units of chunks.
+    fn chunk_coords(&self, chunk_key: Point3i) -> [i32; 3] {
+        let [x, y, z] = chunk_key.0;
+        let [sx, sy, sz] = self.chunk_shape.0;
+
+        [x.div_euclid(sx), y.div_euclid(sy), z.div_euclid(sz)]
+    }
+
+    /// The index of the chunk within its region.
+    fn slot(&self, chunk_key: Point3i) -> usize {
+        let n = self.region_size;
+        let [x, y, z] = self.chunk_coords(chunk_key);
+
+        (x.rem_euclid(n) + n * (y.rem_euclid(n) + n * z.rem_euclid(n))) as usize
+    }
+
+    fn chunk_key_for_slot(&self, region_key: Point3i, slot: usize) -> Point3i {
+        let n = self.region_size;
+        let slot = slot as i32;
+        let local = PointN([slot % n, (slot / n) % n, slot / (n * n)]);
+
+        (region_key * PointN([n; 3]) + local) * self.chunk_shape
+    }
+
+    fn encode_header(&self, index: &[IndexEntry]) -> Vec<u8> {
+        let mut header = Vec::with_capacity(self.header_len() as usize);
+        header.extend_from_slice(MAGIC);
+        header.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        header.extend_from_slice(&(self.region_size as u32).to_le_bytes());
+        for entry in index.iter() {
+            header.extend_from_slice(&encode_index_entry(*entry));
+        }
+
+        header
+    }
+
+    /// Returns `None` if the region doesn't exist and `create` is `false`.
+    fn open_region(&self, region_key: Point3i, create: bool) -> io::Result<Option<File>> {
+        let path = self.region_path(region_key);
+        let mut file = match OpenOptions::new().read(true).write(true).open(&path) {
+            Ok(f) => f,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                if !create {
+                    return Ok(None);
+                }
+                let mut file = OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .open(&path)?;
+                file.write_all(
+                    &self.encode_header(&vec![IndexEntry::default(); self.num_slots()]),
+                )?;
+
+                return Ok(Some(file));
+            }
+            Err(e) => return Err(e),
+        };
+
+        let preamble = read_at(&mut file, 0, PREAMBLE_LEN as usize)?;
+        let version = u32::from_le_bytes(preamble[4..8].try_into().unwrap());
+        let region_size = u32::from_le_bytes(preamble[8..12].try_into().unwrap());
+        if &preamble[0..4] != MAGIC
+            || version != FORMAT_VERSION
+            || region_size != self.region_size as u32
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is not a compatible region file", path.display()),
+            ));
+        }
+
+        Ok(Some(file))
+    }
+
+    fn read_index(&self, file: &mut File) -> io::Result<Vec<IndexEntry>> {
+        let bytes = read_at(
+            file,
+            PREAMBLE_LEN,
+            (INDEX_ENTRY_LEN as usize) * self.num_slots(),
+        )?;
+
+        Ok(bytes
+            .chunks_exact(INDEX_ENTRY_LEN as usize)
+            .map(decode_index_entry)
+            .collect())
+    }
+
+    fn read_index_entry(&self, file: &mut File, slot: usize) -> io::Result<IndexEntry> {
+        let bytes = read_at(
+            file,
+            PREAMBLE_LEN + INDEX_ENTRY_LEN * slot as u64,
+            INDEX_ENTRY_LEN as usize,
+        )?;
+
+        Ok(decode_index_entry(&bytes))
+    }
+
+    fn write_index_entry(&self, file: &mut File, slot: usize, entry: IndexEntry) -> io::Result<()> {
+        file.seek(SeekFrom::Start(
+            PREAMBLE_LEN + INDEX_ENTRY_LEN * slot as u64,
+        ))?;
+
+        file.write_all(&encode_index_entry(entry))
+    }
+}
+
+impl ChunkStore for RegionStore {
+    fn get(&self, chunk_key: Point3i) -> io::Result<Option<Vec<u8>>> {
+        let region_key = self.region_key(chunk_key);
+        let lock = self.lock_region(region_key);
+        let _guard = lock.lock().unwrap();
+
+        let mut file = match self.open_region(region_key, false)? {
+            Some(f) => f,
+            None => return Ok(None),
+        };
+        let entry = self.read_index_entry(&mut file, self.slot(chunk_key))?;
+        if entry.len == 0 {
+            return Ok(None);
+        }
+
+        read_at(&mut file, entry.offset, entry.len as usize).map(Some)
+    }
+
+    fn put(&self, chunk_key: Point3i, bytes: &[u8]) -> io::Result<()> {
+        // An index entry of length zero means the chunk is missing.
+        if bytes.is_empty() || bytes.len() > u32::MAX as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("region files can't hold chunks of {} bytes", bytes.len()),
+            ));
+        }
+
+        let region_key = self.region_key(chunk_key);
+        let lock = self.lock_region(region_key);
+        let _guard = lock.lock().unwrap();
+
+        let mut file = match self.open_region(region_key, true)? {
+            Some(f) => f,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("couldn't create {}", self.region_path(region_key).display()),
+                ))
+            }
+        };
+        let offset = file.seek(SeekFrom::End(0))?;
+        file.write_all(bytes)?;
+        file.sync_data()?;
+
+        self.write_index_entry(
+            &mut file,
+            self.slot(chunk_key),
+            IndexEntry {
+                offset,
+                len: bytes.len() as u32,
+            },
+        )
+    }
+
+    fn delete(&self, chunk_key: Point3i) -> io::Result<()> {
+        let region_key = self.region_key(chunk_key);
+        let lock = self.lock_region(region_key);
+        let _guard = lock.lock().unwrap();
+
+        match self.open_region(region_key, false)? {
+            Some(mut file) => {
+                self.write_index_entry(&mut file, self.slot(chunk_key), IndexEntry::default())
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn chunk_keys_in_extent(&self, key_extent: &Extent3i) -> io::Result<Vec<Point3i>> {
+        let min_region = self.region_key(key_extent.minimum);
+        let max_region = self.region_key(key_extent.max());
+        let region_extent = Extent3i::from_min_and_max(min_region, max_region);
+
+        let mut chunk_keys = Vec::new();
+        for region_key in self.region_keys()? {
+            if !region_extent.contains(&region_key) {
+                continue;
+            }
+            let lock = self.lock_region(region_key);
+            let _guard = lock.lock().unwrap();
+            let mut file = match self.open_region(region_key, false)? {
+                Some(f) => f,
+                None => continue,
+            };
+            for (slot, entry) in self.read_index(&mut file)?.iter().enumerate() {
+                if entry.len == 0 {
+                    continue;
+                }
+                let chunk_key = self.chunk_key_for_slot(region_key, slot);
+                if key_extent.contains(&chunk_key) {
+                    chunk_keys.push(chunk_key);
+                }
+            }
+        }
+
+        Ok(chunk_keys)
+    }
+}
+
+fn read_at(file: &mut File, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+    file.seek(SeekFrom::Start(offset))?;
+    let mut bytes = vec![0; len];
+    file.read_exact(&mut bytes)?;
+
+    Ok(bytes)
+}
+
+fn encode_index_entry(entry: IndexEntry) -> [u8; INDEX_ENTRY_LEN as usize] {
+    let mut bytes = [0; INDEX_ENTRY_LEN as usize];
+    bytes[0..8].copy_from_slice(&entry.offset.to_le_bytes());
+    bytes[8..12].copy_from_slice(&entry.len.to_le_bytes());
+
+    bytes
+}
+
+fn decode_index_entry(bytes: &[u8]) -> IndexEntry {
+    IndexEntry {
+        offset: u64::from_le_bytes(bytes[0..8].try_into().unwrap()),
+        len: u32::from_le_bytes(bytes[8..12].try_into().unwrap()),
+    }
+}
+
+fn parse_region_file_name(file_name: &str) -> Option<Point3i> {
+    let mut coords = file_name
+        .strip_prefix("r_")?
+        .strip_suffix(".region")?
+        .split('_')
+        .map(|c| c.parse::<i32>().ok());
+    let x = coords.next()??;
+    let y = coords.next()??;
+    let z = coords.next()??;
+    if coords.next().is_some() {
+        return None;
+    }
+
+    Some(PointN([x, y, z]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    const CHUNK_SHAPE: Point3i = PointN([16; 3]);
+
+    fn open(dir: &TempDir) -> RegionStore {
+        RegionStore::open(dir.path(), CHUNK_SHAPE, 2).unwrap()
+    }
+
+    #[test]
+    fn put_get_and_delete() {
+        let dir = TempDir::new("region_store");
+        let store = open(&dir);
+        let chunk_key = PointN([-16, 0, 32]);
+        assert_eq!(store.get(chunk_key).unwrap(), None);
+
+        store.put(chunk_key, &[1, 2, 3]).unwrap();
+        assert_eq!(store.get(chunk_key).unwrap(), Some(vec![1, 2, 3]));
+        // A neighbor in the same region doesn't exist until it's written.
+        assert_eq!(store.get(PointN([-32, 0, 32])).unwrap(), None);
+        assert_eq!(store.region_keys().unwrap(), vec![PointN([-1, 0, 1])]);
+
+        store.delete(chunk_key).unwrap();
+        assert_eq!(store.get(chunk_key).unwrap(), None);
+        // Deleting from a missing region isn't an error.
+        store.delete(PointN([160, 0, 0])).unwrap();
+    }
+
+    #[test]
+    fn rewritten_chunks_are_appended_until_compacted() {
+        let dir = TempDir::new("region_store_append");
+        let store = open(&dir);
+        let a = PointN([0; 3]);
+        let b = PointN([16, 0, 0]);
+        store.put(a, &[1; 10]).unwrap();
+        store.put(b, &[2; 10]).unwrap();
+        assert_eq!(store.garbage_ratio(PointN([0; 3])).unwrap(), 0.0);
+
+        store.put(a, &[3; 20]).unwrap();
+        assert_eq!(store.get(a).unwrap(), Some(vec![3; 20]));
+        assert_eq!(store.get(b).unwrap(), Some(vec![2; 10]));
+        assert_eq!(store.garbage_ratio(PointN([0; 3])).unwrap(), 0.25);
+
+        assert_eq!(store.compact(0.2).unwrap(), 1);
+        assert_eq!(store.garbage_ratio(PointN([0; 3])).unwrap(), 0.0);
+        assert_eq!(store.get(a).unwrap(), Some(vec![3; 20]));
+        assert_eq!(store.get(b).unwrap(), Some(vec![2; 10]));
+
+        // Compacting a region without chunks deletes it.
+        store.delete(a).unwrap();
+        store.delete(b).unwrap();
+        store.compact_region(PointN([0; 3])).unwrap();
+        assert!(store.region_keys().unwrap().is_empty());
+    }
+
+    #[test]
+    fn chunks_survive_reopening() {
+        let dir = TempDir::new("region_store_reopen");
+        let chunk_key = PointN([48, -16, 0]);
+        open(&dir).put(chunk_key, &[7; 10]).unwrap();
+
+        let store = open(&dir);
+        assert_eq!(store.get(chunk_key).unwrap(), Some(vec![7; 10]));
+        let key_extent = Extent3i::from_min_and_shape(PointN([0, -64, 0]), PointN([64; 3]));
+        assert_eq!(
+            store.chunk_keys_in_extent(&key_extent).unwrap(),
+            vec![chunk_key]
+        );
+
+        // The region size is part of the file format.
+        store.put(PointN([0; 3]), &[1]).unwrap();
+        let other = RegionStore::open(dir.path(), CHUNK_SHAPE, 4).unwrap();
+        let error = other.get(PointN([0; 3])).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn empty_chunks_are_rejected() {
+        let dir = TempDir::new("region_store_empty");
+        let store = open(&dir);
+        let error = store.put(PointN([0; 3]), &[]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert!(store.region_keys().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,109 @@
+use super::ChunkStore;
+
+use building_blocks::prelude::*;
+use std::{convert::TryInto, io};
+
+/// A `ChunkStore` backed by a sled tree.
+///
+/// Keys are ordered by X, then Y, then Z, so listing the keys in an extent only scans the range of
+/// X coordinates it covers.
+#[derive(Clone)]
+pub struct SledChunkStore {
+    tree: sled::Tree,
+}
+
+impl SledChunkStore {
+    pub fn new(tree: sled::Tree) -> Self {
+        Self { tree }
+    }
+
+    /// Opens the database at `path` and uses its default tree.
+    pub fn open(path: impl AsRef<std::path::Path>) -> io::Result<Self> {
+        let db = sled::open(path).map_err(sled_error)?;
+        // The default tree keeps the database open.
+        let tree: &sled::Tree = &db;
+
+        Ok(Self::new(tree.clone()))
+    }
+
+    pub fn tree(&self) -> &sled::Tree {
+        &self.tree
+    }
+}
+
+fn sled_error(e: sled::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e)
+}
+
+fn encode_coord(c: i32) -> [u8; 4] {
+    // Flipping the sign bit makes the big-endian bytes sort in numeric order.
+    ((c as u32) ^ (1 << 31)).to_be_bytes()
+}
+
+fn decode_coord(bytes: &[u8]) -> i32 {
+    (u32::from_be_bytes(bytes.try_into().unwrap()) ^ (1 << 31)) as i32
+}
+
+fn encode_key(chunk_key: Point3i) -> [u8; 12] {
+    let mut key = [0; 12];
+    for (i, c) in chunk_key.0.iter().enumerate() {
+        key[4 * i..4 * (i + 1)].copy_from_slice(&encode_coord(*c));
+    }
+
+    key
+}
+
+fn decode_key(key: &[u8]) -> Option<Point3i> {
+    if key.len() != 12 {
+        return None;
+    }
+
+    Some(PointN([
+        decode_coord(&key[0..4]),
+        decode_coord(&key[4..8]),
+        decode_coord(&key[8..12]),
+    ]))
+}
+
+impl ChunkStore for SledChunkStore {
+    fn get(&self, chunk_key: Point3i) -> io::Result<Option<Vec<u8>>> {
+        let value = self.tree.get(encode_key(chunk_key)).map_err(sled_error)?;
+
+        Ok(value.map(|v| v.to_vec()))
+    }
+
+    fn put(&self, chunk_key: Point3i, bytes: &[u8]) -> io::Result<()> {
+        self.tree
+            .insert(encode_key(chunk_key), bytes)
+            .map_err(sled_error)?;
+
+        Ok(())
+    }
+
+    fn delete(&self, chunk_key: Point3i) -> io::Result<()> {
+        self.tree
+            .remove(encode_key(chunk_key))
+            .map_err(sled_error)?;
+
+        Ok(())
+    }
+
+    fn chunk_keys_in_extent(&self, key_extent: &Extent3i) -> io::Result<Vec<Point3i>> {
+        let min = key_extent.minimum;
+        let max = key_extent.max();
+        let start = encode_key(PointN([min.x(), i32::MIN, i32::MIN]));
+        let end = encode_key(PointN([max.x(), i32::MAX, i32::MAX]));
+
+        let mut chunk_keys = Vec::new();
+        for entry in self.tree.range(start..=end) {
+            let (key, _) = entry.map_err(sled_error)?;
+            if let Some(chunk_key) = decode_key(&key) {
+                if key_extent.contains(&chunk_key) {
+                    chunk_keys.push(chunk_key);
+                }
+            }
+        }
+
+        Ok(chunk_keys)
+    }
+}
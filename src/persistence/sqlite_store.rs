@@ -0,0 +1,108 @@
+use super::ChunkStore;
+
+use building_blocks::prelude::*;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::{io, sync::Mutex};
+
+/// A `ChunkStore` backed by a table in an SQLite database.
+///
+/// SQLite connections can't be shared between threads, so all access is serialized.
+pub struct SqliteChunkStore {
+    connection: Mutex<Connection>,
+}
+
+impl SqliteChunkStore {
+    /// Uses `connection`, creating the `chunks` table if it doesn't exist.
+    pub fn new(connection: Connection) -> io::Result<Self> {
+        connection
+            .execute(
+                "CREATE TABLE IF NOT EXISTS chunks (
+                    x INTEGER NOT NULL,
+                    y INTEGER NOT NULL,
+                    z INTEGER NOT NULL,
+                    data BLOB NOT NULL,
+                    PRIMARY KEY (x, y, z)
+                ) WITHOUT ROWID",
+                params![],
+            )
+            .map_err(sqlite_error)?;
+
+        Ok(Self {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    /// Opens the database file at `path`.
+    pub fn open(path: impl AsRef<std::path::Path>) -> io::Result<Self> {
+        Self::new(Connection::open(path).map_err(sqlite_error)?)
+    }
+}
+
+fn sqlite_error(e: rusqlite::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e)
+}
+
+impl ChunkStore for SqliteChunkStore {
+    fn get(&self, chunk_key: Point3i) -> io::Result<Option<Vec<u8>>> {
+        let [x, y, z] = chunk_key.0;
+
+        self.connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT data FROM chunks WHERE x = ?1 AND y = ?2 AND z = ?3",
+                params![x, y, z],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(sqlite_error)
+    }
+
+    fn put(&self, chunk_key: Point3i, bytes: &[u8]) -> io::Result<()> {
+        let [x, y, z] = chunk_key.0;
+        self.connection
+            .lock()
+            .unwrap()
+            .execute(
+                "INSERT OR REPLACE INTO chunks (x, y, z, data) VALUES (?1, ?2, ?3, ?4)",
+                params![x, y, z, bytes],
+            )
+            .map_err(sqlite_error)?;
+
+        Ok(())
+    }
+
+    fn delete(&self, chunk_key: Point3i) -> io::Result<()> {
+        let [x, y, z] = chunk_key.0;
+        self.connection
+            .lock()
+            .unwrap()
+            .execute(
+                "DELETE FROM chunks WHERE x = ?1 AND y = ?2 AND z = ?3",
+                params![x, y, z],
+            )
+            .map_err(sqlite_error)?;
+
+        Ok(())
+    }
+
+    fn chunk_keys_in_extent(&self, key_extent: &Extent3i) -> io::Result<Vec<Point3i>> {
+        let min = key_extent.minimum;
+        let max = key_extent.max();
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection
+            .prepare(
+                "SELECT x, y, z FROM chunks
+                WHERE x BETWEEN ?1 AND ?2 AND y BETWEEN ?3 AND ?4 AND z BETWEEN ?5 AND ?6",
+            )
+            .map_err(sqlite_error)?;
+        let rows = statement
+            .query_map(
+                params![min.x(), max.x(), min.y(), max.y(), min.z(), max.z()],
+                |row| Ok(PointN([row.get(0)?, row.get(1)?, row.get(2)?])),
+            )
+            .map_err(sqlite_error)?;
+
+        rows.collect::<Result<_, _>>().map_err(sqlite_error)
+    }
+}
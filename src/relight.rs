@@ -0,0 +1,190 @@
+use crate::{Voxel, VoxelMap};
+
+use bevy::prelude::*;
+use building_blocks::prelude::*;
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+/// Schedules lighting recomputation for large regions over multiple frames. Depends on the
+/// `MapIoPlugin`.
+///
+/// Send a `RelightExtent` event to request that every chunk overlapping the extent be relit. The
+/// lighting system then drains the `RelightQueue` under a time budget:
+///
+/// ```
+/// use bevy::prelude::*;
+/// use bevy_building_blocks::{RelightQueue, Voxel};
+///
+/// fn lighting_system<V: Voxel>(mut relight_queue: ResMut<RelightQueue<V>>) {
+///     for chunk_key in relight_queue.start_batch() {
+///         // Recompute lighting for the chunk at `chunk_key`.
+///     }
+/// }
+/// ```
+///
+/// Lighting systems should run in the `UPDATE` stage. Once every chunk of a request has been handed
+/// out, a `RelightFinished` event is sent in the `POST_UPDATE` stage of the same frame.
+pub struct RelightPlugin<V> {
+    pub time_budget: Duration,
+    marker: std::marker::PhantomData<V>,
+}
+
+impl<V> RelightPlugin<V> {
+    pub fn new(time_budget: Duration) -> Self {
+        Self {
+            time_budget,
+            marker: Default::default(),
+        }
+    }
+}
+
+impl<V> Plugin for RelightPlugin<V>
+where
+    V: Voxel,
+{
+    fn build(&self, app: &mut AppBuilder) {
+        app.add_event::<RelightExtent<V>>()
+            .add_event::<RelightFinished<V>>()
+            .insert_resource(RelightQueue::<V>::new(self.time_budget))
+            .add_system_to_stage(stage::PRE_UPDATE, relight_command_system::<V>.system())
+            .add_system_to_stage(stage::POST_UPDATE, relight_completion_system::<V>.system());
+    }
+}
+
+/// A request to recompute lighting for all chunks overlapping the extent.
+pub struct RelightExtent<V> {
+    pub extent: Extent3i,
+    marker: std::marker::PhantomData<V>,
+}
+
+impl<V> RelightExtent<V> {
+    pub fn new(extent: Extent3i) -> Self {
+        Self {
+            extent,
+            marker: Default::default(),
+        }
+    }
+}
+
+/// Sent when every chunk of a `RelightExtent` request has been relit.
+pub struct RelightFinished<V> {
+    pub extent: Extent3i,
+    marker: std::marker::PhantomData<V>,
+}
+
+impl<V> RelightFinished<V> {
+    fn new(extent: Extent3i) -> Self {
+        Self {
+            extent,
+            marker: Default::default(),
+        }
+    }
+}
+
+/// The chunks that are waiting to be relit, in request order.
+pub struct RelightQueue<V> {
+    pub time_budget: Duration,
+    jobs: VecDeque<RelightJob>,
+    marker: std::marker::PhantomData<V>,
+}
+
+struct RelightJob {
+    extent: Extent3i,
+    remaining_chunk_keys: VecDeque<Point3i>,
+}
+
+impl<V> RelightQueue<V> {
+    pub fn new(time_budget: Duration) -> Self {
+        Self {
+            time_budget,
+            jobs: VecDeque::new(),
+            marker: Default::default(),
+        }
+    }
+
+    /// Returns an iterator over chunk keys that stops yielding once `time_budget` has elapsed,
+    /// including the time spent processing each yielded chunk.
+    pub fn start_batch(&mut self) -> RelightBatch<V> {
+        RelightBatch {
+            start: Instant::now(),
+            time_budget: self.time_budget,
+            queue: self,
+        }
+    }
+
+    /// The number of chunks still waiting to be handed out.
+    pub fn num_pending_chunks(&self) -> usize {
+        self.jobs
+            .iter()
+            .map(|job| job.remaining_chunk_keys.len())
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.num_pending_chunks() == 0
+    }
+
+    fn pop_chunk_key(&mut self) -> Option<Point3i> {
+        self.jobs
+            .iter_mut()
+            .find_map(|job| job.remaining_chunk_keys.pop_front())
+    }
+}
+
+/// An iterator over the chunks to relight this frame. See `RelightQueue::start_batch`.
+pub struct RelightBatch<'a, V> {
+    queue: &'a mut RelightQueue<V>,
+    start: Instant,
+    time_budget: Duration,
+}
+
+impl<'a, V> Iterator for RelightBatch<'a, V> {
+    type Item = Point3i;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.start.elapsed() >= self.time_budget {
+            return None;
+        }
+
+        self.queue.pop_chunk_key()
+    }
+}
+
+/// Converts `RelightExtent` events into jobs on the `RelightQueue`.
+fn relight_command_system<V>(
+    voxel_map: Res<VoxelMap<V>>,
+    relight_commands: Res<Events<RelightExtent<V>>>,
+    mut command_reader: Local<EventReader<RelightExtent<V>>>,
+    mut queue: ResMut<RelightQueue<V>>,
+) where
+    V: Voxel,
+{
+    for command in command_reader.iter(&relight_commands) {
+        queue.jobs.push_back(RelightJob {
+            extent: command.extent,
+            remaining_chunk_keys: voxel_map
+                .voxels
+                .indexer
+                .chunk_keys_for_extent(&command.extent)
+                .collect(),
+        });
+    }
+}
+
+/// Sends a `RelightFinished` event for every job whose chunks have all been handed out.
+fn relight_completion_system<V>(
+    mut queue: ResMut<RelightQueue<V>>,
+    mut finished_events: ResMut<Events<RelightFinished<V>>>,
+) where
+    V: Voxel,
+{
+    while let Some(job) = queue.jobs.front() {
+        if !job.remaining_chunk_keys.is_empty() {
+            break;
+        }
+        finished_events.send(RelightFinished::new(job.extent));
+        queue.jobs.pop_front();
+    }
+}
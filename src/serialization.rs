@@ -0,0 +1,152 @@
+use crate::{
+    default_array, empty_compressible_chunk_map, CodecError, Voxel, VoxelMap, VoxelPalette,
+};
+
+use building_blocks::prelude::*;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+/// A chunk in a compact, serializable form. The voxels are run-length encoded in array order, i.e.
+/// with X varying fastest, so mostly uniform chunks take little space.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SerializedChunk<V> {
+    pub minimum: [i32; 3],
+    pub shape: [i32; 3],
+    /// Runs of identical voxels, as `(length, voxel)`.
+    pub runs: Vec<(u32, V)>,
+}
+
+impl<V> SerializedChunk<V>
+where
+    V: Voxel + PartialEq,
+{
+    pub fn from_array(chunk: &Array3<V>) -> Self {
+        let extent = *chunk.extent();
+        let mut runs: Vec<(u32, V)> = Vec::new();
+        chunk.for_each(&extent, |_p: Point3i, voxel: V| match runs.last_mut() {
+            Some((length, run_voxel)) if *run_voxel == voxel => *length += 1,
+            _ => runs.push((1, voxel)),
+        });
+
+        Self {
+            minimum: extent.minimum.0,
+            shape: extent.shape.0,
+            runs,
+        }
+    }
+
+    pub fn extent(&self) -> Extent3i {
+        Extent3i::from_min_and_shape(PointN(self.minimum), PointN(self.shape))
+    }
+
+    /// Fails if the runs don't cover the chunk's extent exactly.
+    pub fn to_array(&self) -> Result<Array3<V>, CodecError> {
+        if self.shape.iter().any(|&s| s <= 0) {
+            return Err(CodecError::InvalidData(format!(
+                "chunk shape {:?} is not positive",
+                self.shape
+            )));
+        }
+        let extent = self.extent();
+        let num_run_voxels: usize = self.runs.iter().map(|(length, _)| *length as usize).sum();
+        if num_run_voxels != extent.num_points() {
+            return Err(CodecError::InvalidData(format!(
+                "runs cover {} voxels, but the chunk has {}",
+                num_run_voxels,
+                extent.num_points()
+            )));
+        }
+
+        let mut voxels = self
+            .runs
+            .iter()
+            .flat_map(|(length, voxel)| std::iter::repeat(*voxel).take(*length as usize));
+        let mut array = default_array(extent);
+        array.for_each_mut(&extent, |_p: Point3i, voxel: &mut V| {
+            *voxel = voxels.next().unwrap();
+        });
+
+        Ok(array)
+    }
+}
+
+// The serialized layout of a `VoxelMap`. Borrowed for writing, owned for reading; bincode doesn't
+// store field names, so the two must list the same fields in the same order.
+#[derive(Serialize)]
+struct VoxelMapRef<'a, V, I> {
+    chunk_shape: [i32; 3],
+    palette: &'a VoxelPalette<I>,
+    chunks: Vec<SerializedChunk<V>>,
+}
+
+#[derive(Deserialize)]
+struct VoxelMapOwned<V, I> {
+    chunk_shape: [i32; 3],
+    palette: VoxelPalette<I>,
+    chunks: Vec<SerializedChunk<V>>,
+}
+
+impl<V> VoxelMap<V>
+where
+    V: Voxel + PartialEq + Serialize + DeserializeOwned,
+    V::TypeInfo: Serialize + DeserializeOwned,
+{
+    /// Serializes every chunk and the palette with bincode. Compressed chunks are decompressed
+    /// without being cached, and collapsed uniform chunks are expanded. Chunks spilled to disk by
+    /// the `SpilledChunks` aren't included, so reload them first.
+    pub fn to_bytes(&self) -> bincode::Result<Vec<u8>> {
+        let storage = self.voxels.storage();
+        let chunk_keys: Vec<Point3i> = storage.chunk_keys().cloned().collect();
+        let chunk_shape = self.voxels.indexer.chunk_shape();
+        let chunks = chunk_keys
+            .into_iter()
+            .filter_map(|chunk_key| storage.copy_without_caching(chunk_key))
+            .map(|chunk| SerializedChunk::from_array(&chunk.as_decompressed().array))
+            .chain(self.uniform_chunks.iter().map(|(chunk_key, value)| {
+                let extent = Extent3i::from_min_and_shape(*chunk_key, chunk_shape);
+
+                SerializedChunk::from_array(&Array3::fill(extent, *value))
+            }))
+            .collect();
+
+        bincode::serialize(&VoxelMapRef {
+            chunk_shape: self.voxels.indexer.chunk_shape().0,
+            palette: &self.palette,
+            chunks,
+        })
+    }
+
+    /// Reads a map written by `to_bytes`. All chunks start out in the cache, and they'll be
+    /// compressed over time by the `MapIoPlugin`.
+    pub fn from_bytes(bytes: &[u8]) -> bincode::Result<Self> {
+        let VoxelMapOwned {
+            chunk_shape,
+            palette,
+            chunks,
+        } = bincode::deserialize(bytes)?;
+        if chunk_shape.iter().any(|&s| s <= 0) {
+            return Err(Box::new(bincode::ErrorKind::Custom(format!(
+                "chunk shape {:?} is not positive",
+                chunk_shape
+            ))));
+        }
+
+        let mut voxels = empty_compressible_chunk_map(PointN(chunk_shape));
+        for chunk in chunks.into_iter() {
+            let array = chunk
+                .to_array()
+                .map_err(|e| bincode::ErrorKind::Custom(e.to_string()))?;
+            let chunk_key = voxels
+                .indexer
+                .chunk_key_containing_point(&array.extent().minimum);
+            if *array.extent() != voxels.indexer.extent_for_chunk_at_key(chunk_key) {
+                return Err(Box::new(bincode::ErrorKind::Custom(format!(
+                    "chunk at {:?} doesn't match the chunk shape {:?}",
+                    chunk.minimum, chunk_shape
+                ))));
+            }
+            voxels.write_chunk(chunk_key, Chunk3::with_array(array));
+        }
+
+        Ok(Self::new(voxels, palette))
+    }
+}
@@ -0,0 +1,55 @@
+use building_blocks::prelude::*;
+use std::{ops::Deref, sync::Arc};
+
+/// A copy-on-write chunk array, shared by reference count. A chunk replaced by a merge is moved out
+/// of the map into a `SharedChunk` instead of being copied, so the `DirtyChunks` and `MapVersions`
+/// can both hold it: cloning only bumps the count, and the voxels are copied when a shared chunk is
+/// modified with `make_mut`.
+#[derive(Debug)]
+pub struct SharedChunk<V> {
+    array: Arc<Array3<V>>,
+}
+
+impl<V> Clone for SharedChunk<V> {
+    fn clone(&self) -> Self {
+        Self {
+            array: self.array.clone(),
+        }
+    }
+}
+
+impl<V> Deref for SharedChunk<V> {
+    type Target = Array3<V>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.array
+    }
+}
+
+impl<V> From<Array3<V>> for SharedChunk<V> {
+    fn from(array: Array3<V>) -> Self {
+        Self {
+            array: Arc::new(array),
+        }
+    }
+}
+
+impl<V> SharedChunk<V>
+where
+    V: Clone,
+{
+    /// The voxels for writing, copied first if any other `SharedChunk` holds them.
+    pub fn make_mut(&mut self) -> &mut Array3<V> {
+        Arc::make_mut(&mut self.array)
+    }
+
+    /// Takes the voxels, copying them only if any other `SharedChunk` holds them.
+    pub fn into_array(self) -> Array3<V> {
+        Arc::try_unwrap(self.array).unwrap_or_else(|array| (*array).clone())
+    }
+
+    /// `true` if both hold the same voxels, without comparing them.
+    pub fn ptr_eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.array, &other.array)
+    }
+}
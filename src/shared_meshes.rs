@@ -0,0 +1,155 @@
+use crate::{DirtyChunks, EmptyChunks, Voxel, VoxelReader};
+
+use bevy::prelude::*;
+use building_blocks::prelude::*;
+use fnv::FnvHashMap;
+use std::hash::{Hash, Hasher};
+use twox_hash::XxHash64;
+
+/// Manages the `SharedChunkMeshes` resource, which lets identical chunks share one `Handle<Mesh>`,
+/// e.g. in flat terrain or worlds built from repeated prefabs. Depends on the `MapIoPlugin`.
+///
+/// This crate doesn't mesh chunks for rendering itself; the game's meshing system looks up the
+/// mesh for a chunk's hash before generating a new one. Since meshes usually depend on the voxels
+/// bordering a chunk, hash with `padded_chunk_hash`, using the same padding as the mesher.
+///
+/// A chunk's mesh is released when the chunk is dirtied or removed, in the `FIRST` stage, so
+/// meshing systems can treat `DirtyChunks` as the chunks to look up again. A mesh is dropped once no
+/// chunk uses it.
+pub struct SharedChunkMeshesPlugin<V> {
+    marker: std::marker::PhantomData<V>,
+}
+
+impl<V> Default for SharedChunkMeshesPlugin<V> {
+    fn default() -> Self {
+        Self {
+            marker: Default::default(),
+        }
+    }
+}
+
+impl<V> Plugin for SharedChunkMeshesPlugin<V>
+where
+    V: Voxel,
+{
+    fn build(&self, app: &mut AppBuilder) {
+        app.insert_resource(SharedChunkMeshes::<V>::default())
+            .add_system_to_stage(stage::FIRST, shared_chunk_meshes_system::<V>.system());
+    }
+}
+
+/// The xxHash of every voxel in the chunk at `chunk_key`, padded by `padding` voxels on every side.
+/// Two chunks with the same hash produce the same mesh from any mesher that reads at most that far
+/// outside the chunk, though the mesh still has to be placed at each chunk's own position.
+pub fn padded_chunk_hash<V>(voxel_reader: &VoxelReader<V>, chunk_key: Point3i, padding: i32) -> u64
+where
+    V: Voxel + Hash,
+{
+    let extent = voxel_reader
+        .map
+        .voxels
+        .indexer
+        .extent_for_chunk_at_key(chunk_key);
+    let padded = Extent3i::from_min_and_max(
+        extent.minimum - PointN([padding; 3]),
+        extent.max() + PointN([padding; 3]),
+    );
+    let mut hasher = XxHash64::with_seed(0);
+    voxel_reader.for_each(&padded, |_p: Point3i, voxel: V| voxel.hash(&mut hasher));
+
+    hasher.finish()
+}
+
+/// Meshes shared between chunks with the same content hash.
+pub struct SharedChunkMeshes<V> {
+    meshes: FnvHashMap<u64, SharedMesh>,
+    chunk_hashes: FnvHashMap<Point3i, u64>,
+    marker: std::marker::PhantomData<V>,
+}
+
+struct SharedMesh {
+    handle: Handle<Mesh>,
+    num_chunks: usize,
+}
+
+impl<V> Default for SharedChunkMeshes<V> {
+    fn default() -> Self {
+        Self {
+            meshes: Default::default(),
+            chunk_hashes: Default::default(),
+            marker: Default::default(),
+        }
+    }
+}
+
+impl<V> SharedChunkMeshes<V> {
+    /// The mesh of the chunk at `chunk_key`, if it has one.
+    pub fn get(&self, chunk_key: &Point3i) -> Option<&Handle<Mesh>> {
+        self.chunk_hashes
+            .get(chunk_key)
+            .map(|hash| &self.meshes[hash].handle)
+    }
+
+    /// Uses the mesh for `hash` for the chunk at `chunk_key`, calling `make_mesh` only if no other
+    /// chunk with that hash has a mesh yet.
+    pub fn get_or_insert_with(
+        &mut self,
+        chunk_key: Point3i,
+        hash: u64,
+        make_mesh: impl FnOnce() -> Handle<Mesh>,
+    ) -> Handle<Mesh> {
+        if self.chunk_hashes.get(&chunk_key) != Some(&hash) {
+            self.release(&chunk_key);
+            self.chunk_hashes.insert(chunk_key, hash);
+            self.meshes
+                .entry(hash)
+                .or_insert_with(|| SharedMesh {
+                    handle: make_mesh(),
+                    num_chunks: 0,
+                })
+                .num_chunks += 1;
+        }
+
+        self.meshes[&hash].handle.clone()
+    }
+
+    /// Stops using a mesh for the chunk at `chunk_key`, e.g. because it was modified without going
+    /// through the `MapIoPlugin`.
+    pub fn release(&mut self, chunk_key: &Point3i) {
+        let hash = match self.chunk_hashes.remove(chunk_key) {
+            Some(hash) => hash,
+            None => return,
+        };
+        let mesh = self.meshes.get_mut(&hash).unwrap();
+        mesh.num_chunks -= 1;
+        if mesh.num_chunks == 0 {
+            self.meshes.remove(&hash);
+        }
+    }
+
+    /// The number of distinct meshes in use.
+    pub fn num_meshes(&self) -> usize {
+        self.meshes.len()
+    }
+
+    /// The number of chunks with a mesh.
+    pub fn num_chunks(&self) -> usize {
+        self.chunk_hashes.len()
+    }
+}
+
+fn shared_chunk_meshes_system<V>(
+    dirty_chunks: Res<DirtyChunks<V>>,
+    empty_chunks: Res<EmptyChunks<V>>,
+    mut shared_meshes: ResMut<SharedChunkMeshes<V>>,
+) where
+    V: Voxel,
+{
+    for chunk_key in dirty_chunks
+        .dirty_chunk_keys
+        .iter()
+        .chain(empty_chunks.removed_chunk_keys())
+    {
+        shared_meshes.release(chunk_key);
+    }
+}
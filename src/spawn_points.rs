@@ -0,0 +1,167 @@
+use crate::{column_key, ChunkColumns, Voxel, VoxelReader};
+
+use building_blocks::prelude::*;
+use fnv::FnvHashMap;
+
+/// What makes a voxel position a valid place to spawn, for `VoxelReader::find_spawn_point`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SpawnConstraints {
+    /// The number of empty voxels needed above the ground, e.g. the height of a character.
+    pub clearance: i32,
+    /// The largest difference in ground height between the spawn column and any of its 4
+    /// horizontal neighbors.
+    pub max_slope: i32,
+    /// The smallest distance to any other spawn point.
+    pub exclusion_radius: f32,
+    /// How far to search from the center, both horizontally and vertically.
+    pub max_radius: i32,
+}
+
+impl Default for SpawnConstraints {
+    fn default() -> Self {
+        Self {
+            clearance: 2,
+            max_slope: 1,
+            exclusion_radius: 0.0,
+            max_radius: 32,
+        }
+    }
+}
+
+const HORIZONTAL_OFFSETS: [[i32; 2]; 4] = [[-1, 0], [1, 0], [0, -1], [0, 1]];
+
+impl<'a, V> VoxelReader<'a, V>
+where
+    V: Voxel,
+    for<'r> &'r V::TypeInfo: IsEmpty,
+{
+    /// Finds a place to spawn near `center` that satisfies `constraints` and keeps its distance from
+    /// `other_spawns`. The returned point is the first empty voxel above solid ground.
+    ///
+    /// Columns are searched in square rings of growing radius around `center`, so a spawn in a
+    /// nearer ring always wins, and ties within a ring go to the nearest point. Columns without
+    /// chunks in `columns`, from the `ChunkColumnsPlugin`, are skipped without being read. Within a
+    /// column, the ground nearest to `center` vertically is used, so spawns can be found in caves as
+    /// well as on the surface.
+    pub fn find_spawn_point(
+        &self,
+        center: Point3i,
+        constraints: &SpawnConstraints,
+        columns: &ChunkColumns<V>,
+        other_spawns: &[Point3i],
+    ) -> Option<Point3i> {
+        let palette = &self.map.palette;
+        let min_y = center.y() - constraints.max_radius;
+        let max_y = center.y() + constraints.max_radius;
+
+        self.read(|reader| {
+            let is_empty = |p: Point3i| palette.get_voxel_type_info(reader.get(&p)).is_empty();
+            let mut ground_heights: FnvHashMap<[i32; 2], Option<i32>> = Default::default();
+            let mut ground_height = |x: i32, z: i32| {
+                *ground_heights.entry([x, z]).or_insert_with(|| {
+                    let chunk_key = reader
+                        .indexer
+                        .chunk_key_containing_point(&PointN([x, 0, z]));
+                    let column = columns.get(&column_key(chunk_key))?;
+                    let top = max_y.min(column.max_chunk_y()?);
+                    let bottom = min_y.max(column.min_chunk_y()?);
+
+                    nearest_ground(
+                        x,
+                        z,
+                        center.y(),
+                        bottom,
+                        top,
+                        constraints.clearance,
+                        &is_empty,
+                    )
+                })
+            };
+
+            for radius in 0..=constraints.max_radius {
+                let mut best: Option<(i64, Point3i)> = None;
+                for_each_ring_offset(radius, |[dx, dz]| {
+                    let (x, z) = (center.x() + dx, center.z() + dz);
+                    let y = match ground_height(x, z) {
+                        Some(y) => y,
+                        None => return,
+                    };
+                    let level = HORIZONTAL_OFFSETS.iter().all(|[ox, oz]| {
+                        ground_height(x + ox, z + oz)
+                            .map_or(false, |ny| (ny - y).abs() <= constraints.max_slope)
+                    });
+                    if !level {
+                        return;
+                    }
+                    let spawn = PointN([x, y + 1, z]);
+                    let excluded = other_spawns.iter().any(|other| {
+                        (distance_sq(spawn, *other) as f32).sqrt() < constraints.exclusion_radius
+                    });
+                    if excluded {
+                        return;
+                    }
+                    let dist_sq = distance_sq(spawn, center);
+                    if best.map_or(true, |(best_dist_sq, _)| dist_sq < best_dist_sq) {
+                        best = Some((dist_sq, spawn));
+                    }
+                });
+                if let Some((_, spawn)) = best {
+                    return Some(spawn);
+                }
+            }
+
+            None
+        })
+    }
+}
+
+/// The Y of the solid voxel in `bottom..=top` with `clearance` empty voxels above it that's nearest
+/// to `center_y`.
+fn nearest_ground(
+    x: i32,
+    z: i32,
+    center_y: i32,
+    bottom: i32,
+    top: i32,
+    clearance: i32,
+    is_empty: impl Fn(Point3i) -> bool,
+) -> Option<i32> {
+    let mut nearest = None;
+    // The number of empty voxels directly above `y`.
+    let mut empty_above = 0;
+    for y in (bottom..=top + clearance).rev() {
+        let empty = is_empty(PointN([x, y, z]));
+        if !empty && y <= top && empty_above >= clearance {
+            let is_nearer =
+                nearest.map_or(true, |n: i32| (y - center_y).abs() < (n - center_y).abs());
+            if !is_nearer {
+                // Only farther ground is left below.
+                break;
+            }
+            nearest = Some(y);
+        }
+        empty_above = if empty { empty_above + 1 } else { 0 };
+    }
+
+    nearest
+}
+
+fn distance_sq(a: Point3i, b: Point3i) -> i64 {
+    let d = a - b;
+
+    d.x() as i64 * d.x() as i64 + d.y() as i64 * d.y() as i64 + d.z() as i64 * d.z() as i64
+}
+
+/// Visits the horizontal offsets whose largest component has magnitude `radius`.
+fn for_each_ring_offset(radius: i32, mut f: impl FnMut([i32; 2])) {
+    for dz in -radius..=radius {
+        if dz.abs() == radius {
+            for dx in -radius..=radius {
+                f([dx, dz]);
+            }
+        } else {
+            f([-radius, dz]);
+            f([radius, dz]);
+        }
+    }
+}
@@ -0,0 +1,187 @@
+use crate::{Voxel, VoxelEditor};
+
+use bevy::prelude::*;
+use building_blocks::prelude::*;
+use fnv::FnvHashMap;
+use std::fmt;
+
+/// Manages the `Structures` resource, a registry of named voxel structures that can be placed
+/// with `VoxelEditor::try_place_structure`. Depends on the `MapIoPlugin`.
+pub struct StructuresPlugin<V> {
+    marker: std::marker::PhantomData<V>,
+}
+
+impl<V> Default for StructuresPlugin<V> {
+    fn default() -> Self {
+        Self {
+            marker: Default::default(),
+        }
+    }
+}
+
+impl<V> Plugin for StructuresPlugin<V>
+where
+    V: Voxel,
+{
+    fn build(&self, app: &mut AppBuilder) {
+        app.insert_resource(Structures::<V>::default());
+    }
+}
+
+/// When a structure may be placed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PlacementRules {
+    /// Every non-empty voxel in the bottom layer of the structure must rest on a non-empty voxel.
+    pub on_solid_ground: bool,
+    /// The non-empty voxels of the structure must not replace non-empty voxels in the map.
+    pub no_overlap: bool,
+}
+
+impl Default for PlacementRules {
+    fn default() -> Self {
+        Self {
+            on_solid_ground: true,
+            no_overlap: true,
+        }
+    }
+}
+
+/// A structure's voxels and the rules for placing it. Empty voxels in the structure leave the map
+/// untouched, so it doesn't have to be box-shaped.
+pub struct Structure<V> {
+    pub voxels: Array3<V>,
+    pub rules: PlacementRules,
+}
+
+/// Named structures, like houses or dungeon rooms.
+pub struct Structures<V> {
+    structures: FnvHashMap<String, Structure<V>>,
+}
+
+impl<V> Default for Structures<V> {
+    fn default() -> Self {
+        Self {
+            structures: Default::default(),
+        }
+    }
+}
+
+impl<V> Structures<V> {
+    /// Registers `voxels` as `name`, replacing any structure with that name.
+    pub fn register(&mut self, name: impl Into<String>, voxels: Array3<V>, rules: PlacementRules) {
+        self.structures
+            .insert(name.into(), Structure { voxels, rules });
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<Structure<V>> {
+        self.structures.remove(name)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Structure<V>> {
+        self.structures.get(name)
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.structures.keys().map(|name| name.as_str())
+    }
+}
+
+/// Why `VoxelEditor::try_place_structure` didn't place a structure.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum PlacementError {
+    UnknownStructure(String),
+    /// The structure would reach outside the `WorldBounds`.
+    OutOfBounds(Extent3i),
+    /// The structure would replace the non-empty voxel at this point.
+    Overlaps(Point3i),
+    /// The structure's voxel at this point wouldn't rest on a non-empty voxel.
+    Unsupported(Point3i),
+}
+
+impl fmt::Display for PlacementError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PlacementError::UnknownStructure(name) => write!(f, "no structure named {:?}", name),
+            PlacementError::OutOfBounds(extent) => {
+                write!(f, "extent {:?} is outside the world bounds", extent)
+            }
+            PlacementError::Overlaps(p) => write!(f, "overlaps a voxel at {:?}", p.0),
+            PlacementError::Unsupported(p) => write!(f, "no ground under {:?}", p.0),
+        }
+    }
+}
+
+impl std::error::Error for PlacementError {}
+
+impl<'a, V> VoxelEditor<'a, V>
+where
+    V: Voxel,
+    for<'r> &'r V::TypeInfo: IsEmpty,
+{
+    /// Places the structure `name` with its minimum at `origin`, if it satisfies its
+    /// `PlacementRules` against the map, including the edits made so far this frame. Returns the
+    /// extent of the placed structure, or the first rule it broke. All edited chunks and their
+    /// neighbors will be marked as dirty.
+    pub fn try_place_structure(
+        &mut self,
+        structures: &Structures<V>,
+        name: &str,
+        origin: Point3i,
+    ) -> Result<Extent3i, PlacementError> {
+        let structure = structures
+            .get(name)
+            .ok_or_else(|| PlacementError::UnknownStructure(name.to_string()))?;
+        let source_extent = *structure.voxels.extent();
+        let offset = origin - source_extent.minimum;
+        let extent = Extent3i::from_min_and_shape(origin, source_extent.shape);
+        if !self.extent_in_bounds(&extent) {
+            return Err(PlacementError::OutOfBounds(extent));
+        }
+
+        // Look up the palette up front, since the map can't be borrowed during the edit.
+        let is_empty: Vec<bool> = self
+            .map
+            .palette
+            .infos
+            .iter()
+            .map(|info| info.is_empty())
+            .collect();
+        let voxel_is_empty = |voxel: V| {
+            is_empty
+                .get(voxel.get_type_index())
+                .cloned()
+                .unwrap_or(false)
+        };
+
+        let mut solid_points = Vec::new();
+        structure
+            .voxels
+            .for_each(&source_extent, |p: Point3i, voxel: V| {
+                if !voxel_is_empty(voxel) {
+                    solid_points.push(p + offset);
+                }
+            });
+
+        let rules = structure.rules;
+        for &p in solid_points.iter() {
+            if rules.no_overlap && !voxel_is_empty(self.current_voxel(p)) {
+                return Err(PlacementError::Overlaps(p));
+            }
+            if rules.on_solid_ground && p.y() == extent.minimum.y() {
+                let below = p - PointN([0, 1, 0]);
+                if voxel_is_empty(self.current_voxel(below)) {
+                    return Err(PlacementError::Unsupported(p));
+                }
+            }
+        }
+
+        self.edit_extent_and_touch_neighbors(extent, |p: Point3i, voxel: &mut V| {
+            let new_voxel = structure.voxels.get(&(p - offset));
+            if !voxel_is_empty(new_voxel) {
+                *voxel = new_voxel;
+            }
+        });
+
+        Ok(extent)
+    }
+}
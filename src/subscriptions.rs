@@ -0,0 +1,196 @@
+use crate::{DirtyChunks, Voxel, VoxelMap};
+
+use bevy::prelude::*;
+use building_blocks::prelude::*;
+use fnv::{FnvHashMap, FnvHashSet};
+
+/// Manages the `ExtentSubscriptions` resource, which notifies systems when chunks overlapping
+/// specific extents become dirty. Depends on the `MapIoPlugin`.
+///
+/// ```
+/// use bevy::prelude::*;
+/// use bevy_building_blocks::{
+///     bb::prelude::*, ExtentChanged, ExtentSubscriptionId, ExtentSubscriptions, Voxel,
+/// };
+///
+/// fn machine_system<V: Voxel>(
+///     mut subscriptions: ResMut<ExtentSubscriptions<V>>,
+///     mut machine_subscription: Local<Option<ExtentSubscriptionId>>,
+///     changes: Res<Events<ExtentChanged<V>>>,
+///     mut change_reader: Local<EventReader<ExtentChanged<V>>>,
+/// ) {
+///     let machine_area = Extent3i::from_min_and_shape(PointN([0; 3]), PointN([8; 3]));
+///     let subscription =
+///         *machine_subscription.get_or_insert_with(|| subscriptions.subscribe(machine_area));
+///
+///     for change in change_reader.iter(&changes) {
+///         if change.subscription == subscription {
+///             // Re-scan the machine's surroundings.
+///         }
+///     }
+/// }
+/// ```
+pub struct ExtentSubscriptionsPlugin<V> {
+    marker: std::marker::PhantomData<V>,
+}
+
+impl<V> Default for ExtentSubscriptionsPlugin<V> {
+    fn default() -> Self {
+        Self {
+            marker: Default::default(),
+        }
+    }
+}
+
+impl<V> Plugin for ExtentSubscriptionsPlugin<V>
+where
+    V: Voxel,
+{
+    fn build(&self, app: &mut AppBuilder) {
+        app.add_event::<ExtentChanged<V>>()
+            .insert_resource(ExtentSubscriptions::<V>::default())
+            // Notify before consumers run in UPDATE.
+            .add_system_to_stage(stage::PRE_UPDATE, extent_subscriptions_system::<V>.system());
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct ExtentSubscriptionId(u64);
+
+/// Sent at most once per frame for each subscription with dirty chunks in its extent.
+pub struct ExtentChanged<V> {
+    pub subscription: ExtentSubscriptionId,
+    /// The keys of the dirty chunks that overlap the subscribed extent.
+    pub chunk_keys: Vec<Point3i>,
+    marker: std::marker::PhantomData<V>,
+}
+
+/// World-space extents that systems want to watch for changes, e.g. the area around a machine
+/// entity. Every frame, the chunks in `DirtyChunks` are looked up in an index of subscribed chunks,
+/// so the cost doesn't grow with the number of subscriptions.
+///
+/// Subscribing and moving are cheap, but the index is updated by a system, so changes to the
+/// subscriptions take effect on the next `PRE_UPDATE` stage. Indexing costs time proportional to
+/// the number of chunks overlapping the extent.
+pub struct ExtentSubscriptions<V> {
+    next_id: u64,
+    extents: FnvHashMap<ExtentSubscriptionId, Extent3i>,
+    // The subscriptions overlapping each chunk.
+    chunk_subscriptions: FnvHashMap<Point3i, Vec<ExtentSubscriptionId>>,
+    indexed_chunk_keys: FnvHashMap<ExtentSubscriptionId, Vec<Point3i>>,
+    // Subscriptions that were added or moved since the last index update.
+    unindexed: FnvHashSet<ExtentSubscriptionId>,
+    marker: std::marker::PhantomData<V>,
+}
+
+impl<V> Default for ExtentSubscriptions<V> {
+    fn default() -> Self {
+        Self {
+            next_id: 0,
+            extents: Default::default(),
+            chunk_subscriptions: Default::default(),
+            indexed_chunk_keys: Default::default(),
+            unindexed: Default::default(),
+            marker: Default::default(),
+        }
+    }
+}
+
+impl<V> ExtentSubscriptions<V> {
+    pub fn subscribe(&mut self, extent: Extent3i) -> ExtentSubscriptionId {
+        let id = ExtentSubscriptionId(self.next_id);
+        self.next_id += 1;
+        self.extents.insert(id, extent);
+        self.unindexed.insert(id);
+
+        id
+    }
+
+    /// Moves the subscription to a new extent. Returns `false` if there is no such subscription.
+    pub fn set_extent(&mut self, id: ExtentSubscriptionId, extent: Extent3i) -> bool {
+        match self.extents.get_mut(&id) {
+            Some(e) => *e = extent,
+            None => return false,
+        }
+        self.unindex(id);
+        self.unindexed.insert(id);
+
+        true
+    }
+
+    pub fn unsubscribe(&mut self, id: ExtentSubscriptionId) {
+        self.extents.remove(&id);
+        self.unindex(id);
+        self.unindexed.remove(&id);
+    }
+
+    pub fn extent(&self, id: ExtentSubscriptionId) -> Option<Extent3i> {
+        self.extents.get(&id).cloned()
+    }
+
+    pub fn len(&self) -> usize {
+        self.extents.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.extents.is_empty()
+    }
+
+    fn unindex(&mut self, id: ExtentSubscriptionId) {
+        let chunk_keys = match self.indexed_chunk_keys.remove(&id) {
+            Some(k) => k,
+            None => return,
+        };
+        for chunk_key in chunk_keys.iter() {
+            if let Some(ids) = self.chunk_subscriptions.get_mut(chunk_key) {
+                ids.retain(|i| *i != id);
+                if ids.is_empty() {
+                    self.chunk_subscriptions.remove(chunk_key);
+                }
+            }
+        }
+    }
+}
+
+fn extent_subscriptions_system<V>(
+    voxel_map: Res<VoxelMap<V>>,
+    dirty_chunks: Res<DirtyChunks<V>>,
+    mut subscriptions: ResMut<ExtentSubscriptions<V>>,
+    mut change_events: ResMut<Events<ExtentChanged<V>>>,
+) where
+    V: Voxel,
+{
+    let subscriptions = &mut *subscriptions;
+    for id in subscriptions.unindexed.drain() {
+        let extent = subscriptions.extents[&id];
+        let chunk_keys: Vec<Point3i> = voxel_map
+            .voxels
+            .indexer
+            .chunk_keys_for_extent(&extent)
+            .collect();
+        for chunk_key in chunk_keys.iter() {
+            subscriptions
+                .chunk_subscriptions
+                .entry(*chunk_key)
+                .or_default()
+                .push(id);
+        }
+        subscriptions.indexed_chunk_keys.insert(id, chunk_keys);
+    }
+
+    let mut changes: FnvHashMap<ExtentSubscriptionId, Vec<Point3i>> = Default::default();
+    for chunk_key in dirty_chunks.dirty_chunk_keys.iter() {
+        if let Some(ids) = subscriptions.chunk_subscriptions.get(chunk_key) {
+            for id in ids.iter() {
+                changes.entry(*id).or_default().push(*chunk_key);
+            }
+        }
+    }
+    for (subscription, chunk_keys) in changes.into_iter() {
+        change_events.send(ExtentChanged {
+            subscription,
+            chunk_keys,
+            marker: Default::default(),
+        });
+    }
+}
@@ -0,0 +1,121 @@
+use bevy::{
+    prelude::*,
+    tasks::{ComputeTaskPool, TaskPool, TaskPoolBuilder},
+};
+use std::ops::Deref;
+
+/// The task pool used for background voxel work, like compression and octree generation. Inserted by
+/// the `MapIoPlugin` according to its `VoxelTaskPoolConfig`.
+#[derive(Clone, Debug)]
+pub struct VoxelTaskPool(pub TaskPool);
+
+impl Deref for VoxelTaskPool {
+    type Target = TaskPool;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+/// Chooses which threads run background voxel work.
+#[derive(Clone, Debug)]
+pub enum VoxelTaskPoolConfig {
+    /// Share Bevy's `ComputeTaskPool`.
+    Compute,
+    /// Create a dedicated pool with this many threads, so voxel work doesn't compete with other
+    /// systems. Fewer threads also throttle the amount of voxel work done per frame.
+    Dedicated { num_threads: usize },
+    /// Use an existing pool, e.g. one shared with other libraries.
+    Pool(TaskPool),
+}
+
+impl Default for VoxelTaskPoolConfig {
+    fn default() -> Self {
+        VoxelTaskPoolConfig::Compute
+    }
+}
+
+impl VoxelTaskPoolConfig {
+    /// Makes the `VoxelTaskPool` resource available, unless another plugin already inserted one.
+    /// When sharing the `ComputeTaskPool`, the resource is inserted at startup.
+    pub(crate) fn insert_into(&self, app: &mut AppBuilder) {
+        if app.resources().contains::<VoxelTaskPool>() {
+            return;
+        }
+        match self {
+            VoxelTaskPoolConfig::Compute => {
+                app.add_startup_system(share_compute_task_pool_system.system());
+            }
+            VoxelTaskPoolConfig::Dedicated { num_threads } => {
+                app.insert_resource(VoxelTaskPool(
+                    TaskPoolBuilder::new()
+                        .num_threads(*num_threads)
+                        .thread_name("Voxel Task Pool".to_string())
+                        .build(),
+                ));
+            }
+            VoxelTaskPoolConfig::Pool(pool) => {
+                app.insert_resource(VoxelTaskPool(pool.clone()));
+            }
+        }
+    }
+}
+
+fn share_compute_task_pool_system(
+    commands: &mut Commands,
+    compute_pool: Res<ComputeTaskPool>,
+    existing_pool: Option<Res<VoxelTaskPool>>,
+) {
+    // A plugin added later may have inserted a dedicated pool.
+    if existing_pool.is_none() {
+        commands.insert_resource(VoxelTaskPool(compute_pool.0.clone()));
+    }
+}
+
+/// Runs `f` on every item, spreading the work across `pool`. Results are returned in completion
+/// order.
+#[cfg(not(any(feature = "single_thread", target_arch = "wasm32")))]
+pub(crate) fn map_in_pool<I, T, F>(
+    pool: &TaskPool,
+    items: impl IntoIterator<Item = I>,
+    f: F,
+) -> Vec<T>
+where
+    I: Send,
+    T: 'static + Send,
+    F: Fn(I) -> T + Sync,
+{
+    let items: Vec<I> = items.into_iter().collect();
+    let f = &f;
+
+    pool.scope(|s| {
+        for item in items.into_iter() {
+            s.spawn(async move { f(item) });
+        }
+    })
+}
+
+/// Runs `f` on every item, in order, on the current thread.
+#[cfg(any(feature = "single_thread", target_arch = "wasm32"))]
+pub(crate) fn map_in_pool<I, T, F>(
+    _pool: &TaskPool,
+    items: impl IntoIterator<Item = I>,
+    f: F,
+) -> Vec<T>
+where
+    F: Fn(I) -> T,
+{
+    items.into_iter().map(f).collect()
+}
+
+/// Runs `f` in the background on `pool` without waiting for it to finish.
+#[cfg(not(any(feature = "single_thread", target_arch = "wasm32")))]
+pub(crate) fn spawn_detached(pool: &TaskPool, f: impl FnOnce() + Send + 'static) {
+    pool.spawn(async move { f() }).detach();
+}
+
+/// Runs `f` immediately on the current thread.
+#[cfg(any(feature = "single_thread", target_arch = "wasm32"))]
+pub(crate) fn spawn_detached(_pool: &TaskPool, f: impl FnOnce()) {
+    f()
+}
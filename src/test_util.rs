@@ -0,0 +1,91 @@
+//! Voxels and helpers shared by the unit tests.
+
+use crate::{empty_compressible_chunk_map, FixedSizeCodec, Voxel, VoxelMap, VoxelPalette};
+
+use building_blocks::prelude::*;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// Type index 0 is empty. Bytes above `MAX_TEST_VOXEL` don't decode.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct TestVoxel(pub u8);
+
+pub const MAX_TEST_VOXEL: u8 = 200;
+
+impl Voxel for TestVoxel {
+    type TypeInfo = ();
+
+    fn get_type_index(&self) -> usize {
+        self.0 as usize
+    }
+}
+
+pub fn test_codec() -> FixedSizeCodec<TestVoxel> {
+    FixedSizeCodec {
+        version: 1,
+        voxel_size: 1,
+        encode: |v, out| out.push(v.0),
+        decode: |bytes| Some(TestVoxel(bytes[0])).filter(|v| v.0 <= MAX_TEST_VOXEL),
+    }
+}
+
+pub fn test_map(chunk_shape: Point3i) -> VoxelMap<TestVoxel> {
+    VoxelMap::new(
+        empty_compressible_chunk_map(chunk_shape),
+        VoxelPalette { infos: Vec::new() },
+    )
+}
+
+/// A chunk in `extent` where every voxel is different, as far as a `u8` allows.
+pub fn numbered_array(extent: Extent3i) -> Array3<TestVoxel> {
+    let mut array = Array3::fill(extent, TestVoxel::default());
+    let mut i = 0u8;
+    array.for_each_mut(&extent, |_p: Point3i, v: &mut TestVoxel| {
+        *v = TestVoxel(i % MAX_TEST_VOXEL);
+        i = i.wrapping_add(1);
+    });
+
+    array
+}
+
+pub fn assert_same_voxels(a: &Array3<TestVoxel>, b: &Array3<TestVoxel>) {
+    assert_eq!(a.extent(), b.extent());
+    a.for_each(a.extent(), |p: Point3i, v: TestVoxel| {
+        assert_eq!(v, b.get(&p), "voxels differ at {:?}", p.0)
+    });
+}
+
+/// A fresh directory under the system's temporary directory, deleted on drop.
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let path = std::env::temp_dir().join(format!(
+            "bevy_building_blocks_{}_{}_{}",
+            name,
+            std::process::id(),
+            id
+        ));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+
+        Self { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
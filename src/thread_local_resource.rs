@@ -1,14 +1,30 @@
 use std::sync::Arc;
+
+#[cfg(not(any(feature = "single_thread", target_arch = "wasm32")))]
 use thread_local::ThreadLocal;
 
+#[cfg(any(feature = "single_thread", target_arch = "wasm32"))]
+use single_thread::SingleThreadLocal as ThreadLocal;
+
+/// A resource that gives each thread its own instance of `T`.
+///
+/// With the `single_thread` feature, or when targeting wasm32, there is only one instance of `T`.
+/// It may only be accessed from a single thread, which will panic otherwise, so native builds using
+/// this mode should also limit Bevy's task pools to one thread.
+///
+/// Methods that take `&mut self` need every `ThreadLocalResourceHandle` to be dropped first, and
+/// they panic otherwise.
 #[derive(Default)]
 pub struct ThreadLocalResource<T>
 where
     T: Send,
 {
     tls: Arc<ThreadLocal<T>>,
+    init: Option<InitFn<T>>,
 }
 
+type InitFn<T> = Arc<dyn Fn() -> T + Send + Sync>;
+
 impl<T> ThreadLocalResource<T>
 where
     T: Send,
@@ -16,12 +32,23 @@ where
     pub fn new() -> Self {
         Self {
             tls: Arc::new(ThreadLocal::new()),
+            init: None,
+        }
+    }
+
+    /// Each thread's value is created by `init` the first time that thread calls
+    /// `ThreadLocalResourceHandle::get`.
+    pub fn with_init(init: impl Fn() -> T + Send + Sync + 'static) -> Self {
+        Self {
+            tls: Arc::new(ThreadLocal::new()),
+            init: Some(Arc::new(init)),
         }
     }
 
     pub fn get(&self) -> ThreadLocalResourceHandle<T> {
         ThreadLocalResourceHandle {
             tls: self.tls.clone(),
+            init: self.init.clone(),
         }
     }
 
@@ -36,6 +63,45 @@ where
 
         tls.into_iter()
     }
+
+    /// Takes every thread's value, leaving the resource empty. Threads will create new values the
+    /// next time they access the resource.
+    pub fn drain(&mut self) -> impl Iterator<Item = T> {
+        let taken = Self {
+            tls: std::mem::replace(&mut self.tls, Arc::new(ThreadLocal::new())),
+            init: None,
+        };
+
+        taken.into_iter()
+    }
+
+    /// Drops every thread's value.
+    pub fn clear(&mut self) {
+        self.tls_mut().clear();
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut T> {
+        self.tls_mut().iter_mut()
+    }
+
+    /// The number of threads that have created a value.
+    pub fn len(&mut self) -> usize {
+        self.iter_mut().count()
+    }
+
+    pub fn is_empty(&mut self) -> bool {
+        self.len() == 0
+    }
+
+    fn tls_mut(&mut self) -> &mut ThreadLocal<T> {
+        match Arc::get_mut(&mut self.tls) {
+            Some(tls) => tls,
+            None => panic!(
+                "Failed to borrow Arc'd thread-local storage; \
+                there must be an outstanding strong reference"
+            ),
+        }
+    }
 }
 
 pub struct ThreadLocalResourceHandle<T>
@@ -43,16 +109,35 @@ where
     T: Send,
 {
     tls: Arc<ThreadLocal<T>>,
+    init: Option<InitFn<T>>,
 }
 
 impl<T> ThreadLocalResourceHandle<T>
 where
     T: Send,
 {
+    /// This thread's value, created by the resource's init function if needed.
+    ///
+    /// Panics if the resource wasn't created with `ThreadLocalResource::with_init`.
+    pub fn get(&self) -> &T {
+        self.tls.get_or(|| match &self.init {
+            Some(init) => init(),
+            None => panic!("ThreadLocalResource has no init function; use get_or_create_with"),
+        })
+    }
+
     pub fn get_or_create_with(&self, create: impl FnOnce() -> T) -> &T {
         self.tls.get_or(create)
     }
 
+    /// Like `get`, but falls back to `create` if the resource has no init function.
+    pub fn get_or_init_with(&self, create: impl FnOnce() -> T) -> &T {
+        self.tls.get_or(|| match &self.init {
+            Some(init) => init(),
+            None => create(),
+        })
+    }
+
     pub fn get_or_default(&self) -> &T
     where
         T: Default,
@@ -60,3 +145,70 @@ where
         self.tls.get_or_default()
     }
 }
+
+#[cfg(any(feature = "single_thread", target_arch = "wasm32"))]
+mod single_thread {
+    use once_cell::{sync, unsync};
+    use std::thread::{self, ThreadId};
+
+    /// A stand-in for `ThreadLocal` that holds a single value, owned by the first thread to access
+    /// it.
+    pub struct SingleThreadLocal<T> {
+        owner: sync::OnceCell<ThreadId>,
+        value: unsync::OnceCell<T>,
+    }
+
+    // SAFETY: `value` is only ever accessed by the `owner` thread, which is checked on every access.
+    unsafe impl<T> Sync for SingleThreadLocal<T> where T: Send {}
+
+    impl<T> Default for SingleThreadLocal<T> {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl<T> SingleThreadLocal<T> {
+        pub fn new() -> Self {
+            Self {
+                owner: sync::OnceCell::new(),
+                value: unsync::OnceCell::new(),
+            }
+        }
+
+        pub fn get_or(&self, create: impl FnOnce() -> T) -> &T {
+            let current = thread::current().id();
+            assert_eq!(
+                *self.owner.get_or_init(|| current),
+                current,
+                "ThreadLocalResource was accessed from multiple threads in single_thread mode; \
+                limit Bevy's task pools to one thread"
+            );
+
+            self.value.get_or_init(create)
+        }
+
+        pub fn get_or_default(&self) -> &T
+        where
+            T: Default,
+        {
+            self.get_or(T::default)
+        }
+
+        pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut T> {
+            self.value.get_mut().into_iter()
+        }
+
+        pub fn clear(&mut self) {
+            self.value = unsync::OnceCell::new();
+        }
+    }
+
+    impl<T> IntoIterator for SingleThreadLocal<T> {
+        type Item = T;
+        type IntoIter = std::option::IntoIter<T>;
+
+        fn into_iter(self) -> Self::IntoIter {
+            self.value.into_inner().into_iter()
+        }
+    }
+}
@@ -0,0 +1,293 @@
+use crate::{
+    column_key, tasks::map_in_pool, ChunkColumns, DirtyChunks, EmptyChunks, ThreadLocalVoxelCache,
+    Voxel, VoxelMap, VoxelMaterial, VoxelTaskPool,
+};
+
+use bevy::{
+    prelude::*,
+    render::texture::{Extent3d, FilterMode, TextureDimension, TextureFormat},
+};
+use building_blocks::prelude::*;
+use fnv::{FnvHashMap, FnvHashSet};
+use std::collections::VecDeque;
+
+/// Manages the `TopDownMap` resource, a texture with the color of the topmost non-empty voxel of
+/// every column in a fixed XZ extent of the map, e.g. for minimaps and world map screenshots.
+/// Depends on the `ChunkColumnsPlugin`.
+///
+/// Colors come from the `VoxelMaterial::base_color` of each voxel's palette entry. Every column of
+/// chunks in the extent is drawn on the first frame, and after that only the columns with edited or
+/// removed chunks in the previous frame's `DirtyChunks` and `EmptyChunks`, a few columns per frame
+/// in the `PRE_UPDATE` stage.
+pub struct TopDownMapPlugin<V> {
+    /// The X and Z voxel coordinates covered by the map.
+    pub extent: Extent2i,
+    marker: std::marker::PhantomData<V>,
+}
+
+impl<V> TopDownMapPlugin<V> {
+    pub fn new(extent: Extent2i) -> Self {
+        Self {
+            extent,
+            marker: Default::default(),
+        }
+    }
+}
+
+impl<V> Plugin for TopDownMapPlugin<V>
+where
+    V: Voxel,
+    V::TypeInfo: VoxelMaterial,
+    for<'r> &'r V::TypeInfo: IsEmpty,
+{
+    fn build(&self, app: &mut AppBuilder) {
+        app.insert_resource(TopDownMap::<V>::new(self.extent))
+            .add_startup_system(create_top_down_map_texture_system::<V>.system())
+            .add_system_to_stage(stage::PRE_UPDATE, top_down_map_system::<V>.system());
+    }
+}
+
+/// A top-down view of the map as an `Rgba8UnormSrgb` texture, one texel per column of voxels. Texel
+/// `(0, 0)` is the column at the minimum X and Z of the extent, and rows run along +Z.
+///
+/// Columns without any non-empty voxels are drawn with the `background` color.
+pub struct TopDownMap<V> {
+    /// Available after startup.
+    pub texture: Handle<Texture>,
+    /// The most chunk columns drawn in a single frame.
+    pub columns_per_frame: usize,
+    /// Linear RGBA.
+    pub background: [f32; 4],
+    extent: Extent2i,
+    queue: VecDeque<Point2i>,
+    queued: FnvHashSet<Point2i>,
+    needs_full_redraw: bool,
+    marker: std::marker::PhantomData<V>,
+}
+
+impl<V> TopDownMap<V> {
+    fn new(extent: Extent2i) -> Self {
+        Self {
+            texture: Default::default(),
+            columns_per_frame: 32,
+            background: [0.0; 4],
+            extent,
+            queue: VecDeque::new(),
+            queued: Default::default(),
+            needs_full_redraw: true,
+            marker: Default::default(),
+        }
+    }
+
+    /// The X and Z voxel coordinates covered by the map.
+    pub fn extent(&self) -> Extent2i {
+        self.extent
+    }
+
+    /// Queues every column of chunks to be drawn again, e.g. after palette colors change.
+    pub fn redraw(&mut self) {
+        self.needs_full_redraw = true;
+    }
+
+    /// The number of chunk columns waiting to be drawn.
+    pub fn num_queued(&self) -> usize {
+        self.queue.len()
+    }
+
+    fn enqueue(&mut self, column_key: Point2i) {
+        if self.queued.insert(column_key) {
+            self.queue.push_back(column_key);
+        }
+    }
+}
+
+fn create_top_down_map_texture_system<V>(
+    mut top_down_map: ResMut<TopDownMap<V>>,
+    mut textures: ResMut<Assets<Texture>>,
+) where
+    V: Voxel,
+{
+    let shape = top_down_map.extent.shape;
+    let texel = srgb_bytes(top_down_map.background);
+    let mut data = Vec::with_capacity(4 * top_down_map.extent.num_points());
+    for _ in 0..top_down_map.extent.num_points() {
+        data.extend_from_slice(&texel);
+    }
+    let mut texture = Texture::new(
+        Extent3d::new(shape.x() as u32, shape.y() as u32, 1),
+        TextureDimension::D2,
+        data,
+        TextureFormat::Rgba8UnormSrgb,
+    );
+    texture.sampler.mag_filter = FilterMode::Nearest;
+    texture.sampler.min_filter = FilterMode::Nearest;
+    top_down_map.texture = textures.add(texture);
+}
+
+/// Converts a linear RGBA color to sRGB bytes.
+fn srgb_bytes(color: [f32; 4]) -> [u8; 4] {
+    let encode = |c: f32| {
+        let c = c.max(0.0).min(1.0);
+        let srgb = if c <= 0.003_130_8 {
+            12.92 * c
+        } else {
+            1.055 * c.powf(1.0 / 2.4) - 0.055
+        };
+
+        (srgb * 255.0).round() as u8
+    };
+
+    [
+        encode(color[0]),
+        encode(color[1]),
+        encode(color[2]),
+        (color[3].max(0.0).min(1.0) * 255.0).round() as u8,
+    ]
+}
+
+#[allow(clippy::too_many_arguments)]
+fn top_down_map_system<V>(
+    pool: Res<VoxelTaskPool>,
+    voxel_map: Res<VoxelMap<V>>,
+    local_caches: Res<ThreadLocalVoxelCache<V>>,
+    columns: Res<ChunkColumns<V>>,
+    dirty_chunks: Res<DirtyChunks<V>>,
+    empty_chunks: Res<EmptyChunks<V>>,
+    mut top_down_map: ResMut<TopDownMap<V>>,
+    mut textures: ResMut<Assets<Texture>>,
+) where
+    V: Voxel,
+    V::TypeInfo: VoxelMaterial,
+    for<'r> &'r V::TypeInfo: IsEmpty,
+{
+    let top_down_map = &mut *top_down_map;
+    let chunk_shape = voxel_map.voxels.indexer.chunk_shape();
+    let map_extent = top_down_map.extent;
+    let footprint = |column_key: Point2i| {
+        Extent2i::from_min_and_shape(column_key, PointN([chunk_shape.x(), chunk_shape.z()]))
+            .intersection(&map_extent)
+    };
+
+    if top_down_map.needs_full_redraw {
+        top_down_map.needs_full_redraw = false;
+        for (&column_key, _) in columns.iter() {
+            if footprint(column_key).num_points() > 0 {
+                top_down_map.enqueue(column_key);
+            }
+        }
+    }
+    // The ChunkColumns only pick up these chunks later in this frame.
+    let mut edited_chunk_keys: FnvHashMap<Point2i, Vec<Point3i>> = Default::default();
+    for &chunk_key in dirty_chunks.edited_chunk_keys.iter() {
+        edited_chunk_keys
+            .entry(column_key(chunk_key))
+            .or_default()
+            .push(chunk_key);
+    }
+    let changed_columns = edited_chunk_keys
+        .keys()
+        .cloned()
+        .chain(empty_chunks.removed_chunk_keys().map(|k| column_key(*k)))
+        .collect::<Vec<_>>();
+    for column_key in changed_columns.into_iter() {
+        if footprint(column_key).num_points() > 0 {
+            top_down_map.enqueue(column_key);
+        }
+    }
+
+    let num_columns = top_down_map.columns_per_frame.min(top_down_map.queue.len());
+    if num_columns == 0 {
+        return;
+    }
+    let column_keys: Vec<Point2i> = top_down_map.queue.drain(..num_columns).collect();
+    let batch: Vec<(Extent2i, Vec<Point3i>)> = column_keys
+        .into_iter()
+        .map(|column_key| {
+            top_down_map.queued.remove(&column_key);
+            let mut chunk_keys: Vec<Point3i> = columns
+                .get(&column_key)
+                .map(|column| column.chunk_keys().cloned().collect())
+                .unwrap_or_default();
+            if let Some(edited) = edited_chunk_keys.get(&column_key) {
+                chunk_keys.extend(edited.iter().cloned());
+            }
+            // From top to bottom.
+            chunk_keys.sort_by_key(|chunk_key| std::cmp::Reverse(chunk_key.y()));
+            chunk_keys.dedup();
+
+            (footprint(column_key), chunk_keys)
+        })
+        .collect();
+
+    let map = &*voxel_map;
+    let local_caches = &*local_caches;
+    let background = top_down_map.background;
+    let drawn = map_in_pool(&*pool, batch.into_iter(), |(extent, chunk_keys)| {
+        let cache_tls = local_caches.get();
+        let reader = map.reader(&cache_tls);
+        let width = extent.shape.x();
+        let mut top_voxels: Vec<Option<V>> = vec![None; extent.num_points()];
+        let mut remaining = top_voxels.len();
+        for chunk_key in chunk_keys.into_iter() {
+            if remaining == 0 {
+                break;
+            }
+            if reader.get_chunk(chunk_key).is_none() {
+                continue;
+            }
+            let chunk_extent = reader.indexer.extent_for_chunk_at_key(chunk_key);
+            let column_extent = Extent3i::from_min_and_shape(
+                PointN([
+                    extent.minimum.x(),
+                    chunk_extent.minimum.y(),
+                    extent.minimum.y(),
+                ]),
+                PointN([extent.shape.x(), chunk_extent.shape.y(), extent.shape.y()]),
+            );
+            // The highest non-empty voxel of each column in this chunk.
+            let mut chunk_tops: Vec<Option<(i32, V)>> = vec![None; top_voxels.len()];
+            reader.for_each(&column_extent, |p: Point3i, voxel: V| {
+                let i =
+                    ((p.x() - extent.minimum.x()) + width * (p.z() - extent.minimum.y())) as usize;
+                if top_voxels[i].is_some() || map.palette.get_voxel_type_info(voxel).is_empty() {
+                    return;
+                }
+                if chunk_tops[i].map_or(true, |(y, _)| p.y() > y) {
+                    chunk_tops[i] = Some((p.y(), voxel));
+                }
+            });
+            for (top, chunk_top) in top_voxels.iter_mut().zip(chunk_tops.into_iter()) {
+                if let (None, Some((_, voxel))) = (*top, chunk_top) {
+                    *top = Some(voxel);
+                    remaining -= 1;
+                }
+            }
+        }
+        let texels: Vec<[u8; 4]> = top_voxels
+            .into_iter()
+            .map(|voxel| {
+                srgb_bytes(voxel.map_or(background, |v| {
+                    map.palette.get_voxel_type_info(v).base_color()
+                }))
+            })
+            .collect();
+
+        (extent, texels)
+    });
+
+    let texture = match textures.get_mut(&top_down_map.texture) {
+        Some(texture) => texture,
+        None => return,
+    };
+    let texture_width = map_extent.shape.x();
+    for (extent, texels) in drawn.into_iter() {
+        let width = extent.shape.x() as usize;
+        for (row, row_texels) in texels.chunks(width).enumerate() {
+            let offset = extent.minimum - map_extent.minimum;
+            let start = 4 * (offset.x() + texture_width * (offset.y() + row as i32)) as usize;
+            for (i, texel) in row_texels.iter().enumerate() {
+                texture.data[start + 4 * i..start + 4 * i + 4].copy_from_slice(texel);
+            }
+        }
+    }
+}
@@ -0,0 +1,221 @@
+use crate::{
+    tasks::map_in_pool, DirtyChunks, EmptyChunks, PinnedChunks, PrefetchQueue,
+    ThreadLocalVoxelCache, Voxel, VoxelMap, VoxelTaskPool,
+};
+
+use bevy::prelude::*;
+use building_blocks::prelude::*;
+use fnv::FnvHashMap;
+
+/// Collapses chunks that are entirely one voxel down to that voxel, kept in the `UniformChunks` of
+/// the `VoxelMap`. Depends on the `MapIoPlugin`.
+///
+/// Chunks are checked when the `chunk_compressor_system` evicts them from the cache, which also
+/// finds generated and loaded chunks, and after every merge that edits them. A chunk full of the
+/// ambient value (the default voxel) is marked for removal in `EmptyChunks`, since reading a
+/// missing chunk gives the ambient value anyway. Any other uniform chunk, like solid stone or
+/// ocean, is dropped from the chunk storage, and only its voxel is kept. Pinned chunks are never
+/// collapsed.
+///
+/// Like spilled chunks, collapsed chunks aren't in the `VoxelMap` storage, so a raw
+/// `VoxelMap::reader` sees the ambient value. The `VoxelReader` and `copy_chunk_without_caching`
+/// read the kept voxel instead, and the `VoxelEditor` expands a collapsed chunk before editing it.
+/// Chunks are expanded back into the storage at the start of every frame if they are:
+///
+/// - requested with `UniformChunks::expand_chunk`
+/// - pinned in `PinnedChunks`, which includes chunks near `Observer` entities
+/// - waiting in the `PrefetchQueue`
+pub struct UniformChunksPlugin<V> {
+    marker: std::marker::PhantomData<V>,
+}
+
+impl<V> Default for UniformChunksPlugin<V> {
+    fn default() -> Self {
+        Self {
+            marker: Default::default(),
+        }
+    }
+}
+
+impl<V> Plugin for UniformChunksPlugin<V>
+where
+    V: Voxel + PartialEq,
+{
+    fn build(&self, app: &mut AppBuilder) {
+        // Expanded chunks are written directly into the map, so this must happen before any reads.
+        app.add_system_to_stage(stage::FIRST, uniform_chunk_expansion_system::<V>.system())
+            .add_system(uniform_chunks_system::<V>.system());
+    }
+}
+
+/// The chunks that are entirely one voxel, other than the ambient value, along with that voxel.
+/// Only filled in by the `UniformChunksPlugin`.
+///
+/// A chunk is either in the `VoxelMap` storage or here, never both.
+pub struct UniformChunks<V> {
+    values: FnvHashMap<Point3i, V>,
+    expand_chunk_keys: Vec<Point3i>,
+    // Set by the `UniformChunksPlugin`, which needs `V: PartialEq` to compare voxels.
+    eq: Option<fn(&V, &V) -> bool>,
+}
+
+impl<V> Default for UniformChunks<V> {
+    fn default() -> Self {
+        Self {
+            values: Default::default(),
+            expand_chunk_keys: Vec::new(),
+            eq: None,
+        }
+    }
+}
+
+/// Whether all voxels of a chunk are the same, from `UniformChunks::uniformity`.
+pub(crate) enum Uniformity<V> {
+    Mixed,
+    Ambient,
+    Uniform(V),
+}
+
+impl<V> UniformChunks<V>
+where
+    V: Voxel,
+{
+    /// The voxel that fills the collapsed chunk at `chunk_key`.
+    pub fn get(&self, chunk_key: &Point3i) -> Option<V> {
+        self.values.get(chunk_key).cloned()
+    }
+
+    pub fn contains_chunk(&self, chunk_key: &Point3i) -> bool {
+        self.values.contains_key(chunk_key)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Point3i, &V)> {
+        self.values.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// Expands the chunk back into the `VoxelMap` storage at the start of the next frame, if it's
+    /// collapsed.
+    pub fn expand_chunk(&mut self, chunk_key: Point3i) {
+        self.expand_chunk_keys.push(chunk_key);
+    }
+
+    /// A copy of the collapsed chunk at `chunk_key`, without expanding it in the map.
+    pub fn expanded_chunk(&self, chunk_key: Point3i, chunk_shape: Point3i) -> Option<Array3<V>> {
+        self.get(&chunk_key)
+            .map(|value| Array3::fill(Extent3i::from_min_and_shape(chunk_key, chunk_shape), value))
+    }
+
+    pub(crate) fn collapse(&mut self, chunk_key: Point3i, value: V) {
+        self.values.insert(chunk_key, value);
+    }
+
+    /// Forgets a chunk that was written to or removed from the map storage, returning the voxel
+    /// it was collapsed to.
+    pub(crate) fn forget_chunk(&mut self, chunk_key: &Point3i) -> Option<V> {
+        self.values.remove(chunk_key)
+    }
+
+    /// Always `Mixed` without the `UniformChunksPlugin`.
+    pub(crate) fn uniformity(&self, array: &Array3<V>) -> Uniformity<V> {
+        let eq = match self.eq {
+            Some(eq) => eq,
+            None => return Uniformity::Mixed,
+        };
+        let mut first = None;
+        let mut is_uniform = true;
+        array.for_each(array.extent(), |_p: Point3i, voxel: V| match first {
+            None => first = Some(voxel),
+            Some(f) => is_uniform &= eq(&f, &voxel),
+        });
+
+        match first {
+            Some(value) if is_uniform => {
+                if eq(&value, &V::default()) {
+                    Uniformity::Ambient
+                } else {
+                    Uniformity::Uniform(value)
+                }
+            }
+            _ => Uniformity::Mixed,
+        }
+    }
+}
+
+fn uniform_chunk_expansion_system<V>(
+    pinned_chunks: Res<PinnedChunks<V>>,
+    prefetch_queue: Res<PrefetchQueue<V>>,
+    mut voxel_map: ResMut<VoxelMap<V>>,
+) where
+    V: Voxel + PartialEq,
+{
+    let voxel_map = &mut *voxel_map;
+    voxel_map.uniform_chunks.eq = Some(<V as PartialEq>::eq);
+    let mut requested =
+        std::mem::replace(&mut voxel_map.uniform_chunks.expand_chunk_keys, Vec::new());
+    if voxel_map.uniform_chunks.is_empty() {
+        return;
+    }
+    requested.extend(pinned_chunks.chunk_keys().cloned());
+    requested.extend(prefetch_queue.pending_chunk_keys().cloned());
+
+    let chunk_shape = voxel_map.voxels.indexer.chunk_shape();
+    for chunk_key in requested.into_iter() {
+        if let Some(value) = voxel_map.uniform_chunks.forget_chunk(&chunk_key) {
+            let extent = Extent3i::from_min_and_shape(chunk_key, chunk_shape);
+            voxel_map
+                .voxels
+                .write_chunk(chunk_key, Chunk3::with_array(Array3::fill(extent, value)));
+        }
+    }
+}
+
+fn uniform_chunks_system<V>(
+    pool: Res<VoxelTaskPool>,
+    local_caches: Res<ThreadLocalVoxelCache<V>>,
+    dirty_chunks: Res<DirtyChunks<V>>,
+    pinned_chunks: Res<PinnedChunks<V>>,
+    mut voxel_map: ResMut<VoxelMap<V>>,
+    mut empty_chunks: ResMut<EmptyChunks<V>>,
+) where
+    V: Voxel + PartialEq,
+{
+    if dirty_chunks.edited_chunk_keys.is_empty() {
+        return;
+    }
+
+    let map = &*voxel_map;
+    let local_caches = &*local_caches;
+    let uniformities = map_in_pool(
+        &*pool,
+        dirty_chunks.edited_chunk_keys.iter().cloned(),
+        |chunk_key| {
+            let cache_tls = local_caches.get();
+            let reader = map.reader(&cache_tls);
+            // A chunk that was already removed isn't uniform, just missing.
+            let uniformity = reader
+                .get_chunk(chunk_key)
+                .map(|chunk| map.uniform_chunks.uniformity(&chunk.array));
+
+            (chunk_key, uniformity)
+        },
+    );
+
+    for (chunk_key, uniformity) in uniformities.into_iter() {
+        match uniformity {
+            Some(Uniformity::Ambient) => empty_chunks.mark_for_removal(chunk_key),
+            Some(Uniformity::Uniform(value)) if !pinned_chunks.is_pinned(&chunk_key) => {
+                voxel_map.voxels.storage_mut().remove(chunk_key);
+                voxel_map.uniform_chunks.collapse(chunk_key, value);
+            }
+            _ => (),
+        }
+    }
+}
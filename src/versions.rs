@@ -0,0 +1,461 @@
+use crate::{
+    copy_chunk_without_caching, map_io::EditBuffer, DirtyChunks, EmptyChunks, SharedChunk,
+    SpilledChunks, Voxel, VoxelMap,
+};
+
+use bevy::prelude::*;
+use building_blocks::prelude::*;
+use fnv::{FnvHashMap, FnvHashSet};
+use std::io;
+
+/// Manages the `MapVersions` resource, which can tag the current state of the `VoxelMap` and roll
+/// back to it later. Depends on the `MapIoPlugin`.
+///
+/// Tagging is cheap: nothing is saved until a chunk is modified, at which point the original chunk
+/// is saved with the most recent version. Merges hand over the chunks they replace as
+/// `SharedChunk`s, so edited chunks are saved without copying them. Originals are saved when the
+/// edit buffer is merged and when empty chunks are removed, so every edit is captured, whichever
+/// stage it was made in.
+pub struct MapVersionsPlugin<V> {
+    marker: std::marker::PhantomData<V>,
+}
+
+impl<V> Default for MapVersionsPlugin<V> {
+    fn default() -> Self {
+        Self {
+            marker: Default::default(),
+        }
+    }
+}
+
+impl<V> Plugin for MapVersionsPlugin<V>
+where
+    V: Voxel,
+{
+    fn build(&self, app: &mut AppBuilder) {
+        app.insert_resource(MapVersions::<V>::default());
+    }
+}
+
+/// Named versions of the `VoxelMap`, oldest first.
+///
+/// A version captures the map as it was at the start of the frame on which it was tagged, so edits
+/// from that same frame come after the version. Rolling back applies when the edit buffer is merged
+/// at the end of the frame, and it overrides any edits made in the same frame to the restored
+/// chunks.
+///
+/// The oldest versions are dropped once more than `max_saved_chunks` chunks are saved.
+pub struct MapVersions<V> {
+    pub max_saved_chunks: usize,
+    versions: Vec<MapVersion<V>>,
+    pending_rollback: Option<String>,
+    // Chunks written by a rollback, which must not be saved as originals by the merge.
+    restored_chunk_keys: FnvHashSet<Point3i>,
+    errors: Vec<(Point3i, io::Error)>,
+}
+
+struct MapVersion<V> {
+    name: String,
+    // The contents of each chunk modified since this version was tagged, or `None` if the chunk
+    // didn't exist yet. Chunks that aren't here are either unmodified or saved by a later version.
+    original_chunks: FnvHashMap<Point3i, Option<SharedChunk<V>>>,
+}
+
+impl<V> Default for MapVersions<V> {
+    fn default() -> Self {
+        Self {
+            // Assuming 8192-byte chunks, this is a little under a gigabyte.
+            max_saved_chunks: 100000,
+            versions: Vec::new(),
+            pending_rollback: None,
+            restored_chunk_keys: Default::default(),
+            errors: Vec::new(),
+        }
+    }
+}
+
+impl<V> MapVersions<V>
+where
+    V: Voxel,
+{
+    /// Tags the current map state as `name`, replacing any existing version with that name.
+    pub fn tag(&mut self, name: impl Into<String>) {
+        let name = name.into();
+        self.remove(&name);
+        self.versions.push(MapVersion {
+            name,
+            original_chunks: Default::default(),
+        });
+    }
+
+    /// Requests that the map be restored to the version `name` at the end of this frame. All
+    /// versions tagged after `name` are discarded by the rollback. Returns `false` if there is no
+    /// such version.
+    pub fn rollback(&mut self, name: &str) -> bool {
+        if !self.contains(name) {
+            return false;
+        }
+        self.pending_rollback = Some(name.to_string());
+
+        true
+    }
+
+    /// Forgets the version `name`. Any chunks it saved are handed to the previous version, so older
+    /// versions can still be restored. Returns `false` if there is no such version.
+    pub fn remove(&mut self, name: &str) -> bool {
+        let i = match self.index_of(name) {
+            Some(i) => i,
+            None => return false,
+        };
+        let removed = self.versions.remove(i);
+        if i > 0 {
+            let previous = &mut self.versions[i - 1];
+            for (chunk_key, chunk) in removed.original_chunks.into_iter() {
+                previous.original_chunks.entry(chunk_key).or_insert(chunk);
+            }
+        }
+
+        true
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.index_of(name).is_some()
+    }
+
+    /// The names of all versions, oldest first.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.versions.iter().map(|v| v.name.as_str())
+    }
+
+    pub fn len(&self) -> usize {
+        self.versions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.versions.is_empty()
+    }
+
+    /// The number of chunks saved across all versions.
+    pub fn num_saved_chunks(&self) -> usize {
+        self.versions.iter().map(|v| v.original_chunks.len()).sum()
+    }
+
+    /// Takes the errors from reading spilled chunks that were about to be modified. Those chunks
+    /// aren't restored by a rollback.
+    pub fn take_errors(&mut self) -> Vec<(Point3i, io::Error)> {
+        std::mem::replace(&mut self.errors, Vec::new())
+    }
+
+    fn index_of(&self, name: &str) -> Option<usize> {
+        self.versions.iter().position(|v| v.name == name)
+    }
+
+    /// Saves the current contents of the chunk at `chunk_key` with the latest version, unless it's
+    /// already saved there. Spilled chunks are read from disk.
+    pub(crate) fn save_original(
+        &mut self,
+        chunk_key: Point3i,
+        map: &VoxelMap<V>,
+        spilled_chunks: Option<&SpilledChunks<V>>,
+    ) {
+        let latest = match self.versions.last_mut() {
+            Some(latest) => latest,
+            None => return,
+        };
+        if latest.original_chunks.contains_key(&chunk_key) {
+            return;
+        }
+        match copy_chunk_without_caching(map, spilled_chunks, chunk_key) {
+            Ok(chunk) => {
+                latest
+                    .original_chunks
+                    .insert(chunk_key, chunk.map(SharedChunk::from));
+            }
+            Err(e) => self.errors.push((chunk_key, e)),
+        }
+    }
+
+    /// Makes the next merge of `edit_buffer` keep the chunks it replaces, if there is a version to
+    /// save them with. The merge can only replace chunks that are in storage, so the edited chunks
+    /// that are collapsed or spilled are saved now.
+    pub(crate) fn save_originals(
+        &mut self,
+        edit_buffer: &mut EditBuffer<V>,
+        map: &VoxelMap<V>,
+        spilled_chunks: Option<&SpilledChunks<V>>,
+    ) {
+        edit_buffer.keep_replaced_chunks(!self.is_empty());
+        if self.is_empty() {
+            return;
+        }
+        let offloaded_keys: Vec<Point3i> = edit_buffer
+            .edited_chunk_keys()
+            .filter(|chunk_key| {
+                map.uniform_chunks.contains_chunk(chunk_key)
+                    || spilled_chunks.map_or(false, |s| s.is_spilled(chunk_key))
+            })
+            .cloned()
+            .collect();
+        for chunk_key in offloaded_keys.into_iter() {
+            self.save_original(chunk_key, map, spilled_chunks);
+        }
+    }
+
+    /// Saves the chunks replaced by a merge with the latest version, unless they're already saved
+    /// there.
+    pub(crate) fn absorb_merge(&mut self, dirty_chunks: &DirtyChunks<V>) {
+        let restored_chunk_keys = std::mem::take(&mut self.restored_chunk_keys);
+        let latest = match self.versions.last_mut() {
+            Some(latest) => latest,
+            None => return,
+        };
+        for (chunk_key, replaced) in dirty_chunks.replaced_chunks.iter() {
+            if !restored_chunk_keys.contains(chunk_key) {
+                latest
+                    .original_chunks
+                    .entry(*chunk_key)
+                    .or_insert_with(|| replaced.clone());
+            }
+        }
+        self.drop_oldest_versions();
+    }
+
+    fn drop_oldest_versions(&mut self) {
+        while self.versions.len() > 1 && self.num_saved_chunks() > self.max_saved_chunks {
+            self.versions.remove(0);
+        }
+    }
+
+    /// Removes every version after the one at index `i` and returns the chunks that need to be
+    /// restored to get back to version `i`.
+    fn take_rollback_chunks(&mut self, i: usize) -> FnvHashMap<Point3i, Option<SharedChunk<V>>> {
+        // A chunk's state at version `i` is saved in the earliest version at or after `i` that
+        // contains it.
+        let mut restore = FnvHashMap::default();
+        for version in self.versions.drain(i..).rev() {
+            restore.extend(version.original_chunks.into_iter());
+        }
+
+        restore
+    }
+
+    /// Saves the originals of the chunks about to be merged from `edit_buffer`, then applies any
+    /// pending rollback by replacing the buffered edits with the restored chunks. Called by the
+    /// end-of-frame merge, after the pre-merge hooks.
+    pub(crate) fn prepare_merge(
+        &mut self,
+        edit_buffer: &mut EditBuffer<V>,
+        voxel_map: &mut VoxelMap<V>,
+        empty_chunks: &mut EmptyChunks<V>,
+        mut spilled_chunks: Option<&mut SpilledChunks<V>>,
+    ) {
+        self.save_originals(edit_buffer, voxel_map, spilled_chunks.as_deref());
+
+        let name = match self.pending_rollback.take() {
+            Some(name) => name,
+            None => return,
+        };
+        let i = match self.index_of(&name) {
+            Some(i) => i,
+            // The version was removed after the rollback was requested.
+            None => return,
+        };
+        for (chunk_key, chunk) in self.take_rollback_chunks(i).into_iter() {
+            match chunk {
+                Some(chunk) => {
+                    self.restored_chunk_keys.insert(chunk_key);
+                    edit_buffer.insert_chunk(true, chunk_key, chunk.into_array());
+                }
+                None => {
+                    edit_buffer.discard_chunk(chunk_key);
+                    empty_chunks.remove_now(chunk_key, voxel_map, spilled_chunks.as_deref_mut());
+                }
+            }
+        }
+        // The map will match the restored version after the merge, so start tracking changes again.
+        self.versions.push(MapVersion {
+            name,
+            original_chunks: Default::default(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::*;
+
+    const CHUNK_SHAPE: Point3i = PointN([4; 3]);
+    const A: Point3i = PointN([0; 3]);
+    const B: Point3i = PointN([4, 0, 0]);
+
+    struct World {
+        map: VoxelMap<TestVoxel>,
+        empty_chunks: EmptyChunks<TestVoxel>,
+        versions: MapVersions<TestVoxel>,
+    }
+
+    impl World {
+        /// Chunk `A` is filled with 1.
+        fn new() -> Self {
+            let mut map = test_map(CHUNK_SHAPE);
+            map.voxels
+                .write_chunk(A, Chunk3::with_array(filled_chunk(A, 1)));
+
+            Self {
+                map,
+                empty_chunks: Default::default(),
+                versions: Default::default(),
+            }
+        }
+
+        /// Runs the end-of-frame merge of the chunks in `chunks`.
+        fn frame(&mut self, chunks: &[(Point3i, u8)]) -> DirtyChunks<TestVoxel> {
+            let mut edit_buffer = EditBuffer::new(CHUNK_SHAPE, false);
+            for (chunk_key, value) in chunks.iter() {
+                edit_buffer.insert_chunk(false, *chunk_key, filled_chunk(*chunk_key, *value));
+            }
+            self.versions.prepare_merge(
+                &mut edit_buffer,
+                &mut self.map,
+                &mut self.empty_chunks,
+                None,
+            );
+            let dirty_chunks = edit_buffer.merge_edits(&mut self.map.voxels);
+            self.versions.absorb_merge(&dirty_chunks);
+
+            dirty_chunks
+        }
+
+        /// The value that fills the chunk at `chunk_key`, if it exists.
+        fn chunk_value(&self, chunk_key: Point3i) -> Option<u8> {
+            copy_chunk_without_caching(&self.map, None, chunk_key)
+                .unwrap()
+                .map(|chunk| chunk.get(&chunk_key).0)
+        }
+
+        fn names(&self) -> Vec<&str> {
+            self.versions.names().collect()
+        }
+    }
+
+    fn filled_chunk(chunk_key: Point3i, value: u8) -> Array3<TestVoxel> {
+        Array3::fill(
+            Extent3i::from_min_and_shape(chunk_key, CHUNK_SHAPE),
+            TestVoxel(value),
+        )
+    }
+
+    #[test]
+    fn rollback_restores_edited_chunks_and_removes_created_ones() {
+        let mut world = World::new();
+        world.versions.tag("start");
+        world.frame(&[(A, 2), (B, 3)]);
+        world.frame(&[(A, 4)]);
+        assert_eq!(world.versions.num_saved_chunks(), 2);
+        assert_eq!(world.chunk_value(A), Some(4));
+
+        assert!(world.versions.rollback("start"));
+        world.frame(&[]);
+        assert_eq!(world.chunk_value(A), Some(1));
+        assert_eq!(world.chunk_value(B), None);
+        assert!(world.empty_chunks.removed_chunk_keys().any(|k| *k == B));
+        // The restored chunks aren't saved again.
+        assert_eq!(world.names(), vec!["start"]);
+        assert_eq!(world.versions.num_saved_chunks(), 0);
+    }
+
+    #[test]
+    fn rollback_overrides_edits_from_the_same_frame() {
+        let mut world = World::new();
+        world.versions.tag("start");
+        world.frame(&[(A, 2)]);
+
+        world.versions.rollback("start");
+        world.frame(&[(A, 3)]);
+        assert_eq!(world.chunk_value(A), Some(1));
+    }
+
+    #[test]
+    fn merges_share_the_chunks_they_replace() {
+        let mut world = World::new();
+        world.versions.tag("start");
+        let dirty_chunks = world.frame(&[(A, 2), (B, 3)]);
+
+        let saved = &world.versions.versions[0].original_chunks;
+        let replaced = dirty_chunks.replaced_chunk(&A).unwrap();
+        assert!(saved[&A].as_ref().unwrap().ptr_eq(replaced));
+        assert!(saved[&B].is_none());
+        assert!(dirty_chunks.replaced_chunk(&B).is_none());
+    }
+
+    #[test]
+    fn merges_without_versions_keep_nothing() {
+        let mut world = World::new();
+        let dirty_chunks = world.frame(&[(A, 2)]);
+
+        assert!(dirty_chunks.replaced_chunks.is_empty());
+        assert_eq!(world.versions.num_saved_chunks(), 0);
+    }
+
+    #[test]
+    fn rollback_to_a_later_version_keeps_earlier_ones() {
+        let mut world = World::new();
+        world.versions.tag("a");
+        world.frame(&[(A, 2)]);
+        world.versions.tag("b");
+        world.frame(&[(A, 3), (B, 3)]);
+        world.versions.tag("c");
+
+        world.versions.rollback("b");
+        world.frame(&[]);
+        assert_eq!(world.chunk_value(A), Some(2));
+        assert_eq!(world.chunk_value(B), None);
+        assert_eq!(world.names(), vec!["a", "b"]);
+
+        world.versions.rollback("a");
+        world.frame(&[]);
+        assert_eq!(world.chunk_value(A), Some(1));
+    }
+
+    #[test]
+    fn removed_versions_hand_their_chunks_to_the_previous_one() {
+        let mut world = World::new();
+        world.versions.tag("a");
+        world.versions.tag("b");
+        world.frame(&[(A, 2), (B, 2)]);
+        assert!(world.versions.remove("b"));
+        assert!(!world.versions.remove("b"));
+
+        world.versions.rollback("a");
+        world.frame(&[]);
+        assert_eq!(world.chunk_value(A), Some(1));
+        assert_eq!(world.chunk_value(B), None);
+    }
+
+    #[test]
+    fn removed_chunks_are_restored() {
+        let mut world = World::new();
+        world.versions.tag("start");
+        world.versions.save_original(A, &world.map, None);
+        world.empty_chunks.remove_now(A, &mut world.map, None);
+        assert_eq!(world.chunk_value(A), None);
+
+        world.versions.rollback("start");
+        world.frame(&[]);
+        assert_eq!(world.chunk_value(A), Some(1));
+    }
+
+    #[test]
+    fn oldest_versions_are_dropped_over_the_limit() {
+        let mut world = World::new();
+        world.versions.max_saved_chunks = 1;
+        world.versions.tag("a");
+        world.frame(&[(A, 2)]);
+        world.versions.tag("b");
+        world.frame(&[(B, 2)]);
+
+        assert_eq!(world.names(), vec!["b"]);
+        assert!(!world.versions.rollback("a"));
+    }
+}
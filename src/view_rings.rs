@@ -0,0 +1,234 @@
+use crate::{observer::transform_voxel_point, Observer, PinnedChunks, Voxel, VoxelMap};
+
+use bevy::prelude::*;
+use building_blocks::prelude::*;
+use fnv::{FnvHashMap, FnvHashSet};
+
+/// Sorts the chunks around `Observer` entities into view-distance rings, for streaming chunks in and
+/// out: the `Full` ring near an observer, the `Lod` ring around it, and everything else, which
+/// should be unloaded. Depends on the `MapIoPlugin`.
+///
+/// A chunk enters a ring as soon as it's within the ring's radius, but only leaves once it's more
+/// than `ViewRingsConfig::hysteresis` chunks outside of it, so chunks don't thrash between rings
+/// while an observer sits on a boundary. Every change is sent as a `ViewRingChanged` event in the
+/// `POST_UPDATE` stage, up to the budgets in the `ViewRingsConfig`. Chunks in the `Full` ring are
+/// pinned in `PinnedChunks`, so they're never compressed.
+pub struct ViewRingsPlugin<V> {
+    pub config: ViewRingsConfig,
+    marker: std::marker::PhantomData<V>,
+}
+
+impl<V> ViewRingsPlugin<V> {
+    pub fn new(config: ViewRingsConfig) -> Self {
+        Self {
+            config,
+            marker: Default::default(),
+        }
+    }
+}
+
+impl<V> Plugin for ViewRingsPlugin<V>
+where
+    V: Voxel,
+{
+    fn build(&self, app: &mut AppBuilder) {
+        app.insert_resource(self.config)
+            .insert_resource(ViewRings::<V>::default())
+            .add_event::<ViewRingChanged<V>>()
+            .add_system_to_stage(stage::POST_UPDATE, view_rings_system::<V>.system());
+    }
+}
+
+/// The ring radii and per-frame budgets of the `ViewRingsPlugin`. Radii are measured in chunks from
+/// the chunk containing an observer, along the axis where it's farthest, so rings are cubes.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ViewRingsConfig {
+    pub full_radius_in_chunks: i32,
+    /// Must be at least `full_radius_in_chunks`.
+    pub lod_radius_in_chunks: i32,
+    /// How many chunks past a ring's radius a chunk has to be before it leaves the ring.
+    pub hysteresis: i32,
+    /// The most chunks that can enter a ring or move to a more detailed ring in a single frame,
+    /// nearest first.
+    pub max_loads_per_frame: usize,
+    /// The most chunks that can leave a ring or move to a less detailed ring in a single frame,
+    /// farthest first.
+    pub max_unloads_per_frame: usize,
+}
+
+impl Default for ViewRingsConfig {
+    fn default() -> Self {
+        Self {
+            full_radius_in_chunks: 4,
+            lod_radius_in_chunks: 12,
+            hysteresis: 1,
+            max_loads_per_frame: 32,
+            max_unloads_per_frame: 64,
+        }
+    }
+}
+
+/// Overrides the ring radii of the `ViewRingsConfig` for one `Observer`, e.g. a spectator camera
+/// that only needs low detail.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ObserverViewDistance {
+    pub full_radius_in_chunks: i32,
+    pub lod_radius_in_chunks: i32,
+}
+
+/// The detail a chunk is needed at.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum ViewRing {
+    Lod,
+    Full,
+}
+
+/// The ring of every chunk near an `Observer`. Chunks outside of all rings aren't stored.
+pub struct ViewRings<V> {
+    rings: FnvHashMap<Point3i, ViewRing>,
+    marker: std::marker::PhantomData<V>,
+}
+
+impl<V> Default for ViewRings<V> {
+    fn default() -> Self {
+        Self {
+            rings: Default::default(),
+            marker: Default::default(),
+        }
+    }
+}
+
+impl<V> ViewRings<V> {
+    pub fn get(&self, chunk_key: &Point3i) -> Option<ViewRing> {
+        self.rings.get(chunk_key).cloned()
+    }
+
+    pub fn chunk_keys_in_ring(&self, ring: ViewRing) -> impl Iterator<Item = &Point3i> {
+        self.rings
+            .iter()
+            .filter(move |(_, r)| **r == ring)
+            .map(|(chunk_key, _)| chunk_key)
+    }
+
+    /// The number of chunks in any ring.
+    pub fn len(&self) -> usize {
+        self.rings.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rings.is_empty()
+    }
+}
+
+/// Sent when a chunk moves between rings. A `new` ring of `None` means the chunk should be
+/// unloaded.
+pub struct ViewRingChanged<V> {
+    pub chunk_key: Point3i,
+    pub old: Option<ViewRing>,
+    pub new: Option<ViewRing>,
+    marker: std::marker::PhantomData<V>,
+}
+
+/// The ring of a chunk `distance` chunks from an observer, given the ring it's in now.
+fn ring_at_distance(
+    distance: i32,
+    current: Option<ViewRing>,
+    full_radius: i32,
+    lod_radius: i32,
+    hysteresis: i32,
+) -> Option<ViewRing> {
+    if distance <= full_radius
+        || (current == Some(ViewRing::Full) && distance <= full_radius + hysteresis)
+    {
+        Some(ViewRing::Full)
+    } else if distance <= lod_radius || (current.is_some() && distance <= lod_radius + hysteresis) {
+        Some(ViewRing::Lod)
+    } else {
+        None
+    }
+}
+
+fn view_rings_system<V>(
+    config: Res<ViewRingsConfig>,
+    observers: Query<(&GlobalTransform, Option<&ObserverViewDistance>), With<Observer>>,
+    voxel_map: Res<VoxelMap<V>>,
+    mut view_rings: ResMut<ViewRings<V>>,
+    mut pinned_chunks: ResMut<PinnedChunks<V>>,
+    mut events: ResMut<Events<ViewRingChanged<V>>>,
+) where
+    V: Voxel,
+{
+    let indexer = &voxel_map.voxels.indexer;
+    let chunk_shape = indexer.chunk_shape();
+
+    let observers: Vec<(Point3i, i32, i32)> = observers
+        .iter()
+        .map(|(transform, view_distance)| {
+            let chunk_key = indexer.chunk_key_containing_point(&transform_voxel_point(transform));
+            let (full, lod) = match view_distance {
+                Some(d) => (d.full_radius_in_chunks, d.lod_radius_in_chunks),
+                None => (config.full_radius_in_chunks, config.lod_radius_in_chunks),
+            };
+
+            (chunk_key, full, lod)
+        })
+        .collect();
+
+    // Chunks that are in a ring now or that could enter one.
+    let mut candidates: FnvHashSet<Point3i> = view_rings.rings.keys().cloned().collect();
+    for (observer_key, _, lod) in observers.iter() {
+        let padding = chunk_shape * PointN([*lod; 3]);
+        let extent = Extent3i::from_min_and_max(*observer_key - padding, *observer_key + padding);
+        candidates.extend(indexer.chunk_keys_for_extent(&extent));
+    }
+
+    // (distance to the nearest observer, chunk key, old ring, new ring)
+    let mut loads = Vec::new();
+    let mut unloads = Vec::new();
+    for chunk_key in candidates.into_iter() {
+        let current = view_rings.get(&chunk_key);
+        let mut nearest = i32::MAX;
+        let mut ring = None;
+        for (observer_key, full, lod) in observers.iter() {
+            let offset = chunk_key - *observer_key;
+            let distance = (offset.x().abs() / chunk_shape.x())
+                .max(offset.y().abs() / chunk_shape.y())
+                .max(offset.z().abs() / chunk_shape.z());
+            nearest = nearest.min(distance);
+            ring = ring.max(ring_at_distance(
+                distance,
+                current,
+                *full,
+                *lod,
+                config.hysteresis,
+            ));
+        }
+        if ring > current {
+            loads.push((nearest, chunk_key, current, ring));
+        } else if ring < current {
+            unloads.push((nearest, chunk_key, current, ring));
+        }
+    }
+    loads.sort_by_key(|(distance, ..)| *distance);
+    unloads.sort_by_key(|(distance, ..)| std::cmp::Reverse(*distance));
+
+    let loads = loads.into_iter().take(config.max_loads_per_frame);
+    let unloads = unloads.into_iter().take(config.max_unloads_per_frame);
+    for (_, chunk_key, old, new) in loads.chain(unloads) {
+        match new {
+            Some(ring) => view_rings.rings.insert(chunk_key, ring),
+            None => view_rings.rings.remove(&chunk_key),
+        };
+        if new == Some(ViewRing::Full) {
+            pinned_chunks.pin(chunk_key);
+        } else if old == Some(ViewRing::Full) {
+            pinned_chunks.unpin(chunk_key);
+        }
+        events.send(ViewRingChanged {
+            chunk_key,
+            old,
+            new,
+            marker: Default::default(),
+        });
+    }
+}
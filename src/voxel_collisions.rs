@@ -0,0 +1,364 @@
+use crate::{ChunkBvh, Voxel, VoxelReader};
+
+use bevy::ecs::{prelude::*, SystemParam};
+use building_blocks::prelude::*;
+use parry3d::{
+    bounding_volume::{Aabb, BoundingVolume},
+    math::{Point, Vector},
+    query::{Ray, RayCast},
+};
+
+/// A `SystemParam` for collision queries against the voxels in the `VoxelMap`. Depends on the
+/// `ChunkBvhPlugin`.
+///
+/// The chunk-level queries only consult the `ChunkBvh`, so they're cheap, but a returned chunk
+/// isn't guaranteed to have a voxel that's actually hit. The `*_voxels` queries and `raycast`
+/// follow up by reading the voxels in those chunks, where any voxel whose type info isn't empty is
+/// solid. Voxel `p` occupies the unit cube from `p` to `p + 1`.
+///
+/// ```
+/// use bevy::prelude::*;
+/// use bevy_building_blocks::{
+///     bb::prelude::IsEmpty,
+///     parry3d::{math::{Point, Vector}, query::Ray},
+///     Voxel, VoxelCollisions,
+/// };
+///
+/// fn pick_system<V: Voxel>(collisions: VoxelCollisions<V>)
+/// where
+///     for<'r> &'r V::TypeInfo: IsEmpty,
+/// {
+///     let ray = Ray::new(Point::new(0.5, 100.0, 0.5), Vector::new(0.0, -1.0, 0.0));
+///     if let Some(hit) = collisions.raycast(&ray, 200.0) {
+///         // The voxel at hit.point is solid.
+///     }
+/// }
+/// ```
+#[derive(SystemParam)]
+pub struct VoxelCollisions<'a, V: Voxel> {
+    pub bvh: Res<'a, ChunkBvh<V>>,
+    pub voxel_reader: VoxelReader<'a, V>,
+}
+
+/// The first solid voxel hit by a ray.
+#[derive(Clone, Copy, Debug)]
+pub struct VoxelRayHit {
+    pub point: Point3i,
+    pub chunk_key: Point3i,
+    pub toi: f32,
+    /// The normal of the face that was hit, or zero if the ray started inside of the voxel.
+    pub normal: Point3i,
+}
+
+/// The first solid voxel hit by a swept box.
+#[derive(Clone, Copy, Debug)]
+pub struct VoxelSweepHit {
+    pub point: Point3i,
+    pub chunk_key: Point3i,
+    pub toi: f32,
+}
+
+impl<'a, V> VoxelCollisions<'a, V>
+where
+    V: Voxel,
+    for<'r> &'r V::TypeInfo: IsEmpty,
+{
+    /// The chunks whose bounds are hit by `ray`, along with the time of impact where the ray enters
+    /// them, nearest first.
+    pub fn raycast_chunks(&self, ray: &Ray, max_toi: f32) -> Vec<(Point3i, f32)> {
+        self.bvh.cast_ray(ray, max_toi)
+    }
+
+    /// The first solid voxel hit by `ray` within `max_toi`.
+    pub fn raycast(&self, ray: &Ray, max_toi: f32) -> Option<VoxelRayHit> {
+        // Chunk bounds don't overlap, so the first hit in the nearest chunk is the nearest hit.
+        self.voxel_reader.read(|reader| {
+            self.bvh
+                .cast_ray(ray, max_toi)
+                .into_iter()
+                .find_map(|(chunk_key, entry_toi)| {
+                    let chunk_aabb = self.bvh.chunk_aabb(&chunk_key)?;
+                    let exit_toi = exit_toi(chunk_aabb, ray).min(max_toi);
+                    let (point, toi, normal) =
+                        march_voxels(ray, entry_toi, exit_toi, |p| self.is_solid(reader.get(&p)))?;
+
+                    Some(VoxelRayHit {
+                        point,
+                        chunk_key,
+                        toi,
+                        normal,
+                    })
+                })
+        })
+    }
+
+    /// The chunks whose bounds overlap the sphere.
+    pub fn sphere_overlap(&self, center: &Point<f32>, radius: f32) -> Vec<Point3i> {
+        let radius_sq = radius * radius;
+
+        self.bvh
+            .chunks_intersecting_aabb(&sphere_aabb(center, radius))
+            .into_iter()
+            .filter(|chunk_key| {
+                self.bvh
+                    .chunk_aabb(chunk_key)
+                    .map_or(false, |aabb| distance_sq(aabb, center) <= radius_sq)
+            })
+            .collect()
+    }
+
+    /// The solid voxels that overlap the sphere.
+    pub fn sphere_overlap_voxels(&self, center: &Point<f32>, radius: f32) -> Vec<Point3i> {
+        let radius_sq = radius * radius;
+        let chunk_keys = self.sphere_overlap(center, radius);
+
+        self.solid_voxels_in_chunks(&chunk_keys, &sphere_aabb(center, radius), |p| {
+            distance_sq(&voxel_aabb(p), center) <= radius_sq
+        })
+    }
+
+    /// The chunks whose bounds overlap `aabb`.
+    pub fn aabb_overlap(&self, aabb: &Aabb) -> Vec<Point3i> {
+        self.bvh.chunks_intersecting_aabb(aabb)
+    }
+
+    /// The solid voxels that overlap `aabb`.
+    pub fn aabb_overlap_voxels(&self, aabb: &Aabb) -> Vec<Point3i> {
+        let chunk_keys = self.aabb_overlap(aabb);
+
+        self.solid_voxels_in_chunks(&chunk_keys, aabb, |p| voxel_aabb(p).intersects(aabb))
+    }
+
+    /// The chunks whose bounds are touched by `aabb` as it moves along `velocity` for up to
+    /// `max_toi`, along with the time of first contact, earliest first.
+    pub fn sweep(&self, aabb: &Aabb, velocity: &Vector<f32>, max_toi: f32) -> Vec<(Point3i, f32)> {
+        let mut hits: Vec<(Point3i, f32)> = self
+            .bvh
+            .chunks_intersecting_aabb(&swept_aabb(aabb, velocity, max_toi))
+            .into_iter()
+            .filter_map(|chunk_key| {
+                let chunk_aabb = self.bvh.chunk_aabb(&chunk_key)?;
+                let toi = sweep_toi(aabb, velocity, max_toi, chunk_aabb)?;
+
+                Some((chunk_key, toi))
+            })
+            .collect();
+        hits.sort_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+
+        hits
+    }
+
+    /// The first solid voxel touched by `aabb` as it moves along `velocity` for up to `max_toi`.
+    pub fn sweep_voxels(
+        &self,
+        aabb: &Aabb,
+        velocity: &Vector<f32>,
+        max_toi: f32,
+    ) -> Option<VoxelSweepHit> {
+        let swept = swept_aabb(aabb, velocity, max_toi);
+        let mut nearest: Option<VoxelSweepHit> = None;
+        for (chunk_key, chunk_toi) in self.sweep(aabb, velocity, max_toi).into_iter() {
+            if nearest.map_or(false, |n| n.toi <= chunk_toi) {
+                break;
+            }
+            for point in self.solid_voxels_in_chunks(&[chunk_key], &swept, |_| true) {
+                if let Some(toi) = sweep_toi(aabb, velocity, max_toi, &voxel_aabb(point)) {
+                    if nearest.map_or(true, |n| toi < n.toi) {
+                        nearest = Some(VoxelSweepHit {
+                            point,
+                            chunk_key,
+                            toi,
+                        });
+                    }
+                }
+            }
+        }
+
+        nearest
+    }
+
+    fn is_solid(&self, voxel: V) -> bool {
+        !self
+            .voxel_reader
+            .map
+            .palette
+            .get_voxel_type_info(voxel)
+            .is_empty()
+    }
+
+    // The solid voxels in the given chunks and within `bounds` that satisfy `filter`.
+    fn solid_voxels_in_chunks(
+        &self,
+        chunk_keys: &[Point3i],
+        bounds: &Aabb,
+        mut filter: impl FnMut(Point3i) -> bool,
+    ) -> Vec<Point3i> {
+        let bounds_extent = covering_extent(bounds);
+        let mut voxels = Vec::new();
+        for chunk_key in chunk_keys.iter() {
+            let chunk_aabb = match self.bvh.chunk_aabb(chunk_key) {
+                Some(aabb) => aabb,
+                None => continue,
+            };
+            let extent = bounds_extent.intersection(&covering_extent(chunk_aabb));
+            self.voxel_reader.for_each(&extent, |p: Point3i, voxel: V| {
+                if self.is_solid(voxel) && filter(p) {
+                    voxels.push(p);
+                }
+            });
+        }
+
+        voxels
+    }
+}
+
+// Steps through the voxels along `ray` from `start_toi` to `end_toi`, returning the first one that's
+// solid along with the time of impact and the normal of the face it was entered through.
+fn march_voxels(
+    ray: &Ray,
+    start_toi: f32,
+    end_toi: f32,
+    mut is_solid: impl FnMut(Point3i) -> bool,
+) -> Option<(Point3i, f32, Point3i)> {
+    // Nudge into the first voxel so it isn't mistaken for its neighbor across the entry face.
+    let start = ray.point_at(start_toi + 1e-4);
+    let mut voxel = [
+        start.x.floor() as i32,
+        start.y.floor() as i32,
+        start.z.floor() as i32,
+    ];
+    let mut step = [0; 3];
+    let mut t_max = [f32::INFINITY; 3];
+    let mut t_delta = [f32::INFINITY; 3];
+    let mut entry_axis = None;
+    let mut entry_toi = f32::NEG_INFINITY;
+    for i in 0..3 {
+        let d = ray.dir[i];
+        if d == 0.0 {
+            continue;
+        }
+        step[i] = if d > 0.0 { 1 } else { -1 };
+        let (near, far) = if d > 0.0 {
+            (voxel[i], voxel[i] + 1)
+        } else {
+            (voxel[i] + 1, voxel[i])
+        };
+        t_max[i] = (far as f32 - ray.origin[i]) / d;
+        t_delta[i] = 1.0 / d.abs();
+        let t = (near as f32 - ray.origin[i]) / d;
+        if t > entry_toi {
+            entry_toi = t;
+            entry_axis = Some(i);
+        }
+    }
+
+    let mut toi = start_toi;
+    // A ray that starts inside of the first voxel didn't enter it through a face.
+    let mut normal = match entry_axis {
+        Some(i) if entry_toi > 0.0 => axis_normal(i, -step[i]),
+        _ => PointN([0; 3]),
+    };
+    while toi <= end_toi {
+        let point = PointN(voxel);
+        if is_solid(point) {
+            return Some((point, toi, normal));
+        }
+
+        let axis = if t_max[0] < t_max[1] {
+            if t_max[0] < t_max[2] {
+                0
+            } else {
+                2
+            }
+        } else if t_max[1] < t_max[2] {
+            1
+        } else {
+            2
+        };
+        if t_max[axis] == f32::INFINITY {
+            break;
+        }
+        toi = t_max[axis];
+        voxel[axis] += step[axis];
+        t_max[axis] += t_delta[axis];
+        normal = axis_normal(axis, -step[axis]);
+    }
+
+    None
+}
+
+fn axis_normal(axis: usize, sign: i32) -> Point3i {
+    let mut normal = [0; 3];
+    normal[axis] = sign;
+
+    PointN(normal)
+}
+
+// The time of impact where `ray` leaves `aabb`, assuming it's already been entered.
+fn exit_toi(aabb: &Aabb, ray: &Ray) -> f32 {
+    (0..3)
+        .filter(|i| ray.dir[*i] != 0.0)
+        .map(|i| {
+            let bound = if ray.dir[i] > 0.0 {
+                aabb.maxs[i]
+            } else {
+                aabb.mins[i]
+            };
+
+            (bound - ray.origin[i]) / ray.dir[i]
+        })
+        .fold(f32::INFINITY, f32::min)
+}
+
+// The time at which `aabb`, moving along `velocity`, first touches `target`. Equivalent to casting
+// the center of `aabb` against `target` grown by the half extents of `aabb`.
+fn sweep_toi(aabb: &Aabb, velocity: &Vector<f32>, max_toi: f32, target: &Aabb) -> Option<f32> {
+    let half_extents = aabb.half_extents();
+    let grown = Aabb::new(target.mins - half_extents, target.maxs + half_extents);
+
+    grown.cast_local_ray(&Ray::new(aabb.center(), *velocity), max_toi, true)
+}
+
+fn swept_aabb(aabb: &Aabb, velocity: &Vector<f32>, max_toi: f32) -> Aabb {
+    let offset = velocity * max_toi;
+
+    aabb.merged(&Aabb::new(aabb.mins + offset, aabb.maxs + offset))
+}
+
+fn sphere_aabb(center: &Point<f32>, radius: f32) -> Aabb {
+    let half_extents = Vector::repeat(radius);
+
+    Aabb::new(center - half_extents, center + half_extents)
+}
+
+fn voxel_aabb(p: Point3i) -> Aabb {
+    let mins = Point::new(p.x() as f32, p.y() as f32, p.z() as f32);
+
+    Aabb::new(mins, mins + Vector::repeat(1.0))
+}
+
+fn distance_sq(aabb: &Aabb, p: &Point<f32>) -> f32 {
+    (0..3)
+        .map(|i| {
+            let d = (aabb.mins[i] - p[i]).max(p[i] - aabb.maxs[i]).max(0.0);
+
+            d * d
+        })
+        .sum()
+}
+
+// The voxels that overlap `aabb`.
+fn covering_extent(aabb: &Aabb) -> Extent3i {
+    let min = PointN([
+        aabb.mins.x.floor() as i32,
+        aabb.mins.y.floor() as i32,
+        aabb.mins.z.floor() as i32,
+    ]);
+    let max = PointN([
+        aabb.maxs.x.ceil() as i32 - 1,
+        aabb.maxs.y.ceil() as i32 - 1,
+        aabb.maxs.z.ceil() as i32 - 1,
+    ]);
+
+    Extent3i::from_min_and_max(min, max)
+}
@@ -0,0 +1,151 @@
+use crate::{Voxel, VoxelCollisions};
+
+use bevy::{prelude::*, render::camera::Camera};
+use building_blocks::prelude::*;
+use parry3d::{
+    math::{Point, Vector},
+    query::Ray,
+};
+
+/// Manages the `HoveredVoxel` resource by casting a ray from the cursor through the
+/// `VoxelPickingCamera` into the map every frame, in the `PRE_UPDATE` stage. Depends on the
+/// `ChunkBvhPlugin`.
+///
+/// A `HoveredVoxelChanged` event is sent whenever the hovered voxel, the face it's hovered from,
+/// or its type changes, e.g. for editor tools and block placement previews.
+pub struct VoxelPickingPlugin<V> {
+    pub max_distance: f32,
+    marker: std::marker::PhantomData<V>,
+}
+
+impl<V> VoxelPickingPlugin<V> {
+    pub fn new(max_distance: f32) -> Self {
+        Self {
+            max_distance,
+            marker: Default::default(),
+        }
+    }
+}
+
+impl<V> Plugin for VoxelPickingPlugin<V>
+where
+    V: Voxel,
+    for<'r> &'r V::TypeInfo: IsEmpty,
+{
+    fn build(&self, app: &mut AppBuilder) {
+        app.insert_resource(HoveredVoxel::<V>::new(self.max_distance))
+            .add_event::<HoveredVoxelChanged<V>>()
+            .add_system_to_stage(stage::PRE_UPDATE, voxel_picking_system::<V>.system());
+    }
+}
+
+/// Marks the camera that picks voxels under the cursor of the primary window. If several cameras
+/// are marked, the first one found is used.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct VoxelPickingCamera;
+
+/// The solid voxel under the cursor.
+#[derive(Clone, Copy, Debug)]
+pub struct VoxelPick<V> {
+    pub point: Point3i,
+    pub chunk_key: Point3i,
+    /// The normal of the face under the cursor, or zero if the camera is inside of the voxel. The
+    /// voxel at `point + normal` is where a block would be placed against that face.
+    pub normal: Point3i,
+    pub voxel: V,
+    /// The distance from the camera along the cursor ray.
+    pub distance: f32,
+}
+
+/// The voxel under the cursor, as of the `PRE_UPDATE` stage of this frame.
+pub struct HoveredVoxel<V> {
+    /// How far from the camera voxels can be picked.
+    pub max_distance: f32,
+    /// Picking only runs while enabled, e.g. not while the cursor is over UI.
+    pub enabled: bool,
+    pick: Option<VoxelPick<V>>,
+}
+
+impl<V> HoveredVoxel<V> {
+    pub fn new(max_distance: f32) -> Self {
+        Self {
+            max_distance,
+            enabled: true,
+            pick: None,
+        }
+    }
+
+    /// The hovered voxel, or `None` if the cursor isn't over any voxel within `max_distance`.
+    pub fn get(&self) -> Option<&VoxelPick<V>> {
+        self.pick.as_ref()
+    }
+}
+
+/// Sent when the `HoveredVoxel` moves to another voxel or face, or the hovered voxel's type
+/// changes.
+pub struct HoveredVoxelChanged<V> {
+    pub previous: Option<VoxelPick<V>>,
+    pub current: Option<VoxelPick<V>>,
+}
+
+fn voxel_picking_system<V>(
+    windows: Res<Windows>,
+    cameras: Query<(&Camera, &GlobalTransform), With<VoxelPickingCamera>>,
+    collisions: VoxelCollisions<V>,
+    mut hovered: ResMut<HoveredVoxel<V>>,
+    mut changed_events: ResMut<Events<HoveredVoxelChanged<V>>>,
+) where
+    V: Voxel,
+    for<'r> &'r V::TypeInfo: IsEmpty,
+{
+    let pick = if hovered.enabled {
+        cursor_ray(&*windows, &cameras).and_then(|ray| {
+            let hit = collisions.raycast(&ray, hovered.max_distance)?;
+
+            Some(VoxelPick {
+                point: hit.point,
+                chunk_key: hit.chunk_key,
+                normal: hit.normal,
+                voxel: collisions.voxel_reader.get(hit.point),
+                distance: hit.toi,
+            })
+        })
+    } else {
+        None
+    };
+
+    let key = |pick: &Option<VoxelPick<V>>| {
+        pick.as_ref()
+            .map(|p| (p.point, p.normal, p.voxel.get_type_index()))
+    };
+    if key(&pick) != key(&hovered.pick) {
+        changed_events.send(HoveredVoxelChanged {
+            previous: hovered.pick,
+            current: pick,
+        });
+    }
+    hovered.pick = pick;
+}
+
+/// The ray from the picking camera through the cursor, with a unit direction.
+fn cursor_ray(
+    windows: &Windows,
+    cameras: &Query<(&Camera, &GlobalTransform), With<VoxelPickingCamera>>,
+) -> Option<Ray> {
+    let window = windows.get_primary()?;
+    let cursor = window.cursor_position()?;
+    let (camera, transform) = cameras.iter().next()?;
+
+    // The cursor is measured from the bottom left of the window.
+    let ndc_x = 2.0 * cursor.x / window.width() - 1.0;
+    let ndc_y = 2.0 * cursor.y / window.height() - 1.0;
+    let ndc_to_world = transform.compute_matrix() * camera.projection_matrix.inverse();
+    let near = ndc_to_world.transform_point3(Vec3::new(ndc_x, ndc_y, 0.0));
+    let far = ndc_to_world.transform_point3(Vec3::new(ndc_x, ndc_y, 1.0));
+    let direction = (far - near).normalize();
+
+    Some(Ray::new(
+        Point::new(near.x, near.y, near.z),
+        Vector::new(direction.x, direction.y, direction.z),
+    ))
+}
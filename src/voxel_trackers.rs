@@ -0,0 +1,379 @@
+use crate::{
+    copy_chunk_without_caching, map_io::EditBuffer, tasks::map_in_pool, DirtyChunks, EmptyChunks,
+    SpilledChunks, ThreadLocalVoxelCache, Voxel, VoxelMap, VoxelTaskPool,
+};
+
+use bevy::prelude::*;
+use building_blocks::prelude::*;
+use fnv::{FnvHashMap, FnvHashSet};
+
+/// Manages the `VoxelTrackers` resource, which indexes the points of all voxels whose type matches
+/// a predicate, e.g. all torches for lighting, all machine blocks for ticking, or all ores for
+/// minimap markers. Depends on the `MapIoPlugin`, ideally configured with
+/// `with_type_change_tracking`.
+///
+/// A new tracker scans every chunk in the map once, including spilled and collapsed chunks, without
+/// caching them. After that, only the points in `ChunkEdits::type_changes` are looked up, so
+/// keeping the trackers up to date costs O(changed voxels) per frame. Chunks that are replaced with
+/// `insert_chunk` are scanned again, and so is every edited chunk if type changes aren't tracked.
+/// A tracker scans the whole map again when a palette change makes its predicate match different
+/// types. Trackers are updated from the previous frame's `DirtyChunks` in the `PRE_UPDATE` stage.
+pub struct VoxelTrackersPlugin<V> {
+    marker: std::marker::PhantomData<V>,
+}
+
+impl<V> Default for VoxelTrackersPlugin<V> {
+    fn default() -> Self {
+        Self {
+            marker: Default::default(),
+        }
+    }
+}
+
+impl<V> Plugin for VoxelTrackersPlugin<V>
+where
+    V: Voxel,
+{
+    fn build(&self, app: &mut AppBuilder) {
+        app.insert_resource(VoxelTrackers::<V>::default())
+            // Before consumers run in UPDATE.
+            .add_system_to_stage(stage::PRE_UPDATE, voxel_trackers_system::<V>.system());
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct VoxelTrackerId(u64);
+
+/// Sets of voxel points, one per tracker, each holding every voxel in the map whose type info
+/// matches the tracker's predicate.
+///
+/// Predicates are evaluated against the `VoxelPalette` once per frame, so they're cheap to make
+/// arbitrarily complex. A tracker added with `track` is filled in on the next `PRE_UPDATE` stage;
+/// until then, `is_ready` is `false` and it has no points.
+pub struct VoxelTrackers<V>
+where
+    V: Voxel,
+{
+    next_id: u64,
+    trackers: FnvHashMap<VoxelTrackerId, Tracker<V>>,
+    // Copied from the map, to find the chunk containing a point.
+    chunk_shape: Point3i,
+}
+
+struct Tracker<V>
+where
+    V: Voxel,
+{
+    predicate: Box<dyn Fn(&V::TypeInfo) -> bool + Send + Sync>,
+    // Whether each type index matches the predicate, as of this frame's palette.
+    matches: Vec<bool>,
+    chunk_points: FnvHashMap<Point3i, FnvHashSet<Point3i>>,
+    num_points: usize,
+    scanned: bool,
+}
+
+impl<V> Default for VoxelTrackers<V>
+where
+    V: Voxel,
+{
+    fn default() -> Self {
+        Self {
+            next_id: 0,
+            trackers: Default::default(),
+            chunk_shape: PointN([0; 3]),
+        }
+    }
+}
+
+impl<V> VoxelTrackers<V>
+where
+    V: Voxel,
+{
+    pub fn track(
+        &mut self,
+        predicate: impl Fn(&V::TypeInfo) -> bool + Send + Sync + 'static,
+    ) -> VoxelTrackerId {
+        let id = VoxelTrackerId(self.next_id);
+        self.next_id += 1;
+        self.trackers.insert(
+            id,
+            Tracker {
+                predicate: Box::new(predicate),
+                matches: Vec::new(),
+                chunk_points: Default::default(),
+                num_points: 0,
+                scanned: false,
+            },
+        );
+
+        id
+    }
+
+    pub fn untrack(&mut self, id: VoxelTrackerId) {
+        self.trackers.remove(&id);
+    }
+
+    /// `true` once the tracker has scanned the map.
+    pub fn is_ready(&self, id: VoxelTrackerId) -> bool {
+        self.trackers.get(&id).map_or(false, |t| t.scanned)
+    }
+
+    pub fn contains(&self, id: VoxelTrackerId, p: &Point3i) -> bool {
+        let tracker = match self.trackers.get(&id) {
+            Some(tracker) if tracker.scanned => tracker,
+            _ => return false,
+        };
+
+        tracker
+            .chunk_points
+            .get(&self.chunk_key_containing_point(p))
+            .map_or(false, |points| points.contains(p))
+    }
+
+    /// Every point tracked by `id`, in no particular order.
+    pub fn points(&self, id: VoxelTrackerId) -> impl Iterator<Item = &Point3i> {
+        self.trackers
+            .get(&id)
+            .into_iter()
+            .flat_map(|t| t.chunk_points.values().flatten())
+    }
+
+    /// The points tracked by `id` in the chunk at `chunk_key`.
+    pub fn points_in_chunk(
+        &self,
+        id: VoxelTrackerId,
+        chunk_key: &Point3i,
+    ) -> impl Iterator<Item = &Point3i> {
+        self.trackers
+            .get(&id)
+            .and_then(|t| t.chunk_points.get(chunk_key))
+            .into_iter()
+            .flatten()
+    }
+
+    /// The number of points tracked by `id`.
+    pub fn num_points(&self, id: VoxelTrackerId) -> usize {
+        self.trackers.get(&id).map_or(0, |t| t.num_points)
+    }
+
+    /// The number of trackers.
+    pub fn len(&self) -> usize {
+        self.trackers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.trackers.is_empty()
+    }
+
+    fn chunk_key_containing_point(&self, p: &Point3i) -> Point3i {
+        let s = self.chunk_shape;
+
+        PointN([
+            p.x().div_euclid(s.x()) * s.x(),
+            p.y().div_euclid(s.y()) * s.y(),
+            p.z().div_euclid(s.z()) * s.z(),
+        ])
+    }
+
+    /// Replaces the points of the trackers at `ids` in each of the chunks at `chunk_keys` with the
+    /// matching voxels in the map. Spilled chunks that can't be read keep their points, and the
+    /// error is recorded in the `SpilledChunks`.
+    fn scan_chunks(
+        &mut self,
+        ids: &[VoxelTrackerId],
+        chunk_keys: Vec<Point3i>,
+        pool: &VoxelTaskPool,
+        map: &VoxelMap<V>,
+        spilled_chunks: Option<&SpilledChunks<V>>,
+    ) {
+        if ids.is_empty() || chunk_keys.is_empty() {
+            return;
+        }
+
+        let matches: Vec<&[bool]> = ids
+            .iter()
+            .map(|id| self.trackers[id].matches.as_slice())
+            .collect();
+        // Copying doesn't decompress the chunks into the caches, which would fill them with the
+        // whole map.
+        let scanned = map_in_pool(pool, chunk_keys.into_iter(), |chunk_key| {
+            let mut points = vec![FnvHashSet::default(); matches.len()];
+            let chunk = match copy_chunk_without_caching(map, spilled_chunks, chunk_key) {
+                Ok(Some(chunk)) => chunk,
+                // The chunk was already removed.
+                Ok(None) => return (chunk_key, Ok(points)),
+                Err(e) => return (chunk_key, Err(e)),
+            };
+            chunk.for_each(chunk.extent(), |p: Point3i, voxel: V| {
+                let type_index = voxel.get_type_index();
+                for (m, points) in matches.iter().zip(points.iter_mut()) {
+                    if m.get(type_index).cloned().unwrap_or(false) {
+                        points.insert(p);
+                    }
+                }
+            });
+
+            (chunk_key, Ok(points))
+        });
+
+        for (chunk_key, result) in scanned.into_iter() {
+            let points = match result {
+                Ok(points) => points,
+                Err(e) => {
+                    if let Some(spilled_chunks) = spilled_chunks {
+                        spilled_chunks.record_error(chunk_key, e);
+                    }
+                    continue;
+                }
+            };
+            for (id, points) in ids.iter().zip(points.into_iter()) {
+                let tracker = self.trackers.get_mut(id).unwrap();
+                tracker.remove_chunk(&chunk_key);
+                if !points.is_empty() {
+                    tracker.num_points += points.len();
+                    tracker.chunk_points.insert(chunk_key, points);
+                }
+            }
+        }
+    }
+}
+
+impl<V> Tracker<V>
+where
+    V: Voxel,
+{
+    fn matches(&self, type_index: usize) -> bool {
+        self.matches.get(type_index).cloned().unwrap_or(false)
+    }
+
+    fn update_point(&mut self, chunk_key: Point3i, p: Point3i, type_index: usize) {
+        if self.matches(type_index) {
+            if self.chunk_points.entry(chunk_key).or_default().insert(p) {
+                self.num_points += 1;
+            }
+        } else if let Some(points) = self.chunk_points.get_mut(&chunk_key) {
+            if points.remove(&p) {
+                self.num_points -= 1;
+            }
+            if points.is_empty() {
+                self.chunk_points.remove(&chunk_key);
+            }
+        }
+    }
+
+    fn remove_chunk(&mut self, chunk_key: &Point3i) {
+        if let Some(points) = self.chunk_points.remove(chunk_key) {
+            self.num_points -= points.len();
+        }
+    }
+}
+
+fn voxel_trackers_system<V>(
+    pool: Res<VoxelTaskPool>,
+    voxel_map: Res<VoxelMap<V>>,
+    local_caches: Res<ThreadLocalVoxelCache<V>>,
+    edit_buffer: Res<EditBuffer<V>>,
+    dirty_chunks: Res<DirtyChunks<V>>,
+    empty_chunks: Res<EmptyChunks<V>>,
+    spilled_chunks: Option<Res<SpilledChunks<V>>>,
+    mut trackers: ResMut<VoxelTrackers<V>>,
+) where
+    V: Voxel,
+{
+    if trackers.is_empty() {
+        return;
+    }
+    let trackers = &mut *trackers;
+    trackers.chunk_shape = voxel_map.voxels.indexer.chunk_shape();
+    for tracker in trackers.trackers.values_mut() {
+        let predicate = &tracker.predicate;
+        let matches: Vec<bool> = voxel_map.palette.infos.iter().map(predicate).collect();
+        // The indexed points are only valid for the types that matched when they were scanned.
+        // Types added to the palette since then can't be in the map yet.
+        let changed = tracker
+            .matches
+            .iter()
+            .zip(matches.iter())
+            .any(|(old, new)| old != new);
+        if tracker.scanned && changed {
+            tracker.scanned = false;
+            tracker.chunk_points.clear();
+            tracker.num_points = 0;
+        }
+        tracker.matches = matches;
+    }
+
+    for chunk_key in empty_chunks.removed_chunk_keys() {
+        for tracker in trackers.trackers.values_mut() {
+            tracker.remove_chunk(chunk_key);
+        }
+    }
+
+    let mut scanned_ids = Vec::new();
+    let mut unscanned_ids = Vec::new();
+    for (id, tracker) in trackers.trackers.iter_mut() {
+        if tracker.scanned {
+            scanned_ids.push(*id);
+        } else {
+            unscanned_ids.push(*id);
+            tracker.scanned = true;
+        }
+    }
+
+    // New trackers start with the whole map, which already includes this frame's edits.
+    if !unscanned_ids.is_empty() {
+        let mut all_chunk_keys: Vec<Point3i> =
+            voxel_map.voxels.storage().chunk_keys().cloned().collect();
+        all_chunk_keys.extend(
+            voxel_map
+                .uniform_chunks
+                .iter()
+                .map(|(chunk_key, _)| *chunk_key),
+        );
+        if let Some(spilled_chunks) = spilled_chunks.as_ref() {
+            all_chunk_keys.extend(spilled_chunks.chunk_keys().cloned());
+        }
+        trackers.scan_chunks(
+            &unscanned_ids,
+            all_chunk_keys,
+            &*pool,
+            &*voxel_map,
+            spilled_chunks.as_deref(),
+        );
+    }
+
+    let tracks_type_changes = edit_buffer.tracks_type_changes();
+    let mut rescans = Vec::new();
+    let mut type_changes = Vec::new();
+    for chunk_key in dirty_chunks.edited_chunk_keys.iter() {
+        match dirty_chunks.chunk_edits.get(chunk_key) {
+            Some(edits) if tracks_type_changes && !edits.replaced => {
+                type_changes.extend(edits.type_changes.iter().map(|p| (*chunk_key, *p)));
+            }
+            _ => rescans.push(*chunk_key),
+        }
+    }
+    trackers.scan_chunks(
+        &scanned_ids,
+        rescans,
+        &*pool,
+        &*voxel_map,
+        spilled_chunks.as_deref(),
+    );
+
+    if scanned_ids.is_empty() || type_changes.is_empty() {
+        return;
+    }
+    let cache_tls = local_caches.get();
+    let reader = voxel_map.reader(&cache_tls);
+    for (chunk_key, p) in type_changes.into_iter() {
+        let type_index = reader.get(&p).get_type_index();
+        for id in scanned_ids.iter() {
+            trackers
+                .trackers
+                .get_mut(id)
+                .unwrap()
+                .update_point(chunk_key, p, type_index);
+        }
+    }
+}
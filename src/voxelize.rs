@@ -0,0 +1,354 @@
+use crate::{map::bounding_extent, Voxel, VoxelEditor};
+
+use bevy::{
+    prelude::*,
+    render::mesh::{Indices, VertexAttributeValues},
+};
+use building_blocks::prelude::*;
+use fnv::FnvHashMap;
+
+/// A triangle to voxelize, in the local space of its mesh.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MeshTriangle {
+    pub positions: [Vec3; 3],
+    /// Mapped to a voxel by `VoxelEditor::voxelize_triangles` for every voxel this triangle fills.
+    pub material: usize,
+}
+
+/// Which voxels of a mesh are filled.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum VoxelizeMode {
+    /// Only the voxels that intersect a triangle.
+    Shell,
+    /// The shell and every voxel with its center inside the mesh, which must be closed. The inside
+    /// takes the material of the nearest triangle in the -X direction.
+    Solid,
+}
+
+/// The triangles of `mesh`, which must use the `TriangleList` topology, all with `material`.
+/// Returns `None` if the mesh doesn't have `Float3` positions or an index is out of range.
+pub fn mesh_triangles(mesh: &Mesh, material: usize) -> Option<Vec<MeshTriangle>> {
+    let positions = match mesh.attribute(Mesh::ATTRIBUTE_POSITION)? {
+        VertexAttributeValues::Float3(positions) => positions,
+        _ => return None,
+    };
+    let indices: Vec<usize> = match mesh.indices() {
+        Some(Indices::U16(indices)) => indices.iter().map(|&i| i as usize).collect(),
+        Some(Indices::U32(indices)) => indices.iter().map(|&i| i as usize).collect(),
+        None => (0..positions.len()).collect(),
+    };
+    let position = |i: usize| positions.get(i).map(|&p| Vec3::from(p));
+
+    indices
+        .chunks_exact(3)
+        .map(|tri| {
+            Some(MeshTriangle {
+                positions: [position(tri[0])?, position(tri[1])?, position(tri[2])?],
+                material,
+            })
+        })
+        .collect()
+}
+
+impl<'a, V> VoxelEditor<'a, V>
+where
+    V: Voxel,
+{
+    /// Voxelizes `mesh`, placed in the map by `transform`, and fills its voxels with `voxel`. See
+    /// `voxelize_triangles`. Returns `None` if the mesh has no triangles.
+    pub fn voxelize_mesh(
+        &mut self,
+        mesh: &Mesh,
+        transform: &Transform,
+        mode: VoxelizeMode,
+        voxel: V,
+    ) -> Option<Extent3i> {
+        let triangles = mesh_triangles(mesh, 0)?;
+
+        self.voxelize_triangles(&triangles, transform, mode, |_| voxel)
+    }
+
+    /// Voxelizes `triangles`, placed in the map by `transform`, and fills each voxel with
+    /// `material_voxel` of the material of the triangle that filled it. Every voxel is a unit cube
+    /// with its minimum corner at its point. Voxels that aren't filled are left untouched.
+    ///
+    /// Returns the extent that was edited, or `None` if there are no triangles. All edited chunks
+    /// and their neighbors will be marked as dirty.
+    pub fn voxelize_triangles(
+        &mut self,
+        triangles: &[MeshTriangle],
+        transform: &Transform,
+        mode: VoxelizeMode,
+        mut material_voxel: impl FnMut(usize) -> V,
+    ) -> Option<Extent3i> {
+        let triangles: Vec<MapTriangle> = triangles
+            .iter()
+            .map(|t| {
+                let [a, b, c] = t.positions;
+                let world = |v: Vec3| -> [f32; 3] { transform.mul_vec3(v).into() };
+
+                ([world(a), world(b), world(c)], t.material)
+            })
+            .collect();
+        let materials = rasterize(&triangles, mode)?;
+        let extent = *materials.extent();
+
+        let mut voxels: FnvHashMap<usize, V> = Default::default();
+        self.edit_extent_and_touch_neighbors(extent, |p: Point3i, voxel: &mut V| {
+            if let Some(material) = materials.get(&p) {
+                *voxel = *voxels
+                    .entry(material)
+                    .or_insert_with(|| material_voxel(material));
+            }
+        });
+
+        Some(extent)
+    }
+}
+
+/// A triangle in map space and its material.
+type MapTriangle = ([[f32; 3]; 3], usize);
+
+fn rasterize(triangles: &[MapTriangle], mode: VoxelizeMode) -> Option<Array3<Option<usize>>> {
+    let extent = bounding_extent(triangles.iter().map(|(tri, _)| voxel_bounds(tri)))?;
+    let mut materials = Array3::fill(extent, None);
+
+    for (tri, material) in triangles.iter() {
+        let tri_extent = voxel_bounds(tri);
+        for z in tri_extent.minimum.z()..=tri_extent.max().z() {
+            for y in tri_extent.minimum.y()..=tri_extent.max().y() {
+                for x in tri_extent.minimum.x()..=tri_extent.max().x() {
+                    let p = PointN([x, y, z]);
+                    if triangle_overlaps_voxel(tri, p) {
+                        *materials.get_mut(&p) = Some(*material);
+                    }
+                }
+            }
+        }
+    }
+
+    if mode == VoxelizeMode::Solid {
+        fill_interior(triangles, &mut materials);
+    }
+
+    Some(materials)
+}
+
+/// The extent of the voxels containing the bounding box of `tri`.
+fn voxel_bounds(tri: &[[f32; 3]; 3]) -> Extent3i {
+    let (min, max) = tri.iter().fold((tri[0], tri[0]), |(lo, hi), v| {
+        (
+            [lo[0].min(v[0]), lo[1].min(v[1]), lo[2].min(v[2])],
+            [hi[0].max(v[0]), hi[1].max(v[1]), hi[2].max(v[2])],
+        )
+    });
+
+    Extent3i::from_min_and_max(voxel_containing(min), voxel_containing(max))
+}
+
+fn voxel_containing(v: [f32; 3]) -> Point3i {
+    PointN([
+        v[0].floor() as i32,
+        v[1].floor() as i32,
+        v[2].floor() as i32,
+    ])
+}
+
+/// Casts a ray in the +X direction through the center of every row of voxels, and fills the voxels
+/// between each entering and exiting crossing of the surface.
+fn fill_interior(triangles: &[MapTriangle], materials: &mut Array3<Option<usize>>) {
+    let extent = *materials.extent();
+    let mut crossings: Vec<(f32, usize)> = Vec::new();
+    for z in extent.minimum.z()..=extent.max().z() {
+        for y in extent.minimum.y()..=extent.max().y() {
+            let (ray_y, ray_z) = (y as f32 + 0.5, z as f32 + 0.5);
+            crossings.clear();
+            crossings.extend(triangles.iter().filter_map(|(tri, material)| {
+                ray_crossing(tri, ray_y, ray_z).map(|x| (x, *material))
+            }));
+            crossings.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+            // A ray through an edge shared by two triangles crosses the surface only once.
+            crossings.dedup_by(|a, b| (a.0 - b.0).abs() < 1e-5);
+
+            for pair in crossings.chunks_exact(2) {
+                let (enter, material) = pair[0];
+                let exit = pair[1].0;
+                let first = (enter - 0.5).ceil() as i32;
+                let last = (exit - 0.5).floor() as i32;
+                for x in first..=last {
+                    let voxel = materials.get_mut(&PointN([x, y, z]));
+                    if voxel.is_none() {
+                        *voxel = Some(material);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The X where the ray parallel to the X axis at (`y`, `z`) crosses `tri`, if it does.
+fn ray_crossing(tri: &[[f32; 3]; 3], y: f32, z: f32) -> Option<f32> {
+    let [a, b, c] = tri;
+    let edge = |u: &[f32; 3], v: &[f32; 3]| (v[1] - u[1]) * (z - u[2]) - (v[2] - u[2]) * (y - u[1]);
+    let (w0, w1, w2) = (edge(b, c), edge(c, a), edge(a, b));
+    let area = w0 + w1 + w2;
+    if area == 0.0 {
+        return None;
+    }
+    let inside = if area > 0.0 {
+        w0 >= 0.0 && w1 >= 0.0 && w2 >= 0.0
+    } else {
+        w0 <= 0.0 && w1 <= 0.0 && w2 <= 0.0
+    };
+    if !inside {
+        return None;
+    }
+
+    Some((w0 * a[0] + w1 * b[0] + w2 * c[0]) / area)
+}
+
+/// The separating axis test between `tri` and the unit cube of the voxel at `p`.
+fn triangle_overlaps_voxel(tri: &[[f32; 3]; 3], p: Point3i) -> bool {
+    let center = [p.x() as f32 + 0.5, p.y() as f32 + 0.5, p.z() as f32 + 0.5];
+    let v = [
+        sub(tri[0], center),
+        sub(tri[1], center),
+        sub(tri[2], center),
+    ];
+    let edges = [sub(v[1], v[0]), sub(v[2], v[1]), sub(v[0], v[2])];
+    let box_axes = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+
+    let separated = |axis: [f32; 3]| {
+        let (mut lo, mut hi) = (f32::MAX, f32::MIN);
+        for vertex in v.iter() {
+            let d = dot(*vertex, axis);
+            lo = lo.min(d);
+            hi = hi.max(d);
+        }
+        let radius = 0.5 * (axis[0].abs() + axis[1].abs() + axis[2].abs());
+
+        lo > radius || hi < -radius
+    };
+
+    if box_axes.iter().any(|&axis| separated(axis)) {
+        return false;
+    }
+    if separated(cross(edges[0], edges[1])) {
+        return false;
+    }
+    for &edge in edges.iter() {
+        for &axis in box_axes.iter() {
+            if separated(cross(edge, axis)) {
+                return false;
+            }
+        }
+    }
+
+    true
+}
+
+fn sub(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+fn dot(a: [f32; 3], b: [f32; 3]) -> f32 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn cross(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [
+        a[1] * b[2] - a[2] * b[1],
+        a[2] * b[0] - a[0] * b[2],
+        a[0] * b[1] - a[1] * b[0],
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The 12 triangles of a box from `min` to `max`, all with outward normals.
+    fn box_triangles(min: f32, max: f32, material: usize) -> Vec<MapTriangle> {
+        let corner = |i: usize| {
+            [
+                if i & 1 == 0 { min } else { max },
+                if i & 2 == 0 { min } else { max },
+                if i & 4 == 0 { min } else { max },
+            ]
+        };
+        let quads = [
+            [0, 4, 6, 2],
+            [1, 3, 7, 5],
+            [0, 1, 5, 4],
+            [2, 6, 7, 3],
+            [0, 2, 3, 1],
+            [4, 5, 7, 6],
+        ];
+
+        quads
+            .iter()
+            .flat_map(|q| {
+                vec![
+                    ([corner(q[0]), corner(q[1]), corner(q[2])], material),
+                    ([corner(q[0]), corner(q[2]), corner(q[3])], material),
+                ]
+            })
+            .collect()
+    }
+
+    fn num_filled(materials: &Array3<Option<usize>>) -> usize {
+        let mut count = 0;
+        materials.for_each(materials.extent(), |_p: Point3i, m: Option<usize>| {
+            count += m.is_some() as usize;
+        });
+
+        count
+    }
+
+    #[test]
+    fn shell_fills_only_the_surface_voxels() {
+        let materials = rasterize(&box_triangles(0.25, 3.75, 7), VoxelizeMode::Shell).unwrap();
+
+        assert_eq!(
+            *materials.extent(),
+            Extent3i::from_min_and_shape(PointN([0; 3]), PointN([4; 3]))
+        );
+        // Everything but the 2x2x2 inside.
+        assert_eq!(num_filled(&materials), 64 - 8);
+        assert_eq!(materials.get(&PointN([0, 1, 2])), Some(7));
+        assert_eq!(materials.get(&PointN([1, 1, 2])), None);
+    }
+
+    #[test]
+    fn solid_fills_the_inside_too() {
+        let materials = rasterize(&box_triangles(0.25, 3.75, 7), VoxelizeMode::Solid).unwrap();
+
+        assert_eq!(num_filled(&materials), 64);
+        assert_eq!(materials.get(&PointN([1, 1, 2])), Some(7));
+    }
+
+    #[test]
+    fn nothing_to_rasterize_without_triangles() {
+        assert!(rasterize(&[], VoxelizeMode::Solid).is_none());
+    }
+
+    #[test]
+    fn triangles_only_overlap_the_voxels_they_touch() {
+        let tri = [[0.5, 0.5, 0.5], [2.5, 0.5, 0.5], [0.5, 2.5, 0.5]];
+
+        assert!(triangle_overlaps_voxel(&tri, PointN([0, 0, 0])));
+        assert!(triangle_overlaps_voxel(&tri, PointN([1, 1, 0])));
+        assert!(!triangle_overlaps_voxel(&tri, PointN([2, 2, 0])));
+        assert!(!triangle_overlaps_voxel(&tri, PointN([0, 0, 1])));
+        // Rays along X never cross triangles parallel to them.
+        assert_eq!(ray_crossing(&tri, 0.5, 0.5), None);
+    }
+
+    #[test]
+    fn rays_cross_triangles_facing_them() {
+        let tri = [[1.0, 0.0, 0.0], [1.0, 2.0, 0.0], [1.0, 0.0, 2.0]];
+
+        assert_eq!(ray_crossing(&tri, 0.5, 0.5), Some(1.0));
+        assert_eq!(ray_crossing(&tri, 1.5, 1.5), None);
+    }
+}
@@ -0,0 +1,13 @@
+mod biomes;
+mod decoration;
+mod generator;
+mod on_demand;
+mod plugin;
+mod seed;
+
+pub use biomes::{BiomeId, BiomeMap, Biomes};
+pub use decoration::{ChunkDecorator, DecorationWriter};
+pub use generator::{ChunkGenerator, WorldGen};
+pub use on_demand::GeneratingVoxelReader;
+pub use plugin::WorldGenPlugin;
+pub use seed::{ChunkRng, WorldSeed};
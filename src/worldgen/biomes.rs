@@ -0,0 +1,229 @@
+use super::seed::mix64;
+
+use std::sync::Arc;
+
+/// An index into the parameters of `Biomes`.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct BiomeId(pub u16);
+
+/// A coarse map of biomes over the XZ plane, so every column of voxels has one biome.
+#[derive(Clone, Debug)]
+pub struct BiomeMap {
+    source: BiomeSource,
+}
+
+#[derive(Clone, Debug)]
+enum BiomeSource {
+    Grid {
+        min: [i32; 2],
+        cell_size: i32,
+        shape: [usize; 2],
+        cells: Vec<BiomeId>,
+        outside: BiomeId,
+    },
+    Voronoi {
+        seed: u64,
+        cell_size: i32,
+        biomes: Vec<BiomeId>,
+    },
+}
+
+impl BiomeMap {
+    /// A map loaded from data, e.g. a painted image. `cells` is row-major with X varying fastest,
+    /// and each cell covers `cell_size` by `cell_size` columns starting at the XZ point `min`.
+    /// Columns outside of the grid have the `outside` biome.
+    pub fn from_grid(
+        min: [i32; 2],
+        cell_size: i32,
+        shape: [usize; 2],
+        cells: Vec<BiomeId>,
+        outside: BiomeId,
+    ) -> Self {
+        assert!(cell_size > 0);
+        assert_eq!(cells.len(), shape[0] * shape[1]);
+
+        Self {
+            source: BiomeSource::Grid {
+                min,
+                cell_size,
+                shape,
+                cells,
+                outside,
+            },
+        }
+    }
+
+    /// An infinite map of irregular regions generated from cellular noise. Each region is roughly
+    /// `cell_size` columns across, and it's assigned one of `biomes` at random.
+    pub fn voronoi(seed: u64, cell_size: i32, biomes: Vec<BiomeId>) -> Self {
+        assert!(cell_size > 0);
+        assert!(!biomes.is_empty());
+
+        Self {
+            source: BiomeSource::Voronoi {
+                seed,
+                cell_size,
+                biomes,
+            },
+        }
+    }
+
+    pub fn biome_at(&self, x: i32, z: i32) -> BiomeId {
+        match &self.source {
+            BiomeSource::Grid {
+                min,
+                cell_size,
+                shape,
+                cells,
+                outside,
+            } => {
+                let cx = (x - min[0]).div_euclid(*cell_size);
+                let cz = (z - min[1]).div_euclid(*cell_size);
+                if cx < 0 || cz < 0 || cx as usize >= shape[0] || cz as usize >= shape[1] {
+                    *outside
+                } else {
+                    cells[cx as usize + cz as usize * shape[0]]
+                }
+            }
+            BiomeSource::Voronoi {
+                seed,
+                cell_size,
+                biomes,
+            } => {
+                let (cx, cz) = (x.div_euclid(*cell_size), z.div_euclid(*cell_size));
+                // The nearest site is always in one of the 3x3 surrounding cells.
+                let mut nearest = (i64::MAX, 0);
+                for nz in cz - 1..=cz + 1 {
+                    for nx in cx - 1..=cx + 1 {
+                        let hash = hash_cell(*seed, nx, nz);
+                        let site_x = nx * cell_size + (hash % *cell_size as u64) as i32;
+                        let site_z = nz * cell_size + ((hash >> 32) % *cell_size as u64) as i32;
+                        let (dx, dz) = ((x - site_x) as i64, (z - site_z) as i64);
+                        let dist_sq = dx * dx + dz * dz;
+                        if dist_sq < nearest.0 {
+                            nearest = (dist_sq, hash);
+                        }
+                    }
+                }
+
+                biomes[(nearest.1 >> 16) as usize % biomes.len()]
+            }
+        }
+    }
+
+    /// The fraction of each biome in the square of columns within `radius` of `(x, z)`, sampled on
+    /// a 5x5 grid. The weights sum to 1, so they can be used to blend biome parameters at borders.
+    pub fn blend_weights(&self, x: i32, z: i32, radius: i32) -> Vec<(BiomeId, f32)> {
+        const SAMPLES: i32 = 5;
+
+        let mut weights: Vec<(BiomeId, f32)> = Vec::new();
+        let weight = 1.0 / (SAMPLES * SAMPLES) as f32;
+        for j in 0..SAMPLES {
+            for i in 0..SAMPLES {
+                let sx = x - radius + 2 * radius * i / (SAMPLES - 1);
+                let sz = z - radius + 2 * radius * j / (SAMPLES - 1);
+                let biome = self.biome_at(sx, sz);
+                match weights.iter_mut().find(|(b, _)| *b == biome) {
+                    Some((_, w)) => *w += weight,
+                    None => weights.push((biome, weight)),
+                }
+            }
+        }
+
+        weights
+    }
+}
+
+fn hash_cell(seed: u64, x: i32, z: i32) -> u64 {
+    let h = seed ^ (((x as u32 as u64) << 32) | z as u32 as u64);
+
+    mix64(h.wrapping_add(0x9e37_79b9_7f4a_7c15))
+}
+
+/// A `BiomeMap` together with the generation parameters of each biome, indexed by `BiomeId`.
+///
+/// This is cheap to clone, so the same biomes can be given to a `ChunkGenerator`, its
+/// `ChunkDecorator`s, and inserted as a resource for gameplay queries:
+///
+/// ```
+/// use bevy_building_blocks::{
+///     bb::prelude::*, BiomeId, BiomeMap, Biomes, ChunkGenerator, ChunkRng, WorldSeed,
+/// };
+///
+/// struct BiomeParams {
+///     height: f32,
+///     surface_voxel: u8,
+/// }
+///
+/// struct TerrainGenerator {
+///     biomes: Biomes<BiomeParams>,
+/// }
+///
+/// impl ChunkGenerator<u8> for TerrainGenerator {
+///     fn generate_chunk(&self, _chunk_key: Point3i, chunk: &mut Array3<u8>, _rng: &mut ChunkRng) {
+///         let extent = *chunk.extent();
+///         chunk.for_each_mut(&extent, |p: Point3i, voxel: &mut u8| {
+///             // Blend heights so biome borders don't turn into cliffs.
+///             let height = self.biomes.blend(p.x(), p.z(), 8, |params| params.height) as i32;
+///             if p.y() < height {
+///                 *voxel = self.biomes.params_at(p.x(), p.z()).surface_voxel;
+///             }
+///         });
+///     }
+/// }
+///
+/// let seed = WorldSeed(42);
+/// let biomes = Biomes::new(
+///     BiomeMap::voronoi(seed.derive(1).0, 256, vec![BiomeId(0), BiomeId(1)]),
+///     vec![
+///         BiomeParams { height: 8.0, surface_voxel: 1 },
+///         BiomeParams { height: 40.0, surface_voxel: 2 },
+///     ],
+/// );
+/// let generator = TerrainGenerator { biomes: biomes.clone() };
+/// ```
+pub struct Biomes<P> {
+    pub map: Arc<BiomeMap>,
+    params: Arc<Vec<P>>,
+}
+
+impl<P> Clone for Biomes<P> {
+    fn clone(&self) -> Self {
+        Self {
+            map: self.map.clone(),
+            params: self.params.clone(),
+        }
+    }
+}
+
+impl<P> Biomes<P> {
+    pub fn new(map: BiomeMap, params: Vec<P>) -> Self {
+        Self {
+            map: Arc::new(map),
+            params: Arc::new(params),
+        }
+    }
+
+    pub fn biome_at(&self, x: i32, z: i32) -> BiomeId {
+        self.map.biome_at(x, z)
+    }
+
+    pub fn params(&self, biome: BiomeId) -> &P {
+        &self.params[biome.0 as usize]
+    }
+
+    /// The parameters of the biome of column `(x, z)`.
+    pub fn params_at(&self, x: i32, z: i32) -> &P {
+        self.params(self.biome_at(x, z))
+    }
+
+    /// Blends a numeric parameter over the biomes within `radius` columns of `(x, z)`, weighted as
+    /// in `BiomeMap::blend_weights`.
+    pub fn blend(&self, x: i32, z: i32, radius: i32, param: impl Fn(&P) -> f32) -> f32 {
+        self.map
+            .blend_weights(x, z, radius)
+            .into_iter()
+            .map(|(biome, weight)| weight * param(self.params(biome)))
+            .sum()
+    }
+}
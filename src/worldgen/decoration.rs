@@ -0,0 +1,61 @@
+use super::ChunkRng;
+
+use building_blocks::prelude::*;
+
+/// Places features like trees and ruins after a chunk's terrain is generated. Features may
+/// straddle chunk boundaries, up to `WorldGen::decoration_reach` chunks away: when a chunk is
+/// generated, its neighbors are decorated too, and their writes into it are applied after its own
+/// decorators. Writes into chunks that already exist are dropped, since those chunks got them
+/// when they were generated.
+///
+/// Like the `ChunkGenerator`, decorators run in parallel, so they should only depend on the chunk
+/// they're given and the writer's `rng`.
+pub trait ChunkDecorator<V>: Send + Sync {
+    fn decorate_chunk(&self, writer: &mut DecorationWriter<V>);
+}
+
+/// A chunk being decorated.
+pub struct DecorationWriter<V> {
+    pub(crate) chunk_key: Point3i,
+    pub(crate) chunk: Array3<V>,
+    pub(crate) outside_writes: Vec<(Point3i, V)>,
+    pub(crate) rng: ChunkRng,
+}
+
+impl<V> DecorationWriter<V>
+where
+    V: Copy,
+{
+    pub fn chunk_key(&self) -> Point3i {
+        self.chunk_key
+    }
+
+    /// The current decorator's own RNG stream for this chunk, derived from the `WorldSeed`.
+    pub fn rng(&mut self) -> &mut ChunkRng {
+        &mut self.rng
+    }
+
+    /// The generated chunk, including the writes of earlier decorators.
+    pub fn chunk(&self) -> &Array3<V> {
+        &self.chunk
+    }
+
+    /// Reads a voxel of this chunk. Voxels of other chunks can't be read, since they might not be
+    /// generated yet.
+    pub fn get(&self, p: Point3i) -> Option<V> {
+        if self.chunk.extent().contains(&p) {
+            Some(self.chunk.get(&p))
+        } else {
+            None
+        }
+    }
+
+    /// Writes a voxel in this chunk or any chunk within `WorldGen::decoration_reach` of it.
+    pub fn set(&mut self, p: Point3i, voxel: V) {
+        if self.chunk.extent().contains(&p) {
+            *self.chunk.get_mut(&p) = voxel;
+        } else {
+            self.outside_writes.push((p, voxel));
+        }
+    }
+}
@@ -0,0 +1,96 @@
+use super::{ChunkDecorator, ChunkRng};
+
+use building_blocks::prelude::*;
+use fnv::FnvHashSet;
+use std::{collections::VecDeque, sync::Arc};
+
+/// Generates the terrain of a single chunk. Chunks are generated in parallel on the
+/// `VoxelTaskPool`, so this should only depend on `chunk_key` and `rng`.
+pub trait ChunkGenerator<V>: Send + Sync {
+    /// Fills `chunk`, which covers the chunk's extent and starts out filled with `V::default()`.
+    /// `rng` is derived from the `WorldSeed` and `chunk_key`, so it's the same every time the chunk
+    /// is generated.
+    fn generate_chunk(&self, chunk_key: Point3i, chunk: &mut Array3<V>, rng: &mut ChunkRng);
+}
+
+/// The worldgen pipeline, and the chunks waiting to go through it.
+///
+/// Each requested chunk that doesn't already exist in the `VoxelMap` is:
+///
+/// 1. generated by the `ChunkGenerator`
+/// 2. decorated by each `ChunkDecorator`, in the order they were added
+/// 3. given the writes that the decorators of the chunks within `decoration_reach` make into it,
+///    found by running steps 1 and 2 for those chunks as well
+///
+/// and then inserted with the `VoxelEditor`. The output of the pipeline for a chunk only depends on
+/// the `WorldSeed` and the chunk key, so every client generates the same terrain, in any order.
+pub struct WorldGen<V> {
+    /// The most chunks generated in a single frame.
+    pub max_chunks_per_frame: usize,
+    /// The most chunks a `GeneratingVoxelReader` generates synchronously in a single frame, before
+    /// it falls back to queuing them.
+    pub on_demand_chunks_per_frame: usize,
+    /// How many chunks away from its own chunk, on each axis, a decorator may write. Writes further
+    /// away are dropped. Every generated chunk also decorates the `(2 * reach + 1)^3 - 1` chunks
+    /// around it, so keep this small.
+    pub decoration_reach: i32,
+    pub(crate) on_demand_generated: usize,
+    pub(crate) generator: Arc<dyn ChunkGenerator<V>>,
+    pub(crate) decorators: Vec<Arc<dyn ChunkDecorator<V>>>,
+    pub(crate) queue: VecDeque<Point3i>,
+    queued: FnvHashSet<Point3i>,
+    pub(crate) queued_extents: Vec<Extent3i>,
+}
+
+impl<V> WorldGen<V> {
+    pub(crate) fn new(
+        generator: Arc<dyn ChunkGenerator<V>>,
+        decorators: Vec<Arc<dyn ChunkDecorator<V>>>,
+    ) -> Self {
+        Self {
+            max_chunks_per_frame: 16,
+            on_demand_chunks_per_frame: 4,
+            decoration_reach: 1,
+            on_demand_generated: 0,
+            generator,
+            decorators,
+            queue: VecDeque::new(),
+            queued: Default::default(),
+            queued_extents: Vec::new(),
+        }
+    }
+
+    pub fn generator(&self) -> &Arc<dyn ChunkGenerator<V>> {
+        &self.generator
+    }
+
+    /// Generates the chunk at `chunk_key` if it doesn't exist. Chunks are generated in request
+    /// order.
+    pub fn generate_chunk(&mut self, chunk_key: Point3i) {
+        if self.queued.insert(chunk_key) {
+            self.queue.push_back(chunk_key);
+        }
+    }
+
+    /// Generates every missing chunk overlapping `extent`.
+    pub fn generate_extent(&mut self, extent: Extent3i) {
+        self.queued_extents.push(extent);
+    }
+
+    /// The number of chunks waiting to be generated, not counting requested extents that haven't
+    /// been split into chunks yet.
+    pub fn num_queued_chunks(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.queue.is_empty() && self.queued_extents.is_empty()
+    }
+
+    pub(crate) fn pop_queued_chunk(&mut self) -> Option<Point3i> {
+        let chunk_key = self.queue.pop_front()?;
+        self.queued.remove(&chunk_key);
+
+        Some(chunk_key)
+    }
+}
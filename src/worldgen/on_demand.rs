@@ -0,0 +1,111 @@
+use super::{plugin::generate_chunks, WorldGen, WorldSeed};
+
+use crate::{Voxel, VoxelEditor, VoxelTaskPool};
+
+use bevy::ecs::{prelude::*, SystemParam};
+use building_blocks::prelude::*;
+
+/// A `SystemParam` for reading voxels that generates missing chunks as they're accessed, so
+/// callers don't have to handle chunks that haven't been generated yet. Depends on the
+/// `WorldGenPlugin`.
+///
+/// Up to `WorldGen::on_demand_chunks_per_frame` missing chunks are generated each frame, right
+/// away while the calling system waits, and inserted with the `VoxelEditor`. Reads see them
+/// immediately. Past that budget, missing chunks are queued in the `WorldGen` like any other
+/// request, and reads return the map's ambient value until they're generated. Set the budget to 0
+/// to always queue.
+///
+/// ```
+/// use bevy_building_blocks::{bb::prelude::*, GeneratingVoxelReader, Voxel};
+///
+/// fn spawn_system<V: Voxel>(mut voxel_reader: GeneratingVoxelReader<V>) {
+///     // The spawn point may be far outside of the generated world.
+///     let ground = voxel_reader.get(PointN([5000, 0, 5000]));
+/// }
+/// ```
+#[derive(SystemParam)]
+pub struct GeneratingVoxelReader<'a, V: Voxel> {
+    pub voxel_editor: VoxelEditor<'a, V>,
+    world_gen: ResMut<'a, WorldGen<V>>,
+    seed: Res<'a, WorldSeed>,
+    pool: Res<'a, VoxelTaskPool>,
+}
+
+impl<'a, V> GeneratingVoxelReader<'a, V>
+where
+    V: Voxel,
+{
+    pub fn get(&mut self, p: Point3i) -> V {
+        let chunk_key = self
+            .voxel_editor
+            .map
+            .voxels
+            .indexer
+            .chunk_key_containing_point(&p);
+        self.generate_if_missing(chunk_key);
+
+        let editor = &self.voxel_editor;
+        let tls = editor.local_cache.get();
+        let reader = editor.map.reader(&tls);
+        match editor.edited_chunk(chunk_key) {
+            Some(chunk) if reader.get_chunk(chunk_key).is_none() => chunk.get(&p),
+            _ => reader.get(&p),
+        }
+    }
+
+    /// Like `VoxelReader::for_each`, except that points are visited one chunk at a time.
+    pub fn for_each(&mut self, extent: &Extent3i, mut f: impl FnMut(Point3i, V)) {
+        let chunk_keys: Vec<Point3i> = self
+            .voxel_editor
+            .map
+            .voxels
+            .indexer
+            .chunk_keys_for_extent(extent)
+            .collect();
+        for chunk_key in chunk_keys.iter() {
+            self.generate_if_missing(*chunk_key);
+        }
+
+        let editor = &self.voxel_editor;
+        let tls = editor.local_cache.get();
+        let reader = editor.map.reader(&tls);
+        for chunk_key in chunk_keys.into_iter() {
+            let piece = extent.intersection(&reader.indexer.extent_for_chunk_at_key(chunk_key));
+            // Chunks generated on demand are only in the edit buffer until the end of the frame.
+            match editor.edited_chunk(chunk_key) {
+                Some(chunk) if reader.get_chunk(chunk_key).is_none() => {
+                    chunk.for_each(&piece, &mut f)
+                }
+                _ => reader.for_each(&piece, &mut f),
+            }
+        }
+    }
+
+    fn generate_if_missing(&mut self, chunk_key: Point3i) {
+        if self.voxel_editor.chunk_exists(chunk_key)
+            || !self.voxel_editor.chunk_in_bounds(chunk_key)
+        {
+            return;
+        }
+
+        let world_gen = &mut *self.world_gen;
+        if world_gen.on_demand_generated >= world_gen.on_demand_chunks_per_frame {
+            world_gen.generate_chunk(chunk_key);
+            return;
+        }
+        world_gen.on_demand_generated += 1;
+
+        let chunk_shape = self.voxel_editor.map.voxels.indexer.chunk_shape();
+        let generated = generate_chunks(
+            &*self.pool,
+            world_gen,
+            *self.seed,
+            chunk_shape,
+            vec![chunk_key],
+        );
+        for (chunk_key, chunk) in generated.into_iter() {
+            self.voxel_editor
+                .insert_chunk_and_touch_neighbors(chunk_key, chunk);
+        }
+    }
+}
@@ -0,0 +1,220 @@
+use super::{ChunkDecorator, ChunkGenerator, DecorationWriter, WorldGen, WorldSeed};
+
+use crate::{default_array, tasks::map_in_pool, Voxel, VoxelEditor, VoxelTaskPool};
+
+use bevy::{prelude::*, tasks::TaskPool};
+use building_blocks::prelude::*;
+use fnv::{FnvHashMap, FnvHashSet};
+use std::sync::Arc;
+
+/// Manages the `WorldGen` resource, which generates requested chunks on the `VoxelTaskPool` and
+/// inserts them with the `VoxelEditor`. Depends on the `MapIoPlugin`.
+///
+/// The plugin also inserts the `WorldSeed` resource, which is 0 unless set with `with_seed`.
+pub struct WorldGenPlugin<V> {
+    generator: Arc<dyn ChunkGenerator<V>>,
+    decorators: Vec<Arc<dyn ChunkDecorator<V>>>,
+    seed: WorldSeed,
+}
+
+impl<V> WorldGenPlugin<V> {
+    pub fn new(generator: impl ChunkGenerator<V> + 'static) -> Self {
+        Self {
+            generator: Arc::new(generator),
+            decorators: Vec::new(),
+            seed: WorldSeed::default(),
+        }
+    }
+
+    pub fn with_seed(mut self, seed: WorldSeed) -> Self {
+        self.seed = seed;
+
+        self
+    }
+
+    /// Adds a decorator that runs after the decorators added before it.
+    pub fn with_decorator(mut self, decorator: impl ChunkDecorator<V> + 'static) -> Self {
+        self.decorators.push(Arc::new(decorator));
+
+        self
+    }
+}
+
+impl<V> Plugin for WorldGenPlugin<V>
+where
+    V: Voxel,
+{
+    fn build(&self, app: &mut AppBuilder) {
+        app.insert_resource(self.seed)
+            .insert_resource(WorldGen::new(
+                self.generator.clone(),
+                self.decorators.clone(),
+            ))
+            .add_system(world_gen_system::<V>.system());
+    }
+}
+
+fn world_gen_system<V>(
+    pool: Res<VoxelTaskPool>,
+    seed: Res<WorldSeed>,
+    mut world_gen: ResMut<WorldGen<V>>,
+    mut voxel_editor: VoxelEditor<V>,
+) where
+    V: Voxel,
+{
+    let world_gen = &mut *world_gen;
+    world_gen.on_demand_generated = 0;
+
+    let queued_extents = std::mem::replace(&mut world_gen.queued_extents, Vec::new());
+    for extent in queued_extents.iter() {
+        for chunk_key in voxel_editor
+            .map
+            .voxels
+            .indexer
+            .chunk_keys_for_extent(extent)
+        {
+            world_gen.generate_chunk(chunk_key);
+        }
+    }
+
+    // Take a batch of chunks that don't exist yet.
+    let mut batch = Vec::new();
+    while batch.len() < world_gen.max_chunks_per_frame {
+        let chunk_key = match world_gen.pop_queued_chunk() {
+            Some(k) => k,
+            None => break,
+        };
+        // Chunks that were generated on demand this frame are already in the edit buffer.
+        if !voxel_editor.chunk_exists(chunk_key) && voxel_editor.chunk_in_bounds(chunk_key) {
+            batch.push(chunk_key);
+        }
+    }
+    if batch.is_empty() {
+        return;
+    }
+
+    let chunk_shape = voxel_editor.map.voxels.indexer.chunk_shape();
+    for (chunk_key, chunk) in generate_chunks(&*pool, world_gen, *seed, chunk_shape, batch) {
+        voxel_editor.insert_chunk_and_touch_neighbors(chunk_key, chunk);
+    }
+}
+
+/// Runs the worldgen pipeline for each of the chunks at `chunk_keys`, on the `pool`.
+///
+/// The decorators of every chunk within `WorldGen::decoration_reach` run as well, and their writes
+/// into a chunk are applied after its own decorators, in a fixed order. All other writes that leave
+/// a chunk are dropped, since the chunks they land in derive them the same way. So each chunk only
+/// depends on the `WorldSeed` and its key, no matter how often or in what order it's generated.
+pub(crate) fn generate_chunks<V>(
+    pool: &TaskPool,
+    world_gen: &WorldGen<V>,
+    seed: WorldSeed,
+    chunk_shape: Point3i,
+    chunk_keys: Vec<Point3i>,
+) -> Vec<(Point3i, Array3<V>)>
+where
+    V: Voxel,
+{
+    let reach = world_gen.decoration_reach;
+    let requested: FnvHashSet<Point3i> = chunk_keys.iter().cloned().collect();
+    let mut decorated_keys = requested.clone();
+    for chunk_key in chunk_keys.iter() {
+        decorated_keys.extend(neighbor_chunk_keys(*chunk_key, chunk_shape, reach));
+    }
+
+    let generator = &*world_gen.generator;
+    let decorators = &world_gen.decorators;
+    let requested = &requested;
+    let decorated = map_in_pool(pool, decorated_keys, |chunk_key| {
+        let writer = decorate_chunk(
+            generator,
+            decorators,
+            seed,
+            chunk_key,
+            Extent3i::from_min_and_shape(chunk_key, chunk_shape),
+        );
+        // Only the outside writes of the neighbors are needed.
+        let chunk = if requested.contains(&chunk_key) {
+            Some(writer.chunk)
+        } else {
+            None
+        };
+
+        (chunk_key, chunk, writer.outside_writes)
+    });
+
+    let mut chunks = FnvHashMap::default();
+    let mut outside_writes = FnvHashMap::default();
+    for (chunk_key, chunk, writes) in decorated.into_iter() {
+        if let Some(chunk) = chunk {
+            chunks.insert(chunk_key, chunk);
+        }
+        outside_writes.insert(chunk_key, writes);
+    }
+
+    chunk_keys
+        .into_iter()
+        .filter_map(|chunk_key| {
+            let mut chunk = chunks.remove(&chunk_key)?;
+            let extent = *chunk.extent();
+            for neighbor_key in neighbor_chunk_keys(chunk_key, chunk_shape, reach) {
+                for (p, voxel) in outside_writes[&neighbor_key].iter() {
+                    if extent.contains(p) {
+                        *chunk.get_mut(p) = *voxel;
+                    }
+                }
+            }
+
+            Some((chunk_key, chunk))
+        })
+        .collect()
+}
+
+/// Generates and decorates a single chunk, without the writes of its neighbors' decorators.
+fn decorate_chunk<V>(
+    generator: &dyn ChunkGenerator<V>,
+    decorators: &[Arc<dyn ChunkDecorator<V>>],
+    seed: WorldSeed,
+    chunk_key: Point3i,
+    chunk_extent: Extent3i,
+) -> DecorationWriter<V>
+where
+    V: Voxel,
+{
+    let mut chunk = default_array(chunk_extent);
+    generator.generate_chunk(chunk_key, &mut chunk, &mut seed.chunk_rng(chunk_key));
+
+    let mut writer = DecorationWriter {
+        chunk_key,
+        chunk,
+        outside_writes: Vec::new(),
+        rng: seed.chunk_rng(chunk_key),
+    };
+    for (i, decorator) in decorators.iter().enumerate() {
+        writer.rng = seed.chunk_stream_rng(chunk_key, i as u64 + 1);
+        decorator.decorate_chunk(&mut writer);
+    }
+
+    writer
+}
+
+/// The keys of the chunks within `reach` chunks of `chunk_key` on every axis, not including
+/// `chunk_key` itself, always in the same order.
+fn neighbor_chunk_keys(chunk_key: Point3i, chunk_shape: Point3i, reach: i32) -> Vec<Point3i> {
+    let mut keys = Vec::new();
+    for z in -reach..=reach {
+        for y in -reach..=reach {
+            for x in -reach..=reach {
+                if [x, y, z] != [0; 3] {
+                    keys.push(PointN([
+                        chunk_key.x() + x * chunk_shape.x(),
+                        chunk_key.y() + y * chunk_shape.y(),
+                        chunk_key.z() + z * chunk_shape.z(),
+                    ]));
+                }
+            }
+        }
+    }
+
+    keys
+}
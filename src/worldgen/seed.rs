@@ -0,0 +1,83 @@
+use building_blocks::core::Point3i;
+
+/// The seed of the whole world, inserted as a resource by the `WorldGenPlugin`. Every chunk's
+/// `ChunkRng` is derived from this seed and the chunk key, so generating the same chunk with the
+/// same seed always gives the same result, on any machine and in any order.
+///
+/// Changing the resource only affects chunks generated afterwards.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub struct WorldSeed(pub u64);
+
+impl WorldSeed {
+    /// An independent seed for a subsystem, e.g. a `BiomeMap`, so it doesn't share random numbers
+    /// with the terrain.
+    pub fn derive(&self, stream: u64) -> WorldSeed {
+        WorldSeed(mix64(self.0 ^ mix64(stream)))
+    }
+
+    /// The RNG for generating the chunk at `chunk_key`.
+    pub fn chunk_rng(&self, chunk_key: Point3i) -> ChunkRng {
+        self.chunk_stream_rng(chunk_key, 0)
+    }
+
+    /// Like `chunk_rng`, but for one of several independent streams in the same chunk. Each
+    /// `ChunkDecorator` gets its own stream, so adding a decorator doesn't change the random
+    /// numbers seen by the others.
+    pub fn chunk_stream_rng(&self, chunk_key: Point3i, stream: u64) -> ChunkRng {
+        let [x, y, z] = chunk_key.0;
+        let mut h = self.derive(stream).0;
+        for c in [x, y, z].iter() {
+            h = mix64(h ^ (*c as u32 as u64));
+        }
+
+        ChunkRng { state: h }
+    }
+}
+
+/// A small, fast, deterministic RNG (SplitMix64). Not suitable for cryptography.
+#[derive(Clone, Debug)]
+pub struct ChunkRng {
+    state: u64,
+}
+
+impl ChunkRng {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+
+        mix64(self.state)
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        (self.next_u64() >> 32) as u32
+    }
+
+    /// A uniformly distributed number in `[0, 1)`.
+    pub fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    /// A uniformly distributed number in `[min, max)`. Panics if the range is empty.
+    pub fn range_i32(&mut self, min: i32, max: i32) -> i32 {
+        assert!(min < max);
+        let span = (max as i64 - min as i64) as u64;
+
+        (min as i64 + (self.next_u64() % span) as i64) as i32
+    }
+
+    /// `true` with probability `p`.
+    pub fn chance(&mut self, p: f32) -> bool {
+        self.next_f32() < p
+    }
+}
+
+/// The SplitMix64 finalizer.
+pub(crate) fn mix64(mut h: u64) -> u64 {
+    h = (h ^ (h >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    h = (h ^ (h >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+
+    h ^ (h >> 31)
+}